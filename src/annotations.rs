@@ -0,0 +1,41 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::collections::BTreeMap;
+
+/// Source of a line annotation. Later kinds take precedence when drawing overlapping marks
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) enum AnnotationKind {
+    Search,
+}
+
+/// Registry of annotated lines in a buffer, grouped by source. Each source replaces its lines as
+/// a whole whenever they change, so that whatever displays them (scrollbar, gutter) doesn't need
+/// to know where they came from
+#[derive(Debug, Default)]
+pub(crate) struct Annotations {
+    lines: BTreeMap<AnnotationKind, Vec<usize>>,
+}
+
+impl Annotations {
+    /// Replace annotated lines for given kind. Lines should be sorted
+    pub(crate) fn set(&mut self, kind: AnnotationKind, lines: Vec<usize>) {
+        if lines.len() == 0 {
+            self.lines.remove(&kind);
+        } else {
+            self.lines.insert(kind, lines);
+        }
+    }
+
+    pub(crate) fn clear(&mut self, kind: AnnotationKind) {
+        self.lines.remove(&kind);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Iterate over annotated lines, in increasing order of precedence
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (AnnotationKind, &[usize])> {
+        self.lines.iter().map(|(k, v)| (*k, v.as_slice()))
+    }
+}
@@ -0,0 +1,111 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use euclid::size2;
+
+use crate::config::Cfg;
+use crate::core::Core;
+use crate::font::FontCore;
+use crate::substitute::{substitute_lines, Substitution};
+use crate::textbuffer::Buffer;
+use crate::ui::commands::{resolve_range, CommandArgs};
+use crate::ui::Wakeup;
+
+// Lines are still shaped without a window, at this DPI
+const BATCH_DPI: u32 = 96;
+
+/// Run commands on each file in turn without opening a window, for bed --batch. Only commands
+/// that edit or write a buffer are supported, and files are only written by :w, :wq or :x.
+/// Returns the exit code, which is 1 if a file couldn't be opened or a command failed
+pub(crate) fn run(
+    files: &[&str],
+    commands: &[&str],
+    font_core: Rc<RefCell<FontCore>>,
+    config: Rc<RefCell<Cfg>>,
+) -> i32 {
+    let mut core = Core::new(font_core, config, Wakeup::none());
+    let dpi = size2(BATCH_DPI, BATCH_DPI);
+    let mut failed = false;
+    for file in files {
+        let buffer = match core.new_buffer_from_file(file, dpi) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                error!("failed to open file: {}: {}", file, e);
+                failed = true;
+                continue;
+            }
+        };
+        let buffer = &mut *buffer.borrow_mut();
+        for command in commands {
+            match run_command(buffer, command) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    error!("{}: {}: {}", file, command, e);
+                    failed = true;
+                    break;
+                }
+            }
+        }
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+// Run command line on buffer. Addresses are relative to the first line. Returns false if the
+// rest of the commands are to be skipped for this file
+fn run_command(buffer: &mut Buffer, line: &str) -> Result<bool, String> {
+    let args = match CommandArgs::parse(line) {
+        Some(args) => args,
+        None => return Ok(true),
+    };
+    let last = buffer.len_lines().saturating_sub(1);
+    let range = match &args.range {
+        Some(range) => {
+            Some(resolve_range(range, 0, last).ok_or(format!("invalid range: {}", range))?)
+        }
+        None => None,
+    };
+    match args.name.as_str() {
+        ":s" => {
+            let substitution = Substitution::parse(&args.args.join(" "), None)?;
+            if substitution.confirm {
+                return Err("can't ask for confirmation in batch mode".to_owned());
+            }
+            let (start, end) = range.unwrap_or((0, 0));
+            let (changes, _) = substitute_lines(buffer, &substitution, start, end, 0);
+            buffer.replace_lines(&changes);
+        }
+        ":sort" => {
+            let (start, end) = range.unwrap_or((0, last));
+            buffer.sort_lines(start, end, args.bang);
+        }
+        ":w" | ":write" => write(buffer, args.arg(0))?,
+        ":wq" | ":x" => {
+            write(buffer, args.arg(0))?;
+            return Ok(false);
+        }
+        ":q" | ":quit" => {
+            if !args.bang && buffer.is_dirty() {
+                return Err("buffer has unwritten changes (add ! to override)".to_owned());
+            }
+            return Ok(false);
+        }
+        name => return Err(format!("not supported in batch mode: {}", name)),
+    }
+    Ok(true)
+}
+
+fn write(buffer: &mut Buffer, path: Option<&str>) -> Result<(), String> {
+    match buffer.write_to_file(path) {
+        // Remote files are written in the background
+        Some(Ok(())) => buffer.wait_for_write().map_err(|e| e.to_string()),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Err("buffer has no path".to_owned()),
+    }
+}
@@ -0,0 +1,203 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+
+use regex::Regex;
+
+use crate::ui::{Wakeup, WakingSender};
+
+enum BuildEvent {
+    Line(String),
+    // Line of standard error, if it is kept apart from the output
+    ErrLine(String),
+    // Exit code, which is None if the process was killed by a signal
+    Exited(Option<i32>),
+    Failed(String),
+}
+
+/// Build command running in the background, with the output collected so far
+pub(crate) struct Build {
+    command: String,
+    rx: Receiver<BuildEvent>,
+    output: Vec<String>,
+    errors: Vec<String>,
+    result: Option<Result<Option<i32>, String>>,
+}
+
+impl Build {
+    /// Run command with the shell in dir. Standard output and standard error are collected
+    /// together. The main loop is woken up with wakeup when there is output
+    pub(crate) fn start(command: &str, dir: &Path, wakeup: Wakeup) -> Build {
+        Build::spawn(command, dir, wakeup, BuildEvent::Line)
+    }
+
+    /// Run command with the shell in dir, collecting standard error apart from the output
+    pub(crate) fn start_separate(command: &str, dir: &Path, wakeup: Wakeup) -> Build {
+        Build::spawn(command, dir, wakeup, BuildEvent::ErrLine)
+    }
+
+    // Standard error lines are sent as events made by stderr_event
+    fn spawn(
+        command: &str,
+        dir: &Path,
+        wakeup: Wakeup,
+        stderr_event: fn(String) -> BuildEvent,
+    ) -> Build {
+        let (tx, rx) = wakeup.channel();
+        let mut cmd = shell_command(command);
+        cmd.current_dir(dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        thread::spawn(move || {
+            let mut child = match cmd.spawn() {
+                Ok(child) => child,
+                Err(e) => {
+                    let _ = tx.send(BuildEvent::Failed(e.to_string()));
+                    return;
+                }
+            };
+            let stderr = child
+                .stderr
+                .take()
+                .map(|stderr| forward_lines(stderr, tx.clone(), stderr_event));
+            if let Some(stdout) = child.stdout.take() {
+                send_lines(stdout, &tx, BuildEvent::Line);
+            }
+            if let Some(stderr) = stderr {
+                let _ = stderr.join();
+            }
+            let _ = tx.send(match child.wait() {
+                Ok(status) => BuildEvent::Exited(status.code()),
+                Err(e) => BuildEvent::Failed(e.to_string()),
+            });
+        });
+        Build {
+            command: command.to_owned(),
+            rx: rx,
+            output: Vec::new(),
+            errors: Vec::new(),
+            result: None,
+        }
+    }
+
+    pub(crate) fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Collect output printed since the last poll. Returns true once the build has finished
+    pub(crate) fn poll(&mut self) -> bool {
+        while self.result.is_none() {
+            match self.rx.try_recv() {
+                Ok(BuildEvent::Line(line)) => self.output.push(line),
+                Ok(BuildEvent::ErrLine(line)) => self.errors.push(line),
+                Ok(BuildEvent::Exited(code)) => self.result = Some(Ok(code)),
+                Ok(BuildEvent::Failed(e)) => self.result = Some(Err(e)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.result = Some(Err("build thread stopped".to_owned()))
+                }
+            }
+        }
+        self.result.is_some()
+    }
+
+    /// Standard output, or both standard output and standard error if they are collected
+    /// together
+    pub(crate) fn output(&self) -> &[String] {
+        &self.output
+    }
+
+    /// Standard error, if it is collected apart from the output
+    pub(crate) fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// Whether the build finished with exit code 0
+    pub(crate) fn succeeded(&self) -> bool {
+        match &self.result {
+            Some(Ok(Some(0))) => true,
+            _ => false,
+        }
+    }
+
+    /// Output followed by a line describing how the build ended
+    pub(crate) fn report(&self) -> Vec<String> {
+        let mut ret = self.output.clone();
+        ret.extend(self.errors.iter().cloned());
+        ret.push(self.status());
+        ret
+    }
+
+    /// How the build ended, like "[exited with code 1]"
+    pub(crate) fn status(&self) -> String {
+        match &self.result {
+            None => "[running]".to_owned(),
+            Some(Ok(Some(code))) => format!("[exited with code {}]", code),
+            Some(Ok(None)) => "[killed]".to_owned(),
+            Some(Err(e)) => format!("[failed to run: {}]", e),
+        }
+    }
+
+    /// Locations of errors and warnings in the output, like "path:line:col: message", as
+    /// "message [path:line]". Lines pointing at a location without a message, like rustc's
+    /// "--> path:line:col", use the closest line above with text
+    pub(crate) fn locations(&self) -> Vec<String> {
+        let re = Regex::new(r"^\s*(?:--> )?([^\s:][^:]*):(\d+)(?::\d+)?:?\s*(.*)$").unwrap();
+        let mut ret = Vec::new();
+        let mut last_message = "";
+        for line in &self.output {
+            if let Some(caps) = re.captures(line) {
+                let message = caps.get(3).unwrap().as_str();
+                let message = if message.is_empty() {
+                    last_message
+                } else {
+                    message
+                };
+                ret.push(format!("{} [{}:{}]", message, &caps[1], &caps[2]));
+            } else if !line.trim().is_empty() {
+                last_message = line.trim();
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn shell_command(command: &str) -> Command {
+    let mut ret = Command::new("sh");
+    ret.arg("-c").arg(command);
+    ret
+}
+
+#[cfg(target_os = "windows")]
+fn shell_command(command: &str) -> Command {
+    let mut ret = Command::new("cmd");
+    ret.arg("/C").arg(command);
+    ret
+}
+
+fn forward_lines<R: Read + Send + 'static>(
+    r: R,
+    tx: WakingSender<BuildEvent>,
+    event: fn(String) -> BuildEvent,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || send_lines(r, &tx, event))
+}
+
+fn send_lines<R: Read>(r: R, tx: &WakingSender<BuildEvent>, event: fn(String) -> BuildEvent) {
+    for line in BufReader::new(r).lines() {
+        match line {
+            Ok(line) => {
+                if tx.send(event(line)).is_err() {
+                    return;
+                }
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -2,13 +2,16 @@
 
 use std::collections::HashMap;
 use std::default::Default;
-use std::fs::read_to_string;
-use std::path::Path;
+use std::fs::{create_dir_all, read_to_string, write};
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
 
-use directories::ProjectDirs;
+use directories::{BaseDirs, ProjectDirs};
+use regex::Regex;
 use yaml_rust::yaml::{Yaml, YamlLoader};
 
-use crate::font::{FaceKey, FontCore};
+use crate::font::{FaceKey, FontCore, Hinting, RasterOptions};
+use crate::search::{CaseSensitivity, SearchOptions};
 use crate::types::{Color, TextSize, TextSlant, TextStyle, TextWeight};
 
 #[cfg(target_os = "linux")]
@@ -22,13 +25,38 @@ const VARIABLE_FONT: &'static str = "sans";
 const VARIABLE_FONT: &'static str = "Arial";
 
 const TEXT_SIZE: f64 = 8.0;
+const SHAPED_CACHE_BUDGET_MB: i64 = 256;
+const LARGE_FILE_MB: i64 = 8;
+const FLASH_DURATION_MS: i64 = 300;
 const GUTTER_TEXT_SIZE: f64 = 7.0;
 
+// Written to the config file when it is first opened
+const CONFIG_TEMPLATE: &str = include_str!("../res/config_template.yml");
+// Built-in language settings, which the config file can override
+const SYNTAX_DEFAULTS: &str = include_str!("../res/syntax.yml");
+
 #[derive(Debug)]
 pub(crate) struct CfgUiTextview {
     pub(crate) text_size: TextSize,
     pub(crate) fixed_face: FaceKey,
     pub(crate) variable_face: FaceKey,
+    pub(crate) shaped_cache_budget: usize,
+    pub(crate) color_swatches: bool,
+    // Files bigger than this aren't opened without asking
+    pub(crate) large_file_size: u64,
+    // Milliseconds yanked lines and deleted lines are highlighted for. 0 disables the highlight
+    pub(crate) flash_duration: u64,
+    pub(crate) scrollbar: bool,
+    // Paging moves the cursor along with the view, instead of only keeping it in view
+    pub(crate) page_moves_cursor: bool,
+    // How patterns searched for with / are matched
+    pub(crate) search: SearchOptions,
+    // Faces tried, in order, for characters missing from the configured faces
+    pub(crate) fallback_faces: Vec<FaceKey>,
+    // Column a rule is drawn after in new panes, or 0 for none
+    pub(crate) color_column: usize,
+    // Highlight the cursor line in new panes
+    pub(crate) cursor_line: bool,
 }
 
 impl CfgUiTextview {
@@ -36,24 +64,66 @@ impl CfgUiTextview {
         let text_size = TextSize::from_f32(yaml["text_size"].as_f64().unwrap_or(TEXT_SIZE) as f32);
         let fixed_face_names = yaml["fixed_face"].as_str().unwrap_or(FIXED_FONT);
         let variable_face_names = yaml["variable_face"].as_str().unwrap_or(VARIABLE_FONT);
-        let fixed_face =
-            face_from_str(fixed_face_names, font_core).expect("failed to get fixed face");
-        let variable_face =
-            face_from_str(variable_face_names, font_core).expect("failed to get variable face");
+        let fixed_face = face_from_str(fixed_face_names, FIXED_FONT, font_core);
+        let variable_face = face_from_str(variable_face_names, VARIABLE_FONT, font_core);
+        let budget = yaml["shaped_cache_budget_mb"]
+            .as_i64()
+            .unwrap_or(SHAPED_CACHE_BUDGET_MB)
+            .max(0) as usize;
+        let large_file_mb = yaml["large_file_mb"]
+            .as_i64()
+            .unwrap_or(LARGE_FILE_MB)
+            .max(0) as u64;
+        let fallback_faces = match yaml["fallback_faces"].as_vec() {
+            Some(v) => v
+                .iter()
+                .filter_map(|f| f.as_str())
+                .filter_map(|name| fallback_face(name, font_core))
+                .collect(),
+            None => Vec::new(),
+        };
+        font_core.set_fallback_faces(fallback_faces.clone());
         CfgUiTextview {
             text_size: text_size,
             fixed_face: fixed_face,
             variable_face: variable_face,
+            shaped_cache_budget: budget * 1024 * 1024,
+            color_swatches: yaml["color_swatches"].as_bool().unwrap_or(true),
+            large_file_size: large_file_mb * 1024 * 1024,
+            flash_duration: yaml["flash_ms"]
+                .as_i64()
+                .unwrap_or(FLASH_DURATION_MS)
+                .max(0) as u64,
+            scrollbar: yaml["scrollbar"].as_bool().unwrap_or(true),
+            page_moves_cursor: yaml["page_moves_cursor"].as_bool().unwrap_or(true),
+            search: SearchOptions {
+                regex: yaml["search_regex"].as_bool().unwrap_or(false),
+                case: case_sensitivity_from_yaml(&yaml["search_case"]),
+            },
+            fallback_faces: fallback_faces,
+            color_column: yaml["color_column"].as_i64().unwrap_or(0).max(0) as usize,
+            cursor_line: yaml["cursor_line"].as_bool().unwrap_or(false),
         }
     }
 
     fn default(fc: &mut FontCore) -> CfgUiTextview {
         let fixed = fc.find(FIXED_FONT).expect("failed to get fixed face");
         let variable = fc.find(VARIABLE_FONT).expect("failed to get variable face");
+        fc.set_fallback_faces(Vec::new());
         CfgUiTextview {
             text_size: TextSize::from_f32(TEXT_SIZE as f32),
             fixed_face: fixed,
             variable_face: variable,
+            shaped_cache_budget: SHAPED_CACHE_BUDGET_MB as usize * 1024 * 1024,
+            color_swatches: true,
+            large_file_size: LARGE_FILE_MB as u64 * 1024 * 1024,
+            flash_duration: FLASH_DURATION_MS as u64,
+            scrollbar: true,
+            page_moves_cursor: true,
+            search: SearchOptions::default(),
+            fallback_faces: Vec::new(),
+            color_column: 0,
+            cursor_line: false,
         }
     }
 }
@@ -64,6 +134,10 @@ pub(crate) struct CfgUiGutter {
     pub(crate) fixed_face: FaceKey,
     pub(crate) variable_face: FaceKey,
     pub(crate) padding: u32,
+    /// Line numbers with more digits are truncated, so that the gutter doesn't get wider. 0 for
+    /// no limit
+    pub(crate) max_digits: usize,
+    pub(crate) ellipsis: CfgUiGutterEllipsis,
 }
 
 impl CfgUiGutter {
@@ -72,16 +146,16 @@ impl CfgUiGutter {
             TextSize::from_f32(yaml["text_size"].as_f64().unwrap_or(GUTTER_TEXT_SIZE) as f32);
         let fixed_face_names = yaml["fixed_face"].as_str().unwrap_or(FIXED_FONT);
         let variable_face_names = yaml["variable_face"].as_str().unwrap_or(VARIABLE_FONT);
-        let fixed_face =
-            face_from_str(fixed_face_names, font_core).expect("failed to get fixed face");
-        let variable_face =
-            face_from_str(variable_face_names, font_core).expect("failed to get variable face");
-        let padding = yaml["padding"].as_i64().unwrap_or(10) as u32;
+        let fixed_face = face_from_str(fixed_face_names, FIXED_FONT, font_core);
+        let variable_face = face_from_str(variable_face_names, VARIABLE_FONT, font_core);
+        let padding = yaml["padding"].as_i64().unwrap_or(10).max(0) as u32;
         CfgUiGutter {
             text_size: text_size,
             fixed_face: fixed_face,
             variable_face: variable_face,
             padding: padding,
+            max_digits: yaml["max_digits"].as_i64().unwrap_or(0).max(0) as usize,
+            ellipsis: CfgUiGutterEllipsis::from_yaml(&yaml["ellipsis"]),
         }
     }
 
@@ -93,6 +167,64 @@ impl CfgUiGutter {
             fixed_face: fixed,
             variable_face: variable,
             padding: 10,
+            max_digits: 0,
+            ellipsis: CfgUiGutterEllipsis::Start,
+        }
+    }
+}
+
+/// Which end of a truncated line number is replaced by an ellipsis
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CfgUiGutterEllipsis {
+    Start,
+    End,
+}
+
+impl CfgUiGutterEllipsis {
+    fn from_yaml(yaml: &Yaml) -> CfgUiGutterEllipsis {
+        match yaml.as_str() {
+            Some("end") => CfgUiGutterEllipsis::End,
+            _ => CfgUiGutterEllipsis::Start,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CfgUiStatusline {
+    pub(crate) enabled: bool,
+    pub(crate) text_size: TextSize,
+    pub(crate) fixed_face: FaceKey,
+    pub(crate) variable_face: FaceKey,
+    pub(crate) padding: u32,
+}
+
+impl CfgUiStatusline {
+    fn from_yaml(yaml: &Yaml, font_core: &mut FontCore) -> CfgUiStatusline {
+        let text_size =
+            TextSize::from_f32(yaml["text_size"].as_f64().unwrap_or(GUTTER_TEXT_SIZE) as f32);
+        let fixed_face_names = yaml["fixed_face"].as_str().unwrap_or(FIXED_FONT);
+        let variable_face_names = yaml["variable_face"].as_str().unwrap_or(VARIABLE_FONT);
+        let fixed_face = face_from_str(fixed_face_names, FIXED_FONT, font_core);
+        let variable_face = face_from_str(variable_face_names, VARIABLE_FONT, font_core);
+        let padding = yaml["padding"].as_i64().unwrap_or(4).max(0) as u32;
+        CfgUiStatusline {
+            enabled: yaml["enabled"].as_bool().unwrap_or(true),
+            text_size: text_size,
+            fixed_face: fixed_face,
+            variable_face: variable_face,
+            padding: padding,
+        }
+    }
+
+    fn default(fc: &mut FontCore) -> CfgUiStatusline {
+        let fixed = fc.find(FIXED_FONT).expect("failed to get fixed face");
+        let variable = fc.find(VARIABLE_FONT).expect("failed to get variable face");
+        CfgUiStatusline {
+            enabled: true,
+            text_size: TextSize::from_f32(GUTTER_TEXT_SIZE as f32),
+            fixed_face: fixed,
+            variable_face: variable,
+            padding: 4,
         }
     }
 }
@@ -105,7 +237,12 @@ pub(crate) struct CfgUiFuzzy {
     pub(crate) max_height_percentage: u32,
     pub(crate) width_percentage: u32,
     pub(crate) line_spacing: u32,
-    pub(crate) bottom_offset: u32,
+    // Distance from the window edge the popup is placed against. Unused when it's centered
+    pub(crate) edge_offset: u32,
+    pub(crate) placement: CfgUiFuzzyPlacement,
+    // Show at most this many results, if set. Otherwise as many as fit in max_height_percentage
+    pub(crate) max_results: Option<usize>,
+    pub(crate) input_position: CfgUiFuzzyInput,
 }
 
 impl CfgUiFuzzy {
@@ -113,14 +250,21 @@ impl CfgUiFuzzy {
         let text_size = TextSize::from_f32(yaml["text_size"].as_f64().unwrap_or(TEXT_SIZE) as f32);
         let fixed_face_names = yaml["fixed_face"].as_str().unwrap_or(FIXED_FONT);
         let variable_face_names = yaml["variable_face"].as_str().unwrap_or(VARIABLE_FONT);
-        let fixed_face =
-            face_from_str(fixed_face_names, font_core).expect("failed to get fixed face");
-        let variable_face =
-            face_from_str(variable_face_names, font_core).expect("failed to get variable face");
-        let max_height_perc = yaml["max_height_percentage"].as_i64().unwrap_or(40) as u32;
-        let width_perc = yaml["width_percentage"].as_i64().unwrap_or(85) as u32;
-        let line_space = yaml["line_spacing"].as_i64().unwrap_or(1) as u32;
-        let botoff = yaml["bottom_offset"].as_i64().unwrap_or(10) as u32;
+        let fixed_face = face_from_str(fixed_face_names, FIXED_FONT, font_core);
+        let variable_face = face_from_str(variable_face_names, VARIABLE_FONT, font_core);
+        let max_height_perc = yaml["max_height_percentage"].as_i64().unwrap_or(40).max(0) as u32;
+        let width_perc = yaml["width_percentage"].as_i64().unwrap_or(85).max(0) as u32;
+        let line_space = yaml["line_spacing"].as_i64().unwrap_or(1).max(0) as u32;
+        // bottom_offset is the old name, from when the popup was always at the bottom
+        let edgeoff = yaml["edge_offset"]
+            .as_i64()
+            .or_else(|| yaml["bottom_offset"].as_i64())
+            .unwrap_or(10)
+            .max(0) as u32;
+        let max_results = yaml["max_results"]
+            .as_i64()
+            .filter(|n| *n > 0)
+            .map(|n| n as usize);
         CfgUiFuzzy {
             text_size: text_size,
             fixed_face: fixed_face,
@@ -128,7 +272,10 @@ impl CfgUiFuzzy {
             max_height_percentage: max_height_perc,
             width_percentage: width_perc,
             line_spacing: line_space,
-            bottom_offset: botoff,
+            edge_offset: edgeoff,
+            placement: CfgUiFuzzyPlacement::from_yaml(&yaml["placement"]),
+            max_results: max_results,
+            input_position: CfgUiFuzzyInput::from_yaml(&yaml["input_position"]),
         }
     }
 
@@ -142,7 +289,45 @@ impl CfgUiFuzzy {
             max_height_percentage: 40,
             width_percentage: 85,
             line_spacing: 1,
-            bottom_offset: 10,
+            edge_offset: 10,
+            placement: CfgUiFuzzyPlacement::Bottom,
+            max_results: None,
+            input_position: CfgUiFuzzyInput::Below,
+        }
+    }
+}
+
+/// Where the fuzzy popup is placed vertically in the window
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CfgUiFuzzyPlacement {
+    Top,
+    Center,
+    Bottom,
+}
+
+impl CfgUiFuzzyPlacement {
+    fn from_yaml(yaml: &Yaml) -> CfgUiFuzzyPlacement {
+        match yaml.as_str() {
+            Some("top") => CfgUiFuzzyPlacement::Top,
+            Some("center") => CfgUiFuzzyPlacement::Center,
+            _ => CfgUiFuzzyPlacement::Bottom,
+        }
+    }
+}
+
+/// Whether the fuzzy popup's input line is above or below the results. Results are ordered
+/// outwards from the input line, best match first
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CfgUiFuzzyInput {
+    Above,
+    Below,
+}
+
+impl CfgUiFuzzyInput {
+    fn from_yaml(yaml: &Yaml) -> CfgUiFuzzyInput {
+        match yaml.as_str() {
+            Some("above") => CfgUiFuzzyInput::Above,
+            _ => CfgUiFuzzyInput::Below,
         }
     }
 }
@@ -152,8 +337,8 @@ pub(crate) struct CfgUiPrompt {
     pub(crate) text_size: TextSize,
     pub(crate) fixed_face: FaceKey,
     pub(crate) variable_face: FaceKey,
-    pub(crate) width_percentage: u32,
-    pub(crate) bottom_offset: u32,
+    // Keep the bar at the bottom of the window for the prompt even when the prompt isn't active
+    pub(crate) always_visible: bool,
 }
 
 impl CfgUiPrompt {
@@ -161,18 +346,13 @@ impl CfgUiPrompt {
         let text_size = TextSize::from_f32(yaml["text_size"].as_f64().unwrap_or(TEXT_SIZE) as f32);
         let fixed_face_names = yaml["fixed_face"].as_str().unwrap_or(FIXED_FONT);
         let variable_face_names = yaml["variable_face"].as_str().unwrap_or(VARIABLE_FONT);
-        let fixed_face =
-            face_from_str(fixed_face_names, font_core).expect("failed to get fixed face");
-        let variable_face =
-            face_from_str(variable_face_names, font_core).expect("failed to get variable face");
-        let width_perc = yaml["width_percentage"].as_i64().unwrap_or(85) as u32;
-        let botoff = yaml["bottom_offset"].as_i64().unwrap_or(10) as u32;
+        let fixed_face = face_from_str(fixed_face_names, FIXED_FONT, font_core);
+        let variable_face = face_from_str(variable_face_names, VARIABLE_FONT, font_core);
         CfgUiPrompt {
             text_size: text_size,
             fixed_face: fixed_face,
             variable_face: variable_face,
-            width_percentage: width_perc,
-            bottom_offset: botoff,
+            always_visible: yaml["always_visible"].as_bool().unwrap_or(false),
         }
     }
 
@@ -183,8 +363,224 @@ impl CfgUiPrompt {
             text_size: TextSize::from_f32(GUTTER_TEXT_SIZE as f32),
             fixed_face: fixed,
             variable_face: variable,
-            width_percentage: 85,
-            bottom_offset: 10,
+            always_visible: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CfgUiClickAction {
+    None,
+    Focus,
+    MoveCursor,
+}
+
+impl CfgUiClickAction {
+    fn from_yaml(yaml: &Yaml, default: CfgUiClickAction) -> CfgUiClickAction {
+        match yaml.as_str() {
+            Some("none") => CfgUiClickAction::None,
+            Some("focus") => CfgUiClickAction::Focus,
+            Some("move_cursor") => CfgUiClickAction::MoveCursor,
+            _ => default,
+        }
+    }
+}
+
+/// Whether :make writes modified buffers before building
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CfgUiWriteBeforeBuild {
+    Never,
+    Ask,
+    Always,
+}
+
+impl CfgUiWriteBeforeBuild {
+    fn from_yaml(yaml: &Yaml) -> CfgUiWriteBeforeBuild {
+        match yaml.as_str() {
+            Some("never") => CfgUiWriteBeforeBuild::Never,
+            Some("always") => CfgUiWriteBeforeBuild::Always,
+            _ => CfgUiWriteBeforeBuild::Ask,
+        }
+    }
+}
+
+/// Copies of local files made before they are written over
+#[derive(Clone, Debug)]
+pub(crate) struct CfgUiBackup {
+    /// Keep the copy after the file is written
+    pub(crate) keep: bool,
+    /// Make a copy while writing, which is removed once the write succeeds unless it is kept
+    pub(crate) while_writing: bool,
+    /// Directory for copies. Copies are put next to the files by default
+    pub(crate) dir: Option<PathBuf>,
+}
+
+impl CfgUiBackup {
+    // Relative directories are relative to the config directory
+    fn from_yaml(yaml: &Yaml, cfg_dir_path: &Path) -> CfgUiBackup {
+        let dir = yaml["backup_dir"].as_str().map(|dir| {
+            match (dir.strip_prefix("~/"), BaseDirs::new()) {
+                (Some(rest), Some(dirs)) => dirs.home_dir().join(rest),
+                _ => cfg_dir_path.join(dir),
+            }
+        });
+        CfgUiBackup {
+            keep: yaml["backup"].as_bool().unwrap_or(false),
+            while_writing: yaml["writebackup"].as_bool().unwrap_or(true),
+            dir: dir,
+        }
+    }
+
+    fn default() -> CfgUiBackup {
+        CfgUiBackup {
+            keep: false,
+            while_writing: true,
+            dir: None,
+        }
+    }
+}
+
+/// How panes look in presentation mode
+#[derive(Debug)]
+pub(crate) struct CfgUiPresentation {
+    /// Text is scaled up by this factor
+    pub(crate) text_scale: f64,
+    /// Lines are this many times as tall as their text
+    pub(crate) line_height: f64,
+    /// Opacity of the statusline, as a percentage
+    pub(crate) chrome_opacity: u8,
+}
+
+impl CfgUiPresentation {
+    fn from_yaml(yaml: &Yaml) -> CfgUiPresentation {
+        CfgUiPresentation {
+            text_scale: yaml["text_scale"].as_f64().unwrap_or(1.5).max(0.1),
+            line_height: yaml["line_height"].as_f64().unwrap_or(1.3).max(1.0),
+            chrome_opacity: yaml["chrome_opacity"]
+                .as_i64()
+                .unwrap_or(40)
+                .max(0)
+                .min(100) as u8,
+        }
+    }
+
+    fn default() -> CfgUiPresentation {
+        CfgUiPresentation {
+            text_scale: 1.5,
+            line_height: 1.3,
+            chrome_opacity: 40,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CfgUiMouse {
+    pub(crate) natural_scroll: bool,
+    pub(crate) scroll_multiplier: f64,
+    /// Scroll velocity, in pixels per second, added by one step of the scroll wheel
+    pub(crate) scroll_acceleration: f64,
+    /// How quickly scrolling slows down. Velocity decays by a factor of e every 1 / friction
+    /// seconds
+    pub(crate) scroll_friction: f64,
+    pub(crate) click: CfgUiClickAction,
+    pub(crate) ctrl_click: CfgUiClickAction,
+    pub(crate) shift_click: CfgUiClickAction,
+    pub(crate) alt_click: CfgUiClickAction,
+}
+
+impl Default for CfgUiMouse {
+    fn default() -> CfgUiMouse {
+        CfgUiMouse {
+            natural_scroll: false,
+            scroll_multiplier: 1.0,
+            scroll_acceleration: 600.0,
+            scroll_friction: 8.0,
+            click: CfgUiClickAction::MoveCursor,
+            ctrl_click: CfgUiClickAction::Focus,
+            shift_click: CfgUiClickAction::MoveCursor,
+            alt_click: CfgUiClickAction::MoveCursor,
+        }
+    }
+}
+
+impl CfgUiMouse {
+    fn from_yaml(yaml: &Yaml) -> CfgUiMouse {
+        let click = CfgUiClickAction::from_yaml(&yaml["click"], CfgUiClickAction::MoveCursor);
+        CfgUiMouse {
+            natural_scroll: yaml["natural_scroll"].as_bool().unwrap_or(false),
+            scroll_multiplier: yaml["scroll_multiplier"].as_f64().unwrap_or(1.0),
+            scroll_acceleration: yaml["scroll_acceleration"].as_f64().unwrap_or(600.0),
+            scroll_friction: yaml["scroll_friction"].as_f64().unwrap_or(8.0).max(0.0),
+            click: click,
+            ctrl_click: CfgUiClickAction::from_yaml(&yaml["ctrl_click"], CfgUiClickAction::Focus),
+            shift_click: CfgUiClickAction::from_yaml(&yaml["shift_click"], click),
+            alt_click: CfgUiClickAction::from_yaml(&yaml["alt_click"], click),
+        }
+    }
+}
+
+/// How key bindings are resolved against the keyboard layout
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum CfgKeyBinding {
+    /// Bind by the character the key produces in the active layout
+    Logical,
+    /// Bind by the physical key position, as labelled on a US QWERTY keyboard
+    Physical,
+}
+
+impl CfgKeyBinding {
+    fn from_yaml(yaml: &Yaml, default: CfgKeyBinding) -> CfgKeyBinding {
+        match yaml.as_str() {
+            Some("logical") => CfgKeyBinding::Logical,
+            Some("physical") => CfgKeyBinding::Physical,
+            _ => default,
+        }
+    }
+}
+
+/// Layout handling for key bindings. `modifier_keys` applies to Ctrl/Alt chords (e.g. Ctrl-W),
+/// `command_keys` to plain keys in normal and operator-pending modes (e.g. `hjkl`). Text input
+/// is never remapped. If a logical binding can't be resolved because the layout doesn't produce
+/// an ASCII letter or digit for the key, the physical key is used instead
+#[derive(Debug)]
+pub(crate) struct CfgUiKeymap {
+    pub(crate) modifier_keys: CfgKeyBinding,
+    pub(crate) command_keys: CfgKeyBinding,
+    /// Milliseconds a prefix key is pending before hints for the following key are shown
+    pub(crate) hint_delay: u64,
+    /// Milliseconds after which a pending prefix key is cancelled. 0 never cancels
+    pub(crate) timeout: u64,
+    /// Key that starts a user mapping
+    pub(crate) leader: char,
+    /// Keys typed after the leader, and the command line each runs
+    pub(crate) leader_mappings: Vec<(String, String)>,
+}
+
+impl Default for CfgUiKeymap {
+    fn default() -> CfgUiKeymap {
+        CfgUiKeymap {
+            modifier_keys: CfgKeyBinding::Logical,
+            command_keys: CfgKeyBinding::Logical,
+            hint_delay: 500,
+            timeout: 5000,
+            leader: '\\',
+            leader_mappings: Vec::new(),
+        }
+    }
+}
+
+impl CfgUiKeymap {
+    fn from_yaml(yaml: &Yaml) -> CfgUiKeymap {
+        CfgUiKeymap {
+            modifier_keys: CfgKeyBinding::from_yaml(&yaml["modifier_keys"], CfgKeyBinding::Logical),
+            command_keys: CfgKeyBinding::from_yaml(&yaml["command_keys"], CfgKeyBinding::Logical),
+            hint_delay: yaml["hint_delay"].as_i64().unwrap_or(500).max(0) as u64,
+            timeout: yaml["timeout"].as_i64().unwrap_or(5000).max(0) as u64,
+            leader: yaml["leader"]
+                .as_str()
+                .and_then(|s| s.chars().next())
+                .unwrap_or('\\'),
+            leader_mappings: leader_mappings_from_yaml(&yaml["leader_mappings"]),
         }
     }
 }
@@ -198,6 +594,13 @@ pub(crate) struct CfgUiThemeTextview {
     pub(crate) border_width: u32,
     pub(crate) border_color: Color,
     pub(crate) inactive_opacity: u8,
+    pub(crate) search_color: Color,
+    pub(crate) mixed_indent_color: Color,
+    pub(crate) bracket_color: Color,
+    pub(crate) flash_color: Color,
+    pub(crate) selection_color: Color,
+    pub(crate) color_column_color: Color,
+    pub(crate) cursor_line_color: Color,
 }
 
 impl Default for CfgUiThemeTextview {
@@ -210,6 +613,13 @@ impl Default for CfgUiThemeTextview {
             border_width: 1,
             border_color: Color::new(0, 0, 0, 255),
             inactive_opacity: 50,
+            search_color: Color::new(255, 200, 0, 128),
+            mixed_indent_color: Color::new(255, 0, 0, 48),
+            bracket_color: Color::new(0, 160, 255, 96),
+            flash_color: Color::new(255, 140, 0, 96),
+            selection_color: Color::new(0, 120, 215, 64),
+            color_column_color: Color::new(0, 0, 0, 24),
+            cursor_line_color: Color::new(0, 0, 0, 10),
         }
     }
 }
@@ -235,12 +645,44 @@ impl CfgUiThemeTextview {
                 .as_str()
                 .and_then(|s| Color::parse(s))
                 .unwrap_or(bgcol),
-            border_width: yaml["border_width"].as_i64().unwrap_or(1) as u32,
+            border_width: yaml["border_width"].as_i64().unwrap_or(1).max(0) as u32,
             border_color: yaml["border_color"]
                 .as_str()
                 .and_then(|s| Color::parse(s))
                 .unwrap_or(Color::new(0, 0, 0, 255)),
-            inactive_opacity: yaml["inactive_opacity"].as_i64().unwrap_or(50) as u8,
+            inactive_opacity: yaml["inactive_opacity"]
+                .as_i64()
+                .unwrap_or(50)
+                .max(0)
+                .min(100) as u8,
+            search_color: yaml["search_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(255, 200, 0, 128)),
+            mixed_indent_color: yaml["mixed_indent_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(255, 0, 0, 48)),
+            bracket_color: yaml["bracket_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(0, 160, 255, 96)),
+            flash_color: yaml["flash_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(255, 140, 0, 96)),
+            selection_color: yaml["selection_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(0, 120, 215, 64)),
+            color_column_color: yaml["color_column_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(0, 0, 0, 24)),
+            cursor_line_color: yaml["cursor_line_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(0, 0, 0, 10)),
         }
     }
 }
@@ -250,6 +692,11 @@ pub(crate) struct CfgUiThemeGutter {
     pub(crate) background_color: Color,
     pub(crate) foreground_color: Color,
     pub(crate) inactive_opacity: u8,
+    pub(crate) note_color: Color,
+    // Signs for lines changed since the file was last read or written
+    pub(crate) added_color: Color,
+    pub(crate) modified_color: Color,
+    pub(crate) deleted_color: Color,
 }
 
 impl Default for CfgUiThemeGutter {
@@ -258,6 +705,10 @@ impl Default for CfgUiThemeGutter {
             background_color: Color::new(255, 255, 255, 64),
             foreground_color: Color::new(0, 0, 0, 128),
             inactive_opacity: 50,
+            note_color: Color::new(0, 150, 136, 192),
+            added_color: Color::new(76, 175, 80, 192),
+            modified_color: Color::new(33, 150, 243, 192),
+            deleted_color: Color::new(244, 67, 54, 192),
         }
     }
 }
@@ -272,11 +723,114 @@ impl CfgUiThemeGutter {
             .as_str()
             .and_then(|s| Color::parse(s))
             .unwrap_or(Color::new(0, 0, 0, 255));
-        let inop = yaml["inactive_opacity"].as_i64().unwrap_or(50) as u8;
+        let inop = yaml["inactive_opacity"]
+            .as_i64()
+            .unwrap_or(50)
+            .max(0)
+            .min(100) as u8;
         CfgUiThemeGutter {
             background_color: bgcol,
             foreground_color: fgcol,
             inactive_opacity: inop,
+            note_color: yaml["note_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(0, 150, 136, 192)),
+            added_color: yaml["added_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(76, 175, 80, 192)),
+            modified_color: yaml["modified_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(33, 150, 243, 192)),
+            deleted_color: yaml["deleted_color"]
+                .as_str()
+                .and_then(|s| Color::parse(s))
+                .unwrap_or(Color::new(244, 67, 54, 192)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CfgUiThemeScrollbar {
+    pub(crate) track_color: Color,
+    pub(crate) thumb_color: Color,
+    pub(crate) width: u32,
+}
+
+impl Default for CfgUiThemeScrollbar {
+    fn default() -> CfgUiThemeScrollbar {
+        CfgUiThemeScrollbar {
+            track_color: Color::new(0, 0, 0, 8),
+            thumb_color: Color::new(0, 0, 0, 48),
+            width: 8,
+        }
+    }
+}
+
+impl CfgUiThemeScrollbar {
+    fn from_yaml(yaml: &Yaml) -> CfgUiThemeScrollbar {
+        let trackcol = yaml["track_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(0, 0, 0, 8));
+        let thumbcol = yaml["thumb_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(0, 0, 0, 48));
+        let width = yaml["width"].as_i64().unwrap_or(8).max(0) as u32;
+        CfgUiThemeScrollbar {
+            track_color: trackcol,
+            thumb_color: thumbcol,
+            width: width,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CfgUiThemeStatusline {
+    pub(crate) background_color: Color,
+    pub(crate) foreground_color: Color,
+    pub(crate) mode_color: Color,
+    pub(crate) inactive_opacity: u8,
+}
+
+impl Default for CfgUiThemeStatusline {
+    fn default() -> CfgUiThemeStatusline {
+        CfgUiThemeStatusline {
+            background_color: Color::new(0, 0, 0, 16),
+            foreground_color: Color::new(0, 0, 0, 196),
+            mode_color: Color::new(0, 0, 255, 196),
+            inactive_opacity: 50,
+        }
+    }
+}
+
+impl CfgUiThemeStatusline {
+    fn from_yaml(yaml: &Yaml) -> CfgUiThemeStatusline {
+        let bgcol = yaml["background_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(0, 0, 0, 16));
+        let fgcol = yaml["foreground_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(0, 0, 0, 196));
+        let modecol = yaml["mode_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(0, 0, 255, 196));
+        let inop = yaml["inactive_opacity"]
+            .as_i64()
+            .unwrap_or(50)
+            .max(0)
+            .min(100) as u8;
+        CfgUiThemeStatusline {
+            background_color: bgcol,
+            foreground_color: fgcol,
+            mode_color: modecol,
+            inactive_opacity: inop,
         }
     }
 }
@@ -344,7 +898,7 @@ impl CfgUiThemeFuzzy {
             .as_str()
             .and_then(|s| Color::parse(s))
             .unwrap_or(Color::new(0, 0, 0, 255));
-        let edgepad = yaml["edge_padding"].as_i64().unwrap_or(10) as u32;
+        let edgepad = yaml["edge_padding"].as_i64().unwrap_or(10).max(0) as u32;
         CfgUiThemeFuzzy {
             background_color: bgcol,
             foreground_color: fgcol,
@@ -364,6 +918,9 @@ pub(crate) struct CfgUiThemePrompt {
     pub(crate) background_color: Color,
     pub(crate) foreground_color: Color,
     pub(crate) cursor_color: Color,
+    // Colors of error and warning messages
+    pub(crate) error_color: Color,
+    pub(crate) warning_color: Color,
     pub(crate) edge_padding: u32,
 }
 
@@ -373,6 +930,8 @@ impl Default for CfgUiThemePrompt {
             background_color: Color::new(255, 255, 255, 255),
             foreground_color: Color::new(0, 0, 0, 96),
             cursor_color: Color::new(0, 0, 0, 255),
+            error_color: Color::new(200, 30, 30, 255),
+            warning_color: Color::new(180, 110, 0, 255),
             edge_padding: 10,
         }
     }
@@ -392,11 +951,21 @@ impl CfgUiThemePrompt {
             .as_str()
             .and_then(|s| Color::parse(s))
             .unwrap_or(Color::new(0, 0, 0, 255));
-        let edgepad = yaml["edge_padding"].as_i64().unwrap_or(10) as u32;
+        let errorcol = yaml["error_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(200, 30, 30, 255));
+        let warningcol = yaml["warning_color"]
+            .as_str()
+            .and_then(|s| Color::parse(s))
+            .unwrap_or(Color::new(180, 110, 0, 255));
+        let edgepad = yaml["edge_padding"].as_i64().unwrap_or(10).max(0) as u32;
         CfgUiThemePrompt {
             background_color: bgcol,
             foreground_color: fgcol,
             cursor_color: cursorcol,
+            error_color: errorcol,
+            warning_color: warningcol,
             edge_padding: edgepad,
         }
     }
@@ -482,6 +1051,8 @@ impl CfgUiThemeSyntax {
 pub(crate) struct CfgUiTheme {
     pub(crate) textview: CfgUiThemeTextview,
     pub(crate) gutter: CfgUiThemeGutter,
+    pub(crate) scrollbar: CfgUiThemeScrollbar,
+    pub(crate) statusline: CfgUiThemeStatusline,
     pub(crate) fuzzy: CfgUiThemeFuzzy,
     pub(crate) prompt: CfgUiThemePrompt,
     pub(crate) syntax: CfgUiThemeSyntax,
@@ -506,6 +1077,8 @@ impl CfgUiTheme {
         CfgUiTheme {
             textview: CfgUiThemeTextview::from_yaml(&yaml["textview"]),
             gutter: CfgUiThemeGutter::from_yaml(&yaml["gutter"]),
+            scrollbar: CfgUiThemeScrollbar::from_yaml(&yaml["scrollbar"]),
+            statusline: CfgUiThemeStatusline::from_yaml(&yaml["statusline"]),
             fuzzy: CfgUiThemeFuzzy::from_yaml(&yaml["fuzzy"]),
             prompt: CfgUiThemePrompt::from_yaml(&yaml["prompt"]),
             syntax: CfgUiThemeSyntax::from_yaml(&yaml["syntax"]),
@@ -517,8 +1090,20 @@ impl CfgUiTheme {
 pub(crate) struct CfgUi {
     pub(crate) textview: CfgUiTextview,
     pub(crate) gutter: CfgUiGutter,
+    pub(crate) statusline: CfgUiStatusline,
     pub(crate) fuzzy: CfgUiFuzzy,
     pub(crate) prompt: CfgUiPrompt,
+    pub(crate) mouse: CfgUiMouse,
+    pub(crate) presentation: CfgUiPresentation,
+    pub(crate) keymap: CfgUiKeymap,
+    pub(crate) autosave_on_focus_loss: bool,
+    // Use the system clipboard for the unnamed register
+    pub(crate) clipboard_unnamed: bool,
+    pub(crate) write_before_build: CfgUiWriteBeforeBuild,
+    pub(crate) backup: CfgUiBackup,
+    pub(crate) font: RasterOptions,
+    // Files or directories marking the root of a project
+    pub(crate) root_markers: Vec<String>,
     cur_theme: String,
     themes: HashMap<String, CfgUiTheme>,
 }
@@ -531,8 +1116,11 @@ impl CfgUi {
     fn from_yaml(yaml: &Yaml, cfg_dir_path: &Path, font_core: &mut FontCore) -> CfgUi {
         let textview = CfgUiTextview::from_yaml(&yaml["textview"], font_core);
         let gutter = CfgUiGutter::from_yaml(&yaml["gutter"], font_core);
+        let statusline = CfgUiStatusline::from_yaml(&yaml["statusline"], font_core);
         let fuzzy = CfgUiFuzzy::from_yaml(&yaml["fuzzy"], font_core);
         let prompt = CfgUiPrompt::from_yaml(&yaml["prompt"], font_core);
+        let mouse = CfgUiMouse::from_yaml(&yaml["mouse"]);
+        let keymap = CfgUiKeymap::from_yaml(&yaml["keymap"]);
         let mut cur_theme = yaml["theme"].as_str().unwrap_or("default").to_owned();
         let mut themes = HashMap::new();
         themes.insert("default".to_owned(), CfgUiTheme::default());
@@ -552,8 +1140,25 @@ impl CfgUi {
         CfgUi {
             textview: textview,
             gutter: gutter,
+            statusline: statusline,
             fuzzy: fuzzy,
             prompt: prompt,
+            mouse: mouse,
+            presentation: CfgUiPresentation::from_yaml(&yaml["presentation"]),
+            keymap: keymap,
+            autosave_on_focus_loss: yaml["autosave_on_focus_loss"].as_bool().unwrap_or(false),
+            clipboard_unnamed: yaml["clipboard"].as_str() == Some("unnamed"),
+            write_before_build: CfgUiWriteBeforeBuild::from_yaml(&yaml["write_before_build"]),
+            backup: CfgUiBackup::from_yaml(yaml, cfg_dir_path),
+            font: raster_options_from_yaml(&yaml["font"]),
+            root_markers: match yaml["root_markers"].as_vec() {
+                Some(v) => v
+                    .iter()
+                    .filter_map(|m| m.as_str())
+                    .map(|m| m.to_owned())
+                    .collect(),
+                None => default_root_markers(),
+            },
             cur_theme: cur_theme,
             themes: themes,
         }
@@ -566,18 +1171,56 @@ impl CfgUi {
         CfgUi {
             textview: CfgUiTextview::default(font_core),
             gutter: CfgUiGutter::default(font_core),
+            statusline: CfgUiStatusline::default(font_core),
             fuzzy: CfgUiFuzzy::default(font_core),
             prompt: CfgUiPrompt::default(font_core),
+            mouse: CfgUiMouse::default(),
+            presentation: CfgUiPresentation::default(),
+            keymap: CfgUiKeymap::default(),
+            autosave_on_focus_loss: false,
+            clipboard_unnamed: false,
+            write_before_build: CfgUiWriteBeforeBuild::Ask,
+            backup: CfgUiBackup::default(),
+            font: RasterOptions::default(),
+            root_markers: default_root_markers(),
             cur_theme: "default".to_owned(),
             themes: themes,
         }
     }
 }
 
-#[derive(Debug)]
+fn default_root_markers() -> Vec<String> {
+    vec![
+        ".git".to_owned(),
+        "Cargo.toml".to_owned(),
+        "package.json".to_owned(),
+    ]
+}
+
+#[derive(Clone, Debug)]
 pub(crate) struct CfgSyntax {
     pub(crate) tab_width: u32,
     pub(crate) indent_tabs: bool,
+    /// Guess indentation from the leading whitespace of a file when it is opened, instead of
+    /// using tab_width and indent_tabs
+    pub(crate) detect_indent: bool,
+    /// Opening and closing characters that are inserted together
+    pub(crate) pairs: Vec<(char, char)>,
+    /// Lines following a line that matches this are indented one level more
+    pub(crate) indent_increase: Option<Regex>,
+    /// Lines are indented one level less once text typed into them matches this
+    pub(crate) indent_decrease: Option<Regex>,
+    pub(crate) line_comment: Option<String>,
+    /// List item markers at the start of a line, including the indentation. New lines after a
+    /// list item start with the same marker, with numbers incremented
+    pub(crate) list_marker: Option<Regex>,
+    /// Lines opening a fenced block, with the fence as the first group. Opening a block inserts
+    /// the closing fence
+    pub(crate) code_fence: Option<Regex>,
+    /// Shell command run by :make, in the working directory
+    pub(crate) build_command: Option<String>,
+    /// Run the build command whenever a buffer is written
+    pub(crate) build_on_save: bool,
 }
 
 impl Default for CfgSyntax {
@@ -585,38 +1228,158 @@ impl Default for CfgSyntax {
         CfgSyntax {
             tab_width: 8,
             indent_tabs: true,
+            detect_indent: true,
+            pairs: Vec::new(),
+            indent_increase: None,
+            indent_decrease: None,
+            line_comment: None,
+            list_marker: None,
+            code_fence: None,
+            build_command: None,
+            build_on_save: false,
         }
     }
 }
 
 impl CfgSyntax {
-    fn from_yaml(yaml: &Yaml) -> CfgSyntax {
+    // Settings from YAML, with missing keys taken from base
+    fn from_yaml(yaml: &Yaml, base: &CfgSyntax) -> CfgSyntax {
+        let regex = |key: &str, base: &Option<Regex>| match yaml[key].as_str() {
+            Some(s) => match Regex::new(s) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    error!("invalid regex for {}: {}", key, e);
+                    base.clone()
+                }
+            },
+            None => base.clone(),
+        };
+        let pairs = match yaml["pairs"].as_vec() {
+            Some(v) => v
+                .iter()
+                .filter_map(|p| {
+                    let mut chars = p.as_str()?.chars();
+                    match (chars.next(), chars.next(), chars.next()) {
+                        (Some(open), Some(close), None) => Some((open, close)),
+                        _ => None,
+                    }
+                })
+                .collect(),
+            None => base.pairs.clone(),
+        };
         CfgSyntax {
-            tab_width: yaml["tab_width"].as_i64().unwrap_or(8) as u32,
-            indent_tabs: yaml["indent_tabs"].as_bool().unwrap_or(true),
+            tab_width: yaml["tab_width"]
+                .as_i64()
+                .map(|i| i.max(1) as u32)
+                .unwrap_or(base.tab_width),
+            indent_tabs: yaml["indent_tabs"].as_bool().unwrap_or(base.indent_tabs),
+            detect_indent: yaml["detect_indent"]
+                .as_bool()
+                .unwrap_or(base.detect_indent),
+            pairs: pairs,
+            indent_increase: regex("indent_increase", &base.indent_increase),
+            indent_decrease: regex("indent_decrease", &base.indent_decrease),
+            line_comment: yaml["line_comment"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .or(base.line_comment.clone()),
+            list_marker: regex("list_marker", &base.list_marker),
+            code_fence: regex("code_fence", &base.code_fence),
+            build_command: yaml["build_command"]
+                .as_str()
+                .map(|s| s.to_owned())
+                .or(base.build_command.clone()),
+            build_on_save: yaml["build_on_save"]
+                .as_bool()
+                .unwrap_or(base.build_on_save),
         }
     }
+
+    // Built-in settings for each language
+    fn defaults() -> HashMap<String, CfgSyntax> {
+        let docs = YamlLoader::load_from_str(SYNTAX_DEFAULTS).expect("invalid syntax defaults");
+        let yaml = &docs[0];
+        let default = CfgSyntax::from_yaml(&yaml["default"], &CfgSyntax::default());
+        let mut ret = HashMap::new();
+        if let Yaml::Hash(h) = yaml {
+            for (k, v) in h.iter() {
+                if let Some(name) = k.as_str() {
+                    ret.insert(name.to_owned(), CfgSyntax::from_yaml(v, &default));
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Where the configuration was loaded from
+#[derive(Debug)]
+pub(crate) enum CfgSource {
+    // No configuration directory on this platform
+    Default,
+    Missing(PathBuf),
+    Invalid(PathBuf, String),
+    File(PathBuf),
 }
 
 #[derive(Debug)]
 pub(crate) struct Cfg {
     pub(crate) ui: CfgUi,
+    pub(crate) source: CfgSource,
     syntaxes: HashMap<String, CfgSyntax>,
 }
 
 impl Cfg {
     pub(crate) fn load(font_core: &mut FontCore) -> Cfg {
-        if let Some(proj_dirs) = ProjectDirs::from("", "sbarua", "bed") {
-            // Try loading config
-            let cfg_dir_path = proj_dirs.config_dir();
-            read_to_string(cfg_dir_path.join("config.yml"))
-                .ok()
-                .and_then(|data| YamlLoader::load_from_str(&data).ok())
-                .map(|docs| Cfg::from_yaml(&docs[0], cfg_dir_path, font_core))
-                .unwrap_or_else(|| Cfg::default(font_core))
-        } else {
-            Cfg::default(font_core)
+        let path = match Cfg::path() {
+            Some(path) => path,
+            None => return Cfg::default(font_core),
+        };
+        // Try loading config
+        let cfg_dir_path = path.parent().expect("config file has no parent directory");
+        let data = match read_to_string(&path) {
+            Ok(data) => data,
+            Err(_) => {
+                let mut ret = Cfg::default(font_core);
+                ret.source = CfgSource::Missing(path);
+                return ret;
+            }
+        };
+        let (mut ret, source) = match YamlLoader::load_from_str(&data) {
+            Ok(docs) if docs.len() > 0 => (
+                Cfg::from_yaml(&docs[0], cfg_dir_path, font_core),
+                CfgSource::File(path),
+            ),
+            Ok(_) => (
+                Cfg::default(font_core),
+                CfgSource::Invalid(path, "empty document".to_owned()),
+            ),
+            Err(e) => {
+                error!("failed to parse config: {:?}: {}", path, e);
+                (
+                    Cfg::default(font_core),
+                    CfgSource::Invalid(path, e.to_string()),
+                )
+            }
+        };
+        ret.source = source;
+        ret
+    }
+
+    /// Path to the config file, if the platform has a config directory
+    pub(crate) fn path() -> Option<PathBuf> {
+        ProjectDirs::from("", "sbarua", "bed").map(|dirs| dirs.config_dir().join("config.yml"))
+    }
+
+    /// Create config file at path, with all settings commented out, if it doesn't exist
+    pub(crate) fn create_if_missing(path: &Path) -> IOResult<()> {
+        if path.exists() {
+            return Ok(());
+        }
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
         }
+        write(path, CONFIG_TEMPLATE)
     }
 
     pub(crate) fn syntax(&self, name: &str) -> &CfgSyntax {
@@ -626,13 +1389,18 @@ impl Cfg {
     }
 
     fn from_yaml(yaml: &Yaml, cfg_dir_path: &Path, font_core: &mut FontCore) -> Cfg {
-        let mut syntaxes = HashMap::new();
-        syntaxes.insert("default".to_owned(), CfgSyntax::default());
+        let mut syntaxes = CfgSyntax::defaults();
         match &yaml["syntax"] {
             Yaml::Hash(h) => {
                 for (k, v) in h.iter() {
                     if let Some(name) = k.as_str() {
-                        syntaxes.insert(name.to_owned(), CfgSyntax::from_yaml(v));
+                        let syntax = {
+                            let base = syntaxes
+                                .get(name)
+                                .unwrap_or(syntaxes.get("default").unwrap());
+                            CfgSyntax::from_yaml(v, base)
+                        };
+                        syntaxes.insert(name.to_owned(), syntax);
                     }
                 }
             }
@@ -640,20 +1408,82 @@ impl Cfg {
         }
         Cfg {
             ui: CfgUi::from_yaml(&yaml["ui"], cfg_dir_path, font_core),
+            source: CfgSource::Default,
             syntaxes: syntaxes,
         }
     }
 
-    fn default(font_core: &mut FontCore) -> Cfg {
-        let mut syntaxes = HashMap::new();
-        syntaxes.insert("default".to_owned(), CfgSyntax::default());
+    /// Built-in configuration, ignoring the config file
+    pub(crate) fn default(font_core: &mut FontCore) -> Cfg {
         Cfg {
             ui: CfgUi::default(font_core),
-            syntaxes: syntaxes,
+            source: CfgSource::Default,
+            syntaxes: CfgSyntax::defaults(),
         }
     }
 }
 
-fn face_from_str(s: &str, font_core: &mut FontCore) -> Option<FaceKey> {
-    s.split(',').filter_map(|s| font_core.find(s.trim())).next()
+fn leader_mappings_from_yaml(yaml: &Yaml) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    if let Yaml::Hash(h) = yaml {
+        for (k, v) in h.iter() {
+            let seq = match k {
+                Yaml::String(s) => s.clone(),
+                Yaml::Integer(i) => i.to_string(),
+                _ => continue,
+            };
+            if let Some(command) = v.as_str() {
+                ret.push((seq, command.to_owned()));
+            }
+        }
+    }
+    ret.sort();
+    ret
+}
+
+fn raster_options_from_yaml(yaml: &Yaml) -> RasterOptions {
+    let default = RasterOptions::default();
+    RasterOptions {
+        hinting: match yaml["hinting"].as_str() {
+            Some("none") => Hinting::None,
+            Some("slight") => Hinting::Slight,
+            Some("full") => Hinting::Full,
+            _ => default.hinting,
+        },
+        autohint: yaml["autohint"].as_bool().unwrap_or(default.autohint),
+        antialias: yaml["antialias"].as_bool().unwrap_or(default.antialias),
+    }
+}
+
+fn case_sensitivity_from_yaml(yaml: &Yaml) -> CaseSensitivity {
+    match yaml.as_str() {
+        Some("insensitive") => CaseSensitivity::Insensitive,
+        Some("smart") => CaseSensitivity::Smart,
+        _ => CaseSensitivity::Sensitive,
+    }
+}
+
+// First face found from a comma-separated list of names. If none are found, the default face is
+// used instead
+fn face_from_str(s: &str, default: &str, font_core: &mut FontCore) -> FaceKey {
+    match s.split(',').filter_map(|s| font_core.find(s.trim())).next() {
+        Some(key) => key,
+        None => {
+            error!("font not found: {}: using {}", s, default);
+            font_core.find(default).expect("failed to get default face")
+        }
+    }
+}
+
+// Fallback face with exactly the given family name. Fontconfig substitutes some other family for
+// ones that aren't installed, which would defeat pinning a fallback
+fn fallback_face(name: &str, font_core: &mut FontCore) -> Option<FaceKey> {
+    let key = font_core.find(name)?;
+    match font_core.family_name(key) {
+        Some(family) if family.eq_ignore_ascii_case(name) => Some(key),
+        _ => {
+            warning!("fallback face not found: {}", name);
+            None
+        }
+    }
 }
@@ -2,38 +2,111 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::Result as IOResult;
+use std::fs::canonicalize;
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
 use std::rc::Rc;
 
 use euclid::Size2D;
 
 use crate::config::Cfg;
 use crate::font::FontCore;
+use crate::history::SearchHistory;
+use crate::marks::Marks;
+use crate::notes::{absolute_path, Note, Notes};
+use crate::provider::write_error_message;
+use crate::registers::Registers;
+use crate::symbols::SymbolIndex;
 use crate::textbuffer::Buffer;
+use crate::trash::TrashedFile;
 use crate::types::DPI;
+use crate::ui::Wakeup;
 
 pub(crate) struct Core {
     buffers: HashMap<String, Rc<RefCell<Buffer>>>,
+    // Numbers shown in the buffer list, by path. These stay the same while the buffer is open
+    buffer_ids: HashMap<String, usize>,
+    next_buffer_id: usize,
     font_core: Rc<RefCell<FontCore>>,
     config: Rc<RefCell<Cfg>>,
+    marks: Marks,
+    notes: Notes,
+    registers: Registers,
+    search_history: SearchHistory,
+    symbols: SymbolIndex,
+    // Files deleted with :delete-file, most recent last
+    trashed: Vec<TrashedFile>,
     next_view_id: usize,
+    // Given to background work, which wakes the main loop up once it has something to handle
+    wakeup: Wakeup,
 }
 
 impl Core {
-    pub(crate) fn new(font_core: Rc<RefCell<FontCore>>, config: Rc<RefCell<Cfg>>) -> Core {
+    pub(crate) fn new(
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        wakeup: Wakeup,
+    ) -> Core {
+        let search_history = SearchHistory::load();
+        let mut registers = Registers::default();
+        if let Some(pattern) = search_history.last() {
+            registers.set_last_search(pattern);
+        }
         Core {
             buffers: HashMap::new(),
+            buffer_ids: HashMap::new(),
+            next_buffer_id: 1,
             next_view_id: 0,
             font_core: font_core,
             config: config,
+            marks: Marks::load(),
+            notes: Notes::load(&std::env::current_dir().unwrap_or_default()),
+            registers: registers,
+            search_history: search_history,
+            symbols: SymbolIndex::default(),
+            trashed: Vec::new(),
+            wakeup: wakeup,
         }
     }
 
+    /// Handle for background work to wake the main loop up with
+    pub(crate) fn wakeup(&self) -> Wakeup {
+        self.wakeup.clone()
+    }
+
     pub(crate) fn new_empty_buffer(&mut self, dpi: Size2D<u32, DPI>) -> Rc<RefCell<Buffer>> {
         Rc::new(RefCell::new(Buffer::empty(
             dpi,
             self.font_core.clone(),
             self.config.clone(),
+            self.wakeup.clone(),
+        )))
+    }
+
+    pub(crate) fn new_scratch_buffer(
+        &mut self,
+        text: &str,
+        dpi: Size2D<u32, DPI>,
+    ) -> Rc<RefCell<Buffer>> {
+        Rc::new(RefCell::new(Buffer::scratch(
+            text,
+            dpi,
+            self.font_core.clone(),
+            self.config.clone(),
+            self.wakeup.clone(),
+        )))
+    }
+
+    pub(crate) fn new_large_file_buffer(
+        &mut self,
+        path: &str,
+        dpi: Size2D<u32, DPI>,
+    ) -> Rc<RefCell<Buffer>> {
+        Rc::new(RefCell::new(Buffer::large_file(
+            path,
+            dpi,
+            self.font_core.clone(),
+            self.config.clone(),
+            self.wakeup.clone(),
         )))
     }
 
@@ -42,27 +115,278 @@ impl Core {
         path: &str,
         dpi: Size2D<u32, DPI>,
     ) -> IOResult<Rc<RefCell<Buffer>>> {
-        if let Some(buffer) = self.buffers.get_mut(path) {
+        if let Some(buffer) = self.buffer_for_file(path) {
             {
                 let buffer = &mut *buffer.borrow_mut();
                 buffer.reload_from_file(dpi)?;
+                warn_mixed_indentation(buffer);
             }
-            Ok(buffer.clone())
+            Ok(buffer)
         } else {
             let buffer = Rc::new(RefCell::new(Buffer::from_file(
                 path,
                 dpi,
                 self.font_core.clone(),
                 self.config.clone(),
+                self.wakeup.clone(),
             )));
+            warn_mixed_indentation(&mut *buffer.borrow_mut());
+            for (line, text) in self.notes.for_path(path) {
+                let id = self.next_view_id();
+                buffer.borrow_mut().set_note(id, line, &text);
+            }
             self.buffers.insert(path.to_owned(), buffer.clone());
+            self.buffer_ids.insert(path.to_owned(), self.next_buffer_id);
+            self.next_buffer_id += 1;
             Ok(buffer)
         }
     }
 
+    pub(crate) fn buffer(&self, path: &str) -> Option<Rc<RefCell<Buffer>>> {
+        self.buffers.get(path).map(|b| b.clone())
+    }
+
+    /// Buffer for the file at path, even if it was opened through another path to the same file,
+    /// like a symlink or a path with ..
+    pub(crate) fn buffer_for_file(&self, path: &str) -> Option<Rc<RefCell<Buffer>>> {
+        if let Some(buffer) = self.buffer(path) {
+            return Some(buffer);
+        }
+        let canonical = canonicalize(path).ok()?;
+        self.buffers
+            .iter()
+            .find(|(p, _)| canonicalize(p).ok().as_ref() == Some(&canonical))
+            .map(|(_, buffer)| buffer.clone())
+    }
+
+    /// Buffers opened from files
+    pub(crate) fn buffers(&self) -> Vec<Rc<RefCell<Buffer>>> {
+        self.buffers.values().map(|b| b.clone()).collect()
+    }
+
+    /// Buffer with the given number in the buffer list
+    pub(crate) fn buffer_by_id(&self, id: usize) -> Option<Rc<RefCell<Buffer>>> {
+        self.buffer_ids
+            .iter()
+            .find(|(_, i)| **i == id)
+            .and_then(|(path, _)| self.buffer(path))
+    }
+
+    /// Buffer whose path is name, or the only one whose path contains name
+    pub(crate) fn buffer_by_name(&self, name: &str) -> Option<Rc<RefCell<Buffer>>> {
+        if let Some(buffer) = self.buffer(name) {
+            return Some(buffer);
+        }
+        let mut matches = self.buffers.iter().filter(|(path, _)| path.contains(name));
+        match (matches.next(), matches.next()) {
+            (Some((_, buffer)), None) => Some(buffer.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn marks(&self) -> &Marks {
+        &self.marks
+    }
+
+    pub(crate) fn marks_mut(&mut self) -> &mut Marks {
+        &mut self.marks
+    }
+
+    /// Save the notes of a buffer opened from a file, at their current lines
+    pub(crate) fn save_notes(&mut self, buffer: &Buffer) {
+        if let Some(path) = buffer.path() {
+            self.notes.set_for_path(path, buffer.notes());
+        }
+    }
+
+    /// Notes in the project. Notes in open buffers are where they are in the buffer, which may
+    /// not have been written yet
+    pub(crate) fn note_list(&self) -> Vec<Note> {
+        let mut ret = Vec::new();
+        for (path, buffer) in &self.buffers {
+            for (line, text) in buffer.borrow().notes() {
+                ret.push(Note {
+                    path: path.clone(),
+                    line: line,
+                    text: text,
+                });
+            }
+        }
+        let open = self
+            .buffers
+            .keys()
+            .map(|path| absolute_path(path))
+            .collect::<Vec<_>>();
+        let closed = self.notes.all().iter();
+        ret.extend(closed.filter(|note| !open.contains(&note.path)).cloned());
+        ret.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+        ret
+    }
+
+    pub(crate) fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub(crate) fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub(crate) fn search_history(&self) -> &SearchHistory {
+        &self.search_history
+    }
+
+    /// Add pattern to the search history, and keep it in the search register
+    pub(crate) fn add_search(&mut self, pattern: &str) {
+        self.search_history.push(pattern);
+        self.registers.set_last_search(pattern);
+    }
+
+    pub(crate) fn symbols(&self) -> &SymbolIndex {
+        &self.symbols
+    }
+
+    pub(crate) fn push_trashed(&mut self, file: TrashedFile) {
+        self.trashed.push(file);
+    }
+
+    /// Most recently trashed file, which is forgotten
+    pub(crate) fn pop_trashed(&mut self) -> Option<TrashedFile> {
+        self.trashed.pop()
+    }
+
+    /// Move buffer's file to a new path. Fails if another buffer is open at that path
+    pub(crate) fn rename_buffer(
+        &mut self,
+        buffer: &Rc<RefCell<Buffer>>,
+        path: &str,
+    ) -> IOResult<()> {
+        if self.buffers.contains_key(path) {
+            return Err(IOError::new(ErrorKind::AlreadyExists, "buffer is open"));
+        }
+        buffer.borrow_mut().rename_file(path)?;
+        let id = self.remove_buffer(buffer);
+        self.buffers.insert(path.to_owned(), buffer.clone());
+        if let Some(id) = id {
+            self.buffer_ids.insert(path.to_owned(), id);
+        }
+        Ok(())
+    }
+
+    /// Forget buffer. Returns its number in the buffer list, if it was opened from a file
+    pub(crate) fn remove_buffer(&mut self, buffer: &Rc<RefCell<Buffer>>) -> Option<usize> {
+        let path = self
+            .buffers
+            .iter()
+            .find(|(_, b)| Rc::ptr_eq(b, buffer))
+            .map(|(path, _)| path.clone())?;
+        self.buffers.remove(&path);
+        self.buffer_ids.remove(&path)
+    }
+
+    /// Write modified buffers whose contents differ from their files. Files changed by something
+    /// else are left alone. Files of unmodified buffers aren't read
+    pub(crate) fn write_modified_buffers(&mut self) {
+        for (path, buffer) in &self.buffers {
+            let buffer = &mut *buffer.borrow_mut();
+            if !buffer.is_dirty() || buffer.write_conflict() || !buffer.differs_from_file() {
+                continue;
+            }
+            match buffer.write_to_file(None) {
+                Some(Err(e)) => error!("{}", write_error_message(path, &e)),
+                _ => warn_mixed_indentation(buffer),
+            }
+        }
+    }
+
+    /// Whether :make would write any buffers first
+    pub(crate) fn has_modified_buffers(&self) -> bool {
+        self.buffers.values().any(|b| {
+            let buffer = &*b.borrow();
+            buffer.is_dirty() && !buffer.write_conflict() && buffer.differs_from_file()
+        })
+    }
+
+    /// Buffers whose files were changed by something else since they were last checked
+    pub(crate) fn check_disk_changes(&mut self) -> Vec<Rc<RefCell<Buffer>>> {
+        self.buffers
+            .values()
+            .filter(|b| b.borrow_mut().check_disk_change())
+            .map(|b| b.clone())
+            .collect()
+    }
+
+    /// Numbers, paths and approximate memory usage of open buffers, in the order they were
+    /// opened
+    pub(crate) fn buffer_list(&self) -> Vec<(usize, String, usize)> {
+        let mut ret = self
+            .buffers
+            .iter()
+            .map(|(path, buffer)| {
+                (
+                    self.buffer_ids[path],
+                    path.clone(),
+                    buffer.borrow().approx_memory_usage(),
+                )
+            })
+            .collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+
+    /// Evict shaped line caches until they fit within the configured budget. Caches for DPIs
+    /// other than the given one are dropped first, followed by the largest buffers which are not
+    /// visible. Evicted caches are re-generated when the buffer is shown again
+    pub(crate) fn enforce_cache_budget(
+        &mut self,
+        dpi: Size2D<u32, DPI>,
+        visible: &[Rc<RefCell<Buffer>>],
+    ) {
+        let budget = (&*self.config.borrow()).ui.textview.shaped_cache_budget;
+        let mut usage = self
+            .buffers
+            .values()
+            .map(|b| (b.borrow().approx_cache_size(), b.clone()))
+            .collect::<Vec<_>>();
+        let mut total = usage.iter().map(|(size, _)| size).sum::<usize>();
+        if total <= budget {
+            return;
+        }
+        for (size, buffer) in usage.iter_mut() {
+            let buffer = &mut *buffer.borrow_mut();
+            buffer.retain_shaped_dpi(dpi);
+            let new_size = buffer.approx_cache_size();
+            total -= *size - new_size;
+            *size = new_size;
+        }
+        usage.sort_by(|a, b| b.0.cmp(&a.0));
+        for (size, buffer) in usage {
+            if total <= budget {
+                break;
+            }
+            if visible.iter().any(|v| Rc::ptr_eq(v, &buffer)) {
+                continue;
+            }
+            buffer.borrow_mut().unload();
+            total -= size;
+        }
+    }
+
     pub(crate) fn next_view_id(&mut self) -> usize {
         let ret = self.next_view_id;
         self.next_view_id += 1;
         ret
     }
 }
+
+/// Check buffer for lines with mixed indentation, and warn if there are any. The lines stay
+/// highlighted until the text changes
+pub(crate) fn warn_mixed_indentation(buffer: &mut Buffer) {
+    let nlines = buffer.check_indentation();
+    if nlines > 0 {
+        warning!(
+            "mixed indentation: {}: {} lines (:retab to fix)",
+            buffer.path().unwrap_or(""),
+            nlines
+        );
+    }
+}
@@ -0,0 +1,121 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::io::{BufRead, BufReader, Error as IOError, ErrorKind, Result as IOResult, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use crate::ui::Wakeup;
+
+/// Line read from a client of the debug server, and where to send the reply
+pub(crate) struct DebugRequest {
+    pub(crate) line: String,
+    reply: Sender<String>,
+}
+
+impl DebugRequest {
+    pub(crate) fn reply(self, text: String) {
+        let _ = self.reply.send(text);
+    }
+}
+
+/// Socket that external tools, like integration tests, connect to for querying the editor's state
+/// and running commands. Each request is a line, and each reply is a number of lines followed by
+/// an empty line
+pub(crate) struct DebugServer {
+    path: PathBuf,
+    rx: Receiver<DebugRequest>,
+}
+
+impl DebugServer {
+    /// Listen on a unix socket at path. A socket left behind at path by an earlier run is
+    /// replaced, but anything else there is left alone and fails. The main loop is woken up for
+    /// every request
+    #[cfg(unix)]
+    pub(crate) fn start(path: &Path, wakeup: Wakeup) -> IOResult<DebugServer> {
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::UnixListener;
+
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                let msg = "path exists and isn't a socket";
+                return Err(IOError::new(ErrorKind::AlreadyExists, msg));
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(path)?;
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let reader = match stream.try_clone() {
+                    Ok(reader) => BufReader::new(reader),
+                    Err(_) => continue,
+                };
+                let tx = tx.clone();
+                let wakeup = wakeup.clone();
+                thread::spawn(move || serve(reader, stream, tx, wakeup));
+            }
+        });
+        Ok(DebugServer {
+            path: path.to_owned(),
+            rx: rx,
+        })
+    }
+
+    /// Named pipes aren't supported yet
+    #[cfg(not(unix))]
+    pub(crate) fn start(_path: &Path, _wakeup: Wakeup) -> IOResult<DebugServer> {
+        Err(IOError::new(
+            ErrorKind::Other,
+            "the debug server needs unix sockets",
+        ))
+    }
+
+    /// Requests received since the last call
+    pub(crate) fn requests(&self) -> Vec<DebugRequest> {
+        self.rx.try_iter().collect()
+    }
+}
+
+impl Drop for DebugServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Forward lines read from a client to the editor, and write back the replies, until the client
+// disconnects or the editor exits
+fn serve<R: BufRead, W: Write>(reader: R, mut writer: W, tx: Sender<DebugRequest>, wakeup: Wakeup) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let (reply_tx, reply_rx) = channel();
+        let request = DebugRequest {
+            line: line,
+            reply: reply_tx,
+        };
+        if tx.send(request).is_err() {
+            return;
+        }
+        wakeup.wake();
+        let reply = match reply_rx.recv() {
+            Ok(reply) => reply,
+            Err(_) => return,
+        };
+        let reply = reply.trim_end_matches('\n');
+        if write!(writer, "{}\n\n", reply)
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::collections::BTreeMap;
+
+use crate::types::Color;
+
+/// Source of decorations. Each source owns a layer, which it replaces as a whole
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) enum DecorationLayer {
+    Indentation,
+    Search,
+    // Matches and replacements in the preview of a substitution
+    SubstitutePreview,
+}
+
+/// How a decorated range is drawn
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum DecorationStyle {
+    /// Text color, drawn over syntax colors
+    Foreground(Color),
+    /// Highlight behind the text
+    Background(Color),
+    /// Bar under the text
+    Underline(Color),
+}
+
+/// Decorated range of chars within a line
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Decoration {
+    pub(crate) start_cidx: usize,
+    pub(crate) end_cidx: usize,
+    pub(crate) style: DecorationStyle,
+    /// Decorations with higher priority are drawn over those with lower priority
+    pub(crate) priority: i32,
+}
+
+/// Decorated ranges of a buffer, grouped by layer. Decorations aren't adjusted when the buffer is
+/// edited, so sources should replace them when the text changes
+#[derive(Debug, Default)]
+pub(crate) struct Decorations {
+    layers: BTreeMap<DecorationLayer, BTreeMap<usize, Vec<Decoration>>>,
+}
+
+impl Decorations {
+    /// Replace decorations in layer. Each decoration is paired with its line number
+    pub(crate) fn set(&mut self, layer: DecorationLayer, decorations: Vec<(usize, Decoration)>) {
+        let mut lines = BTreeMap::new();
+        for (linum, decoration) in decorations {
+            lines.entry(linum).or_insert(Vec::new()).push(decoration);
+        }
+        self.layers.insert(layer, lines);
+    }
+
+    pub(crate) fn clear(&mut self, layer: DecorationLayer) {
+        self.layers.remove(&layer);
+    }
+
+    /// Decorations in line from all layers
+    pub(crate) fn line(&self, linum: usize) -> impl Iterator<Item = &Decoration> {
+        self.layers
+            .values()
+            .filter_map(move |lines| lines.get(&linum))
+            .flat_map(|decorations| decorations.iter())
+    }
+}
@@ -0,0 +1,157 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+
+use ropey::Rope;
+
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+const UTF16LE_BOM: &[u8] = &[0xff, 0xfe];
+const UTF16BE_BOM: &[u8] = &[0xfe, 0xff];
+
+/// Names of supported encodings, for completion
+pub(crate) const ENCODING_NAMES: &[&str] =
+    &["utf-8", "utf-8-bom", "utf-16le", "utf-16be", "latin1"];
+
+/// Encoding of a file. Buffers hold UTF-8, and are converted when files are read and written
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Encoding {
+    Utf8,
+    /// UTF-8 starting with a byte order mark
+    Utf8Bom,
+    /// UTF-16 files always start with a byte order mark
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Encoding {
+    /// Encoding with the given name, like "utf-8" or "latin1". Case and common aliases are
+    /// ignored
+    pub(crate) fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "utf-8-bom" | "utf8-bom" => Some(Encoding::Utf8Bom),
+            "utf-16le" | "utf16le" | "utf-16" | "utf16" => Some(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Some(Encoding::Utf16Be),
+            "latin1" | "latin-1" | "iso-8859-1" => Some(Encoding::Latin1),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf8Bom => "utf-8-bom",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+        }
+    }
+
+    /// Encoding of file contents with a byte order mark, if they start with one
+    pub(crate) fn from_bom(data: &[u8]) -> Option<Encoding> {
+        if data.starts_with(UTF8_BOM) {
+            Some(Encoding::Utf8Bom)
+        } else if data.starts_with(UTF16LE_BOM) {
+            Some(Encoding::Utf16Le)
+        } else if data.starts_with(UTF16BE_BOM) {
+            Some(Encoding::Utf16Be)
+        } else {
+            None
+        }
+    }
+
+    /// Guess the encoding of file contents. Without a byte order mark, contents that are valid
+    /// UTF-8 are UTF-8, and anything else is Latin-1, in which every byte is valid
+    pub(crate) fn detect(data: &[u8]) -> Encoding {
+        match Encoding::from_bom(data) {
+            Some(encoding) => encoding,
+            None if std::str::from_utf8(data).is_ok() => Encoding::Utf8,
+            None => Encoding::Latin1,
+        }
+    }
+
+    /// Decode file contents, dropping the byte order mark. Invalid sequences are replaced
+    pub(crate) fn decode(&self, data: &[u8]) -> Rope {
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => {
+                let data = skip_bom(data, UTF8_BOM);
+                Rope::from_str(&String::from_utf8_lossy(data))
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let big_endian = *self == Encoding::Utf16Be;
+                let data = skip_bom(data, if big_endian { UTF16BE_BOM } else { UTF16LE_BOM });
+                let units = data.chunks(2).map(|pair| match pair {
+                    [a, b] if big_endian => u16::from_be_bytes([*a, *b]),
+                    [a, b] => u16::from_le_bytes([*a, *b]),
+                    // Odd byte at the end
+                    _ => 0xfffd,
+                });
+                let text = std::char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+                    .collect::<String>();
+                Rope::from_str(&text)
+            }
+            Encoding::Latin1 => {
+                let text = data.iter().map(|&b| b as char).collect::<String>();
+                Rope::from_str(&text)
+            }
+        }
+    }
+
+    /// Encode text for writing to a file, with a byte order mark if the encoding has one. Fails
+    /// if there are characters the encoding can't represent
+    pub(crate) fn encode(&self, data: &Rope) -> IOResult<Vec<u8>> {
+        let mut ret = Vec::with_capacity(data.len_bytes());
+        match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => {
+                if *self == Encoding::Utf8Bom {
+                    ret.extend_from_slice(UTF8_BOM);
+                }
+                for chunk in data.chunks() {
+                    ret.extend_from_slice(chunk.as_bytes());
+                }
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let big_endian = *self == Encoding::Utf16Be;
+                let mut buf = [0u16; 2];
+                for c in std::iter::once('\u{feff}').chain(data.chars()) {
+                    for unit in c.encode_utf16(&mut buf) {
+                        if big_endian {
+                            ret.extend_from_slice(&unit.to_be_bytes());
+                        } else {
+                            ret.extend_from_slice(&unit.to_le_bytes());
+                        }
+                    }
+                }
+            }
+            Encoding::Latin1 => {
+                for (i, line) in data.lines().enumerate() {
+                    for c in line.chars() {
+                        if c as u32 > 0xff {
+                            let msg = format!("latin1 can't encode {:?} on line {}", c, i + 1);
+                            return Err(IOError::new(ErrorKind::InvalidData, msg));
+                        }
+                        ret.push(c as u8);
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+// Data without bom, if it starts with it
+fn skip_bom<'a>(data: &'a [u8], bom: &[u8]) -> &'a [u8] {
+    if data.starts_with(bom) {
+        &data[bom.len()..]
+    } else {
+        data
+    }
+}
+
+/// Detect the encoding of file contents, and decode them
+pub(crate) fn decode(data: &[u8]) -> (Rope, Encoding) {
+    let encoding = Encoding::detect(data);
+    (encoding.decode(data), encoding)
+}
@@ -0,0 +1,228 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::f64::consts;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// Evaluate an arithmetic expression, like "2 * (3 + 4) ^ 2" or "sqrt(2) / 0x10". Numbers may
+/// be decimal, with an optional fraction and exponent, or hexadecimal with a "0x" prefix.
+/// Operators are +, -, *, /, % and ^, which is right-associative and binds tightest. pi and e
+/// are defined, and so are the functions abs, ceil, floor, round, sqrt, ln, log, sin, cos, tan,
+/// min and max
+pub(crate) fn eval(s: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        s: s,
+        chars: s.char_indices().peekable(),
+    };
+    let ret = parser.sum()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        Some((i, _)) => Err(format!("unexpected input: {}", &s[i..])),
+        None => Ok(ret),
+    }
+}
+
+/// Format the result of eval. Whole numbers are shown without a fraction
+pub(crate) fn format_value(val: f64) -> String {
+    if val.fract() == 0.0 && val.abs() < 1e15 {
+        format!("{}", val as i64)
+    } else {
+        format!("{}", val)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    // sum = product (("+" | "-") product)*
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut ret = self.product()?;
+        loop {
+            match self.peek() {
+                Some('+') => {
+                    self.chars.next();
+                    ret += self.product()?;
+                }
+                Some('-') => {
+                    self.chars.next();
+                    ret -= self.product()?;
+                }
+                _ => return Ok(ret),
+            }
+        }
+    }
+
+    // product = unary (("*" | "/" | "%") unary)*
+    fn product(&mut self) -> Result<f64, String> {
+        let mut ret = self.unary()?;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.chars.next();
+                    ret *= self.unary()?;
+                }
+                Some(c) if c == '/' || c == '%' => {
+                    self.chars.next();
+                    let rhs = self.unary()?;
+                    if rhs == 0.0 {
+                        return Err("division by zero".to_owned());
+                    }
+                    ret = if c == '/' { ret / rhs } else { ret % rhs };
+                }
+                _ => return Ok(ret),
+            }
+        }
+    }
+
+    // unary = ("-" | "+") unary | power
+    fn unary(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('-') => {
+                self.chars.next();
+                Ok(-self.unary()?)
+            }
+            Some('+') => {
+                self.chars.next();
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    // power = atom ("^" unary)?
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.chars.next();
+            Ok(base.powf(self.unary()?))
+        } else {
+            Ok(base)
+        }
+    }
+
+    // atom = number | "(" sum ")" | name | name "(" sum ("," sum)* ")"
+    fn atom(&mut self) -> Result<f64, String> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let ret = self.sum()?;
+                self.expect(')')?;
+                Ok(ret)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number(),
+            Some(c) if c.is_alphabetic() => {
+                let name = self.take_while(|c| c.is_alphanumeric() || c == '_');
+                if self.peek() != Some('(') {
+                    return constant(name);
+                }
+                self.chars.next();
+                let mut args = vec![self.sum()?];
+                while self.peek() == Some(',') {
+                    self.chars.next();
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                call(name, &args)
+            }
+            Some(c) => Err(format!("unexpected character: {}", c)),
+            None => Err("expression ended early".to_owned()),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let start = self.offset();
+        let text = self.take_while(|c| c.is_ascii_alphanumeric() || c == '.');
+        // An exponent may have a sign, like 1e-3
+        let text = if text.ends_with(|c| c == 'e' || c == 'E') && !text.starts_with("0x") {
+            match self.chars.peek() {
+                Some((_, c)) if *c == '-' || *c == '+' => {
+                    self.chars.next();
+                    self.take_while(|c| c.is_ascii_digit());
+                    let end = self.offset();
+                    &self.s[start..end]
+                }
+                _ => text,
+            }
+        } else {
+            text
+        };
+        let ret = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16).map(|x| x as f64).ok(),
+            None => text.parse::<f64>().ok(),
+        };
+        ret.ok_or(format!("invalid number: {}", text))
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.chars.next();
+            Ok(())
+        } else {
+            Err(format!("expected {}", c))
+        }
+    }
+
+    // Next character that isn't whitespace, which isn't consumed
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some((_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, f: F) -> &'a str {
+        let start = self.offset();
+        while let Some((_, c)) = self.chars.peek() {
+            if !f(*c) {
+                break;
+            }
+            self.chars.next();
+        }
+        let end = self.offset();
+        &self.s[start..end]
+    }
+
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map(|(i, _)| *i).unwrap_or(self.s.len())
+    }
+}
+
+fn constant(name: &str) -> Result<f64, String> {
+    match name {
+        "pi" => Ok(consts::PI),
+        "e" => Ok(consts::E),
+        _ => Err(format!("unknown name: {}", name)),
+    }
+}
+
+fn call(name: &str, args: &[f64]) -> Result<f64, String> {
+    let unary = |f: fn(f64) -> f64| match args {
+        [x] => Ok(f(*x)),
+        _ => Err(format!("{} takes 1 argument", name)),
+    };
+    match name {
+        "abs" => unary(f64::abs),
+        "ceil" => unary(f64::ceil),
+        "floor" => unary(f64::floor),
+        "round" => unary(f64::round),
+        "sqrt" => unary(f64::sqrt),
+        "ln" => unary(f64::ln),
+        "log" => unary(f64::log10),
+        "sin" => unary(f64::sin),
+        "cos" => unary(f64::cos),
+        "tan" => unary(f64::tan),
+        "min" => Ok(args.iter().cloned().fold(f64::INFINITY, f64::min)),
+        "max" => Ok(args.iter().cloned().fold(f64::NEG_INFINITY, f64::max)),
+        _ => Err(format!("unknown function: {}", name)),
+    }
+}
@@ -0,0 +1,164 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::fmt::Write;
+use std::fs::{metadata, File};
+use std::io::{ErrorKind, Read, Result as IOResult};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use crate::encoding::Encoding;
+use crate::provider::provider;
+use crate::ui::Wakeup;
+
+// Number of bytes at the start of a file that are checked for binary content
+const SAMPLE_SIZE: usize = 8192;
+// Number of bytes read at a time in large-file mode
+const LARGE_FILE_CHUNK: usize = 1024 * 1024;
+/// Number of bytes shown in hex mode
+pub(crate) const HEX_PREVIEW: usize = 256 * 1024;
+const HEX_BYTES_PER_LINE: usize = 16;
+
+/// What was found checking a file before opening it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FileCheck {
+    Ok,
+    /// Bigger than the configured limit, with the size in bytes
+    Large(u64),
+    /// Sample has NUL bytes, or control characters in text that isn't UTF-8
+    Binary,
+}
+
+/// Check local file before it is opened and shaped. Missing files, remote files and files that
+/// can't be read are Ok, since opening them doesn't shape anything big
+pub(crate) fn check_file(path: &str, max_size: u64) -> FileCheck {
+    if provider(path).is_remote() {
+        return FileCheck::Ok;
+    }
+    let size = match metadata(path) {
+        Ok(m) if m.is_file() => m.len(),
+        _ => return FileCheck::Ok,
+    };
+    let sample = match read_head(path, SAMPLE_SIZE) {
+        Ok(sample) => sample,
+        Err(_) => return FileCheck::Ok,
+    };
+    if is_binary(&sample) {
+        FileCheck::Binary
+    } else if size > max_size {
+        FileCheck::Large(size)
+    } else {
+        FileCheck::Ok
+    }
+}
+
+/// Read file in chunks on a background thread, for large-file mode. Chunks end at line breaks,
+/// except for the last one, and invalid UTF-8 is replaced. The channel is closed once the whole
+/// file is read
+pub(crate) fn stream_file(path: &str, wakeup: Wakeup) -> Receiver<IOResult<String>> {
+    let (tx, rx) = wakeup.channel();
+    let path = path.to_owned();
+    thread::spawn(move || {
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                return;
+            }
+        };
+        let mut data = Vec::new();
+        loop {
+            let start = data.len();
+            data.resize(start + LARGE_FILE_CHUNK, 0);
+            let n = match file.read(&mut data[start..]) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {
+                    data.truncate(start);
+                    continue;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            data.truncate(start + n);
+            if n == 0 {
+                // Rest of the file, which doesn't end with a line break
+                if !data.is_empty() {
+                    let _ = tx.send(Ok(String::from_utf8_lossy(&data).into_owned()));
+                }
+                return;
+            }
+            // A line break is never part of a multi-byte sequence, so chunks cut after one are
+            // valid on their own
+            if let Some(idx) = data.iter().rposition(|&b| b == b'\n') {
+                let rest = data.split_off(idx + 1);
+                if tx
+                    .send(Ok(String::from_utf8_lossy(&data).into_owned()))
+                    .is_err()
+                {
+                    return;
+                }
+                data = rest;
+            }
+        }
+    });
+    rx
+}
+
+/// Hex dump of start of file for hex mode, with offsets and printable ASCII on each line.
+/// Returns the dump and whether it was cut short
+pub(crate) fn hex_dump(path: &str) -> IOResult<(String, bool)> {
+    let mut data = read_head(path, HEX_PREVIEW + 1)?;
+    let truncated = data.len() > HEX_PREVIEW;
+    data.truncate(HEX_PREVIEW);
+    let mut ret = String::new();
+    for (i, chunk) in data.chunks(HEX_BYTES_PER_LINE).enumerate() {
+        if i > 0 {
+            ret.push('\n');
+        }
+        write!(&mut ret, "{:08x}:", i * HEX_BYTES_PER_LINE).unwrap();
+        for j in 0..HEX_BYTES_PER_LINE {
+            if j % 2 == 0 {
+                ret.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => write!(&mut ret, "{:02x}", b).unwrap(),
+                None => ret.push_str("  "),
+            }
+        }
+        ret.push_str("  ");
+        ret.extend(chunk.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+    }
+    Ok((ret, truncated))
+}
+
+fn read_head(path: &str, max: usize) -> IOResult<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(path)?.take(max as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// Text with a byte order mark is never binary, since UTF-16 has NUL bytes. Text that isn't
+// UTF-8 is read as Latin-1, so it is only binary if it has control characters other than
+// whitespace and escapes. A sequence cut off at the end of the sample isn't invalid
+fn is_binary(sample: &[u8]) -> bool {
+    if Encoding::from_bom(sample).is_some() {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => false,
+        Err(e) if e.error_len().is_none() => false,
+        Err(_) => sample
+            .iter()
+            .any(|&b| b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b)),
+    }
+}
@@ -8,12 +8,17 @@ use std::slice;
 use euclid::{size2, Size2D};
 use freetype::freetype::{
     FT_Done_Face, FT_Done_FreeType, FT_Face, FT_Get_Char_Index, FT_Init_FreeType, FT_Library,
-    FT_Load_Glyph, FT_New_Face, FT_Set_Char_Size, FT_LOAD_FORCE_AUTOHINT, FT_LOAD_RENDER,
+    FT_Load_Glyph, FT_New_Face, FT_Pixel_Mode, FT_Set_Char_Size, FT_LOAD_FORCE_AUTOHINT,
+    FT_LOAD_MONOCHROME, FT_LOAD_NO_HINTING, FT_LOAD_RENDER,
 };
 
-use super::{RasterizedGlyph, ScaledFaceMetrics};
+use super::{Hinting, RasterOptions, RasterizedGlyph, ScaledFaceMetrics};
 use crate::types::{TextSize, DPI};
 
+// FT_LOAD_TARGET_LIGHT and FT_LOAD_TARGET_MONO are macros, so they aren't in the bindings
+const FT_LOAD_TARGET_LIGHT: u32 = 1 << 16;
+const FT_LOAD_TARGET_MONO: u32 = 2 << 16;
+
 pub(super) struct RasterCore {
     ft_lib: FT_Library,
 }
@@ -46,6 +51,7 @@ impl RasterCore {
         } else {
             Some(RasterFace {
                 face: unsafe { face.assume_init() },
+                mono_buffer: Vec::new(),
             })
         }
     }
@@ -59,6 +65,7 @@ impl RasterCore {
         } else {
             Some(RasterFace {
                 face: unsafe { face.assume_init() },
+                mono_buffer: Vec::new(),
             })
         }
     }
@@ -66,6 +73,8 @@ impl RasterCore {
 
 pub(crate) struct RasterFace {
     face: FT_Face,
+    // Monochrome bitmaps, expanded to one byte per pixel
+    mono_buffer: Vec<u8>,
 }
 
 impl std::ops::Drop for RasterFace {
@@ -80,15 +89,10 @@ impl RasterFace {
         gid: u32,
         size: TextSize,
         dpi: Size2D<u32, DPI>,
+        options: RasterOptions,
     ) -> Option<RasterizedGlyph> {
         self.set_char_size(size, dpi);
-        let ret = unsafe {
-            FT_Load_Glyph(
-                self.face,
-                gid,
-                (FT_LOAD_RENDER | FT_LOAD_FORCE_AUTOHINT) as i32,
-            )
-        };
+        let ret = unsafe { FT_Load_Glyph(self.face, gid, load_flags(options) as i32) };
         if ret != 0 {
             return None;
         }
@@ -100,7 +104,21 @@ impl RasterFace {
             let rows = bitmap.rows;
             let width = bitmap.width;
             let ptr = bitmap.buffer;
-            let buffer = slice::from_raw_parts(ptr, rows as usize * width as usize);
+            let buffer = if bitmap.pixel_mode == FT_Pixel_Mode::FT_PIXEL_MODE_MONO as u8 {
+                self.mono_buffer.clear();
+                let row_bytes = (width as usize + 7) / 8;
+                for row in 0..rows as isize {
+                    let src =
+                        slice::from_raw_parts(ptr.offset(row * bitmap.pitch as isize), row_bytes);
+                    for col in 0..width as usize {
+                        let set = src[col / 8] & (0x80 >> (col % 8)) != 0;
+                        self.mono_buffer.push(if set { 255 } else { 0 });
+                    }
+                }
+                &self.mono_buffer[..]
+            } else {
+                slice::from_raw_parts(ptr, rows as usize * width as usize)
+            };
             Some(RasterizedGlyph {
                 size: size2(width, rows),
                 bearing: size2(bitmap_left, bitmap_top),
@@ -173,3 +191,19 @@ impl RasterFace {
         )
     }
 }
+
+fn load_flags(options: RasterOptions) -> u32 {
+    let mut ret = FT_LOAD_RENDER;
+    ret |= match options.hinting {
+        Hinting::None => FT_LOAD_NO_HINTING,
+        Hinting::Slight => FT_LOAD_TARGET_LIGHT,
+        Hinting::Full => 0,
+    };
+    if options.autohint {
+        ret |= FT_LOAD_FORCE_AUTOHINT;
+    }
+    if !options.antialias {
+        ret = (ret & !FT_LOAD_TARGET_LIGHT) | FT_LOAD_TARGET_MONO | FT_LOAD_MONOCHROME;
+    }
+    ret
+}
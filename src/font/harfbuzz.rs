@@ -10,9 +10,10 @@ use crate::types::{PixelSize, TextSize, DPI};
 use harfbuzz_sys::{
     hb_blob_create_from_file, hb_blob_destroy, hb_blob_t, hb_buffer_add, hb_buffer_clear_contents,
     hb_buffer_create, hb_buffer_destroy, hb_buffer_get_glyph_infos, hb_buffer_get_glyph_positions,
-    hb_buffer_guess_segment_properties, hb_buffer_set_content_type, hb_buffer_t, hb_face_create,
-    hb_face_destroy, hb_font_create, hb_font_destroy, hb_font_set_scale, hb_font_t,
-    hb_glyph_info_t, hb_glyph_position_t, hb_shape, HB_BUFFER_CONTENT_TYPE_UNICODE,
+    hb_buffer_guess_segment_properties, hb_buffer_set_cluster_level, hb_buffer_set_content_type,
+    hb_buffer_t, hb_face_create, hb_face_destroy, hb_font_create, hb_font_destroy,
+    hb_font_set_scale, hb_font_t, hb_glyph_info_t, hb_glyph_position_t, hb_shape,
+    HB_BUFFER_CLUSTER_LEVEL_MONOTONE_CHARACTERS, HB_BUFFER_CONTENT_TYPE_UNICODE,
 };
 
 pub(crate) fn shape<'a>(font: &HbFont, buf: &'a mut HbBuffer) -> GlyphInfoIter<'a> {
@@ -68,10 +69,15 @@ impl HbBuffer {
     pub(super) fn new() -> Option<HbBuffer> {
         let ptr = unsafe { hb_buffer_create() };
         if ptr.is_null() {
-            None
-        } else {
-            Some(HbBuffer { raw: ptr })
+            return None;
+        }
+        // Characters that are merged into a grapheme, like combining marks, keep clusters of
+        // their own, so that glyphs can be mapped back to graphemes exactly. Clearing the
+        // contents keeps the cluster level
+        unsafe {
+            hb_buffer_set_cluster_level(ptr, HB_BUFFER_CLUSTER_LEVEL_MONOTONE_CHARACTERS);
         }
+        Some(HbBuffer { raw: ptr })
     }
 
     pub(crate) fn clear_contents(&mut self) {
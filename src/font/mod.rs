@@ -101,6 +101,8 @@ pub(crate) struct FontCore {
     raster_core: RasterCore,
     hb_buffer: HbBuffer,
     source: source::FontSource,
+    // Faces tried for characters missing from a face, before asking fontconfig
+    fallback_faces: Vec<FaceKey>,
 }
 
 impl FontCore {
@@ -115,6 +117,7 @@ impl FontCore {
             raster_core: raster_core,
             hb_buffer: hb_buffer,
             next_key: 0,
+            fallback_faces: Vec::new(),
         })
     }
 
@@ -154,6 +157,30 @@ impl FontCore {
         }
     }
 
+    /// Family name of given face
+    pub(crate) fn family_name(&self, key: FaceKey) -> Option<&str> {
+        self.key_face_map
+            .get(&key)
+            .map(|group| group.family.name.as_str())
+    }
+
+    /// Family names of all loaded faces, including fallbacks
+    pub(crate) fn loaded_families(&self) -> Vec<&str> {
+        let mut ret = self
+            .key_face_map
+            .values()
+            .map(|group| group.family.name.as_str())
+            .collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+
+    /// Set faces to try, in order, for characters that a face has no glyph for. These are
+    /// tried before fallbacks found by fontconfig
+    pub(crate) fn set_fallback_faces(&mut self, faces: Vec<FaceKey>) {
+        self.fallback_faces = faces;
+    }
+
     pub(crate) fn find_for_char(&mut self, base: FaceKey, c: char) -> Option<FaceKey> {
         let default_style = TextStyle::default();
 
@@ -163,6 +190,18 @@ impl FontCore {
             return Some(base);
         }
 
+        for key in &self.fallback_faces {
+            let face = self
+                .key_face_map
+                .get(key)
+                .and_then(|group| group.family.get_face(default_style));
+            if let Some(face) = face {
+                if face.raster.has_glyph_for_char(c) {
+                    return Some(*key);
+                }
+            }
+        }
+
         for key in &group.fallbacks {
             let group = self.key_face_map.get(&key)?;
             let face = group.family.get_face(default_style)?;
@@ -220,6 +259,34 @@ impl FontCore {
     }
 }
 
+/// How far glyph outlines are moved to line up with the pixel grid
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Hinting {
+    None,
+    // Only vertically, which keeps glyph shapes closer to the design
+    Slight,
+    Full,
+}
+
+/// How glyphs are rasterized
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct RasterOptions {
+    pub(crate) hinting: Hinting,
+    // Use FreeType's auto-hinter instead of the font's own hinting instructions
+    pub(crate) autohint: bool,
+    pub(crate) antialias: bool,
+}
+
+impl Default for RasterOptions {
+    fn default() -> RasterOptions {
+        RasterOptions {
+            hinting: Hinting::Full,
+            autohint: true,
+            antialias: true,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct RasterizedGlyph<'a> {
     pub(crate) size: Size2D<u32, PixelSize>,
@@ -0,0 +1,21 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Contents of object, like "HEAD~1:src/main.rs", as of git revision. Paths starting with "./"
+/// are relative to dir, and other paths to the root of the repository containing dir
+pub(crate) fn show(object: &str, dir: &Path) -> IOResult<String> {
+    let output = Command::new("git")
+        .arg("show")
+        .arg(object)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(IOError::new(ErrorKind::Other, stderr.trim().to_owned()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,126 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::collections::HashMap;
+
+use euclid::Size2D;
+
+use crate::config::{Cfg, CfgUiGutterEllipsis};
+use crate::font::FontCore;
+use crate::types::{TextPitch, TextSlant, TextStyle, TextWeight, DPI};
+use crate::ui::text::{ShapedTextLine, TextSpan};
+
+// Shaped numbers are dropped once there are more than this many
+const MAX_CACHED_NUMBERS: usize = 4096;
+
+/// Line numbers shaped at one DPI. Numbers are only shaped when they are about to be drawn, so
+/// that opening a huge file doesn't shape a number for every line
+#[derive(Default)]
+pub(crate) struct ShapedGutter {
+    numbers: HashMap<usize, ShapedTextLine>,
+    // Largest number in the buffer. Its width is the width of the gutter, and its metrics stand
+    // in for numbers that aren't shaped yet, since all numbers have the same height
+    widest: ShapedTextLine,
+    max_number: usize,
+}
+
+impl ShapedGutter {
+    /// Shaped number, or the widest number if n isn't shaped yet
+    pub(crate) fn get(&self, n: usize) -> &ShapedTextLine {
+        self.numbers.get(&n).unwrap_or(&self.widest)
+    }
+
+    /// Largest number, which is as wide as the gutter has to be
+    pub(crate) fn widest(&self) -> &ShapedTextLine {
+        &self.widest
+    }
+
+    /// Set the largest number, which is the number of lines in the buffer
+    pub(crate) fn set_max_number(
+        &mut self,
+        n: usize,
+        dpi: Size2D<u32, DPI>,
+        config: &Cfg,
+        font_core: &mut FontCore,
+    ) {
+        if n == self.max_number {
+            return;
+        }
+        self.max_number = n;
+        self.numbers.retain(|k, _| *k <= n);
+        self.widest = shape_number(n, dpi, config, font_core);
+    }
+
+    /// Shape numbers that aren't shaped yet. Numbers past the largest one are skipped
+    pub(crate) fn shape(
+        &mut self,
+        numbers: &[usize],
+        dpi: Size2D<u32, DPI>,
+        config: &Cfg,
+        font_core: &mut FontCore,
+    ) {
+        if self.numbers.len() + numbers.len() > MAX_CACHED_NUMBERS {
+            self.numbers.clear();
+        }
+        for &n in numbers {
+            if n <= self.max_number && !self.numbers.contains_key(&n) {
+                let shaped = shape_number(n, dpi, config, font_core);
+                self.numbers.insert(n, shaped);
+            }
+        }
+    }
+
+    /// Drop shaped numbers, for when the config changes. The largest number has to be set again
+    pub(crate) fn clear(&mut self) {
+        self.numbers.clear();
+        self.widest = ShapedTextLine::default();
+        self.max_number = 0;
+    }
+
+    /// Approximate memory used by shaped numbers, in bytes
+    pub(crate) fn approx_size(&self) -> usize {
+        let numbers = self
+            .numbers
+            .values()
+            .map(|x| x.approx_size())
+            .sum::<usize>();
+        numbers + self.widest.approx_size()
+    }
+}
+
+fn shape_number(
+    n: usize,
+    dpi: Size2D<u32, DPI>,
+    config: &Cfg,
+    font_core: &mut FontCore,
+) -> ShapedTextLine {
+    let cfggtr = &config.ui.gutter;
+    let text = truncate_number(n, cfggtr.max_digits, cfggtr.ellipsis);
+    let span = TextSpan::new(
+        &text,
+        cfggtr.text_size,
+        TextStyle::new(TextWeight::Medium, TextSlant::Roman),
+        config.ui.theme().gutter.foreground_color,
+        TextPitch::Fixed,
+        None,
+    );
+    ShapedTextLine::from_textstr(
+        span,
+        cfggtr.fixed_face,
+        cfggtr.variable_face,
+        font_core,
+        dpi,
+    )
+}
+
+// Number with at most max_digits characters, counting the ellipsis
+fn truncate_number(n: usize, max_digits: usize, ellipsis: CfgUiGutterEllipsis) -> String {
+    let s = n.to_string();
+    if max_digits == 0 || s.len() <= max_digits {
+        return s;
+    }
+    let keep = max_digits - 1;
+    match ellipsis {
+        CfgUiGutterEllipsis::Start => format!("\u{2026}{}", &s[s.len() - keep..]),
+        CfgUiGutterEllipsis::End => format!("{}\u{2026}", &s[..keep]),
+    }
+}
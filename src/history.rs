@@ -0,0 +1,75 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::fs::{create_dir_all, read_to_string, write};
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+
+// Older patterns are dropped beyond this
+const MAX_PATTERNS: usize = 100;
+
+/// Patterns searched for with `/`, oldest first. These are saved in the data directory, so they
+/// persist across sessions
+pub(crate) struct SearchHistory {
+    patterns: Vec<String>,
+    file_path: Option<PathBuf>,
+}
+
+impl SearchHistory {
+    pub(crate) fn load() -> SearchHistory {
+        let file_path = ProjectDirs::from("", "sbarua", "bed")
+            .map(|dirs| dirs.data_dir().join("search_history"));
+        // Each line is a pattern
+        let patterns = file_path
+            .as_ref()
+            .and_then(|p| read_to_string(p).ok())
+            .map(|data| {
+                data.lines()
+                    .filter(|l| l.len() > 0)
+                    .map(|l| l.to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        SearchHistory {
+            patterns: patterns,
+            file_path: file_path,
+        }
+    }
+
+    pub(crate) fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    pub(crate) fn last(&self) -> Option<&str> {
+        self.patterns.last().map(|s| s.as_str())
+    }
+
+    /// Add pattern as the newest entry, moving it there if it was searched for before
+    pub(crate) fn push(&mut self, pattern: &str) {
+        self.patterns.retain(|p| p != pattern);
+        self.patterns.push(pattern.to_owned());
+        if self.patterns.len() > MAX_PATTERNS {
+            let extra = self.patterns.len() - MAX_PATTERNS;
+            self.patterns.drain(..extra);
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.file_path {
+            if let Err(e) = self.write_to(path) {
+                error!("failed to save search history: {:?}: {}", path, e);
+            }
+        }
+    }
+
+    fn write_to(&self, path: &Path) -> IOResult<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut data = self.patterns.join("\n");
+        data.push('\n');
+        write(path, data)
+    }
+}
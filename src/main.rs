@@ -1,20 +1,48 @@
 // (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
 
 use std::cell::RefCell;
+use std::cmp::min;
+use std::path::Path;
 use std::rc::Rc;
-use std::{thread, time};
+use std::time;
 
+// Declared first, so that its macros are available in the other modules
+#[macro_use]
+mod messages;
+
+mod annotations;
+mod batch;
+mod build;
 mod config;
 mod core;
+mod debug_server;
+mod decorations;
+mod encoding;
+mod expr;
+mod filecheck;
 mod font;
+mod git;
+mod gutter;
+mod history;
+mod marks;
+mod notes;
+mod project;
+mod provider;
+mod registers;
+mod search;
+mod session;
+mod substitute;
+mod symbols;
 mod syntax;
 mod textbuffer;
+mod trash;
 mod types;
 mod ui;
 
 const WIDTH: u32 = 800;
 const HEIGHT: u32 = 600;
 const TITLE: &str = "bed";
+const FPS: u64 = 60;
 
 fn main() {
     let args = parse_args();
@@ -25,34 +53,90 @@ fn main() {
     ));
     let config = {
         let fc = &mut *font_core.borrow_mut();
-        Rc::new(RefCell::new(config::Cfg::load(fc)))
+        if args.is_present("default-config") {
+            Rc::new(RefCell::new(config::Cfg::default(fc)))
+        } else {
+            Rc::new(RefCell::new(config::Cfg::load(fc)))
+        }
     };
 
+    // Batch mode runs commands on files without a window, and exits
+    if args.is_present("batch") {
+        let files = args
+            .values_of("FILE")
+            .map(|v| v.collect())
+            .unwrap_or(Vec::new());
+        let commands = args
+            .values_of("command")
+            .map(|v| v.collect())
+            .unwrap_or(Vec::new());
+        std::process::exit(batch::run(&files, &commands, font_core, config));
+    }
+
+    let debug_socket = args.value_of("debug-socket").map(|path| path.to_owned());
+
     let (mut ui_core, window, events) =
         ui::UICore::init(args, font_core, config, WIDTH, HEIGHT, TITLE);
     let mut windows = vec![(window, events, time::Instant::now())];
 
-    let target_duration = time::Duration::from_nanos(1_000_000_000 / 60);
+    let debug_server = debug_socket.and_then(|path| {
+        match debug_server::DebugServer::start(Path::new(&path), ui_core.wakeup()) {
+            Ok(server) => Some(server),
+            Err(e) => {
+                error!("failed to start debug server: {}: {}", path, e);
+                None
+            }
+        }
+    });
+
+    let target_duration = time::Duration::from_nanos(1_000_000_000 / FPS);
+    let mut timeout = Some(time::Duration::from_secs(0));
 
     while windows.len() > 0 {
+        ui_core.wait_events(timeout);
         let start = time::Instant::now();
-        ui_core.poll_events();
         windows.retain(|(window, _, _)| !window.should_close());
 
         for i in 0..windows.len() {
             let (window, events, last_time) = &mut windows[i];
             let cur_time = time::Instant::now();
-            let should_refresh = window.handle_events(events, cur_time - *last_time);
+            // Animations that start after a long wait for events begin from their first frame
+            let duration = min(cur_time - *last_time, target_duration);
+            let should_refresh = window.handle_events(events, duration);
             if should_refresh {
                 window.refresh();
             }
             windows[i].2 = cur_time;
         }
 
-        let diff = start.elapsed();
-        if diff < target_duration {
-            thread::sleep(target_duration - diff);
+        // Requests from the debug server are answered by the first window
+        if let (Some(server), Some((window, _, _))) = (&debug_server, windows.first_mut()) {
+            let requests = server.requests();
+            if !requests.is_empty() {
+                for request in requests {
+                    let reply = window.debug_request(&request.line);
+                    request.reply(reply);
+                }
+                window.refresh();
+            }
         }
+
+        // Wait for the next frame while something is animating. Otherwise sleep until an event
+        // arrives. Work running in the background wakes the loop up when it has something to
+        // hand over
+        let target = if windows.iter().any(|(window, _, _)| window.is_animating()) {
+            Some(target_duration)
+        } else {
+            None
+        };
+        let diff = start.elapsed();
+        timeout = target.map(|target| {
+            if diff < target {
+                target - diff
+            } else {
+                time::Duration::from_secs(0)
+            }
+        });
     }
 }
 
@@ -64,9 +148,43 @@ fn parse_args() -> clap::ArgMatches<'static> {
         .about("Barua's editor")
         .arg(
             Arg::with_name("FILE")
-                .help("file to open")
+                .help("file to open, or files to run commands on in batch mode")
                 .required(false)
+                .multiple(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("session")
+                .long("session")
+                .value_name("NAME")
+                .help("restore session saved with :mksession")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .help("run commands on files without opening a window, and exit"),
+        )
+        .arg(
+            Arg::with_name("command")
+                .short("c")
+                .value_name("COMMAND")
+                .help("command to run in batch mode, like ':%s/a/b/g' or ':w'")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("debug-socket")
+                .long("debug-socket")
+                .value_name("PATH")
+                .help("answer queries about the editor's state on a unix socket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("default-config")
+                .long("default-config")
+                .help("ignore the config file, so that rendering can be compared across machines"),
+        )
         .get_matches()
 }
@@ -0,0 +1,154 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::collections::HashMap;
+use std::fs::{canonicalize, create_dir_all, read_to_string, write};
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+
+/// A position in a file. Lines and grapheme indices are 0-based
+#[derive(Clone, Debug)]
+pub(crate) struct FileMark {
+    pub(crate) path: String,
+    pub(crate) line: usize,
+    pub(crate) gidx: usize,
+}
+
+impl FileMark {
+    // Marks outlive the session, so they shouldn't depend on the working directory
+    fn absolute(self) -> FileMark {
+        match canonicalize(&self.path) {
+            Ok(path) => FileMark {
+                path: path.to_string_lossy().into_owned(),
+                line: self.line,
+                gidx: self.gidx,
+            },
+            Err(_) => self,
+        }
+    }
+}
+
+/// Global (uppercase) marks and named bookmarks. These are saved in the data directory, so they
+/// persist across sessions
+pub(crate) struct Marks {
+    global: HashMap<char, FileMark>,
+    bookmarks: Vec<(String, FileMark)>,
+    file_path: Option<PathBuf>,
+}
+
+impl Marks {
+    pub(crate) fn load() -> Marks {
+        let file_path =
+            ProjectDirs::from("", "sbarua", "bed").map(|dirs| dirs.data_dir().join("marks"));
+        let mut ret = Marks {
+            global: HashMap::new(),
+            bookmarks: Vec::new(),
+            file_path: file_path,
+        };
+        let data = match ret.file_path.as_ref().and_then(|p| read_to_string(p).ok()) {
+            Some(data) => data,
+            None => return ret,
+        };
+        // Each line is "<kind>\t<name>\t<line>\t<gidx>\t<path>"
+        for line in data.lines() {
+            let fields = line.splitn(5, '\t').collect::<Vec<_>>();
+            if fields.len() != 5 {
+                continue;
+            }
+            let mark = match (fields[2].parse(), fields[3].parse()) {
+                (Ok(line), Ok(gidx)) => FileMark {
+                    path: fields[4].to_owned(),
+                    line: line,
+                    gidx: gidx,
+                },
+                _ => continue,
+            };
+            match fields[0] {
+                "mark" => {
+                    let mut chars = fields[1].chars();
+                    if let (Some(c), None) = (chars.next(), chars.next()) {
+                        if c.is_ascii_uppercase() {
+                            ret.global.insert(c, mark);
+                        }
+                    }
+                }
+                "bookmark" => ret.bookmarks.push((fields[1].to_owned(), mark)),
+                _ => {}
+            }
+        }
+        ret
+    }
+
+    pub(crate) fn global(&self, c: char) -> Option<&FileMark> {
+        self.global.get(&c)
+    }
+
+    pub(crate) fn set_global(&mut self, c: char, mark: FileMark) {
+        self.global.insert(c, mark.absolute());
+        self.save();
+    }
+
+    pub(crate) fn bookmark(&self, name: &str) -> Option<&FileMark> {
+        self.bookmarks
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, mark)| mark)
+    }
+
+    pub(crate) fn bookmarks(&self) -> &[(String, FileMark)] {
+        &self.bookmarks
+    }
+
+    /// Add bookmark, replacing any existing bookmark with the same name
+    pub(crate) fn add_bookmark(&mut self, name: &str, mark: FileMark) {
+        let mark = mark.absolute();
+        if let Some(existing) = self.bookmarks.iter_mut().find(|(n, _)| n == name) {
+            existing.1 = mark;
+        } else {
+            self.bookmarks.push((name.to_owned(), mark));
+        }
+        self.save();
+    }
+
+    /// Remove bookmark. Returns false if there was no bookmark with the given name
+    pub(crate) fn remove_bookmark(&mut self, name: &str) -> bool {
+        let len = self.bookmarks.len();
+        self.bookmarks.retain(|(n, _)| n != name);
+        if self.bookmarks.len() == len {
+            return false;
+        }
+        self.save();
+        true
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.file_path {
+            if let Err(e) = self.write_to(path) {
+                error!("failed to save marks: {:?}: {}", path, e);
+            }
+        }
+    }
+
+    fn write_to(&self, path: &Path) -> IOResult<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut global = self.global.iter().collect::<Vec<_>>();
+        global.sort_by_key(|(c, _)| **c);
+        let mut data = String::new();
+        for (c, mark) in global {
+            data.push_str(&format!(
+                "mark\t{}\t{}\t{}\t{}\n",
+                c, mark.line, mark.gidx, mark.path
+            ));
+        }
+        for (name, mark) in &self.bookmarks {
+            data.push_str(&format!(
+                "bookmark\t{}\t{}\t{}\t{}\n",
+                name, mark.line, mark.gidx, mark.path
+            ));
+        }
+        write(path, data)
+    }
+}
@@ -0,0 +1,114 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+
+// Messages kept for :messages. Older ones are dropped
+const MAX_MESSAGES: usize = 500;
+
+/// Report an error, with arguments like format!
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::messages::push($crate::messages::Severity::Error, format!($($arg)*))
+    };
+}
+
+/// Report something that may need attention, like unwritten changes, with arguments like format!
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::messages::push($crate::messages::Severity::Warning, format!($($arg)*))
+    };
+}
+
+/// Report the result of a command, with arguments like format!
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::messages::push($crate::messages::Severity::Info, format!($($arg)*))
+    };
+}
+
+/// How serious a message is
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A message for the user, like the result of a command or an error
+#[derive(Clone, Debug)]
+pub(crate) struct Message {
+    pub(crate) severity: Severity,
+    pub(crate) text: String,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Info => write!(f, "{}", self.text),
+            _ => write!(f, "{}: {}", self.severity.name(), self.text),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Log {
+    messages: VecDeque<Message>,
+    // Messages reported so far, including dropped ones
+    count: u64,
+}
+
+thread_local! {
+    // Everything that reports messages runs on the main thread. Background work hands its
+    // results back to the main thread, which reports them
+    static LOG: RefCell<Log> = RefCell::new(Log::default());
+}
+
+/// Report a message. Windows show the latest one in the prompt bar. Messages are also printed to
+/// stderr, which is all there is in batch mode
+pub(crate) fn push(severity: Severity, text: String) {
+    let message = Message {
+        severity: severity,
+        text: text,
+    };
+    eprintln!("{}", message);
+    LOG.with(|log| {
+        let log = &mut *log.borrow_mut();
+        if log.messages.len() == MAX_MESSAGES {
+            log.messages.pop_front();
+        }
+        log.messages.push_back(message);
+        log.count += 1;
+    });
+}
+
+/// Number of messages reported so far. It changes when there's a new message to show
+pub(crate) fn count() -> u64 {
+    LOG.with(|log| log.borrow().count)
+}
+
+/// The latest message, if any
+pub(crate) fn latest() -> Option<Message> {
+    LOG.with(|log| log.borrow().messages.back().cloned())
+}
+
+/// Messages kept so far, oldest first
+pub(crate) fn history() -> Vec<Message> {
+    LOG.with(|log| log.borrow().messages.iter().cloned().collect())
+}
+
+/// Forget messages kept so far
+pub(crate) fn clear() {
+    LOG.with(|log| log.borrow_mut().messages.clear());
+}
@@ -0,0 +1,111 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::fs::{canonicalize, create_dir_all, read_to_string, write};
+use std::io::Result as IOResult;
+use std::path::{Path, PathBuf};
+
+use directories::ProjectDirs;
+
+/// Text note attached to a line in a file. Lines are 0-based
+#[derive(Clone, Debug)]
+pub(crate) struct Note {
+    pub(crate) path: String,
+    pub(crate) line: usize,
+    pub(crate) text: String,
+}
+
+/// Notes on lines of files in a project. These are saved in the data directory, in a separate
+/// file for each project directory
+pub(crate) struct Notes {
+    notes: Vec<Note>,
+    file_path: Option<PathBuf>,
+}
+
+impl Notes {
+    pub(crate) fn load(project_dir: &Path) -> Notes {
+        // Name the file after the project directory, with separators replaced
+        let name = absolute_path(&project_dir.to_string_lossy())
+            .replace(|c| c == '/' || c == '\\' || c == ':', "%");
+        let file_path = ProjectDirs::from("", "sbarua", "bed")
+            .map(|dirs| dirs.data_dir().join("notes").join(name));
+        let mut ret = Notes {
+            notes: Vec::new(),
+            file_path: file_path,
+        };
+        let data = match ret.file_path.as_ref().and_then(|p| read_to_string(p).ok()) {
+            Some(data) => data,
+            None => return ret,
+        };
+        // Each line is "<line>\t<path>\t<text>"
+        for line in data.lines() {
+            let fields = line.splitn(3, '\t').collect::<Vec<_>>();
+            if fields.len() != 3 {
+                continue;
+            }
+            if let Ok(linum) = fields[0].parse() {
+                ret.notes.push(Note {
+                    path: fields[1].to_owned(),
+                    line: linum,
+                    text: fields[2].to_owned(),
+                });
+            }
+        }
+        ret
+    }
+
+    pub(crate) fn all(&self) -> &[Note] {
+        &self.notes
+    }
+
+    /// Notes for file at path, as lines and text
+    pub(crate) fn for_path(&self, path: &str) -> Vec<(usize, String)> {
+        let path = absolute_path(path);
+        self.notes
+            .iter()
+            .filter(|note| note.path == path)
+            .map(|note| (note.line, note.text.clone()))
+            .collect()
+    }
+
+    /// Replace all notes for file at path
+    pub(crate) fn set_for_path(&mut self, path: &str, notes: Vec<(usize, String)>) {
+        let path = absolute_path(path);
+        self.notes.retain(|note| note.path != path);
+        for (line, text) in notes {
+            self.notes.push(Note {
+                path: path.clone(),
+                line: line,
+                text: text,
+            });
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.file_path {
+            if let Err(e) = self.write_to(path) {
+                error!("failed to save notes: {:?}: {}", path, e);
+            }
+        }
+    }
+
+    fn write_to(&self, path: &Path) -> IOResult<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut data = String::new();
+        for note in &self.notes {
+            data.push_str(&format!("{}\t{}\t{}\n", note.line, note.path, note.text));
+        }
+        write(path, data)
+    }
+}
+
+/// Canonical form of path that notes are saved with. Notes outlive the session, so they
+/// shouldn't depend on the working directory
+pub(crate) fn absolute_path(path: &str) -> String {
+    match canonicalize(path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => path.to_owned(),
+    }
+}
@@ -0,0 +1,12 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::path::{Path, PathBuf};
+
+/// Root of the project a file is in. This is the nearest directory above the file that holds
+/// one of the markers, like ".git" or "Cargo.toml"
+pub(crate) fn project_root(path: &Path, markers: &[String]) -> Option<PathBuf> {
+    path.parent()?
+        .ancestors()
+        .find(|dir| markers.iter().any(|m| dir.join(m).exists()))
+        .map(|dir| dir.to_path_buf())
+}
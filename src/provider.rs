@@ -0,0 +1,358 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::ffi::OsString;
+use std::fs::{
+    canonicalize, copy, create_dir_all, metadata, read, remove_file, rename, write, File, Metadata,
+    OpenOptions,
+};
+use std::io::{Error as IOError, ErrorKind, Result as IOResult, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::process::{self, Command, Stdio};
+use std::time::SystemTime;
+
+use crate::config::CfgUiBackup;
+
+/// Reads and writes the files that buffers are backed by. Contents are bytes, which buffers
+/// decode and encode
+pub(crate) trait FileProvider: Sync {
+    /// Reads may be slow, and should happen in the background
+    fn is_remote(&self) -> bool;
+
+    fn read(&self, path: &str) -> IOResult<Vec<u8>>;
+
+    /// Backups are only made of local files
+    fn write(&self, path: &str, data: &[u8], backup: &CfgUiBackup) -> IOResult<()>;
+
+    /// Modification time, if it can be found cheaply
+    fn mtime(&self, path: &str) -> Option<SystemTime>;
+}
+
+/// Provider for the file at path. Paths starting with "ssh://" are remote, and everything else
+/// is a local path
+pub(crate) fn provider(path: &str) -> &'static dyn FileProvider {
+    if path.starts_with("ssh://") {
+        &SshFiles
+    } else {
+        &LocalFiles
+    }
+}
+
+/// Write data to the local file at path as the root user. pkexec asks for a password in a
+/// dialog, since there is no terminal to ask in
+pub(crate) fn write_as_root(path: &str, data: &[u8]) -> IOResult<()> {
+    let mut child = Command::new("pkexec")
+        .arg("tee")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(data)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let msg = match stderr.trim() {
+            "" => "pkexec failed".to_owned(),
+            stderr => stderr.to_owned(),
+        };
+        return Err(IOError::new(ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+/// Message for an error writing the file at path, saying what can be done about it
+pub(crate) fn write_error_message(path: &str, e: &IOError) -> String {
+    let hint = match (e.kind(), e.raw_os_error()) {
+        (ErrorKind::PermissionDenied, _) if !provider(path).is_remote() => {
+            "permission denied: write as root, or :w to another file"
+        }
+        (_, Some(code)) if READ_ONLY_ERRORS.contains(&code) => {
+            "file system is read-only: :w to a file on another file system"
+        }
+        (_, Some(code)) if DISK_FULL_ERRORS.contains(&code) => {
+            "no space left on device: free up space, or :w to a file on another device"
+        }
+        (ErrorKind::NotFound, _) => "directory doesn't exist: create it, or :w to another file",
+        _ => return format!("failed to write {}: {}", path, e),
+    };
+    format!(
+        "failed to write {}: {}. The buffer still has your changes",
+        path, hint
+    )
+}
+
+// OS error codes for errors that std doesn't have kinds for yet. EROFS, and ENOSPC and EDQUOT
+#[cfg(target_os = "linux")]
+const READ_ONLY_ERRORS: &[i32] = &[30];
+#[cfg(target_os = "linux")]
+const DISK_FULL_ERRORS: &[i32] = &[28, 122];
+
+// ERROR_WRITE_PROTECT, and ERROR_HANDLE_DISK_FULL and ERROR_DISK_FULL
+#[cfg(target_os = "windows")]
+const READ_ONLY_ERRORS: &[i32] = &[19];
+#[cfg(target_os = "windows")]
+const DISK_FULL_ERRORS: &[i32] = &[39, 112];
+
+struct LocalFiles;
+
+impl FileProvider for LocalFiles {
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    fn read(&self, path: &str) -> IOResult<Vec<u8>> {
+        read(path)
+    }
+
+    fn write(&self, path: &str, data: &[u8], backup: &CfgUiBackup) -> IOResult<()> {
+        // Write through symlinks to the files they point to, rather than replacing the links
+        let path = canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        let meta = metadata(&path).ok().filter(|m| m.is_file());
+        let backup_path = if meta.is_some() && (backup.keep || backup.while_writing) {
+            let backup_path = backup_path(&path, backup.dir.as_ref().map(|d| d.as_path()));
+            if let Err(e) = make_backup(&path, &backup_path) {
+                let msg = format!("failed to back up to {}: {}", backup_path.display(), e);
+                return Err(IOError::new(e.kind(), msg));
+            }
+            Some(backup_path)
+        } else {
+            None
+        };
+        write_replacing(&path, data, meta.as_ref())?;
+        match backup_path {
+            Some(backup_path) if !backup.keep => {
+                let _ = remove_file(backup_path);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn mtime(&self, path: &str) -> Option<SystemTime> {
+        metadata(path).and_then(|m| m.modified()).ok()
+    }
+}
+
+// Backups go next to files, or in the backup directory with the full path in their name so that
+// files with the same name don't share backups
+fn backup_path(path: &Path, dir: Option<&Path>) -> PathBuf {
+    match dir {
+        Some(dir) => {
+            let name = path.to_string_lossy().replace(MAIN_SEPARATOR, "%");
+            dir.join(format!("{}~", name))
+        }
+        None => {
+            let mut name = path.as_os_str().to_owned();
+            name.push("~");
+            PathBuf::from(name)
+        }
+    }
+}
+
+fn make_backup(path: &Path, backup_path: &Path) -> IOResult<()> {
+    if let Some(dir) = backup_path.parent() {
+        create_dir_all(dir)?;
+    }
+    copy(path, backup_path).map(|_| ())
+}
+
+// Write data to path through a temporary file next to it, which replaces it once it is complete,
+// so that a failed write leaves the old contents intact. The file is written in place if the
+// temporary file can't be created, or can't replace the file without changing more than its
+// contents. meta is the file's metadata, if it exists
+fn write_replacing(path: &Path, data: &[u8], meta: Option<&Metadata>) -> IOResult<()> {
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    // The directory may not be writable even though the file is
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp_path)
+    {
+        Ok(file) => file,
+        Err(_) => return write(path, data),
+    };
+    let replaceable = match (meta, file.metadata()) {
+        (Some(meta), Ok(temp_meta)) => can_replace(meta, &temp_meta),
+        (Some(_), Err(_)) => false,
+        (None, _) => true,
+    };
+    if !replaceable {
+        drop(file);
+        let _ = remove_file(&temp_path);
+        return write(path, data);
+    }
+    let ret = fill_temp(&mut file, data, meta).and_then(|_| rename(&temp_path, path));
+    if ret.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    ret
+}
+
+// Permissions are copied before writing, so that the contents are never readable by more users
+// than the file's are
+fn fill_temp(file: &mut File, data: &[u8], meta: Option<&Metadata>) -> IOResult<()> {
+    if let Some(meta) = meta {
+        file.set_permissions(meta.permissions())?;
+    }
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+// Whether the file with metadata new can replace the one with metadata old, without losing
+// anything but its contents. Replacing a file separates it from its hard links
+#[cfg(unix)]
+fn can_replace(old: &Metadata, new: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    old.nlink() == 1 && old.uid() == new.uid() && old.gid() == new.gid()
+}
+
+#[cfg(not(unix))]
+fn can_replace(_old: &Metadata, _new: &Metadata) -> bool {
+    true
+}
+
+// Files at ssh://[user@]host[:port]/path. Paths starting with /~/ are relative to the home
+// directory. The system ssh client is used, so ~/.ssh/config, keys and agents work as usual.
+// Connections are kept open and shared between reads and writes where ssh supports it
+struct SshFiles;
+
+// Exit status of the read command if the file doesn't exist
+const SSH_NOT_FOUND: i32 = 3;
+
+impl FileProvider for SshFiles {
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    fn read(&self, path: &str) -> IOResult<Vec<u8>> {
+        let target = SshTarget::parse(path)?;
+        let path = target.quoted_path();
+        let output = target
+            .command(&format!(
+                "test -e {} || exit {}; cat -- {}",
+                path, SSH_NOT_FOUND, path
+            ))
+            .stdin(Stdio::null())
+            .output()?;
+        match output.status.code() {
+            Some(0) => Ok(output.stdout),
+            Some(SSH_NOT_FOUND) => Err(IOError::new(ErrorKind::NotFound, "no such file")),
+            _ => Err(ssh_error(&output.stderr)),
+        }
+    }
+
+    // The data is written to a temporary file next to the file, which then replaces it, so that
+    // a failed transfer leaves the file as it was. The temporary file gets the file's permissions,
+    // or the default ones for a new file. Symlinks are followed, so that they stay links
+    fn write(&self, path: &str, data: &[u8], _backup: &CfgUiBackup) -> IOResult<()> {
+        let target = SshTarget::parse(path)?;
+        let script = format!(
+            "f={}\n\
+             if [ -L \"$f\" ]; then f=$(readlink -f -- \"$f\") || exit 1; fi\n\
+             case $f in /*) ;; *) f=./$f ;; esac\n\
+             t=$(mktemp -- \"$f.bed-XXXXXX\") || exit 1\n\
+             if [ -e \"$f\" ]; then cp -p -- \"$f\" \"$t\"; \
+             else chmod \"$(printf %o $((0666 & ~$(umask))))\" \"$t\"; fi \
+             && cat > \"$t\" && mv -f -- \"$t\" \"$f\" || {{ rm -f -- \"$t\"; exit 1; }}",
+            target.quoted_path()
+        );
+        let mut child = target.command(&script).stdin(Stdio::piped()).spawn()?;
+        let written = child.stdin.take().unwrap().write_all(data);
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(ssh_error(&output.stderr));
+        }
+        written
+    }
+
+    // Finding the modification time takes a round trip, so changes made on the remote machine
+    // aren't detected
+    fn mtime(&self, _path: &str) -> Option<SystemTime> {
+        None
+    }
+}
+
+struct SshTarget<'a> {
+    destination: &'a str,
+    port: Option<&'a str>,
+    path: &'a str,
+}
+
+impl<'a> SshTarget<'a> {
+    fn parse(uri: &'a str) -> IOResult<SshTarget<'a>> {
+        let invalid = || IOError::new(ErrorKind::InvalidInput, "expected ssh://[user@]host/path");
+        let rest = uri.strip_prefix("ssh://").ok_or_else(invalid)?;
+        let slash = rest.find('/').ok_or_else(invalid)?;
+        let (authority, path) = rest.split_at(slash);
+        let (destination, port) = match authority.rfind(':') {
+            Some(i) if authority[(i + 1)..].bytes().all(|b| b.is_ascii_digit()) => {
+                (&authority[..i], Some(&authority[(i + 1)..]))
+            }
+            _ => (authority, None),
+        };
+        if destination.is_empty() || path.len() < 2 {
+            return Err(invalid());
+        }
+        Ok(SshTarget {
+            destination: destination,
+            port: port.filter(|p| !p.is_empty()),
+            path: path.strip_prefix("/~/").unwrap_or(path),
+        })
+    }
+
+    // Path quoted for the remote shell
+    fn quoted_path(&self) -> String {
+        format!("'{}'", self.path.replace('\'', "'\\''"))
+    }
+
+    // ssh command running the given command on the remote machine
+    fn command(&self, remote_command: &str) -> Command {
+        let mut cmd = Command::new("ssh");
+        // There's no terminal to ask for passwords or host key confirmation on
+        cmd.arg("-o").arg("BatchMode=yes");
+        if let Some(dir) = control_dir() {
+            cmd.arg("-o").arg("ControlMaster=auto");
+            cmd.arg("-o")
+                .arg(format!("ControlPath={}/ssh-%C", dir.display()));
+            cmd.arg("-o").arg("ControlPersist=600");
+        }
+        if let Some(port) = self.port {
+            cmd.arg("-p").arg(port);
+        }
+        cmd.arg("--").arg(self.destination).arg(remote_command);
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        cmd
+    }
+}
+
+// Directory for shared connection sockets. Only the per-user runtime directory is used, since
+// other users could connect to sockets in a shared directory
+#[cfg(unix)]
+fn control_dir() -> Option<std::path::PathBuf> {
+    use directories::BaseDirs;
+
+    let dir = BaseDirs::new()?.runtime_dir()?.join("bed");
+    std::fs::create_dir_all(&dir).ok()?;
+    Some(dir)
+}
+
+#[cfg(not(unix))]
+fn control_dir() -> Option<std::path::PathBuf> {
+    None
+}
+
+fn ssh_error(stderr: &[u8]) -> IOError {
+    let msg = String::from_utf8_lossy(stderr);
+    let msg = msg.trim();
+    if msg.is_empty() {
+        IOError::new(ErrorKind::Other, "ssh command failed")
+    } else {
+        IOError::new(ErrorKind::Other, msg.to_owned())
+    }
+}
@@ -0,0 +1,65 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::collections::HashMap;
+
+/// Yanked or deleted text
+#[derive(Clone, Debug)]
+pub(crate) struct Register {
+    pub(crate) text: String,
+    /// Whether the text consists of whole lines, which are pasted on lines of their own
+    pub(crate) linewise: bool,
+}
+
+impl Register {
+    /// Register holding text from the system clipboard. Text ending with a newline is treated as
+    /// whole lines
+    pub(crate) fn from_clipboard(text: String) -> Register {
+        let linewise = text.ends_with('\n');
+        Register {
+            text: text,
+            linewise: linewise,
+        }
+    }
+}
+
+/// Register holding the last search pattern
+pub(crate) const SEARCH_REGISTER: char = '/';
+
+/// The unnamed register, and named registers selected with `"<name>`. The clipboard registers
+/// (`+` and `*`) aren't stored here, since they need access to a window
+#[derive(Debug, Default)]
+pub(crate) struct Registers {
+    unnamed: Option<Register>,
+    named: HashMap<char, Register>,
+}
+
+impl Registers {
+    pub(crate) fn get(&self, name: Option<char>) -> Option<&Register> {
+        match name {
+            Some(c) => self.named.get(&c),
+            None => self.unnamed.as_ref(),
+        }
+    }
+
+    /// Store text in register. The unnamed register always holds the last stored text
+    pub(crate) fn set(&mut self, name: Option<char>, reg: Register) {
+        if let Some(c) = name {
+            self.named.insert(c, reg.clone());
+        }
+        self.unnamed = Some(reg);
+    }
+
+    /// Keep the last search pattern in the search register. This doesn't change the unnamed
+    /// register
+    pub(crate) fn set_last_search(&mut self, pattern: &str) {
+        let reg = Register {
+            text: pattern.to_owned(),
+            linewise: false,
+        };
+        self.named.insert(SEARCH_REGISTER, reg);
+    }
+
+    pub(crate) fn last_search(&self) -> Option<&str> {
+        self.named.get(&SEARCH_REGISTER).map(|r| r.text.as_str())
+    }
+}
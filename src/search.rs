@@ -0,0 +1,188 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::borrow::Cow;
+
+use regex::{Regex, RegexBuilder};
+use ropey::RopeSlice;
+
+/// How letter case is treated when matching
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CaseSensitivity {
+    Sensitive,
+    Insensitive,
+    /// Insensitive unless the pattern has an uppercase letter
+    Smart,
+}
+
+/// How `/` search and `:s` interpret patterns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SearchOptions {
+    /// Treat the pattern as a regular expression instead of literal text
+    pub(crate) regex: bool,
+    pub(crate) case: CaseSensitivity,
+}
+
+impl Default for SearchOptions {
+    fn default() -> SearchOptions {
+        SearchOptions {
+            regex: false,
+            case: CaseSensitivity::Sensitive,
+        }
+    }
+}
+
+/// Compiled search pattern, for `/` search and `:s`
+#[derive(Clone)]
+pub(crate) struct Searcher {
+    pattern: String,
+    regex: Regex,
+    // Text to look for with str::find, when the pattern is literal and case sensitive
+    literal: Option<String>,
+}
+
+impl Searcher {
+    pub(crate) fn new(pattern: &str, options: SearchOptions) -> Result<Searcher, String> {
+        let ignore_case = match options.case {
+            CaseSensitivity::Sensitive => false,
+            CaseSensitivity::Insensitive => true,
+            CaseSensitivity::Smart => !pattern.chars().any(|c| c.is_uppercase()),
+        };
+        let source = if options.regex {
+            Cow::Borrowed(pattern)
+        } else {
+            Cow::Owned(regex::escape(pattern))
+        };
+        let regex = RegexBuilder::new(&source)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let literal = if options.regex || ignore_case {
+            None
+        } else {
+            Some(pattern.to_owned())
+        };
+        Ok(Searcher {
+            pattern: pattern.to_owned(),
+            regex: regex,
+            literal: literal,
+        })
+    }
+
+    pub(crate) fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Compiled pattern, for expanding capture groups. Literal patterns are escaped
+    pub(crate) fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    /// Matches in a line of a rope, in order, leaving out the line break. The line is only
+    /// copied if it is split across chunks of the rope
+    pub(crate) fn find_iter_line<'s, 't>(&'s self, line: RopeSlice<'t>) -> Matches<'s, 't> {
+        Matches::new(self, line_str(line))
+    }
+
+    // Byte range of the first match starting at or after byte index from
+    fn find_at(&self, text: &str, from: usize) -> Option<(usize, usize)> {
+        match &self.literal {
+            Some(literal) => text[from..]
+                .find(literal.as_str())
+                .map(|i| (from + i, from + i + literal.len())),
+            None => self.regex.find_at(text, from).map(|m| (m.start(), m.end())),
+        }
+    }
+}
+
+/// Match as byte and char index ranges in the searched text
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SearchMatch {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) start_cidx: usize,
+    pub(crate) end_cidx: usize,
+}
+
+/// Iterator over matches of a searcher. An empty match right after another match is skipped,
+/// like in the regex crate
+pub(crate) struct Matches<'s, 't> {
+    searcher: &'s Searcher,
+    text: Cow<'t, str>,
+    // Byte index to search from
+    pos: usize,
+    // End of the last match, and byte and char index of its start, to count chars from
+    last_end: Option<usize>,
+    last_start: usize,
+    last_start_cidx: usize,
+}
+
+impl<'s, 't> Matches<'s, 't> {
+    fn new(searcher: &'s Searcher, text: Cow<'t, str>) -> Matches<'s, 't> {
+        Matches {
+            searcher: searcher,
+            text: text,
+            pos: 0,
+            last_end: None,
+            last_start: 0,
+            last_start_cidx: 0,
+        }
+    }
+
+    // Byte index of the char after the one at pos
+    fn next_char(&self, pos: usize) -> usize {
+        pos + self.text[pos..]
+            .chars()
+            .next()
+            .map(|c| c.len_utf8())
+            .unwrap_or(1)
+    }
+}
+
+impl<'s, 't> Iterator for Matches<'s, 't> {
+    type Item = SearchMatch;
+
+    fn next(&mut self) -> Option<SearchMatch> {
+        let (start, end) = loop {
+            if self.pos > self.text.len() {
+                return None;
+            }
+            let (start, end) = self.searcher.find_at(&self.text, self.pos)?;
+            if start == end && self.last_end == Some(start) {
+                self.pos = self.next_char(start);
+                continue;
+            }
+            break (start, end);
+        };
+        let start_cidx = self.last_start_cidx + self.text[self.last_start..start].chars().count();
+        let end_cidx = start_cidx + self.text[start..end].chars().count();
+        self.pos = if start == end {
+            self.next_char(end)
+        } else {
+            end
+        };
+        self.last_end = Some(end);
+        self.last_start = start;
+        self.last_start_cidx = start_cidx;
+        Some(SearchMatch {
+            start: start,
+            end: end,
+            start_cidx: start_cidx,
+            end_cidx: end_cidx,
+        })
+    }
+}
+
+// Line without its line break, borrowed if it lies in one chunk of the rope
+fn line_str<'t>(line: RopeSlice<'t>) -> Cow<'t, str> {
+    let mut len = line.len_chars();
+    while len > 0 && (line.char(len - 1) == '\n' || line.char(len - 1) == '\r') {
+        len -= 1;
+    }
+    let line = line.slice(..len);
+    let mut chunks = line.chunks();
+    match (chunks.next(), chunks.next()) {
+        (None, _) => Cow::Borrowed(""),
+        (Some(chunk), None) => Cow::Borrowed(chunk),
+        _ => Cow::Owned(line.to_string()),
+    }
+}
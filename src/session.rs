@@ -0,0 +1,169 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::fs::{create_dir_all, read_to_string, write};
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::path::PathBuf;
+use std::str::Lines;
+
+use directories::ProjectDirs;
+
+/// Name of the session used when none is given
+pub(crate) const DEFAULT_SESSION: &str = "default";
+
+/// A file shown in a pane, with the cursor position and the first line in view. Lines and
+/// grapheme indices are 0-based
+#[derive(Debug)]
+pub(crate) struct SessionView {
+    pub(crate) path: String,
+    pub(crate) line: usize,
+    pub(crate) gidx: usize,
+    pub(crate) start_line: usize,
+}
+
+/// Layout of panes. Splits have the index of their active child, and panes the index of the
+/// view they show
+#[derive(Debug)]
+pub(crate) enum SessionNode {
+    // Children side by side (false), or one above the other (true)
+    Split(bool, Vec<SessionNode>, usize),
+    Pane(Vec<SessionView>, usize),
+}
+
+/// Open files, the layout of panes and the working directory, saved with :mksession and
+/// restored with --session
+#[derive(Debug)]
+pub(crate) struct Session {
+    pub(crate) working_directory: PathBuf,
+    pub(crate) root: SessionNode,
+}
+
+impl Session {
+    pub(crate) fn load(name: &str) -> IOResult<Session> {
+        let data = read_to_string(session_path(name)?)?;
+        let mut lines = data.lines();
+        let working_directory = match lines.next().and_then(|l| field(l, "cwd")) {
+            Some(dir) => PathBuf::from(dir),
+            None => return Err(invalid()),
+        };
+        let root = parse_node(&mut lines).ok_or_else(invalid)?;
+        Ok(Session {
+            working_directory: working_directory,
+            root: root,
+        })
+    }
+
+    pub(crate) fn save(&self, name: &str) -> IOResult<()> {
+        let path = session_path(name)?;
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut data = format!("cwd\t{}\n", self.working_directory.to_string_lossy());
+        write_node(&self.root, &mut data);
+        write(path, data)
+    }
+}
+
+fn session_path(name: &str) -> IOResult<PathBuf> {
+    if name.is_empty() || name.contains(|c| c == '/' || c == '\\') {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            "invalid session name",
+        ));
+    }
+    ProjectDirs::from("", "sbarua", "bed")
+        .map(|dirs| dirs.data_dir().join("sessions").join(name))
+        .ok_or_else(|| IOError::new(ErrorKind::NotFound, "no data directory"))
+}
+
+fn invalid() -> IOError {
+    IOError::new(ErrorKind::InvalidData, "invalid session file")
+}
+
+// Rest of line after "<key>\t"
+fn field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let mut fields = line.splitn(2, '\t');
+    match (fields.next(), fields.next()) {
+        (Some(k), Some(rest)) if k == key => Some(rest),
+        _ => None,
+    }
+}
+
+// Nodes are written depth-first. Each line is one of
+//   "split\t<vertical>\t<children>\t<active>"
+//   "pane\t<views>\t<current>"
+//   "view\t<line>\t<gidx>\t<start_line>\t<path>"
+fn write_node(node: &SessionNode, data: &mut String) {
+    match node {
+        SessionNode::Split(vertical, children, active) => {
+            let vertical = if *vertical { 1 } else { 0 };
+            data.push_str(&format!(
+                "split\t{}\t{}\t{}\n",
+                vertical,
+                children.len(),
+                active
+            ));
+            for child in children {
+                write_node(child, data);
+            }
+        }
+        SessionNode::Pane(views, current) => {
+            data.push_str(&format!("pane\t{}\t{}\n", views.len(), current));
+            for view in views {
+                data.push_str(&format!(
+                    "view\t{}\t{}\t{}\t{}\n",
+                    view.line, view.gidx, view.start_line, view.path
+                ));
+            }
+        }
+    }
+}
+
+fn parse_node(lines: &mut Lines) -> Option<SessionNode> {
+    let line = lines.next()?;
+    if let Some(rest) = field(line, "split") {
+        let nums = parse_nums(rest, 3)?;
+        if nums[1] == 0 {
+            return None;
+        }
+        let mut children = Vec::new();
+        for _ in 0..nums[1] {
+            children.push(parse_node(lines)?);
+        }
+        let active = nums[2].min(children.len() - 1);
+        Some(SessionNode::Split(nums[0] != 0, children, active))
+    } else if let Some(rest) = field(line, "pane") {
+        let nums = parse_nums(rest, 2)?;
+        let mut views = Vec::new();
+        for _ in 0..nums[0] {
+            let fields = field(lines.next()?, "view")?
+                .splitn(4, '\t')
+                .collect::<Vec<_>>();
+            if fields.len() != 4 {
+                return None;
+            }
+            let nums = parse_nums(&fields[..3].join("\t"), 3)?;
+            views.push(SessionView {
+                path: fields[3].to_owned(),
+                line: nums[0],
+                gidx: nums[1],
+                start_line: nums[2],
+            });
+        }
+        Some(SessionNode::Pane(views, nums[1]))
+    } else {
+        None
+    }
+}
+
+// Exactly n tab-separated numbers
+fn parse_nums(s: &str, n: usize) -> Option<Vec<usize>> {
+    let nums = s
+        .split('\t')
+        .map(|f| f.parse().ok())
+        .collect::<Option<Vec<usize>>>()?;
+    if nums.len() == n {
+        Some(nums)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,249 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cmp::min;
+
+use crate::decorations::{Decoration, DecorationStyle};
+use crate::search::{CaseSensitivity, SearchOptions, Searcher};
+use crate::textbuffer::Buffer;
+use crate::types::Color;
+
+// Above search matches, in case the preview is searched
+const PREVIEW_PRIORITY: i32 = 20;
+
+/// Substitution parsed from the argument of :s, like "/foo/bar/g". Patterns are regular
+/// expressions, and replacements may refer to the match with & and to groups with \1 to \9
+pub(crate) struct Substitution {
+    pub(crate) searcher: Searcher,
+    // Replacement in the syntax of the regex crate
+    replacement: String,
+    /// Replace all matches in a line, instead of the first one
+    pub(crate) global: bool,
+    /// Ask before replacing each match
+    pub(crate) confirm: bool,
+    /// Show the lines that would change before changing them
+    pub(crate) preview: bool,
+}
+
+impl Substitution {
+    /// Parse "<d>pattern<d>replacement<d>flags", where the delimiter d is any character that
+    /// isn't alphanumeric or whitespace. It can be escaped with a backslash in the pattern and
+    /// replacement. An empty pattern uses the last search pattern. Flags are g (all matches in a
+    /// line), c (confirm each match), p (preview changes) and i (ignore case)
+    pub(crate) fn parse(s: &str, last_search: Option<&str>) -> Result<Substitution, String> {
+        let delim = match s.chars().next() {
+            Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && c != '\\' => c,
+            _ => return Err("expected a delimiter, like :s/pattern/replacement/".to_owned()),
+        };
+        let parts = split_delimited(&s[delim.len_utf8()..], delim);
+        let pattern = match parts[0].as_str() {
+            "" => last_search.ok_or("no previous search pattern")?.to_owned(),
+            pattern => pattern.to_owned(),
+        };
+        let replacement = parts.get(1).map(|s| s.as_str()).unwrap_or("");
+        let flags = parts.get(2).map(|s| s.as_str()).unwrap_or("");
+        let (mut global, mut confirm, mut preview, mut ignore_case) = (false, false, false, false);
+        for c in flags.chars() {
+            match c {
+                'g' => global = true,
+                'c' => confirm = true,
+                'p' => preview = true,
+                'i' => ignore_case = true,
+                c => return Err(format!("unknown flag: {}", c)),
+            }
+        }
+        let options = SearchOptions {
+            regex: true,
+            case: if ignore_case {
+                CaseSensitivity::Insensitive
+            } else {
+                CaseSensitivity::Sensitive
+            },
+        };
+        Ok(Substitution {
+            searcher: Searcher::new(&pattern, options)?,
+            replacement: regex_replacement(replacement),
+            global: global,
+            confirm: confirm,
+            preview: preview,
+        })
+    }
+
+    /// Line with the first match starting at or after byte index from replaced, or all such
+    /// matches if global, and the number of matches replaced. None if nothing matched
+    pub(crate) fn replace_from(&self, line: &str, from: usize) -> Option<(String, usize)> {
+        self.replace_ranges(line, from)
+            .map(|(ret, matches, _)| (ret, matches.len()))
+    }
+
+    // Like replace_from, but with the byte ranges of the matches in line and of their
+    // replacements in the returned line, instead of the number of matches
+    fn replace_ranges(
+        &self,
+        line: &str,
+        from: usize,
+    ) -> Option<(String, Vec<(usize, usize)>, Vec<(usize, usize)>)> {
+        let (mut ret, mut last) = (String::new(), 0);
+        let (mut matches, mut replacements) = (Vec::new(), Vec::new());
+        for caps in self.searcher.regex().captures_iter(line) {
+            let m = caps.get(0).unwrap();
+            if m.start() < from {
+                continue;
+            }
+            ret.push_str(&line[last..m.start()]);
+            let start = ret.len();
+            caps.expand(&self.replacement, &mut ret);
+            matches.push((m.start(), m.end()));
+            replacements.push((start, ret.len()));
+            last = m.end();
+            if !self.global {
+                break;
+            }
+        }
+        if matches.is_empty() {
+            return None;
+        }
+        ret.push_str(&line[last..]);
+        Some((ret, matches, replacements))
+    }
+
+    /// First match in line starting at or after byte index from, as a byte range and the
+    /// replacement for it
+    pub(crate) fn next_match(&self, line: &str, from: usize) -> Option<(usize, usize, String)> {
+        // Searching the whole line keeps anchors like ^ meaning the start of the line
+        let caps = self
+            .searcher
+            .regex()
+            .captures_iter(line)
+            .find(|caps| caps.get(0).map(|m| m.start() >= from).unwrap_or(false))?;
+        let m = caps.get(0)?;
+        let mut replacement = String::new();
+        caps.expand(&self.replacement, &mut replacement);
+        Some((m.start(), m.end(), replacement))
+    }
+}
+
+/// Lines from start to end with the substitution applied from byte index from in the first one,
+/// leaving out lines that didn't match, and the number of matches replaced
+pub(crate) fn substitute_lines(
+    buffer: &Buffer,
+    substitution: &Substitution,
+    start: usize,
+    end: usize,
+    from: usize,
+) -> (Vec<(usize, String)>, usize) {
+    let (mut changes, mut count) = (Vec::new(), 0);
+    let end = min(end, buffer.len_lines().saturating_sub(1));
+    for linum in start..=end {
+        let from = if linum == start { from } else { 0 };
+        let text = buffer.line_text(linum);
+        if let Some((replaced, n)) = substitution.replace_from(&text, from) {
+            changes.push((linum, replaced));
+            count += n;
+        }
+    }
+    (changes, count)
+}
+
+/// Preview of the substitution on lines from start to end, as text listing each line that
+/// changes before and after, and decorations marking the matches in the removed color and their
+/// replacements in the added color. None if nothing matched
+pub(crate) fn preview_lines(
+    buffer: &Buffer,
+    substitution: &Substitution,
+    start: usize,
+    end: usize,
+    removed: Color,
+    added: Color,
+) -> Option<(String, Vec<(usize, Decoration)>)> {
+    let end = min(end, buffer.len_lines().saturating_sub(1));
+    let width = (end + 1).to_string().len();
+    let (mut text, mut decorations, mut linum) = (String::new(), Vec::new(), 0);
+    for buf_linum in start..=end {
+        let before = buffer.line_text(buf_linum);
+        let (after, matches, replacements) = match substitution.replace_ranges(&before, 0) {
+            Some(replaced) => replaced,
+            None => continue,
+        };
+        for (sign, line, ranges, color) in &[
+            ('-', &before, &matches, removed),
+            ('+', &after, &replacements, added),
+        ] {
+            if linum > 0 {
+                text.push('\n');
+            }
+            let prefix = format!("{:>width$} {} ", buf_linum + 1, sign, width = width);
+            text.push_str(&prefix);
+            text.push_str(line);
+            let style = DecorationStyle::Foreground(*color);
+            decorations.push((linum, preview_decoration(width + 1, width + 2, style)));
+            let style = DecorationStyle::Background(color.opacity(40));
+            for (start, end) in ranges.iter() {
+                let start_cidx = prefix.len() + line[..*start].chars().count();
+                let end_cidx = start_cidx + line[*start..*end].chars().count();
+                decorations.push((linum, preview_decoration(start_cidx, end_cidx, style)));
+            }
+            linum += 1;
+        }
+    }
+    if linum == 0 {
+        None
+    } else {
+        Some((text, decorations))
+    }
+}
+
+fn preview_decoration(start_cidx: usize, end_cidx: usize, style: DecorationStyle) -> Decoration {
+    Decoration {
+        start_cidx: start_cidx,
+        end_cidx: end_cidx,
+        style: style,
+        priority: PREVIEW_PRIORITY,
+    }
+}
+
+// Split at unescaped delimiters into at most 3 parts. Escaped delimiters lose their backslash,
+// and other escapes are kept for the regex or the replacement
+fn split_delimited(s: &str, delim: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == delim && parts.len() < 3 {
+            parts.push(String::new());
+            continue;
+        }
+        let part = parts.last_mut().unwrap();
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if c == delim => part.push(c),
+                Some(c) => {
+                    part.push('\\');
+                    part.push(c);
+                }
+                None => part.push('\\'),
+            },
+            c => part.push(c),
+        }
+    }
+    parts
+}
+
+// Vim-style replacement, where & is the match and \1 is a group, in the syntax of the regex crate
+fn regex_replacement(s: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => ret.push_str("${0}"),
+            '$' => ret.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => ret.push_str(&format!("${{{}}}", d)),
+                Some('t') => ret.push('\t'),
+                Some('$') => ret.push_str("$$"),
+                Some(c) => ret.push(c),
+                None => ret.push('\\'),
+            },
+            c => ret.push(c),
+        }
+    }
+    ret
+}
@@ -0,0 +1,109 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{metadata, File};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use ropey::Rope;
+use walkdir::WalkDir;
+
+use crate::filecheck::{check_file, FileCheck};
+use crate::syntax::Syntax;
+use crate::ui::Wakeup;
+
+// Bigger files aren't indexed
+const MAX_INDEX_FILE_SIZE: u64 = 1024 * 1024;
+// Tabs don't change symbol names, so any size works
+const INDEX_TABSIZE: usize = 4;
+
+// Symbols in a file, as line numbers and names, and the file's modification time when they were
+// found
+struct IndexedFile {
+    mtime: SystemTime,
+    symbols: Arc<Vec<(usize, String)>>,
+}
+
+/// Function definitions, headings and other names found by the syntax highlighters in files
+/// under a directory. The index is kept across searches, and files are only read again when
+/// their modification time changes
+#[derive(Default)]
+pub(crate) struct SymbolIndex {
+    files: Arc<Mutex<HashMap<PathBuf, IndexedFile>>>,
+}
+
+impl SymbolIndex {
+    /// Walk files under root in the background, indexing files that changed since they were
+    /// last indexed. Every symbol is sent as "name [path:line]", with the path relative to root
+    /// and a 1-based line number. Dropping the receiver stops the walk
+    pub(crate) fn search(&self, root: &Path, wakeup: Wakeup) -> Receiver<String> {
+        let files = self.files.clone();
+        let root = root.to_path_buf();
+        let (tx, rx) = wakeup.channel();
+        thread::spawn(move || {
+            let mut seen = HashSet::new();
+            for e in WalkDir::new(&root)
+                .into_iter()
+                .filter_entry(|e| {
+                    e.file_name()
+                        .to_str()
+                        .map(|s| !s.starts_with("."))
+                        .unwrap_or(true)
+                })
+                .filter_map(|e| e.ok())
+            {
+                let path = e.path();
+                if !path.is_file() {
+                    continue;
+                }
+                let symbols = match file_symbols(&files, path) {
+                    Some(symbols) => symbols,
+                    None => continue,
+                };
+                seen.insert(path.to_path_buf());
+                let rel = path.strip_prefix(&root).unwrap_or(path).to_string_lossy();
+                for (line, name) in symbols.iter() {
+                    if tx.send(format!("{} [{}:{}]", name, rel, line + 1)).is_err() {
+                        return;
+                    }
+                }
+            }
+            // Forget files under root that are gone
+            let files = &mut *files.lock().unwrap();
+            files.retain(|path, _| !path.starts_with(&root) || seen.contains(path));
+        });
+        rx
+    }
+}
+
+// Symbols in file, from the index if the file hasn't changed. Returns None for files that
+// aren't indexed
+fn file_symbols(
+    files: &Mutex<HashMap<PathBuf, IndexedFile>>,
+    path: &Path,
+) -> Option<Arc<Vec<(usize, String)>>> {
+    let mtime = metadata(path).and_then(|m| m.modified()).ok()?;
+    if let Some(file) = files.lock().unwrap().get(path) {
+        if file.mtime == mtime {
+            return Some(file.symbols.clone());
+        }
+    }
+    let spath = path.to_str()?;
+    if check_file(spath, MAX_INDEX_FILE_SIZE) != FileCheck::Ok {
+        return None;
+    }
+    let data = File::open(path).and_then(|f| Rope::from_reader(f)).ok()?;
+    let mut syntax = Syntax::from_path(spath, &data.line(0).to_string());
+    let symbols = Arc::new(syntax.symbols(&data, INDEX_TABSIZE));
+    files.lock().unwrap().insert(
+        path.to_path_buf(),
+        IndexedFile {
+            mtime: mtime,
+            symbols: symbols.clone(),
+        },
+    );
+    Some(symbols)
+}
@@ -2,7 +2,7 @@
 
 use std::ops::Range;
 
-use super::{SyntaxBackend, Tok};
+use super::{line_states, SyntaxBackend, Tok};
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 enum State {
@@ -10,6 +10,7 @@ enum State {
     BlockComment,
 }
 
+#[derive(Clone)]
 pub(crate) struct CSyntax {
     states: Vec<(State, State)>, // start, end state
     linum: usize,
@@ -22,6 +23,14 @@ impl CSyntax {
             linum: 0,
         }
     }
+
+    // Copy holding only the state line linum starts in, with the line number to lex it as
+    pub(super) fn line_copy(&self, linum: usize) -> (CSyntax, usize) {
+        let (states, linum) = line_states(&self.states, linum);
+        let mut ret = CSyntax::new();
+        ret.states = states;
+        (ret, linum)
+    }
 }
 
 impl SyntaxBackend for CSyntax {
@@ -4,6 +4,7 @@ use std::ops::Range;
 
 use super::{SyntaxBackend, Tok};
 
+#[derive(Clone)]
 pub(crate) struct DefaultSyntax;
 
 impl SyntaxBackend for DefaultSyntax {
@@ -4,16 +4,23 @@ use std::ops::Range;
 
 use super::{SyntaxBackend, Tok};
 
-pub(crate) struct MarkdownSyntax {}
+#[derive(Clone)]
+pub(crate) struct MarkdownSyntax {
+    at_line_start: bool,
+}
 
 impl MarkdownSyntax {
     pub(super) fn new() -> MarkdownSyntax {
-        MarkdownSyntax {}
+        MarkdownSyntax {
+            at_line_start: true,
+        }
     }
 }
 
 impl SyntaxBackend for MarkdownSyntax {
-    fn start_of_line(&mut self, _linum: usize) {}
+    fn start_of_line(&mut self, _linum: usize) {
+        self.at_line_start = true;
+    }
 
     fn can_end_highlight(&self) -> bool {
         true
@@ -26,8 +33,21 @@ impl SyntaxBackend for MarkdownSyntax {
     fn next_tok<'a>(&mut self, s: &'a str) -> Option<Tok<'a>> {
         if s.len() == 0 {
             None
+        } else if std::mem::replace(&mut self.at_line_start, false) && is_heading(s) {
+            Some(Tok::entity_name(s).variable_pitch())
         } else {
             Some(Tok::misc(s).variable_pitch())
         }
     }
 }
+
+// ATX headings, like "## Title", indented by up to 3 spaces
+fn is_heading(line: &str) -> bool {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return false;
+    }
+    let rest = trimmed.trim_start_matches('#');
+    let level = trimmed.len() - rest.len();
+    level >= 1 && level <= 6 && (rest.is_empty() || rest.starts_with(' '))
+}
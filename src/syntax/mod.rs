@@ -1,25 +1,40 @@
 // (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
 
+use std::collections::hash_map::DefaultHasher;
 use std::default::Default;
-use std::fmt::Write as FmtWrite;
+use std::hash::{Hash, Hasher};
 use std::ops::Range;
 use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread;
 
 use euclid::Size2D;
-use ropey::RopeSlice;
+use ropey::{Rope, RopeSlice};
 
 use crate::config::{Cfg, CfgUiTheme};
 use crate::font::FontCore;
-use crate::types::{Color, TextPitch, TextSlant, TextStyle, TextWeight, DPI};
+use crate::types::{Color, TextPitch, TextStyle, DPI};
 use crate::ui::text::{ShapedTextLine, TextLine, TextSpan};
+use crate::ui::Wakeup;
 
 mod c;
 mod default;
 mod markdown;
+mod python;
 mod rust;
+mod shell;
 mod toml;
+#[cfg(feature = "treesitter")]
+mod treesitter;
+
+// Number of lines highlighted in the background that are sent back together
+const HIGHLIGHT_CHUNK_LINES: usize = 128;
 
 trait SyntaxBackend {
+    // Called with the whole text before highlighting from start_linum. Backends that parse the
+    // whole file, rather than one line at a time, catch up with edits here
+    fn prepare(&mut self, _data: &Rope, _start_linum: usize, _tabsize: usize) {}
+
     fn start_of_line(&mut self, linum: usize);
 
     fn can_end_highlight(&self) -> bool;
@@ -31,11 +46,16 @@ trait SyntaxBackend {
     fn next_tok<'a>(&mut self, s: &'a str) -> Option<Tok<'a>>;
 }
 
+#[derive(Clone)]
 pub(crate) enum Syntax {
     C(c::CSyntax),
     Markdown(markdown::MarkdownSyntax),
+    Python(python::PythonSyntax),
     Rust(rust::RustSyntax),
+    Shell(shell::ShellSyntax),
     TOML(toml::TOMLSyntax),
+    #[cfg(feature = "treesitter")]
+    TreeSitter(treesitter::TreeSitterSyntax),
     Default(default::DefaultSyntax),
 }
 
@@ -46,72 +66,91 @@ impl Default for Syntax {
 }
 
 impl Syntax {
-    pub(crate) fn from_path(path: &str) -> Syntax {
-        Path::new(path)
+    /// Detect syntax from the file's extension or name, or the interpreter named by the shebang
+    /// on its first line
+    pub(crate) fn from_path(path: &str, first_line: &str) -> Syntax {
+        let path = Path::new(path);
+        path
             // Try with extension
             .extension()
             .and_then(|s| s.to_str())
             .and_then(|s| match s {
-                "c" | "h" | "cpp" | "hpp" | "cxx" => Some(Syntax::C(c::CSyntax::new())),
+                "c" | "h" => Some(c_syntax()),
+                "cpp" | "hpp" | "cxx" => Some(Syntax::C(c::CSyntax::new())),
                 "md" => Some(Syntax::Markdown(markdown::MarkdownSyntax::new())),
-                "rs" => Some(Syntax::Rust(rust::RustSyntax::new())),
+                "py" => Some(Syntax::Python(python::PythonSyntax::new())),
+                "rs" => Some(rust_syntax()),
+                "sh" | "bash" | "zsh" | "ksh" | "mk" => {
+                    Some(Syntax::Shell(shell::ShellSyntax::new()))
+                }
                 "toml" => Some(Syntax::TOML(toml::TOMLSyntax::new())),
                 _ => None,
             })
-            // TODO: Try with filename
+            // Try with filename
+            .or_else(|| {
+                path.file_name()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| match s {
+                        "Cargo.lock" | "Pipfile" => Some(Syntax::TOML(toml::TOMLSyntax::new())),
+                        "SConstruct" | "SConscript" => {
+                            Some(Syntax::Python(python::PythonSyntax::new()))
+                        }
+                        // There's no make backend, but makefiles are close enough to shell
+                        "Makefile" | "makefile" | "GNUmakefile" | "PKGBUILD" | "APKBUILD"
+                        | ".bashrc" | ".bash_profile" | ".bash_login" | ".bash_logout"
+                        | ".bash_aliases" | ".profile" | ".zshrc" | ".zshenv" | ".zprofile"
+                        | ".zlogin" | ".kshrc" => Some(Syntax::Shell(shell::ShellSyntax::new())),
+                        _ => None,
+                    })
+            })
+            // Try with shebang
+            .or_else(|| match shebang_interpreter(first_line)? {
+                "sh" | "bash" | "zsh" | "ksh" | "dash" | "ash" => {
+                    Some(Syntax::Shell(shell::ShellSyntax::new()))
+                }
+                s if s.starts_with("python") => Some(Syntax::Python(python::PythonSyntax::new())),
+                _ => None,
+            })
             .unwrap_or_default()
     }
 
+    /// Highlight lines from start_linum, until highlighting stops changing. Lines that were
+    /// shaped are re-shaped if their highlighting changed. Other lines are left as placeholders,
+    /// to be shaped with shape_line once they are in view. Lines that were already there are
+    /// only re-highlighted before sync_end_linum. If highlighting hasn't settled by then, the
+    /// line to continue from in the background is returned
     pub(crate) fn format_lines(
         &mut self,
         dpi: Size2D<u32, DPI>,
         start_linum: usize,
         opt_min_end_linum: Option<usize>,
-        data: RopeSlice,
+        sync_end_linum: usize,
+        data: &Rope,
         config: &Cfg,
         tabsize: usize,
         shaped_text: &mut Vec<ShapedTextLine>,
-        shaped_gutter: &mut Vec<ShapedTextLine>,
         font_core: &mut FontCore,
-    ) {
+    ) -> Option<usize> {
         let mut fmtbuf = String::new();
+        let empty = empty_line(dpi, config, font_core);
         let backend = self.get_backend();
-        let theme = config.ui.theme();
+        let mut ret = None;
+        backend.prepare(data, start_linum, tabsize);
 
         for i in start_linum..data.len_lines() {
-            let line = data.line(i);
-            let mut j = 0;
-            let mut fmtline = TextLine::default();
-            backend.start_of_line(i);
-            expand_line(line, tabsize, &mut fmtbuf);
-
-            while let Some(tok) = backend.next_tok(&fmtbuf[j..]) {
-                j += tok.s.len();
-                let (style, color) = tok_hl(theme, tok.typ);
-                let fmtspan = TextSpan::new(
-                    &tok.s,
-                    config.ui.textview.text_size,
-                    style,
-                    color,
-                    tok.pitch,
-                    None,
-                );
-                fmtline.0.push(fmtspan);
-                if j == fmtbuf.len() {
-                    break;
-                }
+            if i > start_linum && i >= sync_end_linum && i < shaped_text.len() {
+                ret = Some(i);
+                break;
             }
-            let shaped_line = ShapedTextLine::from_textline(
-                fmtline,
-                config.ui.textview.fixed_face,
-                config.ui.textview.variable_face,
-                font_core,
-                dpi,
-            );
+            let (fmtline, key) = highlight_line(backend, i, data, tabsize, config, &mut fmtbuf);
             if i >= shaped_text.len() {
-                shaped_text.push(shaped_line);
-            } else if i == start_linum || shaped_text[i] != shaped_line {
-                shaped_text[i] = shaped_line;
+                shaped_text.push(empty.placeholder(key));
+            } else if i == start_linum || shaped_text[i].key() != key {
+                shaped_text[i] = if shaped_text[i].is_placeholder() {
+                    empty.placeholder(key)
+                } else {
+                    shape_textline(fmtline, key, dpi, config, font_core)
+                };
             } else if backend.can_end_highlight() {
                 if let Some(min) = opt_min_end_linum {
                     if i < min {
@@ -121,26 +160,149 @@ impl Syntax {
                 break;
             }
         }
-        for linum in shaped_gutter.len()..(shaped_text.len() + 1) {
-            fmtbuf.clear();
-            write!(&mut fmtbuf, "{}", linum).unwrap();
-            let fmtspan = TextSpan::new(
-                &fmtbuf,
-                config.ui.gutter.text_size,
-                TextStyle::new(TextWeight::Medium, TextSlant::Roman),
-                theme.gutter.foreground_color,
-                TextPitch::Fixed,
-                None,
-            );
-            let shaped_line = ShapedTextLine::from_textstr(
-                fmtspan,
-                config.ui.gutter.fixed_face,
-                config.ui.gutter.variable_face,
-                font_core,
-                dpi,
-            );
-            shaped_gutter.push(shaped_line);
+        ret
+    }
+
+    /// Shape line that was left as a placeholder. This continues from the state left by
+    /// highlighting
+    pub(crate) fn shape_line(
+        &mut self,
+        dpi: Size2D<u32, DPI>,
+        linum: usize,
+        data: &Rope,
+        config: &Cfg,
+        tabsize: usize,
+        font_core: &mut FontCore,
+    ) -> ShapedTextLine {
+        let mut fmtbuf = String::new();
+        let backend = self.get_backend();
+        let (fmtline, key) = highlight_line(backend, linum, data, tabsize, config, &mut fmtbuf);
+        shape_textline(fmtline, key, dpi, config, font_core)
+    }
+
+    /// Highlight lines from start_linum on a worker thread, until highlighting stops changing,
+    /// but not before min_end_linum. Highlighted lines are sent back in chunks, followed by the
+    /// syntax state after highlighting. Dropping the receiver stops the worker
+    pub(crate) fn highlight_async(
+        &self,
+        start_linum: usize,
+        min_end_linum: usize,
+        data: Rope,
+        tabsize: usize,
+        wakeup: Wakeup,
+    ) -> Receiver<HighlightMsg> {
+        let mut syntax = self.clone();
+        let (tx, rx) = wakeup.channel();
+        thread::spawn(move || {
+            {
+                let backend = syntax.get_backend();
+                let mut fmtbuf = String::new();
+                let mut chunk = HighlightedLines {
+                    start_linum: start_linum,
+                    lines: Vec::new(),
+                };
+                for i in start_linum..data.len_lines() {
+                    let mut j = 0;
+                    let mut line = Vec::new();
+                    backend.start_of_line(i);
+                    expand_line(data.line(i), tabsize, &mut fmtbuf);
+                    while let Some(tok) = backend.next_tok(&fmtbuf[j..]) {
+                        j += tok.s.len();
+                        line.push((tok.s.to_owned(), tok.typ, tok.pitch));
+                        if j == fmtbuf.len() {
+                            break;
+                        }
+                    }
+                    chunk.lines.push(line);
+                    let done = i + 1 >= min_end_linum && backend.can_end_highlight();
+                    if done || chunk.lines.len() == HIGHLIGHT_CHUNK_LINES {
+                        let next = HighlightedLines {
+                            start_linum: i + 1,
+                            lines: Vec::new(),
+                        };
+                        let chunk = std::mem::replace(&mut chunk, next);
+                        if tx.send(HighlightMsg::Lines(chunk)).is_err() {
+                            return;
+                        }
+                    }
+                    if done {
+                        break;
+                    }
+                }
+                if chunk.lines.len() > 0 && tx.send(HighlightMsg::Lines(chunk)).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(HighlightMsg::Done(syntax));
+        });
+        rx
+    }
+
+    /// Names defined in text, like functions, headings and TOML tables, with their line numbers
+    pub(crate) fn symbols(&mut self, data: &Rope, tabsize: usize) -> Vec<(usize, String)> {
+        let mut ret = Vec::new();
+        if let Syntax::Default(_) = self {
+            return ret;
+        }
+        let backend = self.get_backend();
+        let mut fmtbuf = String::new();
+        backend.prepare(data, 0, tabsize);
+        for i in 0..data.len_lines() {
+            let mut j = 0;
+            backend.start_of_line(i);
+            expand_line(data.line(i), tabsize, &mut fmtbuf);
+            while let Some(tok) = backend.next_tok(&fmtbuf[j..]) {
+                j += tok.s.len();
+                if tok.typ == TokTyp::FuncDefn || tok.typ == TokTyp::EntityName {
+                    let name = tok.s.trim_start_matches('#').trim();
+                    if !name.is_empty() {
+                        ret.push((i, name.to_owned()));
+                    }
+                }
+                if j == fmtbuf.len() {
+                    break;
+                }
+            }
+        }
+        ret
+    }
+
+    /// Whether each char in the line is code, rather than part of a comment or a string or char
+    /// literal. This continues from the state left by highlighting, which isn't changed
+    pub(crate) fn code_chars(&self, linum: usize, line: RopeSlice, tabsize: usize) -> Vec<bool> {
+        let (mut syntax, linum) = self.line_copy(linum);
+        let backend = syntax.get_backend();
+        let mut fmtbuf = String::new();
+        backend.start_of_line(linum);
+        expand_line(line, tabsize, &mut fmtbuf);
+        // Whether each byte in the expanded line is code
+        let mut code = Vec::with_capacity(fmtbuf.len());
+        let mut j = 0;
+        while let Some(tok) = backend.next_tok(&fmtbuf[j..]) {
+            j += tok.s.len();
+            let is_code = match tok.typ {
+                TokTyp::Comment | TokTyp::String | TokTyp::Char | TokTyp::EscapedChar => false,
+                _ => true,
+            };
+            code.extend(std::iter::repeat(is_code).take(tok.s.len()));
+            if j == fmtbuf.len() {
+                break;
+            }
+        }
+        let mut ret = Vec::new();
+        let (mut x, mut off) = (0, 0);
+        for c in trim_newlines(line).chars() {
+            ret.push(code.get(off).cloned().unwrap_or(true));
+            if c == '\t' {
+                let next = (x / tabsize) * tabsize + tabsize;
+                off += next - x;
+                x = next;
+            } else {
+                off += c.len_utf8();
+                x += 1;
+            }
         }
+        ret
     }
 
     pub(crate) fn insert_lines(&mut self, linum: usize, nlines: usize) {
@@ -156,24 +318,214 @@ impl Syntax {
     pub(crate) fn name(&self) -> &'static str {
         match self {
             Syntax::C(_) => "c",
+            Syntax::Python(_) => "python",
             Syntax::Rust(_) => "rust",
+            Syntax::Shell(_) => "sh",
             Syntax::TOML(_) => "toml",
             Syntax::Markdown(_) => "markdown",
+            #[cfg(feature = "treesitter")]
+            Syntax::TreeSitter(t) => t.name(),
             Syntax::Default(_) => "default",
         }
     }
 
+    // Copy with only the state needed to lex line linum again, and the line number to lex it as.
+    // Backends that don't keep a state per line are cheap to copy whole
+    fn line_copy(&self, linum: usize) -> (Syntax, usize) {
+        match self {
+            Syntax::C(c) => {
+                let (c, linum) = c.line_copy(linum);
+                (Syntax::C(c), linum)
+            }
+            Syntax::Python(p) => {
+                let (p, linum) = p.line_copy(linum);
+                (Syntax::Python(p), linum)
+            }
+            Syntax::Rust(r) => {
+                let (r, linum) = r.line_copy(linum);
+                (Syntax::Rust(r), linum)
+            }
+            Syntax::Shell(s) => {
+                let (s, linum) = s.line_copy(linum);
+                (Syntax::Shell(s), linum)
+            }
+            _ => (self.clone(), linum),
+        }
+    }
+
     fn get_backend(&mut self) -> &mut dyn SyntaxBackend {
         match self {
             Syntax::C(c) => c,
+            Syntax::Python(p) => p,
             Syntax::Rust(r) => r,
+            Syntax::Shell(s) => s,
             Syntax::TOML(t) => t,
             Syntax::Markdown(m) => m,
+            #[cfg(feature = "treesitter")]
+            Syntax::TreeSitter(t) => t,
             Syntax::Default(d) => d,
         }
     }
 }
 
+/// Lines highlighted in the background, which still have to be shaped
+pub(crate) struct HighlightedLines {
+    start_linum: usize,
+    lines: Vec<Vec<(String, TokTyp, TextPitch)>>,
+}
+
+impl HighlightedLines {
+    pub(crate) fn start_linum(&self) -> usize {
+        self.start_linum
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Hash of the highlighted text of the line at given index into these lines
+    pub(crate) fn key(&self, idx: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for (s, typ, pitch) in &self.lines[idx] {
+            (s.as_str(), *typ, *pitch).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Shape the line at given index into these lines
+    pub(crate) fn shape(
+        &self,
+        idx: usize,
+        dpi: Size2D<u32, DPI>,
+        config: &Cfg,
+        font_core: &mut FontCore,
+    ) -> ShapedTextLine {
+        let mut fmtline = TextLine::default();
+        for (s, typ, pitch) in &self.lines[idx] {
+            fmtline.0.push(tok_span(s, *typ, *pitch, config));
+        }
+        shape_textline(fmtline, self.key(idx), dpi, config, font_core)
+    }
+}
+
+/// Results of background highlighting
+pub(crate) enum HighlightMsg {
+    Lines(HighlightedLines),
+    // Syntax state after the last highlighted line
+    Done(Syntax),
+}
+
+// Rust and C are highlighted with tree-sitter when it is enabled. The built-in lexers are used
+// otherwise, or if the grammar fails to load
+fn rust_syntax() -> Syntax {
+    #[cfg(feature = "treesitter")]
+    {
+        if let Some(t) = treesitter::TreeSitterSyntax::rust() {
+            return Syntax::TreeSitter(t);
+        }
+    }
+    Syntax::Rust(rust::RustSyntax::new())
+}
+
+fn c_syntax() -> Syntax {
+    #[cfg(feature = "treesitter")]
+    {
+        if let Some(t) = treesitter::TreeSitterSyntax::c() {
+            return Syntax::TreeSitter(t);
+        }
+    }
+    Syntax::C(c::CSyntax::new())
+}
+
+// Name of the interpreter in a shebang line, e.g. "bash" for "#!/usr/bin/env bash"
+fn shebang_interpreter<'a>(line: &'a str) -> Option<&'a str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let name = |s: &'a str| Path::new(s).file_name().and_then(|s| s.to_str());
+    match name(words.next()?)? {
+        // Skip options to env, like -S
+        "env" => words
+            .find(|s| !s.starts_with('-') && !s.contains('='))
+            .and_then(name),
+        s => Some(s),
+    }
+}
+
+/// Empty line of a buffer, which placeholders for lines that haven't been shaped take their
+/// height from
+pub(crate) fn empty_line(
+    dpi: Size2D<u32, DPI>,
+    config: &Cfg,
+    font_core: &mut FontCore,
+) -> ShapedTextLine {
+    let mut fmtline = TextLine::default();
+    fmtline
+        .0
+        .push(tok_span(" ", TokTyp::Misc, TextPitch::Fixed, config));
+    shape_textline(fmtline, 0, dpi, config, font_core)
+}
+
+// Highlight line at linum, continuing from the state the backend was left in. Returns the
+// highlighted line, and a hash of it
+fn highlight_line<'a>(
+    backend: &mut dyn SyntaxBackend,
+    linum: usize,
+    data: &Rope,
+    tabsize: usize,
+    config: &Cfg,
+    fmtbuf: &'a mut String,
+) -> (TextLine<'a>, u64) {
+    backend.start_of_line(linum);
+    expand_line(data.line(linum), tabsize, fmtbuf);
+    let fmtbuf: &'a str = fmtbuf;
+    let mut j = 0;
+    let mut fmtline = TextLine::default();
+    let mut hasher = DefaultHasher::new();
+    while let Some(tok) = backend.next_tok(&fmtbuf[j..]) {
+        j += tok.s.len();
+        (tok.s, tok.typ, tok.pitch).hash(&mut hasher);
+        fmtline.0.push(tok_span(tok.s, tok.typ, tok.pitch, config));
+        if j == fmtbuf.len() {
+            break;
+        }
+    }
+    (fmtline, hasher.finish())
+}
+
+fn shape_textline(
+    fmtline: TextLine,
+    key: u64,
+    dpi: Size2D<u32, DPI>,
+    config: &Cfg,
+    font_core: &mut FontCore,
+) -> ShapedTextLine {
+    let mut ret = ShapedTextLine::from_textline(
+        fmtline,
+        config.ui.textview.fixed_face,
+        config.ui.textview.variable_face,
+        font_core,
+        dpi,
+    );
+    ret.set_key(key);
+    ret
+}
+
+fn tok_span<'a>(s: &'a str, typ: TokTyp, pitch: TextPitch, config: &Cfg) -> TextSpan<'a> {
+    let (style, color) = tok_hl(config.ui.theme(), typ);
+    TextSpan::new(s, config.ui.textview.text_size, style, color, pitch, None)
+}
+
+// States for a copy of a backend that keeps start and end states per line, to lex line linum
+// with. Only the end state of the line before is kept, and linum is lexed as the line after it
+fn line_states<S: Clone>(states: &[(S, S)], linum: usize) -> (Vec<(S, S)>, usize) {
+    let prev = linum
+        .checked_sub(1)
+        .and_then(|prev| states.get(prev).or_else(|| states.last()));
+    match prev {
+        Some((_, end)) => (vec![(end.clone(), end.clone())], 1),
+        None => (Vec::new(), 0),
+    }
+}
+
 fn expand_line(slice: RopeSlice, tabsize: usize, buf: &mut String) {
     buf.clear();
     let slice = trim_newlines(slice);
@@ -449,7 +801,7 @@ impl<'a> Tok<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Hash, PartialEq)]
 enum TokTyp {
     Operator,
     Separator,
@@ -0,0 +1,395 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::ops::Range;
+
+use super::{line_states, SyntaxBackend, Tok};
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum State {
+    Base,
+    FnDef,
+    Decorator,
+    // Quote character, whether the string is triple-quoted, and whether it is raw
+    String(char, bool, bool),
+}
+
+#[derive(Clone)]
+pub(crate) struct PythonSyntax {
+    states: Vec<(State, State)>, // start, end state
+    linum: usize,
+    // Only whitespace has been seen on this line so far
+    line_start: bool,
+}
+
+impl PythonSyntax {
+    pub(super) fn new() -> PythonSyntax {
+        PythonSyntax {
+            states: Vec::new(),
+            linum: 0,
+            line_start: true,
+        }
+    }
+
+    // Copy holding only the state line linum starts in, with the line number to lex it as
+    pub(super) fn line_copy(&self, linum: usize) -> (PythonSyntax, usize) {
+        let (states, linum) = line_states(&self.states, linum);
+        let mut ret = PythonSyntax::new();
+        ret.states = states;
+        (ret, linum)
+    }
+}
+
+impl SyntaxBackend for PythonSyntax {
+    fn start_of_line(&mut self, linum: usize) {
+        self.linum = linum;
+        self.line_start = true;
+        if self.states.len() == 0 {
+            self.states.push((State::Base, State::Base));
+        } else if linum >= self.states.len() {
+            let prev = self.states[self.states.len() - 1].1;
+            self.states.push((prev, prev));
+        } else if linum == 0 {
+            self.states[linum] = (State::Base, State::Base);
+        } else {
+            self.states[linum].0 = self.states[linum - 1].1;
+            self.states[linum].1 = self.states[linum].0;
+        }
+        // Only triple-quoted strings continue on the next line
+        match self.states[linum].0 {
+            State::String(_, true, _) => {}
+            _ => self.states[linum] = (State::Base, State::Base),
+        }
+    }
+
+    fn insert_lines(&mut self, linum: usize, nlines: usize) {
+        for _ in 0..nlines {
+            self.states.insert(linum, (State::Base, State::Base));
+        }
+    }
+
+    fn can_end_highlight(&self) -> bool {
+        if self.linum + 1 < self.states.len() {
+            self.states[self.linum].1 == self.states[self.linum + 1].0
+        } else {
+            true
+        }
+    }
+
+    fn remove_lines(&mut self, range: Range<usize>) {
+        self.states.drain(range);
+    }
+
+    fn next_tok<'a>(&mut self, s: &'a str) -> Option<Tok<'a>> {
+        if s.len() == 0 {
+            return None;
+        }
+        let mut lex = Lexer::new(s);
+        let line_start = self.line_start;
+        match self.states[self.linum].1 {
+            State::Base => {
+                let tok = lex.next()?;
+                match tok {
+                    (PyTok::Space, _) => {}
+                    _ => self.line_start = false,
+                }
+                match tok {
+                    (PyTok::Comment, _) => Some(Tok::comment(s)),
+                    (PyTok::StringStart(quote, triple, raw), i) => {
+                        self.states[self.linum].1 = State::String(quote, triple, raw);
+                        Some(Tok::string(&s[..i]))
+                    }
+                    (PyTok::Num, i) => Some(Tok::num(&s[..i])),
+                    (PyTok::Ident, i) => match lex.next() {
+                        Some((PyTok::OpLp, _)) => Some(Tok::func_call(&s[..i])),
+                        _ => Some(Tok::ident(&s[..i])),
+                    },
+                    (PyTok::At, i) => {
+                        if line_start {
+                            self.states[self.linum].1 = State::Decorator;
+                            Some(Tok::entity_tag(&s[..i]))
+                        } else {
+                            Some(Tok::operator(&s[..i]))
+                        }
+                    }
+                    (PyTok::Op, i) => Some(Tok::operator(&s[..i])),
+                    (PyTok::KeyDef, i) => {
+                        self.states[self.linum].1 = State::FnDef;
+                        Some(Tok::keyword(&s[..i]))
+                    }
+                    (PyTok::Key, i) => Some(Tok::keyword(&s[..i])),
+                    (PyTok::KeyTyp, i) => Some(Tok::data_type(&s[..i])),
+                    (PyTok::Separator, i) => Some(Tok::separator(&s[..i])),
+                    (PyTok::Dot, i) | (PyTok::Space, i) | (PyTok::Misc, i) | (PyTok::OpLp, i) => {
+                        Some(Tok::misc(&s[..i]))
+                    }
+                }
+            }
+            State::FnDef => match lex.next()? {
+                (PyTok::Space, i) => Some(Tok::misc(&s[..i])),
+                (PyTok::Ident, i) => {
+                    self.states[self.linum].1 = State::Base;
+                    Some(Tok::func_defn(&s[..i]))
+                }
+                (_, i) => {
+                    self.states[self.linum].1 = State::Base;
+                    Some(Tok::misc(&s[..i]))
+                }
+            },
+            // Dotted name following the @
+            State::Decorator => match lex.next()? {
+                (PyTok::Ident, i)
+                | (PyTok::Key, i)
+                | (PyTok::KeyDef, i)
+                | (PyTok::KeyTyp, i)
+                | (PyTok::Dot, i) => Some(Tok::entity_tag(&s[..i])),
+                _ => {
+                    self.states[self.linum].1 = State::Base;
+                    self.next_tok(s)
+                }
+            },
+            State::String(quote, triple, raw) => {
+                let mut iter = s.char_indices();
+                while let Some((i, c)) = iter.next() {
+                    if c == '\\' {
+                        if raw {
+                            // Backslashes still keep quotes from ending raw strings
+                            iter.next();
+                        } else if i > 0 {
+                            return Some(Tok::string(&s[..i]));
+                        } else if let Some(l) = escaped_char(&s[1..]) {
+                            return Some(Tok::escaped_char(&s[..(l + 1)]));
+                        } else {
+                            iter.next();
+                        }
+                    } else if c == quote {
+                        let len = if triple { 3 } else { 1 };
+                        if !triple || s[i..].starts_with(&s[i..(i + 1)].repeat(3)) {
+                            self.states[self.linum].1 = State::Base;
+                            return Some(Tok::string(&s[..(i + len)]));
+                        }
+                    }
+                }
+                Some(Tok::string(s))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PyTok {
+    Comment,
+    // Prefixed and quoted start of a string
+    StringStart(char, bool, bool),
+    Num,
+    Ident,
+    Separator,
+    Dot,
+    OpLp,
+    At,
+    Op,
+    KeyDef,
+    KeyTyp,
+    Key,
+    Space,
+    Misc,
+}
+
+struct Lexer<'a> {
+    s: &'a str,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(s: &'a str) -> Lexer<'a> {
+        Lexer { s: s }
+    }
+
+    fn next(&mut self) -> Option<(PyTok, usize)> {
+        let mut iter = self.s.char_indices().peekable();
+        let (typ, i) = match iter.next()? {
+            (_, '#') => (PyTok::Comment, self.s.len()),
+            (_, '(') => (PyTok::OpLp, 1),
+            (_, ';') | (_, ',') => (PyTok::Separator, 1),
+            (_, '@') => match iter.next() {
+                Some((_, '=')) => (PyTok::Op, 2),
+                _ => (PyTok::At, 1),
+            },
+            (_, '.') => match iter.next() {
+                Some((_, c)) if c.is_ascii_digit() => (PyTok::Num, dec_num_or_float(self.s)),
+                _ => (PyTok::Dot, 1),
+            },
+            (_, '+') | (_, '%') | (_, '^') | (_, '&') | (_, '|') | (_, '=') | (_, '!') => {
+                match iter.next() {
+                    Some((_, '=')) => (PyTok::Op, 2),
+                    _ => (PyTok::Op, 1),
+                }
+            }
+            (_, '-') => match iter.next() {
+                Some((_, '=')) | Some((_, '>')) => (PyTok::Op, 2),
+                _ => (PyTok::Op, 1),
+            },
+            (_, ':') => match iter.next() {
+                Some((_, '=')) => (PyTok::Op, 2),
+                _ => (PyTok::Misc, 1),
+            },
+            (_, '~') => (PyTok::Op, 1),
+            (_, c) if c == '*' || c == '/' || c == '<' || c == '>' => match iter.next() {
+                Some((_, d)) if d == c => match iter.next() {
+                    Some((_, '=')) => (PyTok::Op, 3),
+                    _ => (PyTok::Op, 2),
+                },
+                Some((_, '=')) => (PyTok::Op, 2),
+                _ => (PyTok::Op, 1),
+            },
+            (_, '"') | (_, '\'') => string_start(self.s, 0),
+            (_, '0') => (PyTok::Num, bin_num_or_float(self.s)),
+            (_, c) if c.is_digit(10) => (PyTok::Num, dec_num_or_float(self.s)),
+            (_, c) if c.is_whitespace() => loop {
+                if let Some((i, c)) = iter.next() {
+                    if !c.is_whitespace() {
+                        break (PyTok::Space, i);
+                    }
+                } else {
+                    break (PyTok::Space, self.s.len());
+                }
+            },
+            (_, c) if c == '_' || c.is_alphabetic() => loop {
+                if let Some((i, c)) = iter.next() {
+                    if c == '"' || c == '\'' {
+                        if is_string_prefix(&self.s[..i]) {
+                            break string_start(self.s, i);
+                        }
+                        break (key_or_ident(&self.s[..i]), i);
+                    }
+                    if c != '_' && !c.is_alphanumeric() {
+                        break (key_or_ident(&self.s[..i]), i);
+                    }
+                } else {
+                    break (key_or_ident(self.s), self.s.len());
+                }
+            },
+            _ => {
+                if let Some((i, _)) = iter.next() {
+                    (PyTok::Misc, i)
+                } else {
+                    (PyTok::Misc, self.s.len())
+                }
+            }
+        };
+        self.s = &self.s[i..];
+        Some((typ, i))
+    }
+}
+
+// Start of string, with prefix of given length
+fn string_start(s: &str, prefix_len: usize) -> (PyTok, usize) {
+    let quote = s[prefix_len..].chars().next().unwrap();
+    let raw = s[..prefix_len].contains(|c| c == 'r' || c == 'R');
+    if s[prefix_len..].starts_with(&s[prefix_len..(prefix_len + 1)].repeat(3)) {
+        (PyTok::StringStart(quote, true, raw), prefix_len + 3)
+    } else {
+        (PyTok::StringStart(quote, false, raw), prefix_len + 1)
+    }
+}
+
+fn is_string_prefix(s: &str) -> bool {
+    match s.to_ascii_lowercase().as_str() {
+        "r" | "u" | "b" | "f" | "br" | "rb" | "fr" | "rf" => true,
+        _ => false,
+    }
+}
+
+fn bin_num_or_float(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    if bytes.len() < 3 {
+        return dec_num_or_float(s);
+    }
+    let is_digit: fn(u8) -> bool = match bytes[1] {
+        b'b' | b'B' => |b: u8| b == b'0' || b == b'1',
+        b'o' | b'O' => |b: u8| b.is_ascii_digit() && b < b'8',
+        b'x' | b'X' => |b: u8| b.is_ascii_hexdigit(),
+        _ => return dec_num_or_float(s),
+    };
+    if !is_digit(bytes[2]) {
+        return 1;
+    }
+    let mut len = 3;
+    while len < bytes.len() && (bytes[len] == b'_' || is_digit(bytes[len])) {
+        len += 1;
+    }
+    len
+}
+
+// Decimal integers and floats, with optional exponent and imaginary suffix
+fn dec_num_or_float(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let digits = |mut len: usize| {
+        while len < bytes.len() && (bytes[len] == b'_' || bytes[len].is_ascii_digit()) {
+            len += 1;
+        }
+        len
+    };
+    let mut len = digits(0);
+    if len < bytes.len() && bytes[len] == b'.' {
+        len = digits(len + 1);
+    }
+    if len + 1 < bytes.len() && (bytes[len] == b'e' || bytes[len] == b'E') {
+        let mut exp = len + 1;
+        if bytes[exp] == b'+' || bytes[exp] == b'-' {
+            exp += 1;
+        }
+        if exp < bytes.len() && bytes[exp].is_ascii_digit() {
+            len = digits(exp);
+        }
+    }
+    if len < bytes.len() && (bytes[len] == b'j' || bytes[len] == b'J') {
+        len += 1;
+    }
+    len
+}
+
+// Length of escape sequence, after the backslash
+fn escaped_char(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let hex = |len: usize| {
+        if bytes.len() > len && bytes[1..=len].iter().all(|b| b.is_ascii_hexdigit()) {
+            Some(len + 1)
+        } else {
+            None
+        }
+    };
+    match *bytes.get(0)? {
+        b'\\' | b'\'' | b'"' | b'a' | b'b' | b'f' | b'n' | b'r' | b't' | b'v' => Some(1),
+        b'0'..=b'7' => {
+            let mut len = 1;
+            while len < 3 && len < bytes.len() && bytes[len] >= b'0' && bytes[len] < b'8' {
+                len += 1;
+            }
+            Some(len)
+        }
+        b'x' => hex(2),
+        b'u' => hex(4),
+        b'U' => hex(8),
+        b'N' => {
+            if bytes.get(1) == Some(&b'{') {
+                s.find('}').map(|i| i + 1)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn key_or_ident(s: &str) -> PyTok {
+    match s {
+        "and" | "as" | "assert" | "async" | "await" | "break" | "continue" | "del" | "elif"
+        | "else" | "except" | "finally" | "for" | "from" | "global" | "if" | "import" | "in"
+        | "is" | "lambda" | "nonlocal" | "not" | "or" | "pass" | "raise" | "return" | "try"
+        | "while" | "with" | "yield" => PyTok::Key,
+        "def" | "class" => PyTok::KeyDef,
+        "bool" | "bytearray" | "bytes" | "complex" | "dict" | "float" | "frozenset" | "int"
+        | "list" | "object" | "set" | "str" | "tuple" | "type" => PyTok::KeyTyp,
+        "True" | "False" | "None" => PyTok::Num,
+        _ => PyTok::Ident,
+    }
+}
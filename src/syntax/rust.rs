@@ -2,7 +2,7 @@
 
 use std::ops::Range;
 
-use super::{SyntaxBackend, Tok};
+use super::{line_states, SyntaxBackend, Tok};
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 enum State {
@@ -14,6 +14,7 @@ enum State {
     String,
 }
 
+#[derive(Clone)]
 pub(crate) struct RustSyntax {
     states: Vec<(State, State)>, // start, end state
     linum: usize,
@@ -26,6 +27,14 @@ impl RustSyntax {
             linum: 0,
         }
     }
+
+    // Copy holding only the state line linum starts in, with the line number to lex it as
+    pub(super) fn line_copy(&self, linum: usize) -> (RustSyntax, usize) {
+        let (states, linum) = line_states(&self.states, linum);
+        let mut ret = RustSyntax::new();
+        ret.states = states;
+        (ret, linum)
+    }
 }
 
 impl SyntaxBackend for RustSyntax {
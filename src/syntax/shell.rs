@@ -0,0 +1,433 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::ops::Range;
+
+use super::{line_states, SyntaxBackend, Tok};
+
+#[derive(Clone, Eq, PartialEq)]
+enum State {
+    Base,
+    // Here-document starts on the next line. Holds the delimiter, and whether leading tabs are
+    // stripped (<<-)
+    HeredocNext(String, bool),
+    Heredoc(String, bool),
+    // Quote character
+    String(char),
+}
+
+#[derive(Clone)]
+pub(crate) struct ShellSyntax {
+    states: Vec<(State, State)>, // start, end state
+    linum: usize,
+    // Next word is a command
+    cmd_start: bool,
+    // Assignments are allowed here, e.g. after "local"
+    decl: bool,
+    // Inside the value of an assignment
+    assign: bool,
+    // Next word is the name of a function
+    fn_def: bool,
+    // "in" is a keyword here, after "for", "select" or "case"
+    expect_in: bool,
+    // Next word is a here-document delimiter. Holds whether leading tabs are stripped
+    heredoc: Option<bool>,
+}
+
+impl ShellSyntax {
+    pub(super) fn new() -> ShellSyntax {
+        ShellSyntax {
+            states: Vec::new(),
+            linum: 0,
+            cmd_start: true,
+            decl: false,
+            assign: false,
+            fn_def: false,
+            expect_in: false,
+            heredoc: None,
+        }
+    }
+
+    // Copy holding only the state line linum starts in, with the line number to lex it as
+    pub(super) fn line_copy(&self, linum: usize) -> (ShellSyntax, usize) {
+        let (states, linum) = line_states(&self.states, linum);
+        let mut ret = ShellSyntax::new();
+        ret.states = states;
+        (ret, linum)
+    }
+
+    // Word at the current position, which isn't a variable, string or operator
+    fn word<'a>(&mut self, s: &'a str, i: usize) -> Tok<'a> {
+        let word = &s[..i];
+        if self.fn_def {
+            self.fn_def = false;
+            self.cmd_start = false;
+            return Tok::func_defn(word);
+        }
+        if self.cmd_start || self.decl {
+            if let Some(eq) = word.find('=') {
+                if is_name(&word[..eq]) {
+                    self.cmd_start = false;
+                    self.assign = true;
+                    return Tok::entity_tag(&word[..eq]);
+                }
+            }
+        }
+        if self.assign {
+            return Tok::misc(word);
+        }
+        if self.expect_in && word == "in" {
+            self.expect_in = false;
+            return Tok::keyword(word);
+        }
+        if !self.cmd_start {
+            if word.bytes().all(|b| b.is_ascii_digit()) {
+                return Tok::num(word);
+            }
+            return Tok::misc(word);
+        }
+        match word {
+            "if" | "then" | "else" | "elif" | "do" | "while" | "until" | "!" | "time" | "{" => {
+                Tok::keyword(word)
+            }
+            "fi" | "done" | "esac" | "}" | "[[" | "coproc" => {
+                self.cmd_start = false;
+                Tok::keyword(word)
+            }
+            "for" | "select" | "case" => {
+                self.cmd_start = false;
+                self.expect_in = true;
+                Tok::keyword(word)
+            }
+            "function" => {
+                self.cmd_start = false;
+                self.fn_def = true;
+                Tok::keyword(word)
+            }
+            "declare" | "local" | "export" | "readonly" | "typeset" => {
+                self.cmd_start = false;
+                self.decl = true;
+                Tok::keyword(word)
+            }
+            _ => {
+                self.cmd_start = false;
+                if s[i..].trim_start().starts_with("()") {
+                    Tok::func_defn(word)
+                } else {
+                    Tok::func_call(word)
+                }
+            }
+        }
+    }
+}
+
+impl SyntaxBackend for ShellSyntax {
+    fn start_of_line(&mut self, linum: usize) {
+        self.linum = linum;
+        self.cmd_start = true;
+        self.decl = false;
+        self.assign = false;
+        self.fn_def = false;
+        self.expect_in = false;
+        self.heredoc = None;
+        if self.states.len() == 0 {
+            self.states.push((State::Base, State::Base));
+        } else if linum >= self.states.len() {
+            let prev = self.states[self.states.len() - 1].1.clone();
+            self.states.push((prev.clone(), prev));
+        } else if linum == 0 {
+            self.states[linum] = (State::Base, State::Base);
+        } else {
+            self.states[linum].0 = self.states[linum - 1].1.clone();
+            self.states[linum].1 = self.states[linum].0.clone();
+        }
+        if let State::HeredocNext(delim, strip) = &self.states[linum].0 {
+            let state = State::Heredoc(delim.clone(), *strip);
+            self.states[linum] = (state.clone(), state);
+        }
+    }
+
+    fn insert_lines(&mut self, linum: usize, nlines: usize) {
+        for _ in 0..nlines {
+            self.states.insert(linum, (State::Base, State::Base));
+        }
+    }
+
+    fn can_end_highlight(&self) -> bool {
+        if self.linum + 1 < self.states.len() {
+            self.states[self.linum].1 == self.states[self.linum + 1].0
+        } else {
+            true
+        }
+    }
+
+    fn remove_lines(&mut self, range: Range<usize>) {
+        self.states.drain(range);
+    }
+
+    fn next_tok<'a>(&mut self, s: &'a str) -> Option<Tok<'a>> {
+        if s.len() == 0 {
+            return None;
+        }
+        match self.states[self.linum].1.clone() {
+            State::Base | State::HeredocNext(_, _) => {
+                let mut lex = Lexer::new(s);
+                let tok = lex.next()?;
+                if let Some(strip) = self.heredoc {
+                    match tok {
+                        (ShTok::Space, i) => return Some(Tok::misc(&s[..i])),
+                        _ => {
+                            self.heredoc = None;
+                            let (delim, i) = heredoc_delimiter(s);
+                            if i > 0 {
+                                self.states[self.linum].1 = State::HeredocNext(delim, strip);
+                                return Some(Tok::entity_tag(&s[..i]));
+                            }
+                        }
+                    }
+                }
+                match tok {
+                    (ShTok::Space, i) => {
+                        if self.assign {
+                            self.assign = false;
+                            self.cmd_start = !self.decl;
+                        }
+                        Some(Tok::misc(&s[..i]))
+                    }
+                    (ShTok::Comment, _) => Some(Tok::comment(s)),
+                    (ShTok::StringStart(quote), i) => {
+                        self.cmd_start = false;
+                        self.states[self.linum].1 = State::String(quote);
+                        Some(Tok::string(&s[..i]))
+                    }
+                    (ShTok::Var, i) => {
+                        self.cmd_start = false;
+                        Some(Tok::ident(&s[..i]))
+                    }
+                    (ShTok::Subst, i) => {
+                        self.cmd_start = true;
+                        self.assign = false;
+                        Some(Tok::operator(&s[..i]))
+                    }
+                    (ShTok::Heredoc(strip), i) => {
+                        self.heredoc = Some(strip);
+                        Some(Tok::operator(&s[..i]))
+                    }
+                    (ShTok::Op, i) => Some(Tok::operator(&s[..i])),
+                    (ShTok::CmdSep, i) => {
+                        self.cmd_start = true;
+                        self.decl = false;
+                        self.assign = false;
+                        Some(Tok::operator(&s[..i]))
+                    }
+                    (ShTok::Separator, i) => {
+                        self.cmd_start = true;
+                        self.decl = false;
+                        self.assign = false;
+                        Some(Tok::separator(&s[..i]))
+                    }
+                    (ShTok::Word, i) => Some(self.word(s, i)),
+                }
+            }
+            State::Heredoc(delim, strip) => {
+                let line = if strip { s.trim_start_matches(' ') } else { s };
+                if line == delim {
+                    self.states[self.linum].1 = State::Base;
+                    Some(Tok::entity_tag(s))
+                } else {
+                    Some(Tok::string(s))
+                }
+            }
+            State::String('"') => {
+                let mut iter = s.char_indices();
+                while let Some((i, c)) = iter.next() {
+                    match c {
+                        '\\' => {
+                            if i > 0 {
+                                return Some(Tok::string(&s[..i]));
+                            }
+                            return match s[1..].chars().next() {
+                                Some(c) if c == '$' || c == '`' || c == '"' || c == '\\' => {
+                                    Some(Tok::escaped_char(&s[..2]))
+                                }
+                                _ => Some(Tok::string(&s[..1])),
+                            };
+                        }
+                        '$' => {
+                            if i > 0 {
+                                return Some(Tok::string(&s[..i]));
+                            }
+                            return match var_len(s) {
+                                Some(len) => Some(Tok::ident(&s[..len])),
+                                None => Some(Tok::string(&s[..1])),
+                            };
+                        }
+                        '"' => {
+                            self.states[self.linum].1 = State::Base;
+                            return Some(Tok::string(&s[..(i + 1)]));
+                        }
+                        _ => {}
+                    }
+                }
+                Some(Tok::string(s))
+            }
+            State::String(quote) => match s.find(quote) {
+                Some(i) => {
+                    self.states[self.linum].1 = State::Base;
+                    Some(Tok::string(&s[..(i + 1)]))
+                }
+                None => Some(Tok::string(s)),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ShTok {
+    Comment,
+    StringStart(char),
+    // Parameter expansion
+    Var,
+    // Start of command substitution
+    Subst,
+    // Here-document redirection, and whether leading tabs are stripped
+    Heredoc(bool),
+    Op,
+    // Operators after which a new command starts
+    CmdSep,
+    Separator,
+    Word,
+    Space,
+}
+
+struct Lexer<'a> {
+    s: &'a str,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(s: &'a str) -> Lexer<'a> {
+        Lexer { s: s }
+    }
+
+    fn next(&mut self) -> Option<(ShTok, usize)> {
+        let mut iter = self.s.char_indices().peekable();
+        let (typ, i) = match iter.next()? {
+            // Words containing "#" are handled below, so this is always at the start of a word
+            (_, '#') => (ShTok::Comment, self.s.len()),
+            (_, '\'') => (ShTok::StringStart('\''), 1),
+            (_, '"') => (ShTok::StringStart('"'), 1),
+            (_, '`') => (ShTok::Subst, 1),
+            (_, '$') => match iter.next() {
+                Some((_, '(')) => match iter.next() {
+                    Some((_, '(')) => (ShTok::Op, 3),
+                    _ => (ShTok::Subst, 2),
+                },
+                // ANSI-C and locale-translated strings
+                Some((_, '\'')) => (ShTok::StringStart('\''), 2),
+                Some((_, '"')) => (ShTok::StringStart('"'), 2),
+                _ => match var_len(self.s) {
+                    Some(len) => (ShTok::Var, len),
+                    None => (ShTok::Word, 1),
+                },
+            },
+            (_, ';') => match iter.next() {
+                Some((_, ';')) => (ShTok::Separator, 2),
+                _ => (ShTok::Separator, 1),
+            },
+            (_, c) if c == '|' || c == '&' => match iter.next() {
+                Some((_, d)) if d == c => (ShTok::CmdSep, 2),
+                Some((_, '>')) if c == '&' => (ShTok::Op, 2),
+                _ => (ShTok::CmdSep, 1),
+            },
+            (_, '(') => (ShTok::CmdSep, 1),
+            (_, ')') => (ShTok::Op, 1),
+            (_, '<') => match iter.next() {
+                Some((_, '<')) => match iter.next() {
+                    Some((_, '<')) => (ShTok::Op, 3),
+                    Some((_, '-')) => (ShTok::Heredoc(true), 3),
+                    _ => (ShTok::Heredoc(false), 2),
+                },
+                Some((_, '&')) | Some((_, '(')) | Some((_, '>')) => (ShTok::Op, 2),
+                _ => (ShTok::Op, 1),
+            },
+            (_, '>') => match iter.next() {
+                Some((_, '>')) | Some((_, '&')) | Some((_, '|')) | Some((_, '(')) => (ShTok::Op, 2),
+                _ => (ShTok::Op, 1),
+            },
+            (_, c) if c.is_whitespace() => loop {
+                if let Some((i, c)) = iter.next() {
+                    if !c.is_whitespace() {
+                        break (ShTok::Space, i);
+                    }
+                } else {
+                    break (ShTok::Space, self.s.len());
+                }
+            },
+            _ => (ShTok::Word, word_len(self.s)),
+        };
+        self.s = &self.s[i..];
+        Some((typ, i))
+    }
+}
+
+// Length of word, up to whitespace, an operator, a quote or an expansion. Backslashes escape the
+// next character
+fn word_len(s: &str) -> usize {
+    let mut iter = s.char_indices();
+    while let Some((i, c)) = iter.next() {
+        match c {
+            '\\' => {
+                iter.next();
+            }
+            '|' | '&' | ';' | '(' | ')' | '<' | '>' | '\'' | '"' | '`' | '$' if i > 0 => {
+                return i;
+            }
+            c if c.is_whitespace() => return i,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+// Length of parameter expansion starting with "$", e.g. "$HOME", "${1:-x}" or "$?"
+fn var_len(s: &str) -> Option<usize> {
+    let bytes = s.as_bytes();
+    match *bytes.get(1)? {
+        b'{' => Some(s.find('}').map(|i| i + 1).unwrap_or(s.len())),
+        b'@' | b'*' | b'#' | b'?' | b'$' | b'!' | b'-' | b'0'..=b'9' => Some(2),
+        b if b == b'_' || b.is_ascii_alphabetic() => {
+            let mut len = 2;
+            while len < bytes.len() && (bytes[len] == b'_' || bytes[len].is_ascii_alphanumeric()) {
+                len += 1;
+            }
+            Some(len)
+        }
+        _ => None,
+    }
+}
+
+// Here-document delimiter at the start of s, with quotes removed, and its length in s
+fn heredoc_delimiter(s: &str) -> (String, usize) {
+    let mut delim = String::new();
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => delim.push(c),
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (None, '\\') => {}
+            (None, c) if c.is_whitespace() || "|&;()<>".contains(c) => return (delim, i),
+            (None, c) => delim.push(c),
+        }
+    }
+    (delim, s.len())
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
@@ -4,6 +4,7 @@ use std::ops::Range;
 
 use super::{SyntaxBackend, Tok};
 
+#[derive(Clone)]
 enum State {
     LineStart,
     TableNameStart,
@@ -15,6 +16,7 @@ enum State {
     LineEnd,
 }
 
+#[derive(Clone)]
 pub(crate) struct TOMLSyntax {
     state: State,
 }
@@ -0,0 +1,259 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use ropey::Rope;
+use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
+
+use crate::types::TextPitch;
+
+use super::{SyntaxBackend, Tok, TokTyp};
+
+/// Highlighting from a tree-sitter parse of the whole file. The tree is updated incrementally
+/// after edits, and highlight captures are mapped to the usual token types
+#[derive(Clone)]
+pub(crate) struct TreeSitterSyntax {
+    name: &'static str,
+    language: Language,
+    query: Arc<Query>,
+    // Text the tree was parsed from
+    text: Rope,
+    tree: Option<Tree>,
+    // Lines changed since the last parse, as (start, end before edits, end after edits)
+    edited: Option<(usize, usize, usize)>,
+    tabsize: usize,
+    // Tokens in the current line, as lengths in the line with tabs expanded
+    line_toks: Vec<(usize, TokTyp)>,
+    tok_idx: usize,
+}
+
+impl TreeSitterSyntax {
+    pub(super) fn rust() -> Option<TreeSitterSyntax> {
+        TreeSitterSyntax::new(
+            "rust",
+            tree_sitter_rust::language(),
+            tree_sitter_rust::HIGHLIGHT_QUERY,
+        )
+    }
+
+    pub(super) fn c() -> Option<TreeSitterSyntax> {
+        TreeSitterSyntax::new(
+            "c",
+            tree_sitter_c::language(),
+            tree_sitter_c::HIGHLIGHT_QUERY,
+        )
+    }
+
+    // Returns None if the grammar or query can't be loaded
+    fn new(name: &'static str, language: Language, query: &str) -> Option<TreeSitterSyntax> {
+        if let Err(e) = Parser::new().set_language(language) {
+            error!("failed to load tree-sitter grammar for {}: {}", name, e);
+            return None;
+        }
+        let query = match Query::new(language, query) {
+            Ok(query) => query,
+            Err(e) => {
+                error!("failed to load tree-sitter query for {}: {}", name, e);
+                return None;
+            }
+        };
+        Some(TreeSitterSyntax {
+            name: name,
+            language: language,
+            query: Arc::new(query),
+            text: Rope::new(),
+            tree: None,
+            edited: None,
+            tabsize: 4,
+            line_toks: Vec::new(),
+            tok_idx: 0,
+        })
+    }
+
+    pub(super) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Record that lines start..old_end in the current text were replaced by lines
+    // start..new_end, merging with earlier edits since the last parse
+    fn edit_lines(&mut self, start: usize, old_end: usize, new_end: usize) {
+        self.edited = Some(match self.edited {
+            None => (start, old_end, new_end),
+            Some((s, oe, ne)) => {
+                let end = ne.max(old_end);
+                (s.min(start), end - ne + oe, end - old_end + new_end)
+            }
+        });
+    }
+}
+
+impl SyntaxBackend for TreeSitterSyntax {
+    fn prepare(&mut self, data: &Rope, start_linum: usize, tabsize: usize) {
+        self.tabsize = tabsize;
+        // Edits within a line don't insert or remove lines, but highlighting always starts at
+        // the edited line
+        self.edit_lines(start_linum, start_linum + 1, start_linum + 1);
+        let edited = self.edited.take();
+        if let (Some(tree), Some((start, old_end, new_end))) = (&mut self.tree, edited) {
+            let (start_byte, start_position) = line_start(&self.text, start);
+            let (old_end_byte, old_end_position) = line_start(&self.text, old_end);
+            let (new_end_byte, new_end_position) = line_start(data, new_end);
+            tree.edit(&InputEdit {
+                start_byte: start_byte,
+                old_end_byte: old_end_byte,
+                new_end_byte: new_end_byte,
+                start_position: start_position,
+                old_end_position: old_end_position,
+                new_end_position: new_end_position,
+            });
+        }
+        let mut parser = Parser::new();
+        if parser.set_language(self.language).is_ok() {
+            self.tree = parser.parse_with(&mut |byte, _| text_at(data, byte), self.tree.as_ref());
+        }
+        self.text = data.clone();
+    }
+
+    fn start_of_line(&mut self, linum: usize) {
+        self.line_toks.clear();
+        self.tok_idx = 0;
+        let tree = match &self.tree {
+            Some(tree) if linum < self.text.len_lines() => tree,
+            _ => return,
+        };
+        let text = &self.text;
+        let line = text.line(linum).to_string();
+        let line = line.trim_end_matches(|c| match c {
+            '\n' | '\x0b' | '\x0c' | '\r' | '\u{0085}' | '\u{2028}' | '\u{2029}' => true,
+            _ => false,
+        });
+        let line_byte = text.line_to_byte(linum);
+
+        // Token type of every byte in the line
+        let mut typs = vec![TokTyp::Misc; line.len()];
+        let mut seen = Vec::new();
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(line_byte..(line_byte + line.len()));
+        let captures = cursor.captures(&self.query, tree.root_node(), |node: Node| {
+            let range = node.byte_range();
+            text.slice(text.byte_to_char(range.start)..text.byte_to_char(range.end))
+                .chunks()
+                .map(str::as_bytes)
+        });
+        for (m, idx) in captures {
+            let capture = m.captures[idx];
+            let node = capture.node;
+            // Nodes can be matched by multiple patterns, and the earliest pattern wins
+            if seen.contains(&node.id()) {
+                continue;
+            }
+            seen.push(node.id());
+            let name = &self.query.capture_names()[capture.index as usize];
+            if let Some(typ) = capture_typ(name, node) {
+                let start = node.start_byte().max(line_byte) - line_byte;
+                let end = node.end_byte().min(line_byte + line.len()) - line_byte;
+                for t in typs.iter_mut().take(end).skip(start) {
+                    *t = typ;
+                }
+            }
+        }
+
+        // Merge runs of the same type, with lengths in the line as the backend sees it
+        let mut x = 0;
+        for (i, c) in line.char_indices() {
+            let len = if c == '\t' {
+                let next = (x / self.tabsize) * self.tabsize + self.tabsize;
+                let len = next - x;
+                x = next;
+                len
+            } else {
+                x += 1;
+                c.len_utf8()
+            };
+            match self.line_toks.last_mut() {
+                Some((l, typ)) if *typ == typs[i] => *l += len,
+                _ => self.line_toks.push((len, typs[i])),
+            }
+        }
+    }
+
+    fn can_end_highlight(&self) -> bool {
+        true
+    }
+
+    fn insert_lines(&mut self, linum: usize, nlines: usize) {
+        self.edit_lines(linum, linum, linum + nlines);
+    }
+
+    fn remove_lines(&mut self, range: Range<usize>) {
+        self.edit_lines(range.start, range.end, range.start);
+    }
+
+    fn next_tok<'a>(&mut self, s: &'a str) -> Option<Tok<'a>> {
+        if s.len() == 0 {
+            return None;
+        }
+        let (len, typ) = match self.line_toks.get(self.tok_idx) {
+            Some(&(len, typ)) if len <= s.len() && s.is_char_boundary(len) => (len, typ),
+            // Nothing was parsed for this line
+            _ => (s.len(), TokTyp::Misc),
+        };
+        self.tok_idx += 1;
+        Some(Tok {
+            s: &s[..len],
+            typ: typ,
+            pitch: TextPitch::Fixed,
+        })
+    }
+}
+
+// Byte offset and position of the start of a line. Lines past the end are clamped to the end of
+// the text
+fn line_start(text: &Rope, linum: usize) -> (usize, Point) {
+    if linum < text.len_lines() {
+        (text.line_to_byte(linum), Point::new(linum, 0))
+    } else {
+        let last = text.len_lines() - 1;
+        let len = text.len_bytes();
+        (len, Point::new(last, len - text.line_to_byte(last)))
+    }
+}
+
+// Text from byte offset to the end of the chunk containing it, for the parser
+fn text_at(text: &Rope, byte: usize) -> &[u8] {
+    if byte >= text.len_bytes() {
+        return &[];
+    }
+    let (chunk, chunk_byte, _, _) = text.chunk_at_byte(byte);
+    &chunk.as_bytes()[(byte - chunk_byte)..]
+}
+
+// Token type for a highlight capture, e.g. "function.method". Captures of names in function
+// definitions are told apart from calls by the parent node
+fn capture_typ(name: &str, node: Node) -> Option<TokTyp> {
+    let typ = match name {
+        "constant.builtin" => TokTyp::Num,
+        "function" | "function.method" => match node.parent().map(|p| p.kind()) {
+            Some("function_item")
+            | Some("function_signature_item")
+            | Some("function_declarator") => TokTyp::FuncDefn,
+            _ => TokTyp::FuncCall,
+        },
+        _ => match name.split('.').next().unwrap_or(name) {
+            "comment" => TokTyp::Comment,
+            "string" => TokTyp::String,
+            "escape" => TokTyp::EscapedChar,
+            "number" => TokTyp::Num,
+            "keyword" => TokTyp::Keyword,
+            "operator" => TokTyp::Operator,
+            "punctuation" | "delimiter" => TokTyp::Separator,
+            "type" | "constructor" => TokTyp::DataType,
+            "function" => TokTyp::FuncCall,
+            "constant" | "attribute" | "label" => TokTyp::EntityName,
+            "property" | "variable" => TokTyp::Identifier,
+            _ => return None,
+        },
+    };
+    Some(typ)
+}
@@ -2,19 +2,32 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Result as IOResult;
+use std::fs::{create_dir_all, rename};
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::path::{Path, PathBuf};
 use std::rc::{Rc, Weak};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::SystemTime;
 
 use euclid::Size2D;
 use ropey::{iter::Chunks, str_utils::byte_to_char_idx, Rope, RopeSlice};
 use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
 
+use crate::annotations::{AnnotationKind, Annotations};
 use crate::config::Cfg;
+use crate::decorations::{Decoration, DecorationLayer, DecorationStyle, Decorations};
+use crate::encoding::{self, Encoding};
+use crate::filecheck::stream_file;
 use crate::font::FontCore;
-use crate::syntax::Syntax;
-use crate::types::DPI;
+use crate::gutter::ShapedGutter;
+use crate::project::project_root;
+use crate::provider::{provider, write_as_root};
+use crate::search::Searcher;
+use crate::syntax::{empty_line, HighlightMsg, Syntax};
+use crate::types::{Color, DPI};
 use crate::ui::text::ShapedTextLine;
+use crate::ui::Wakeup;
 
 /// A cursor into the buffer. The buffer maintains references to all cursors, so they are
 /// updated on editing the buffer
@@ -75,6 +88,7 @@ impl BufferCursorInner {
         self.line_gidx = gidx;
         self.line_global_x = self.line_gidx;
         self.char_idx = data.line_to_char(self.line_num) + self.line_cidx;
+        self.debug_check(data, tabsize);
     }
 
     fn sync_line_cidx_gidx_right(&mut self, data: &Rope, tabsize: usize) {
@@ -94,6 +108,7 @@ impl BufferCursorInner {
         self.line_gidx = gidx;
         self.line_global_x = self.line_gidx;
         self.char_idx = data.line_to_char(self.line_num) + self.line_cidx;
+        self.debug_check(data, tabsize);
     }
 
     fn sync_from_global_x(&mut self, data: &Rope, tabsize: usize) {
@@ -103,6 +118,7 @@ impl BufferCursorInner {
         self.line_cidx = cidx;
         self.line_gidx = gidx;
         self.char_idx = data.line_to_char(self.line_num) + self.line_cidx;
+        self.debug_check(data, tabsize);
     }
 
     fn sync_from_gidx(&mut self, data: &Rope, tabsize: usize) {
@@ -112,9 +128,50 @@ impl BufferCursorInner {
         self.line_gidx = gidx;
         self.line_global_x = self.line_gidx;
         self.char_idx = data.line_to_char(self.line_num) + self.line_cidx;
+        self.debug_check(data, tabsize);
+    }
+
+    // Check that cursor fields are consistent with each other and with the buffer contents
+    fn debug_check(&self, data: &Rope, tabsize: usize) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let line = trim_newlines(data.line(self.line_num));
+        debug_assert!(
+            self.line_cidx <= line.len_chars(),
+            "cursor past end of line"
+        );
+        debug_assert!(
+            self.past_end || self.line_cidx == 0 || self.line_cidx < line.len_chars(),
+            "cursor past end of line without past_end"
+        );
+        debug_assert!(
+            is_grapheme_boundary(&line, self.line_cidx),
+            "cursor not on grapheme boundary"
+        );
+        debug_assert_eq!(
+            self.char_idx,
+            data.line_to_char(self.line_num) + self.line_cidx,
+            "cursor char index inconsistent with line"
+        );
+        debug_assert_eq!(
+            self.line_gidx,
+            gidx_from_cidx(&line, self.line_cidx, tabsize),
+            "cursor grapheme index inconsistent with char index"
+        );
     }
 }
 
+// Extensions of files where hex color literals are decorated
+const COLOR_SWATCH_EXTENSIONS: &[&str] = &[
+    "css", "scss", "sass", "less", "yml", "yaml", "toml", "json", "ini", "conf", "svg", "html",
+];
+
+// Priorities of built-in decorations
+const SWATCH_PRIORITY: i32 = 0;
+const INDENTATION_PRIORITY: i32 = 5;
+const SEARCH_PRIORITY: i32 = 10;
+
 /// A location within a buffer. This is invalidated on editing the buffer
 pub(crate) struct BufferPos {
     char_idx: usize,
@@ -129,17 +186,85 @@ impl BufferPos {
     }
 }
 
+// Whether the file's contents are in the buffer. Remote files are read in the background, and
+// files in large-file mode are read in chunks that are appended as they come in
+enum FileLoad {
+    Done,
+    Pending(Receiver<IOResult<(Rope, Encoding)>>),
+    Streaming(Receiver<IOResult<String>>),
+    // Writing to the file is refused, so that it isn't overwritten with nothing
+    Failed,
+}
+
+// Write of a remote file running in the background, with the path and contents being written
+struct FileWrite {
+    rx: Receiver<IOResult<()>>,
+    path: String,
+    data: Rope,
+}
+
+/// How a line differs from the last read or written contents of the file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum LineChange {
+    Added,
+    Modified,
+    // Lines were deleted just above this one
+    Deleted,
+}
+
 // Actual text storage
 pub(crate) struct Buffer {
     data: Rope,
     tabsize: usize,
     indent_tabs: bool,
+    // Indentation was set with :indent-style, so it isn't detected or taken from the config
+    indent_overridden: bool,
     path: Option<String>,
+    // Root of the project the file is in
+    project_root: Option<PathBuf>,
+    // Modification time of file when last read, written or checked
+    mtime: Option<SystemTime>,
+    // Contents changed since the file was last read or written
+    dirty: bool,
+    // The file didn't exist when it was read. It is created by the first write
+    new_file: bool,
+    load: FileLoad,
+    // The buffer was edited before a background read finished. The file's contents are kept as
+    // the last read contents, and writing asks before replacing them
+    edited_while_loading: bool,
+    write: Option<FileWrite>,
+    scratch: bool,
+    // Opened in large-file mode. Lines aren't highlighted
+    large: bool,
+    // Name shown for scratch buffers
+    title: Option<String>,
     cursors: HashMap<usize, Weak<RefCell<BufferCursorInner>>>,
+    marks: HashMap<char, BufferCursor>,
+    // Notes on lines, with cursors so that they move with edits
+    notes: Vec<(BufferCursor, String)>,
+    annotations: Annotations,
+    decorations: Decorations,
+    // Pattern search matches were highlighted for. Cleared on edits
+    search_pattern: Option<String>,
     font_core: Rc<RefCell<FontCore>>,
     config: Rc<RefCell<Cfg>>,
+    // Wakes the main loop up once background work for the buffer has something to hand over
+    wakeup: Wakeup,
     syntax: Syntax,
-    dpi_shaped_lines: Vec<(Size2D<u32, DPI>, Vec<ShapedTextLine>, Vec<ShapedTextLine>)>,
+    dpi_shaped_lines: Vec<(Size2D<u32, DPI>, ShapedGutter, Vec<ShapedTextLine>)>,
+    // Contents of the file when last read or written, and how lines differ from it. Changes are
+    // recomputed when needed after edits
+    saved: Option<Rope>,
+    // Modification time of the file when saved was read or written
+    saved_mtime: Option<SystemTime>,
+    // Encoding the file was read in, and is written in
+    encoding: Encoding,
+    line_changes: Option<Vec<(usize, LineChange)>>,
+    // Highlighting of off-screen lines, running in the background
+    highlight_job: Option<Receiver<HighlightMsg>>,
+    // End of lines that have to be re-highlighted if the job is cancelled, and the number of
+    // lines in the buffer at the time
+    unsettled: Option<(usize, usize)>,
 }
 
 impl Buffer {
@@ -148,6 +273,7 @@ impl Buffer {
         initial_dpi: Size2D<u32, DPI>,
         font_core: Rc<RefCell<FontCore>>,
         config: Rc<RefCell<Cfg>>,
+        wakeup: Wakeup,
     ) -> Buffer {
         let syntax = Syntax::default();
         let (tabsize, indent_tabs) = {
@@ -158,13 +284,36 @@ impl Buffer {
         let mut ret = Buffer {
             data: Rope::new(),
             cursors: HashMap::new(),
+            marks: HashMap::new(),
+            notes: Vec::new(),
+            annotations: Annotations::default(),
+            decorations: Decorations::default(),
+            search_pattern: None,
             path: None,
+            project_root: None,
+            mtime: None,
+            dirty: false,
+            new_file: false,
+            edited_while_loading: false,
+            write: None,
+            scratch: false,
+            large: false,
+            title: None,
+            load: FileLoad::Done,
             tabsize: tabsize,
             indent_tabs: indent_tabs,
-            dpi_shaped_lines: vec![(initial_dpi, Vec::new(), Vec::new())],
+            indent_overridden: false,
+            dpi_shaped_lines: vec![(initial_dpi, ShapedGutter::default(), Vec::new())],
+            saved: None,
+            saved_mtime: None,
+            encoding: Encoding::Utf8,
+            line_changes: None,
+            highlight_job: None,
+            unsettled: None,
             config: config.clone(),
             syntax: Syntax::default(),
             font_core: font_core,
+            wakeup: wakeup,
         };
         ret.format_lines_from(0, None);
         ret
@@ -176,88 +325,1040 @@ impl Buffer {
         initial_dpi: Size2D<u32, DPI>,
         font_core: Rc<RefCell<FontCore>>,
         config: Rc<RefCell<Cfg>>,
+        wakeup: Wakeup,
     ) -> Buffer {
-        let rope = File::open(path)
-            .and_then(|f| Rope::from_reader(f))
-            .unwrap_or(Rope::new());
-        let syntax = Syntax::from_path(path);
+        let provider = provider(path);
+        let (rope, encoding, load, new_file) = if provider.is_remote() {
+            let (tx, rx) = wakeup.channel();
+            let spath = path.to_owned();
+            thread::spawn(move || {
+                let _ = tx.send(provider.read(&spath).map(|data| encoding::decode(&data)));
+            });
+            (Rope::new(), Encoding::Utf8, FileLoad::Pending(rx), false)
+        } else {
+            match provider.read(path) {
+                Ok(data) => {
+                    let (rope, encoding) = encoding::decode(&data);
+                    (rope, encoding, FileLoad::Done, false)
+                }
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                    (Rope::new(), Encoding::Utf8, FileLoad::Done, true)
+                }
+                Err(e) => {
+                    error!("failed to read file: {}: {} (:e to retry)", path, e);
+                    (Rope::new(), Encoding::Utf8, FileLoad::Failed, false)
+                }
+            }
+        };
+        let syntax = Syntax::from_path(path, &rope.line(0).to_string());
         let (tabsize, indent_tabs) = {
             let cfg = &*config.borrow();
             let cfgsyn = cfg.syntax(syntax.name());
             (cfgsyn.tab_width as usize, cfgsyn.indent_tabs)
         };
+        let saved = match load {
+            FileLoad::Done => Some(rope.clone()),
+            _ => None,
+        };
+        let mtime = file_mtime(path);
         let mut ret = Buffer {
             data: rope,
             cursors: HashMap::new(),
+            marks: HashMap::new(),
+            notes: Vec::new(),
+            annotations: Annotations::default(),
+            decorations: Decorations::default(),
+            search_pattern: None,
             path: Some(path.to_owned()),
+            project_root: None,
+            mtime: mtime,
+            dirty: false,
+            new_file: new_file,
+            edited_while_loading: false,
+            write: None,
+            scratch: false,
+            large: false,
+            title: None,
+            load: load,
             tabsize: tabsize,
             indent_tabs: indent_tabs,
-            dpi_shaped_lines: vec![(initial_dpi, Vec::new(), Vec::new())],
+            indent_overridden: false,
+            dpi_shaped_lines: vec![(initial_dpi, ShapedGutter::default(), Vec::new())],
+            saved: saved,
+            saved_mtime: mtime,
+            encoding: encoding,
+            line_changes: None,
+            highlight_job: None,
+            unsettled: None,
             syntax: syntax,
             config: config.clone(),
             font_core: font_core,
+            wakeup: wakeup,
+        };
+        let (tabsize, indent_tabs) = ret.indent_settings();
+        ret.tabsize = tabsize;
+        ret.indent_tabs = indent_tabs;
+        ret.detect_project_root();
+        ret.format_lines_from(0, None);
+        ret
+    }
+
+    /// Create read-only scratch buffer holding given text. Scratch buffers aren't backed by a file
+    pub(crate) fn scratch(
+        text: &str,
+        initial_dpi: Size2D<u32, DPI>,
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        wakeup: Wakeup,
+    ) -> Buffer {
+        let syntax = Syntax::default();
+        let (tabsize, indent_tabs) = {
+            let cfg = &*config.borrow();
+            let cfgsyn = cfg.syntax(syntax.name());
+            (cfgsyn.tab_width as usize, cfgsyn.indent_tabs)
+        };
+        let mut ret = Buffer {
+            data: Rope::from_str(text),
+            cursors: HashMap::new(),
+            marks: HashMap::new(),
+            notes: Vec::new(),
+            annotations: Annotations::default(),
+            decorations: Decorations::default(),
+            search_pattern: None,
+            path: None,
+            project_root: None,
+            mtime: None,
+            dirty: false,
+            new_file: false,
+            edited_while_loading: false,
+            write: None,
+            scratch: true,
+            large: false,
+            title: None,
+            load: FileLoad::Done,
+            tabsize: tabsize,
+            indent_tabs: indent_tabs,
+            indent_overridden: false,
+            dpi_shaped_lines: vec![(initial_dpi, ShapedGutter::default(), Vec::new())],
+            saved: None,
+            saved_mtime: None,
+            encoding: Encoding::Utf8,
+            line_changes: None,
+            highlight_job: None,
+            unsettled: None,
+            config: config.clone(),
+            syntax: syntax,
+            font_core: font_core,
+            wakeup: wakeup,
         };
         ret.format_lines_from(0, None);
         ret
     }
 
-    /// Reload buffer contents and reset all cursors
+    /// Create read-only scratch buffer for a file in large-file mode. The file is read in the
+    /// background, and appended to the buffer as it comes in
+    pub(crate) fn large_file(
+        path: &str,
+        initial_dpi: Size2D<u32, DPI>,
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        wakeup: Wakeup,
+    ) -> Buffer {
+        let mut ret = Buffer::scratch("", initial_dpi, font_core, config, wakeup.clone());
+        ret.large = true;
+        ret.load = FileLoad::Streaming(stream_file(path, wakeup));
+        ret.format_lines_from(0, None);
+        ret
+    }
+
+    /// Whether the file didn't exist when it was opened, and hasn't been written yet
+    pub(crate) fn is_new_file(&self) -> bool {
+        self.new_file
+    }
+
+    pub(crate) fn is_scratch(&self) -> bool {
+        self.scratch
+    }
+
+    pub(crate) fn title(&self) -> Option<&str> {
+        self.title.as_ref().map(|s| s.as_str())
+    }
+
+    pub(crate) fn set_title(&mut self, title: &str) {
+        self.title = Some(title.to_owned());
+    }
+
+    pub(crate) fn path(&self) -> Option<&str> {
+        self.path.as_ref().map(|s| s.as_str())
+    }
+
+    /// Root of the project the file is in, if it is in one
+    pub(crate) fn project_root(&self) -> Option<&Path> {
+        self.project_root.as_ref().map(|p| p.as_path())
+    }
+
+    /// Look for the project root again, like after the root markers change. Remote files
+    /// aren't in a project
+    pub(crate) fn detect_project_root(&mut self) {
+        self.project_root = match &self.path {
+            Some(path) if !provider(path).is_remote() => {
+                project_root(Path::new(path), &self.config.borrow().ui.root_markers)
+            }
+            _ => None,
+        };
+    }
+
+    pub(crate) fn syntax_name(&self) -> &'static str {
+        self.syntax.name()
+    }
+
+    /// Set local mark at given line and grapheme index. The mark is a cursor, so it moves with
+    /// edits. The ID must not be used by any other cursor into this buffer
+    pub(crate) fn set_mark(&mut self, c: char, id: usize, linum: usize, gidx: usize) {
+        let pos = self.get_pos_at_line(linum);
+        let mut cursor = self.add_cursor_at_pos(id, &pos, true);
+        self.move_cursor_to_linum_gidx(&mut cursor, linum, gidx);
+        self.marks.insert(c, cursor);
+    }
+
+    /// Line and grapheme index of local mark
+    pub(crate) fn mark(&self, c: char) -> Option<(usize, usize)> {
+        self.marks
+            .get(&c)
+            .map(|cursor| (cursor.line_num(), cursor.line_gidx()))
+    }
+
+    /// Attach note to line, replacing any note on it. The ID must not be used by any other cursor
+    /// into this buffer
+    pub(crate) fn set_note(&mut self, id: usize, linum: usize, text: &str) {
+        let linum = linum.min(self.data.len_lines().saturating_sub(1));
+        self.remove_note(linum);
+        let pos = self.get_pos_at_line(linum);
+        let cursor = self.add_cursor_at_pos(id, &pos, true);
+        self.notes.push((cursor, text.to_owned()));
+    }
+
+    /// Remove note on line. Returns false if there was none
+    pub(crate) fn remove_note(&mut self, linum: usize) -> bool {
+        let len = self.notes.len();
+        self.notes.retain(|(cursor, _)| cursor.line_num() != linum);
+        self.notes.len() != len
+    }
+
+    pub(crate) fn note(&self, linum: usize) -> Option<&str> {
+        self.notes
+            .iter()
+            .find(|(cursor, _)| cursor.line_num() == linum)
+            .map(|(_, text)| text.as_str())
+    }
+
+    /// Lines with notes, and the notes, sorted by line
+    pub(crate) fn notes(&self) -> Vec<(usize, String)> {
+        let mut ret = self
+            .notes
+            .iter()
+            .map(|(cursor, text)| (cursor.line_num(), text.clone()))
+            .collect::<Vec<_>>();
+        ret.sort_by_key(|(linum, _)| *linum);
+        ret
+    }
+
+    /// Reload buffer contents from file. The difference from the current contents is applied as
+    /// line edits, so cursors stay with their text where possible
     pub(crate) fn reload_from_file(&mut self, dpi: Size2D<u32, DPI>) -> IOResult<()> {
-        if let Some(path) = &self.path {
-            File::open(path)
-                .and_then(|f| Rope::from_reader(f))
-                .map(|r| {
-                    self.data = r;
-                    self.clean_cursors();
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let (new_data, encoding, new_file) = match provider(&path).read(&path) {
+            Ok(data) => {
+                let (data, encoding) = encoding::decode(&data);
+                (data, encoding, false)
+            }
+            // Files that haven't been written yet are reloaded as empty
+            Err(ref e) if e.kind() == ErrorKind::NotFound && self.new_file => {
+                (Rope::new(), self.encoding, true)
+            }
+            Err(e) => return Err(e),
+        };
+        self.new_file = new_file;
+        self.encoding = encoding;
+        self.mtime = file_mtime(&path);
+        self.dirty = false;
+        self.load = FileLoad::Done;
+        self.edited_while_loading = false;
+        self.replace_contents(new_data.clone(), dpi);
+        self.set_saved(new_data);
+        Ok(())
+    }
+
+    /// Fill in the contents of a file that is read in the background. Returns true once the read
+    /// is done
+    pub(crate) fn poll_load(&mut self, dpi: Size2D<u32, DPI>) -> bool {
+        let res = match &self.load {
+            FileLoad::Pending(rx) => match rx.try_recv() {
+                Ok(res) => res,
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    Err(IOError::new(ErrorKind::Other, "read was interrupted"))
+                }
+            },
+            _ => return false,
+        };
+        let path = self.path.clone().unwrap_or_default();
+        self.load = FileLoad::Done;
+        match res {
+            Ok((data, encoding)) if self.dirty => {
+                warning!(
+                    "buffer was edited while loading: {} (:w! to overwrite)",
+                    path
+                );
+                self.encoding = encoding;
+                self.mtime = file_mtime(&path);
+                self.set_saved(data);
+                self.edited_while_loading = true;
+            }
+            Ok((data, encoding)) => {
+                self.encoding = encoding;
+                self.mtime = file_mtime(&path);
+                self.replace_contents(data.clone(), dpi);
+                self.set_saved(data);
+                // The first line may have a shebang
+                self.set_path(&path);
+                let (tabsize, indent_tabs) = self.indent_settings();
+                self.set_tabsize(tabsize, indent_tabs);
+            }
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                self.new_file = true;
+                self.set_saved(Rope::new());
+            }
+            Err(e) => {
+                error!("failed to read file: {}: {} (:e to retry)", path, e);
+                self.load = FileLoad::Failed;
+            }
+        }
+        true
+    }
+
+    /// Append chunks of a file read in large-file mode, if any are ready. Returns true if text
+    /// was added
+    pub(crate) fn poll_stream(&mut self) -> bool {
+        let mut added = 0;
+        while added < POLL_STREAM_CHUNKS {
+            let res = match &self.load {
+                FileLoad::Streaming(rx) => rx.try_recv(),
+                _ => break,
+            };
+            match res {
+                Ok(Ok(chunk)) => {
+                    // The last line was cut short if the previous chunk didn't end with a line
+                    // break
+                    let start = self.data.len_lines() - 1;
                     let len_chars = self.data.len_chars();
-                    for (_, weak) in self.cursors.iter_mut() {
-                        let strong = weak.upgrade().unwrap();
-                        let inner = &mut *strong.borrow_mut();
-                        if inner.char_idx >= len_chars {
-                            inner.char_idx = len_chars;
-                            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
-                        }
-                    }
-                    let mut found = false;
-                    for (d, _, t) in &mut self.dpi_shaped_lines {
-                        t.clear();
-                        if *d == dpi {
-                            found = true;
-                        }
-                    }
-                    if !found {
-                        self.dpi_shaped_lines.push((dpi, Vec::new(), Vec::new()));
-                    }
-                    self.format_lines_from(0, None);
-                })
-        } else {
-            Ok(())
+                    self.data.insert(len_chars, &chunk);
+                    self.format_lines_from(start, None);
+                    added += 1;
+                }
+                Ok(Err(e)) => {
+                    let title = self.title().unwrap_or("[scratch]");
+                    error!("failed to read file: {}: {}", title, e);
+                    self.load = FileLoad::Failed;
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.load = FileLoad::Done;
+                    break;
+                }
+            }
+        }
+        // Chunks left over are added after the next frame
+        if added == POLL_STREAM_CHUNKS {
+            self.wakeup.wake();
+        }
+        added > 0
+    }
+
+    /// Shape lines from start to end that haven't been shaped yet. Lines are only shaped once
+    /// they are in view, and are left as placeholders until then
+    pub(crate) fn shape_visible(&mut self, dpi: Size2D<u32, DPI>, start: usize, end: usize) {
+        let font_core = &mut *self.font_core.borrow_mut();
+        let cfg = &*self.config.borrow();
+        for (d, _, tvec) in &mut self.dpi_shaped_lines {
+            if *d != dpi {
+                continue;
+            }
+            for linum in start..end.min(tvec.len()) {
+                if tvec[linum].is_placeholder() {
+                    tvec[linum] = self.syntax.shape_line(
+                        dpi,
+                        linum,
+                        &self.data,
+                        cfg,
+                        self.tabsize,
+                        font_core,
+                    );
+                }
+            }
+        }
+    }
+
+    // Replace buffer contents. The difference from the current contents is applied as line
+    // edits, so cursors stay with their text where possible
+    fn replace_contents(&mut self, new_data: Rope, dpi: Size2D<u32, DPI>) {
+        self.clean_cursors();
+
+        let hunks = line_diff(&self.data, &new_data);
+        for hunk in hunks.iter().rev() {
+            self.apply_line_hunk(hunk, &new_data);
+        }
+        self.line_changes = None;
+
+        // Re-sync cursors with their new positions
+        let len_chars = self.data.len_chars();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.char_idx > len_chars {
+                inner.char_idx = len_chars;
+            }
+            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
+        }
+
+        // Reformat
+        if !self.ensure_shaped(dpi) {
+            if let (Some(first), Some(last)) = (hunks.first(), hunks.last()) {
+                self.format_lines_from(first.new_start, Some(last.new_end));
+            }
         }
     }
 
-    /// Write buffer to file
+    /// Make sure shaped lines exist for given DPI and cover the whole buffer. Lines that aren't
+    /// in view may only be placeholders. Returns true if lines had to be shaped
+    pub(crate) fn ensure_shaped(&mut self, dpi: Size2D<u32, DPI>) -> bool {
+        if self.dpi_shaped_lines.iter().any(|(d, _, _)| *d == dpi) {
+            // Edits already check after shaping. Checking every frame too is for debug builds
+            return cfg!(debug_assertions) && self.check_shaped();
+        }
+        self.dpi_shaped_lines
+            .push((dpi, ShapedGutter::default(), Vec::new()));
+        for (_, _, t) in &mut self.dpi_shaped_lines {
+            t.clear();
+        }
+        self.format_lines_from(0, None);
+        true
+    }
+
+    // Shaped lines are indexed by line number while drawing and moving the cursor, so they have
+    // to stay in step with the rope. If an edit left them out of step, log it and shape all
+    // lines again instead of panicking later. Returns true if lines were re-shaped
+    fn check_shaped(&mut self) -> bool {
+        let len_lines = self.data.len_lines();
+        let mut in_sync = true;
+        for (_, _, t) in &self.dpi_shaped_lines {
+            if t.len() != len_lines {
+                warning!(
+                    "shaped lines out of sync: {:?}: {} lines, {} shaped. Re-shaping",
+                    self.path
+                        .as_ref()
+                        .map(|s| s.as_str())
+                        .unwrap_or("[no path]"),
+                    len_lines,
+                    t.len(),
+                );
+                in_sync = false;
+            }
+        }
+        if in_sync {
+            return false;
+        }
+        for (_, _, t) in &mut self.dpi_shaped_lines {
+            t.clear();
+        }
+        self.shape_lines_from(0, None);
+        true
+    }
+
+    /// Apply changes to the config. Indentation settings are re-read, and lines are re-shaped
+    pub(crate) fn reload_config(&mut self) {
+        let (tabsize, indent_tabs) = self.indent_settings();
+        self.tabsize = tabsize;
+        self.indent_tabs = indent_tabs;
+        self.detect_project_root();
+
+        // Cursor columns depend on the tab size
+        self.clean_cursors();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
+        }
+
+        for (_, l, t) in &mut self.dpi_shaped_lines {
+            l.clear();
+            t.clear();
+        }
+        self.format_lines_from(0, None);
+    }
+
+    /// Drop all shaped lines. They are re-generated by ensure_shaped
+    pub(crate) fn unload(&mut self) {
+        self.dpi_shaped_lines.clear();
+    }
+
+    /// Whether any view, in any window, still shows the buffer
+    pub(crate) fn has_views(&mut self) -> bool {
+        self.clean_cursors();
+        self.cursors.len() > 0
+    }
+
+    /// Panic unless the buffer holds text, and each cursor is at the given char index and
+    /// consistent with the text. For tests
+    #[cfg(test)]
+    pub(crate) fn assert_state(&self, text: &str, cursors: &[(&BufferCursor, usize)]) {
+        assert_eq!(self.data, text, "buffer contents differ");
+        for (i, (cursor, char_idx)) in cursors.iter().enumerate() {
+            let inner = &*cursor.inner.borrow();
+            assert_eq!(
+                inner.char_idx, *char_idx,
+                "cursor {} is at the wrong place",
+                i
+            );
+            inner.debug_check(&self.data, self.tabsize);
+        }
+    }
+
+    /// Drop shaped lines for all DPIs other than the given one
+    pub(crate) fn retain_shaped_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.dpi_shaped_lines.retain(|(d, _, _)| *d == dpi);
+    }
+
+    /// Approximate memory used by buffer contents and shaped lines, in bytes
+    pub(crate) fn approx_memory_usage(&self) -> usize {
+        self.data.len_bytes() + self.approx_cache_size()
+    }
+
+    /// Approximate memory used by shaped lines, in bytes
+    pub(crate) fn approx_cache_size(&self) -> usize {
+        let mut ret = 0;
+        for (_, l, t) in &self.dpi_shaped_lines {
+            ret += l.approx_size();
+            ret += t.iter().map(|x| x.approx_size()).sum::<usize>();
+        }
+        ret
+    }
+
+    /// Write buffer to file. Remote files are written in the background, and the result comes
+    /// from poll_write
     pub(crate) fn write_to_file(&mut self, optpath: Option<&str>) -> Option<IOResult<()>> {
-        if let Some(path) = optpath {
-            self.path = Some(path.to_owned());
-            let syntax = Syntax::from_path(path);
-            if self.syntax.name() != syntax.name() {
-                let (tabsize, indent_tabs) = {
-                    let cfg = &*self.config.borrow();
-                    let cfgsyn = cfg.syntax(syntax.name());
-                    (cfgsyn.tab_width as usize, cfgsyn.indent_tabs)
+        if self.write.is_some() {
+            let msg = "file is still being written";
+            return Some(Err(IOError::new(ErrorKind::Other, msg)));
+        }
+        match (&self.load, optpath) {
+            (FileLoad::Streaming(_), _) => {
+                let msg = "file is still loading";
+                return Some(Err(IOError::new(ErrorKind::Other, msg)));
+            }
+            (FileLoad::Done, _) | (_, Some(_)) => {}
+            _ => {
+                let msg = "file isn't loaded (:e to retry)";
+                return Some(Err(IOError::new(ErrorKind::Other, msg)));
+            }
+        }
+        // The buffer only takes the new path once it is written there, so that a failed write
+        // doesn't leave it pointing at a file that doesn't have its contents
+        let path = optpath.map(|p| p.to_owned()).or(self.path.clone())?;
+        let encoded = match self.encoding.encode(&self.data) {
+            Ok(encoded) => encoded,
+            Err(e) => return Some(Err(e)),
+        };
+        let backup = self.config.borrow().ui.backup.clone();
+        let provider = provider(&path);
+        if provider.is_remote() {
+            let (tx, rx) = self.wakeup.channel();
+            let spath = path.clone();
+            thread::spawn(move || {
+                let _ = tx.send(provider.write(&spath, &encoded, &backup));
+            });
+            self.write = Some(FileWrite {
+                rx: rx,
+                path: path,
+                data: self.data.clone(),
+            });
+            return Some(Ok(()));
+        }
+        let ret = provider.write(&path, &encoded, &backup);
+        if ret.is_ok() {
+            self.written_to(&path, self.data.clone());
+        }
+        Some(ret)
+    }
+
+    /// Result of a write running in the background, once it is done. The buffer stays modified
+    /// if it was edited while it was written
+    pub(crate) fn poll_write(&mut self) -> Option<(String, IOResult<()>)> {
+        let res = match &self.write {
+            Some(write) => match write.rx.try_recv() {
+                Ok(res) => res,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => {
+                    Err(IOError::new(ErrorKind::Other, "write was interrupted"))
+                }
+            },
+            None => return None,
+        };
+        let write = self.write.take().unwrap();
+        if res.is_ok() {
+            self.written_to(&write.path, write.data);
+        }
+        Some((write.path, res))
+    }
+
+    /// Wait for a write running in the background, for batch mode, which exits once it is done
+    pub(crate) fn wait_for_write(&mut self) -> IOResult<()> {
+        let res = match &self.write {
+            Some(write) => write
+                .rx
+                .recv()
+                .unwrap_or_else(|_| Err(IOError::new(ErrorKind::Other, "write was interrupted"))),
+            None => return Ok(()),
+        };
+        let write = self.write.take().unwrap();
+        if res.is_ok() {
+            self.written_to(&write.path, write.data);
+        }
+        res
+    }
+
+    /// Write the buffer to its file as the root user, for files the user can't write to. This
+    /// asks for a password with pkexec
+    pub(crate) fn write_to_file_as_root(&mut self) -> IOResult<()> {
+        let path = match &self.path {
+            Some(path) if !provider(path).is_remote() => path.clone(),
+            Some(_) => return Err(IOError::new(ErrorKind::Other, "file is remote")),
+            None => return Err(IOError::new(ErrorKind::NotFound, "buffer has no path")),
+        };
+        write_as_root(&path, &self.encoding.encode(&self.data)?)?;
+        self.written_to(&path, self.data.clone());
+        Ok(())
+    }
+
+    // Note that data was written to the file at path. The buffer may have been edited since
+    fn written_to(&mut self, path: &str, data: Rope) {
+        if self.path.as_ref().map(|p| p.as_str()) != Some(path) {
+            self.set_path(path);
+        }
+        self.load = FileLoad::Done;
+        self.edited_while_loading = false;
+        self.mtime = file_mtime(path);
+        self.dirty = self.data != data;
+        self.new_file = false;
+        self.set_saved(data);
+    }
+
+    /// Move the file to a new path, creating directories as needed. Existing files are not
+    /// overwritten. Buffer contents and unwritten changes are kept
+    pub(crate) fn rename_file(&mut self, path: &str) -> IOResult<()> {
+        let old_path = match &self.path {
+            Some(old_path) => old_path.clone(),
+            None => return Err(IOError::new(ErrorKind::NotFound, "buffer has no path")),
+        };
+        if provider(&old_path).is_remote() || provider(path).is_remote() {
+            let msg = "remote files can't be renamed";
+            return Err(IOError::new(ErrorKind::Other, msg));
+        }
+        if Path::new(path).exists() {
+            return Err(IOError::new(ErrorKind::AlreadyExists, "file exists"));
+        }
+        if let Some(parent) = Path::new(path).parent() {
+            create_dir_all(parent)?;
+        }
+        rename(&old_path, path)?;
+        self.set_path(path);
+        self.mtime = file_mtime(path);
+        Ok(())
+    }
+
+    // Change path, and the syntax and indentation settings that depend on it
+    fn set_path(&mut self, path: &str) {
+        self.path = Some(path.to_owned());
+        self.detect_project_root();
+        self.set_syntax_from_path(path);
+    }
+
+    /// Pick syntax and indentation settings for a file at path, without changing the buffer's
+    /// path. This is for scratch buffers holding the contents of a file
+    pub(crate) fn set_syntax_from_path(&mut self, path: &str) {
+        // Large files aren't highlighted
+        if self.large {
+            return;
+        }
+        let syntax = Syntax::from_path(path, &self.data.line(0).to_string());
+        if self.syntax.name() != syntax.name() {
+            self.syntax = syntax;
+            let (tabsize, indent_tabs) = self.indent_settings();
+            self.tabsize = tabsize;
+            self.indent_tabs = indent_tabs;
+            for (_, _, t) in &mut self.dpi_shaped_lines {
+                t.clear();
+            }
+            self.format_lines_from(0, None);
+        }
+    }
+
+    /// Indentation, like "tabs" or "4 spaces"
+    pub(crate) fn indent_style(&self) -> String {
+        if self.indent_tabs {
+            "tabs".to_owned()
+        } else {
+            format!("{} spaces", self.tabsize)
+        }
+    }
+
+    /// Indent with tabs, or with the given number of spaces. This is kept when the config is
+    /// reloaded, until detect_indent_style is called
+    pub(crate) fn set_indent_style(&mut self, indent_tabs: bool, tabsize: usize) {
+        self.indent_overridden = true;
+        self.set_tabsize(tabsize, indent_tabs);
+    }
+
+    /// Undo set_indent_style, and detect indentation again or take it from the config
+    pub(crate) fn detect_indent_style(&mut self) {
+        self.indent_overridden = false;
+        let (tabsize, indent_tabs) = self.indent_settings();
+        self.set_tabsize(tabsize, indent_tabs);
+    }
+
+    // Tab size and whether to indent with tabs. Unless overridden, these are guessed from the
+    // text if the config allows, and taken from the config otherwise
+    fn indent_settings(&self) -> (usize, bool) {
+        if self.indent_overridden {
+            return (self.tabsize, self.indent_tabs);
+        }
+        let cfg = &*self.config.borrow();
+        let cfgsyn = cfg.syntax(self.syntax.name());
+        let tabsize = cfgsyn.tab_width as usize;
+        if !cfgsyn.detect_indent {
+            return (tabsize, cfgsyn.indent_tabs);
+        }
+        match detect_indentation(&self.data) {
+            Some(DetectedIndent::Tabs) => (tabsize, true),
+            Some(DetectedIndent::Spaces(width)) => (width, false),
+            None => (tabsize, cfgsyn.indent_tabs),
+        }
+    }
+
+    // Cursor columns and shaped lines depend on the tab size, so they're updated if it changes
+    fn set_tabsize(&mut self, tabsize: usize, indent_tabs: bool) {
+        self.indent_tabs = indent_tabs;
+        if self.tabsize == tabsize {
+            return;
+        }
+        self.tabsize = tabsize;
+        self.clean_cursors();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
+        }
+        for (_, _, t) in &mut self.dpi_shaped_lines {
+            t.clear();
+        }
+        self.format_lines_from(0, None);
+    }
+
+    /// Highlight lines whose indentation mixes tabs and spaces, until the text changes. These
+    /// are lines with spaces before tabs, or more spaces after tabs than a tab stop, and lines
+    /// indented differently from most other lines. Returns the number of such lines
+    pub(crate) fn check_indentation(&mut self) -> usize {
+        // Indented lines with their kind of indentation, and its length in chars
+        let mut lines = Vec::new();
+        let (mut ntabs, mut nspaces) = (0, 0);
+        for (linum, line) in self.data.lines().enumerate() {
+            let (mut len, mut tabs, mut spaces, mut mixed) = (0, 0, 0, false);
+            for c in line.chars() {
+                match c {
+                    '\t' => {
+                        mixed |= spaces > 0;
+                        tabs += 1;
+                    }
+                    ' ' => spaces += 1,
+                    _ => break,
+                }
+                len += 1;
+            }
+            mixed |= tabs > 0 && spaces >= self.tabsize;
+            let kind = if mixed {
+                Indentation::Mixed
+            } else if tabs > 0 {
+                ntabs += 1;
+                Indentation::Tabs
+            } else if spaces > 0 {
+                nspaces += 1;
+                Indentation::Spaces
+            } else {
+                continue;
+            };
+            lines.push((linum, kind, len));
+        }
+
+        let minority = if ntabs == 0 || nspaces == 0 {
+            Indentation::Mixed
+        } else if ntabs < nspaces || (ntabs == nspaces && !self.indent_tabs) {
+            Indentation::Tabs
+        } else {
+            Indentation::Spaces
+        };
+        let color = self.config.borrow().ui.theme().textview.mixed_indent_color;
+        let decorations = lines
+            .into_iter()
+            .filter(|(_, kind, _)| *kind == Indentation::Mixed || *kind == minority)
+            .map(|(linum, _, len)| {
+                let decoration = Decoration {
+                    start_cidx: 0,
+                    end_cidx: len,
+                    style: DecorationStyle::Background(color),
+                    priority: INDENTATION_PRIORITY,
                 };
-                self.tabsize = tabsize;
-                self.indent_tabs = indent_tabs;
-                self.syntax = syntax;
-                for (_, _, t) in &mut self.dpi_shaped_lines {
-                    t.clear();
+                (linum, decoration)
+            })
+            .collect::<Vec<_>>();
+        let ret = decorations.len();
+        if ret == 0 {
+            self.decorations.clear(DecorationLayer::Indentation);
+        } else {
+            self.decorations
+                .set(DecorationLayer::Indentation, decorations);
+        }
+        ret
+    }
+
+    /// Re-indent all lines with tabs or spaces, depending on the indentation settings
+    pub(crate) fn retab(&mut self) {
+        let (tabsize, indent_tabs) = (self.tabsize, self.indent_tabs);
+        let len_lines = self.data.len_lines();
+        self.transform_lines(0, len_lines - 1, |s| {
+            s.split('\n')
+                .map(|line| retab_line(line, tabsize, indent_tabs))
+                .collect::<Vec<_>>()
+                .join("\n")
+        });
+        self.decorations.clear(DecorationLayer::Indentation);
+    }
+
+    /// Sort lines start..=end, in reverse if set
+    pub(crate) fn sort_lines(&mut self, start: usize, end: usize, reverse: bool) {
+        self.transform_lines(start, end, |s| {
+            // The line break after the last line stays at the end
+            let (s, newline) = match s.strip_suffix('\n') {
+                Some(s) => (s, "\n"),
+                None => (s, ""),
+            };
+            let mut lines = s.split('\n').collect::<Vec<_>>();
+            lines.sort();
+            if reverse {
+                lines.reverse();
+            }
+            format!("{}{}", lines.join("\n"), newline)
+        });
+    }
+
+    /// Check if buffer was edited since the file was last read or written
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Check if the file was modified by something else since it was last read, written or
+    /// checked
+    pub(crate) fn check_disk_change(&mut self) -> bool {
+        if !self.changed_on_disk() {
+            return false;
+        }
+        self.mtime = self.path.as_ref().and_then(|p| file_mtime(p));
+        true
+    }
+
+    /// Check if the file was modified by something else since it was last read, written or
+    /// checked, without marking the change as seen
+    pub(crate) fn changed_on_disk(&self) -> bool {
+        match &self.path {
+            Some(path) => file_mtime(path) != self.mtime,
+            None => false,
+        }
+    }
+
+    /// Check if the file was changed by something else since it was last read or written, like
+    /// another editor, so that writing the buffer would lose those changes. The file is only
+    /// read if its modification time changed. Edits made before the file finished loading
+    /// always conflict, since they weren't made to its contents
+    pub(crate) fn write_conflict(&self) -> bool {
+        if self.edited_while_loading {
+            return true;
+        }
+        let (path, saved) = match (&self.path, &self.saved) {
+            (Some(path), Some(saved)) => (path, saved),
+            _ => return false,
+        };
+        if file_mtime(path) == self.saved_mtime {
+            return false;
+        }
+        match provider(path).read(path) {
+            Ok(data) => encoding::decode(&data).0 != *saved,
+            // Writing a deleted file creates it again, and other errors are reported by the write
+            Err(_) => false,
+        }
+    }
+
+    /// Check if buffer contents, in the buffer's encoding, differ from the file on disk
+    pub(crate) fn differs_from_file(&self) -> bool {
+        match &self.path {
+            Some(path) => match (provider(path).read(path), self.encoding.encode(&self.data)) {
+                (Ok(data), Ok(encoded)) => data != encoded,
+                _ => true,
+            },
+            None => false,
+        }
+    }
+
+    /// Encoding the file was read in, and is written in
+    pub(crate) fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Convert to encoding when the file is next written. The buffer counts as edited until
+    /// then, since the file doesn't match it any more
+    pub(crate) fn set_encoding(&mut self, encoding: Encoding) {
+        if self.encoding != encoding {
+            self.encoding = encoding;
+            self.dirty = true;
+        }
+    }
+
+    pub(crate) fn annotations(&self) -> &Annotations {
+        &self.annotations
+    }
+
+    /// Decorations registered with the buffer. Decorations from the buffer itself (search
+    /// matches, color swatches) are added by `line_decorations`
+    pub(crate) fn decorations_mut(&mut self) -> &mut Decorations {
+        &mut self.decorations
+    }
+
+    /// Decorations in given line as grapheme index ranges, in increasing order of priority
+    pub(crate) fn line_decorations(&self, linum: usize) -> Vec<(usize, usize, DecorationStyle)> {
+        let mut decorations = self.decorations.line(linum).cloned().collect::<Vec<_>>();
+        if self.config.borrow().ui.textview.color_swatches && self.has_color_swatches() {
+            decorations.append(&mut self.color_swatches(linum));
+        }
+        decorations.sort_by_key(|d| d.priority);
+        let line = self.data.line(linum);
+        decorations
+            .into_iter()
+            .map(|d| {
+                (
+                    gidx_from_cidx(&line, d.start_cidx, self.tabsize),
+                    gidx_from_cidx(&line, d.end_cidx, self.tabsize),
+                    d.style,
+                )
+            })
+            .collect()
+    }
+
+    /// Highlight and annotate matches of pattern, or clear them if there is no pattern
+    pub(crate) fn highlight_search(&mut self, pattern: Option<&str>) {
+        if self.search_pattern.as_deref() == pattern {
+            return;
+        }
+        let mut lines = Vec::new();
+        let mut decorations = Vec::new();
+        let options = self.config.borrow().ui.textview.search;
+        let searcher = pattern.and_then(|p| Searcher::new(p, options).ok());
+        if let Some(searcher) = &searcher {
+            let color = self.config.borrow().ui.theme().textview.search_color;
+            for linum in 0..self.len_lines() {
+                let matches = self.line_match_cidxs(linum, searcher);
+                if matches.len() > 0 {
+                    lines.push(linum);
+                }
+                for (start, end) in matches {
+                    let decoration = Decoration {
+                        start_cidx: start,
+                        end_cidx: end,
+                        style: DecorationStyle::Background(color),
+                        priority: SEARCH_PRIORITY,
+                    };
+                    decorations.push((linum, decoration));
                 }
-                self.format_lines_from(0, None);
             }
         }
-        self.path
-            .as_ref()
-            .map(|path| File::create(path).and_then(|f| self.data.write_to(f)))
+        self.annotations.set(AnnotationKind::Search, lines);
+        self.decorations.set(DecorationLayer::Search, decorations);
+        self.search_pattern = pattern.map(|s| s.to_owned());
+    }
+
+    /// Find the next match of pattern after the cursor (before it, if not forward), wrapping
+    /// around the ends of the buffer. A match at the cursor is accepted if inclusive is set.
+    /// Returns line number and grapheme index of the start of the match
+    pub(crate) fn find(
+        &self,
+        cursor: &BufferCursor,
+        searcher: &Searcher,
+        forward: bool,
+        inclusive: bool,
+    ) -> Option<(usize, usize)> {
+        if searcher.pattern().len() == 0 {
+            return None;
+        }
+        let (cur_line, cur_cidx) = {
+            let cursor = &*cursor.inner.borrow();
+            (cursor.line_num, cursor.line_cidx)
+        };
+        let len_lines = self.data.len_lines();
+        let to_gidx = |linum: usize, cidx: usize| {
+            (
+                linum,
+                gidx_from_cidx(&self.data.line(linum), cidx, self.tabsize),
+            )
+        };
+        // Rest of the current line, other lines, and then the part of the current line behind
+        // the cursor
+        let matches = self.line_match_cidxs(cur_line, searcher);
+        let found = if forward {
+            matches
+                .iter()
+                .find(|(s, _)| *s > cur_cidx || (inclusive && *s == cur_cidx))
+        } else {
+            matches
+                .iter()
+                .rev()
+                .find(|(s, _)| *s < cur_cidx || (inclusive && *s == cur_cidx))
+        };
+        if let Some((start, _)) = found {
+            return Some(to_gidx(cur_line, *start));
+        }
+        for i in 1..len_lines {
+            let linum = if forward {
+                (cur_line + i) % len_lines
+            } else {
+                (cur_line + len_lines - i) % len_lines
+            };
+            let matches = self.line_match_cidxs(linum, searcher);
+            let found = if forward {
+                matches.first()
+            } else {
+                matches.last()
+            };
+            if let Some((start, _)) = found {
+                return Some(to_gidx(linum, *start));
+            }
+        }
+        let found = if forward {
+            matches.first()
+        } else {
+            matches.last()
+        };
+        found.map(|(start, _)| to_gidx(cur_line, *start))
     }
 
     /// Number of lines in buffer
@@ -269,12 +1370,12 @@ impl Buffer {
     pub(crate) fn shaped_data(
         &self,
         dpi: Size2D<u32, DPI>,
-    ) -> Option<(&[ShapedTextLine], &[ShapedTextLine])> {
+    ) -> Option<(&ShapedGutter, &[ShapedTextLine])> {
         self.dpi_shaped_lines
             .iter()
             .filter_map(|(x, l, t)| {
                 if *x == dpi {
-                    Some((l.as_ref(), t.as_ref()))
+                    Some((l, t.as_ref()))
                 } else {
                     None
                 }
@@ -319,205 +1420,133 @@ impl Buffer {
             line_gidx: pos.line_gidx,
             line_global_x: pos.line_gidx,
             past_end: past_end,
-            view_id: view_id,
-        };
-        if !inner.past_end {
-            let trimmed = trim_newlines(self.data.line(inner.line_num));
-            if inner.line_cidx == trimmed.len_chars() && inner.line_cidx > 0 {
-                let (cidx, gidx) = cidx_gidx_from_cidx(&trimmed, inner.line_cidx - 1, self.tabsize);
-                inner.line_cidx = cidx;
-                inner.line_gidx = gidx;
-                inner.line_global_x = inner.line_gidx;
-                inner.char_idx = self.data.line_to_char(inner.line_num) + inner.line_cidx;
-            }
-        }
-        let strong = Rc::new(RefCell::new(inner));
-        self.cursors.insert(view_id, Rc::downgrade(&strong));
-        BufferCursor { inner: strong }
-    }
-
-    /// Delete to the left of cursor
-    pub(crate) fn delete_left(&mut self, cursor: &mut BufferCursor, n: usize) {
-        // Delete contents and re-format
-        let (start_cidx, end_cidx, view_id) = {
-            let cursor = &mut *cursor.inner.borrow_mut();
-            if cursor.char_idx == 0 {
-                return;
-            }
-            let cidx = if cursor.char_idx <= n {
-                0
-            } else {
-                cursor.char_idx - n
-            };
-            // Calculate formatting replace range
-            let start_line = self.data.char_to_line(cidx);
-            let end_line = cursor.line_num;
-            // Delete
-            self.data.remove(cidx..cursor.char_idx);
-            // Reformat
-            for (_, _, t) in &mut self.dpi_shaped_lines {
-                if end_line > start_line {
-                    t.drain(start_line..end_line);
-                }
-            }
-            if end_line > start_line {
-                self.syntax.remove_lines(start_line..end_line);
-            }
-            self.format_lines_from(start_line, None);
-            // Metrics to place cursors
-            (cidx, cursor.char_idx, cursor.view_id)
-        };
-
-        // Update cursors after current cursor position (inclusive of current cursor)
-        self.clean_cursors_except(view_id);
-
-        for (_, weak) in self.cursors.iter_mut() {
-            let strong = weak.upgrade().unwrap();
-            let inner = &mut *strong.borrow_mut();
-            if inner.char_idx < start_cidx {
-                continue;
-            }
-            if inner.char_idx <= end_cidx {
-                inner.char_idx = start_cidx;
-            } else {
-                inner.char_idx -= end_cidx - start_cidx;
-            }
-            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
-        }
-    }
-
-    /// Delete to the right of cursor
-    pub(crate) fn delete_right(&mut self, cursor: &mut BufferCursor, n: usize) {
-        // Delete contents and reformat
-        let (start_cidx, end_cidx, view_id) = {
-            let cursor = &mut *cursor.inner.borrow_mut();
-            let len_chars = self.data.len_chars();
-            let final_cidx = if cursor.char_idx + n >= len_chars {
-                len_chars
-            } else {
-                cursor.char_idx + n
-            };
-            if final_cidx == cursor.char_idx {
-                return;
-            }
-            // Calculate formatting replace range
-            let start_line = self.data.char_to_line(cursor.char_idx);
-            let end_line = self.data.char_to_line(final_cidx);
-            // Delete
-            self.data.remove(cursor.char_idx..final_cidx);
-            // Reformat
-            for (_, _, t) in &mut self.dpi_shaped_lines {
-                if end_line > start_line {
-                    t.drain(start_line..end_line);
-                }
-            }
-            if end_line > start_line {
-                self.syntax.remove_lines(start_line..end_line);
-            }
-            self.format_lines_from(start_line, None);
-            // Metrics to place cursors
-            (cursor.char_idx, final_cidx, cursor.view_id)
-        };
-
-        // Update cursors after current cursor position (inclusive of current cursor)
-        self.clean_cursors_except(view_id);
-
-        for (_, weak) in self.cursors.iter_mut() {
-            let strong = weak.upgrade().unwrap();
-            let inner = &mut *strong.borrow_mut();
-            if inner.char_idx < start_cidx {
-                continue;
-            }
-            if inner.char_idx <= end_cidx {
-                inner.char_idx = start_cidx;
-            } else {
-                inner.char_idx -= end_cidx - start_cidx;
+            view_id: view_id,
+        };
+        if !inner.past_end {
+            let trimmed = trim_newlines(self.data.line(inner.line_num));
+            if inner.line_cidx == trimmed.len_chars() && inner.line_cidx > 0 {
+                let (cidx, gidx) = cidx_gidx_from_cidx(&trimmed, inner.line_cidx - 1, self.tabsize);
+                inner.line_cidx = cidx;
+                inner.line_gidx = gidx;
+                inner.line_global_x = inner.line_gidx;
+                inner.char_idx = self.data.line_to_char(inner.line_num) + inner.line_cidx;
             }
-            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
         }
+        let strong = Rc::new(RefCell::new(inner));
+        self.cursors.insert(view_id, Rc::downgrade(&strong));
+        BufferCursor { inner: strong }
+    }
+
+    /// Delete to the left of cursor
+    pub(crate) fn delete_left(&mut self, cursor: &mut BufferCursor, n: usize) {
+        self.delete_around(cursor, n, 0);
+    }
+
+    /// Delete to the right of cursor
+    pub(crate) fn delete_right(&mut self, cursor: &mut BufferCursor, n: usize) {
+        self.delete_around(cursor, 0, n);
+    }
+
+    /// Delete characters on both sides of cursor, like an empty pair of brackets around it, as a
+    /// single edit
+    pub(crate) fn delete_around(&mut self, cursor: &mut BufferCursor, left: usize, right: usize) {
+        let (start_cidx, end_cidx, view_id) = {
+            let cursor = &*cursor.inner.borrow();
+            let start_cidx = cursor.char_idx.saturating_sub(left);
+            let end_cidx = (cursor.char_idx + right).min(self.data.len_chars());
+            (start_cidx, end_cidx, cursor.view_id)
+        };
+        self.remove_chars(start_cidx, end_cidx, view_id);
     }
 
     /// Delete to start of line
     pub(crate) fn delete_to_line_start(&mut self, cursor: &mut BufferCursor) {
-        // Delete contents
-        let cursor = &mut *cursor.inner.borrow_mut();
-        let cidx = self.data.line_to_char(cursor.line_num);
-        let diff = cursor.char_idx - cidx;
-        if diff == 0 {
+        let (start_cidx, end_cidx, view_id) = {
+            let cursor = &*cursor.inner.borrow();
+            let start_cidx = self.data.line_to_char(cursor.line_num);
+            (start_cidx, cursor.char_idx, cursor.view_id)
+        };
+        self.remove_chars(start_cidx, end_cidx, view_id);
+    }
+
+    /// Delete word to the left of cursor
+    pub(crate) fn delete_word_left(&mut self, cursor: &mut BufferCursor) {
+        if self.scratch {
             return;
         }
-        self.data.remove(cidx..cursor.char_idx);
-        cursor.char_idx = cidx;
-        cursor.line_cidx = 0;
-        cursor.line_gidx = 0;
-        cursor.line_global_x = 0;
-
-        // Update cursors after current cursor position
-        self.clean_cursors_except(cursor.view_id);
-
-        for (&k, weak) in self.cursors.iter_mut() {
-            if k == cursor.view_id {
-                continue;
-            }
-            let strong = weak.upgrade().unwrap();
-            let inner = &mut *strong.borrow_mut();
-            if inner.line_num <= cursor.line_num {
-                continue;
-            }
-            if inner.line_num == cursor.line_num {
-                if inner.line_cidx <= diff {
-                    inner.char_idx = cidx;
-                    inner.line_cidx = 0;
-                    inner.line_gidx = 0;
-                    inner.line_global_x = 0;
-                } else {
-                    inner.char_idx -= diff;
-                    inner.line_cidx -= diff;
-                    inner.sync_line_cidx_gidx_left(&self.data, self.tabsize);
-                }
+        let n = {
+            let cursor = &*cursor.inner.borrow();
+            if cursor.line_cidx == 0 {
+                1
             } else {
-                inner.char_idx -= diff;
+                let mut chars = self.data.line(cursor.line_num).chars_at(cursor.line_cidx);
+                let (mut n, mut class) = (0, None);
+                while let Some(c) = chars.prev() {
+                    let cur_class = CharClass::of(c);
+                    match class {
+                        None if cur_class != CharClass::Space => class = Some(cur_class),
+                        Some(class) if class != cur_class => break,
+                        _ => {}
+                    }
+                    n += 1;
+                }
+                n
             }
-        }
-
-        // Re-format lines
-        self.format_lines_from(cursor.line_num, None);
+        };
+        self.delete_left(cursor, n);
     }
 
     /// Delete to the end of line
     pub(crate) fn delete_to_line_end(&mut self, cursor: &mut BufferCursor) {
-        // Delete contents
-        let (linum, diff, view_id, char_idx) = {
-            let cursor = &mut *cursor.inner.borrow_mut();
+        let (start_cidx, end_cidx, view_id) = {
+            let cursor = &*cursor.inner.borrow();
             let len_chars = trim_newlines(self.data.line(cursor.line_num)).len_chars();
-            let diff = len_chars - cursor.line_cidx;
-            if diff == 0 {
-                return;
-            }
-            self.data.remove(cursor.char_idx..(cursor.char_idx + diff));
-            (cursor.line_num, diff, cursor.view_id, cursor.char_idx)
+            let end_cidx = cursor.char_idx - cursor.line_cidx + len_chars;
+            (cursor.char_idx, end_cidx, cursor.view_id)
         };
+        self.remove_chars(start_cidx, end_cidx, view_id);
+    }
 
-        // Update cursors after current cursor position
+    // Delete characters from start_cidx to end_cidx. Cursors in the range move to its start.
+    // Lines are reformatted once, after the cursors are updated
+    fn remove_chars(&mut self, start_cidx: usize, end_cidx: usize, view_id: usize) {
+        if self.scratch || start_cidx >= end_cidx {
+            return;
+        }
+        let start_line = self.data.char_to_line(start_cidx);
+        let end_line = self.data.char_to_line(end_cidx);
+        self.data.remove(start_cidx..end_cidx);
+        self.mark_dirty();
+        if end_line > start_line {
+            for (_, _, t) in &mut self.dpi_shaped_lines {
+                t.drain(start_line..end_line);
+            }
+            self.syntax.remove_lines(start_line..end_line);
+        }
+
+        // Update cursors after the start of the range, including the one deleting
         self.clean_cursors_except(view_id);
         for (_, weak) in self.cursors.iter_mut() {
             let strong = weak.upgrade().unwrap();
             let inner = &mut *strong.borrow_mut();
-            if inner.char_idx < char_idx {
+            if inner.char_idx < start_cidx {
                 continue;
             }
-            if inner.line_num == linum {
-                inner.sync_line_cidx_gidx_left(&self.data, self.tabsize);
+            if inner.char_idx <= end_cidx {
+                inner.char_idx = start_cidx;
             } else {
-                inner.char_idx -= diff;
+                inner.char_idx -= end_cidx - start_cidx;
             }
+            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
         }
 
-        // Re-format lines
-        self.format_lines_from(linum, None);
+        self.format_lines_from(start_line, None);
     }
 
     pub(crate) fn delete_lines(&mut self, cursor: &mut BufferCursor, nlines: usize) {
+        if self.scratch {
+            return;
+        }
         let (start, end, linum, nlines, view_id) = {
             let cursor = &mut *cursor.inner.borrow_mut();
             let start = cursor.char_idx - cursor.line_cidx;
@@ -533,6 +1562,7 @@ impl Buffer {
                 (nlines, self.data.line_to_char(cursor.line_num + nlines))
             };
             self.data.remove(start..end);
+            self.mark_dirty();
             (start, end, cursor.line_num, nlines, cursor.view_id)
         };
 
@@ -544,16 +1574,14 @@ impl Buffer {
             if inner.char_idx <= start {
                 continue;
             }
+            // Lines are counted again from char_idx, since deleting the last lines removes the
+            // text after the last newline too
             if inner.char_idx >= end {
                 inner.char_idx -= end - start;
-                inner.line_num -= nlines;
-                continue;
+            } else {
+                inner.char_idx = start;
             }
-            inner.char_idx = start;
-            inner.line_num = linum;
-            inner.line_cidx = 0;
-            inner.line_gidx = 0;
-            inner.line_global_x = 0;
+            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
         }
 
         // Reformat
@@ -597,8 +1625,116 @@ impl Buffer {
         self.delete_lines(cursor, self.data.len_lines());
     }
 
+    /// Indent line containing cursor by one level
+    pub(crate) fn indent_line(&mut self, cursor: &mut BufferCursor) {
+        if self.scratch {
+            return;
+        }
+        let linum = cursor.line_num();
+        let start = self.data.line_to_char(linum);
+        let n = if self.indent_tabs {
+            self.data.insert_char(start, '\t');
+            1
+        } else {
+            self.data.insert(start, &" ".repeat(self.tabsize));
+            self.tabsize
+        };
+        self.mark_dirty();
+
+        // Update cursors on or after this line
+        self.clean_cursors();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.char_idx < start {
+                continue;
+            }
+            inner.char_idx += n;
+            if inner.line_num == linum {
+                inner.line_cidx += n;
+                inner.sync_line_cidx_gidx_left(&self.data, self.tabsize);
+            }
+        }
+
+        // Reformat
+        self.format_lines_from(linum, None);
+    }
+
+    /// Remove one level of indentation from line containing cursor
+    pub(crate) fn dedent_line(&mut self, cursor: &mut BufferCursor) {
+        if self.scratch {
+            return;
+        }
+        let linum = cursor.line_num();
+        let start = self.data.line_to_char(linum);
+        let mut n = 0;
+        for c in self.data.line(linum).chars().take(self.tabsize) {
+            match c {
+                ' ' => n += 1,
+                '\t' => {
+                    n += 1;
+                    break;
+                }
+                _ => break,
+            }
+        }
+        if n == 0 {
+            return;
+        }
+        self.data.remove(start..(start + n));
+        self.mark_dirty();
+
+        // Update cursors on or after this line
+        self.clean_cursors();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.char_idx < start {
+                continue;
+            }
+            if inner.line_num == linum {
+                inner.line_cidx = if inner.line_cidx < n {
+                    0
+                } else {
+                    inner.line_cidx - n
+                };
+                inner.sync_line_cidx_gidx_left(&self.data, self.tabsize);
+            } else {
+                inner.char_idx -= n;
+            }
+        }
+
+        // Reformat
+        self.format_lines_from(linum, None);
+    }
+
+    /// Shift lines start..=end right by one level of indentation, or left by removing up to one
+    /// level. Empty lines aren't indented. The cursor is moved to the first non-blank character
+    /// in the first line
+    pub(crate) fn shift_lines(
+        &mut self,
+        cursor: &mut BufferCursor,
+        start: usize,
+        end: usize,
+        right: bool,
+    ) {
+        if self.scratch {
+            return;
+        }
+        let start = start.min(self.data.len_lines() - 1);
+        let (tabsize, indent_tabs) = (self.tabsize, self.indent_tabs);
+        self.transform_lines(start, end, |s| shift_lines(s, tabsize, indent_tabs, right));
+        let line = self.data.line(start);
+        let cidx = line.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+        let gidx = gidx_from_cidx(&line, cidx, self.tabsize);
+        self.move_cursor_to_linum_gidx(cursor, start, gidx);
+    }
+
     /// Insert character at given cursor position
     pub(crate) fn insert_char(&mut self, cursor: &mut BufferCursor, c: char) {
+        if self.scratch {
+            return;
+        }
         let (old_char_idx, nchars, view_id) = {
             let cursor = &mut *cursor.inner.borrow_mut();
             let nchars = if c == '\t' && !self.indent_tabs {
@@ -612,6 +1748,7 @@ impl Buffer {
                 self.data.insert_char(cursor.char_idx, c);
                 1
             };
+            self.mark_dirty();
             (cursor.char_idx, nchars, cursor.view_id)
         };
 
@@ -632,59 +1769,370 @@ impl Buffer {
             inner.sync_from_and_udpate_char_idx_right(&self.data, self.tabsize);
         }
 
-        // Reformat
-        let linum = self.data.char_to_line(old_char_idx);
-        let mut end = None;
-        if c == '\n' {
-            for (_, _, t) in &mut self.dpi_shaped_lines {
-                t.insert(linum + 1, ShapedTextLine::default());
-            }
-            end = Some(linum + 1);
+        // Reformat
+        let linum = self.data.char_to_line(old_char_idx);
+        let mut end = None;
+        if c == '\n' {
+            for (_, _, t) in &mut self.dpi_shaped_lines {
+                t.insert(linum + 1, ShapedTextLine::default());
+            }
+            end = Some(linum + 1);
+        }
+        self.syntax.insert_lines(linum + 1, 1);
+        self.format_lines_from(linum, end);
+    }
+
+    /// Text of the cursor's line before the cursor, and the character at the cursor, if any
+    pub(crate) fn text_around_cursor(&self, cursor: &BufferCursor) -> (String, Option<char>) {
+        let cursor = &*cursor.inner.borrow();
+        let line = trim_newlines(self.data.line(cursor.line_num));
+        let cidx = cursor.line_cidx.min(line.len_chars());
+        (line.slice(..cidx).to_string(), line.chars_at(cidx).next())
+    }
+
+    /// Position of the bracket matching the one under the cursor, as line number and grapheme
+    /// index. Brackets in strings and comments only match other brackets in strings and comments
+    pub(crate) fn matching_bracket(&self, cursor: &BufferCursor) -> Option<(usize, usize)> {
+        let (linum, cidx) = {
+            let cursor = &*cursor.inner.borrow();
+            (cursor.line_num, cursor.line_cidx)
+        };
+        let line = trim_newlines(self.data.line(linum));
+        let (open, close, forward) = match line.chars_at(cidx).next()? {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => (')', '(', false),
+            ']' => (']', '[', false),
+            '}' => ('}', '{', false),
+            _ => return None,
+        };
+        let syntax = &self.syntax;
+        let want_code = syntax.code_chars(linum, line, self.tabsize)[cidx];
+        let lines = if forward {
+            let end = (linum + MAX_BRACKET_LINES).min(self.data.len_lines());
+            (linum..end).collect::<Vec<_>>()
+        } else {
+            (linum.saturating_sub(MAX_BRACKET_LINES)..=linum)
+                .rev()
+                .collect()
+        };
+        let mut depth = 0;
+        for l in lines {
+            let line = trim_newlines(self.data.line(l));
+            // Most lines have no brackets, and don't have to be lexed
+            if !line.chars().any(|c| c == open || c == close) {
+                continue;
+            }
+            let code = syntax.code_chars(l, line, self.tabsize);
+            let mut chars = line.chars().enumerate().collect::<Vec<_>>();
+            if l == linum {
+                if forward {
+                    chars.drain(..cidx);
+                } else {
+                    chars.truncate(cidx + 1);
+                }
+            }
+            if !forward {
+                chars.reverse();
+            }
+            for (i, c) in chars {
+                if code[i] != want_code {
+                    continue;
+                }
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((l, gidx_from_cidx(&line, i, self.tabsize)));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Insert string at given cursor position
+    pub(crate) fn insert_str(&mut self, cursor: &mut BufferCursor, s: &str) {
+        if self.scratch {
+            return;
+        }
+        let ccount = s.chars().count();
+        let (old_char_idx, view_id) = {
+            let cursor = &*cursor.inner.borrow();
+            (cursor.char_idx, cursor.view_id)
+        };
+
+        // Insert string
+        self.data.insert(old_char_idx, s);
+        self.mark_dirty();
+
+        // Update cursors after current cursor position
+        self.clean_cursors_except(view_id);
+
+        for (&k, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.char_idx < old_char_idx {
+                continue;
+            }
+            if inner.char_idx == old_char_idx && k != view_id {
+                inner.sync_line_cidx_gidx_right(&self.data, self.tabsize);
+                continue;
+            }
+            inner.char_idx += ccount;
+            inner.sync_from_and_udpate_char_idx_right(&self.data, self.tabsize);
+        }
+
+        // Reformat
+        let linum = self.data.char_to_line(old_char_idx);
+        let end_line = self.data.char_to_line(old_char_idx + ccount);
+        for (_, _, t) in &mut self.dpi_shaped_lines {
+            for _ in linum..end_line {
+                t.insert(linum + 1, ShapedTextLine::default());
+            }
+        }
+        if end_line > linum {
+            self.syntax.insert_lines(linum + 1, end_line - linum);
+        }
+        self.format_lines_from(linum, Some(end_line));
+    }
+
+    /// Replace text in lines start..=end with the result of the given function. The function
+    /// must preserve the number of lines
+    pub(crate) fn transform_lines<F>(&mut self, mut start: usize, mut end: usize, f: F)
+    where
+        F: Fn(&str) -> String,
+    {
+        if self.scratch {
+            return;
+        }
+        let len_lines = self.data.len_lines();
+        if end >= len_lines {
+            end = len_lines - 1;
+        }
+        if start > end {
+            start = end;
+        }
+        let start_cidx = self.data.line_to_char(start);
+        let end_cidx = self.data.line_to_char(end + 1);
+        let old = self.data.slice(start_cidx..end_cidx).to_string();
+        let new = f(&old);
+        if new == old {
+            return;
+        }
+        let (old_len, new_len) = (end_cidx - start_cidx, new.chars().count());
+
+        // Replace contents
+        self.data.remove(start_cidx..end_cidx);
+        self.data.insert(start_cidx, &new);
+        self.mark_dirty();
+
+        // Update cursors within and after the range
+        self.clean_cursors();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.char_idx < start_cidx {
+                continue;
+            }
+            if inner.char_idx >= end_cidx {
+                inner.char_idx = inner.char_idx - old_len + new_len;
+            } else {
+                inner.char_idx = self.data.line_to_char(inner.line_num) + inner.line_cidx;
+                inner.sync_line_cidx_gidx_left(&self.data, self.tabsize);
+            }
+        }
+
+        // Reformat
+        self.format_lines_from(start, Some(end + 1));
+    }
+
+    /// Text of a line, without the newline
+    pub(crate) fn line_text(&self, linum: usize) -> String {
+        trim_newlines(self.data.line(linum)).to_string()
+    }
+
+    /// Replace the text of lines, given as line numbers in increasing order and text without the
+    /// newline. Only the replaced lines are edited, so this is cheaper than transform_lines for a
+    /// few lines spread over a large range. Cursors stay at the same column of their line
+    pub(crate) fn replace_lines(&mut self, lines: &[(usize, String)]) {
+        if self.scratch || lines.is_empty() {
+            return;
+        }
+        let len_lines = self.data.len_lines();
+        // Edit from the end, so that earlier lines keep their positions
+        for (linum, text) in lines.iter().rev() {
+            if *linum >= len_lines {
+                continue;
+            }
+            let start_cidx = self.data.line_to_char(*linum);
+            let end_cidx = start_cidx + trim_newlines(self.data.line(*linum)).len_chars();
+            self.data.remove(start_cidx..end_cidx);
+            self.data.insert(start_cidx, text);
+        }
+        self.mark_dirty();
+
+        // The number of lines doesn't change, so cursors keep their line
+        let first = lines[0].0;
+        self.clean_cursors();
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.line_num < first {
+                continue;
+            }
+            let line_len = trim_newlines(self.data.line(inner.line_num)).len_chars();
+            inner.char_idx = self.data.line_to_char(inner.line_num) + inner.line_cidx.min(line_len);
+            inner.sync_line_cidx_gidx_left(&self.data, self.tabsize);
+        }
+
+        let last = lines[lines.len() - 1].0;
+        self.format_lines_from(first, Some(last + 1));
+    }
+
+    /// Text of nlines lines starting at given line. The text always ends with a newline
+    pub(crate) fn lines_text(&self, start: usize, nlines: usize) -> String {
+        let len_lines = self.data.len_lines();
+        let start = start.min(len_lines - 1);
+        let end = (start + nlines).min(len_lines);
+        let start_cidx = self.data.line_to_char(start);
+        let end_cidx = self.data.line_to_char(end);
+        let mut ret = self.data.slice(start_cidx..end_cidx).to_string();
+        if !ret.ends_with('\n') {
+            ret.push('\n');
+        }
+        ret
+    }
+
+    /// Text between two positions, given as line numbers and columns. The end is exclusive
+    pub(crate) fn range_text(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let (start_cidx, end_cidx) = (self.cidx_at(start), self.cidx_at(end));
+        if end_cidx <= start_cidx {
+            return String::new();
         }
-        self.syntax.insert_lines(linum + 1, 1);
-        self.format_lines_from(linum, end);
+        self.data.slice(start_cidx..end_cidx).to_string()
     }
 
-    /// Insert string at given cursor position
-    pub(crate) fn insert_str(&mut self, cursor: &mut BufferCursor, s: &str) {
-        let ccount = s.chars().count();
-        let (old_char_idx, view_id) = {
-            let cursor = &*cursor.inner.borrow();
-            (cursor.char_idx, cursor.view_id)
-        };
+    /// Delete text between two positions, given as line numbers and columns, and move the
+    /// cursor to the start. The end is exclusive
+    pub(crate) fn delete_range(
+        &mut self,
+        cursor: &mut BufferCursor,
+        start: (usize, usize),
+        end: (usize, usize),
+    ) {
+        let (start_cidx, end_cidx) = (self.cidx_at(start), self.cidx_at(end));
+        if end_cidx <= start_cidx {
+            return;
+        }
+        (&mut *cursor.inner.borrow_mut()).char_idx = start_cidx;
+        self.delete_right(cursor, end_cidx - start_cidx);
+    }
 
-        // Insert string
-        self.data.insert(old_char_idx, s);
+    /// Column just past the grapheme at column gidx in line linum
+    pub(crate) fn next_gidx(&self, linum: usize, gidx: usize) -> usize {
+        let line = trim_newlines(self.data.line(linum));
+        let (cidx, start) = cidx_gidx_from_gidx(&line, gidx, self.tabsize, true);
+        if cidx >= line.len_chars() {
+            return start + 1;
+        }
+        gidx_from_cidx(&line, next_grapheme_boundary(&line, cidx), self.tabsize)
+    }
 
-        // Update cursors after current cursor position
-        self.clean_cursors_except(view_id);
+    /// Columns of the first and last graphemes of the word, run of punctuation, or run of
+    /// whitespace at column gidx in line linum. Returns None for empty lines
+    pub(crate) fn word_at(&self, linum: usize, gidx: usize) -> Option<(usize, usize)> {
+        // Graphemes with their start columns, and whether they are word characters,
+        // whitespace or neither
+        let line = trim_newlines(self.data.line(linum));
+        let mut graphemes = Vec::new();
+        let mut col = 0;
+        for g in RopeGraphemes::new(&line) {
+            let class = match g.chars().next() {
+                Some(c) if c.is_alphanumeric() || c == '_' => 0,
+                Some(c) if c.is_whitespace() => 1,
+                _ => 2,
+            };
+            let next = if g == "\t" {
+                (col / self.tabsize) * self.tabsize + self.tabsize
+            } else {
+                col + 1
+            };
+            graphemes.push((col, next, class));
+            col = next;
+        }
+        let idx = graphemes
+            .iter()
+            .position(|(_, next, _)| gidx < *next)
+            .or(graphemes.len().checked_sub(1))?;
+        let class = graphemes[idx].2;
+        let mut first = idx;
+        while first > 0 && graphemes[first - 1].2 == class {
+            first -= 1;
+        }
+        let mut last = idx;
+        while last + 1 < graphemes.len() && graphemes[last + 1].2 == class {
+            last += 1;
+        }
+        Some((graphemes[first].0, graphemes[last].0))
+    }
 
-        for (&k, weak) in self.cursors.iter_mut() {
-            let strong = weak.upgrade().unwrap();
-            let inner = &mut *strong.borrow_mut();
-            if inner.char_idx < old_char_idx {
-                continue;
-            }
-            if inner.char_idx == old_char_idx && k != view_id {
-                inner.sync_line_cidx_gidx_right(&self.data, self.tabsize);
-                continue;
-            }
-            inner.char_idx += ccount;
-            inner.sync_from_and_udpate_char_idx_right(&self.data, self.tabsize);
+    // Char index of column gidx in line linum. Columns past the end of a line are at its end,
+    // and lines past the end of the buffer are at the end of the buffer
+    fn cidx_at(&self, (linum, gidx): (usize, usize)) -> usize {
+        if linum >= self.data.len_lines() {
+            return self.data.len_chars();
         }
+        let line = trim_newlines(self.data.line(linum));
+        let (cidx, _) = cidx_gidx_from_gidx(&line, gidx, self.tabsize, true);
+        self.data.line_to_char(linum) + cidx
+    }
 
-        // Reformat
-        let linum = self.data.char_to_line(old_char_idx);
-        let end_line = self.data.char_to_line(old_char_idx + ccount);
-        for (_, _, t) in &mut self.dpi_shaped_lines {
-            for _ in linum..end_line {
-                t.insert(linum + 1, ShapedTextLine::default());
-            }
+    /// Paste text after the cursor (before it, if not after). Linewise text is pasted on lines
+    /// below (or above) the cursor line, and the cursor is moved to the first pasted line.
+    /// Otherwise the cursor is moved to the last pasted character
+    pub(crate) fn paste(
+        &mut self,
+        cursor: &mut BufferCursor,
+        text: &str,
+        linewise: bool,
+        after: bool,
+    ) {
+        if self.scratch || text.len() == 0 {
+            return;
         }
-        if end_line > linum {
-            self.syntax.insert_lines(linum + 1, end_line - linum);
+        let linum = cursor.line_num();
+        if linewise {
+            let text = text.trim_end_matches('\n');
+            let target = if after { linum + 1 } else { linum };
+            let (cidx, text) = if target < self.data.len_lines() {
+                (self.data.line_to_char(target), format!("{}\n", text))
+            } else {
+                // Last line has no newline
+                (self.data.len_chars(), format!("\n{}", text))
+            };
+            (&mut *cursor.inner.borrow_mut()).char_idx = cidx;
+            self.insert_str(cursor, &text);
+            self.move_cursor_to_linum_gidx(cursor, target, 0);
+        } else {
+            let cidx = {
+                let inner = &*cursor.inner.borrow();
+                let line = trim_newlines(self.data.line(inner.line_num));
+                if after && inner.line_cidx < line.len_chars() {
+                    inner.char_idx - inner.line_cidx
+                        + next_grapheme_boundary(&line, inner.line_cidx)
+                } else {
+                    inner.char_idx
+                }
+            };
+            (&mut *cursor.inner.borrow_mut()).char_idx = cidx;
+            self.insert_str(cursor, text);
+            let inner = &mut *cursor.inner.borrow_mut();
+            inner.char_idx = cidx + text.chars().count() - 1;
+            inner.sync_from_and_udpate_char_idx_left(&self.data, self.tabsize);
         }
-        self.format_lines_from(linum, Some(end_line));
     }
 
     /// Move cursor to given line number and gidx
@@ -809,20 +2257,437 @@ impl Buffer {
         self.cursors.retain(|_, weak| weak.strong_count() > 0);
     }
 
+    // Whether hex color literals in this buffer should be decorated with swatches
+    fn has_color_swatches(&self) -> bool {
+        self.path
+            .as_ref()
+            .and_then(|p| Path::new(p).extension())
+            .and_then(|e| e.to_str())
+            .map(|e| COLOR_SWATCH_EXTENSIONS.contains(&e))
+            .unwrap_or(false)
+    }
+
+    // Underline hex color literals (#rrggbb or #rrggbbaa) in given line with the color they
+    // describe
+    fn color_swatches(&self, linum: usize) -> Vec<Decoration> {
+        let mut ret = Vec::new();
+        if linum >= self.data.len_lines() {
+            return ret;
+        }
+        let chars = self.data.line(linum).chars().collect::<Vec<_>>();
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] != '#' || (i > 0 && is_word(chars[i - 1])) {
+                i += 1;
+                continue;
+            }
+            let mut j = i + 1;
+            while j < chars.len() && chars[j].is_ascii_hexdigit() {
+                j += 1;
+            }
+            let ndigits = j - i - 1;
+            if (ndigits == 6 || ndigits == 8) && (j == chars.len() || !is_word(chars[j])) {
+                let s = chars[i..j].iter().collect::<String>();
+                if let Some(color) = Color::parse(&s) {
+                    ret.push(Decoration {
+                        start_cidx: i,
+                        end_cidx: j,
+                        style: DecorationStyle::Underline(color),
+                        priority: SWATCH_PRIORITY,
+                    });
+                }
+            }
+            i = j;
+        }
+        ret
+    }
+
+    // Matches of pattern in given line, as char index ranges
+    fn line_match_cidxs(&self, linum: usize, searcher: &Searcher) -> Vec<(usize, usize)> {
+        if searcher.pattern().len() == 0 {
+            return Vec::new();
+        }
+        searcher
+            .find_iter_line(self.data.line(linum))
+            .filter(|m| m.end > m.start)
+            .map(|m| (m.start_cidx, m.end_cidx))
+            .collect()
+    }
+
     fn format_lines_from(&mut self, start: usize, opt_min_end: Option<usize>) {
+        self.shape_lines_from(start, opt_min_end);
+        self.check_shaped();
+    }
+
+    fn shape_lines_from(&mut self, start: usize, opt_min_end: Option<usize>) {
+        // Text changed, so search matches have to be recomputed
+        if self.search_pattern.is_some() {
+            self.annotations.clear(AnnotationKind::Search);
+            self.decorations.clear(DecorationLayer::Search);
+            self.search_pattern = None;
+        }
+
+        if self.large {
+            self.shape_placeholders(start);
+            return;
+        }
+
+        // Lines left unsettled by a cancelled job still have to be re-highlighted. Lines
+        // inserted since then shift them down
+        self.highlight_job = None;
+        let len_lines = self.data.len_lines();
+        let mut min_end = opt_min_end.unwrap_or(0);
+        if let Some((end, old_len_lines)) = self.unsettled {
+            min_end = min_end.max((end + len_lines).saturating_sub(old_len_lines));
+        }
+        let min_end = min_end.min(len_lines);
+        let sync_end = (start + SYNC_HIGHLIGHT_LINES).max(opt_min_end.unwrap_or(0));
+
         let font_core = &mut *self.font_core.borrow_mut();
-        for (dpi, lvec, tvec) in &mut self.dpi_shaped_lines {
-            self.syntax.format_lines(
+        let cfg = &*self.config.borrow();
+        let mut opt_continue = None;
+        for (dpi, gutter, tvec) in &mut self.dpi_shaped_lines {
+            let ret = self.syntax.format_lines(
                 *dpi,
                 start,
-                opt_min_end,
-                self.data.slice(..),
-                &*self.config.borrow(),
+                Some(min_end),
+                sync_end,
+                &self.data,
+                cfg,
                 self.tabsize,
                 tvec,
-                lvec,
                 font_core,
             );
+            opt_continue = opt_continue.max(ret);
+            gutter.set_max_number(len_lines, *dpi, cfg, font_core);
+        }
+
+        match opt_continue {
+            Some(linum) => {
+                let min_end = min_end.max(linum);
+                self.unsettled = Some((min_end, len_lines));
+                self.highlight_job = Some(self.syntax.highlight_async(
+                    linum,
+                    min_end,
+                    self.data.clone(),
+                    self.tabsize,
+                    self.wakeup.clone(),
+                ));
+            }
+            None => self.unsettled = None,
+        }
+    }
+
+    // Replace shaped lines from start with placeholders, without highlighting them
+    fn shape_placeholders(&mut self, start: usize) {
+        let len_lines = self.data.len_lines();
+        let font_core = &mut *self.font_core.borrow_mut();
+        let cfg = &*self.config.borrow();
+        for (dpi, gutter, tvec) in &mut self.dpi_shaped_lines {
+            let empty = empty_line(*dpi, cfg, font_core);
+            tvec.truncate(start);
+            tvec.resize_with(len_lines, || empty.placeholder(0));
+            gutter.set_max_number(len_lines, *dpi, cfg, font_core);
+        }
+    }
+
+    /// Shape line numbers that are about to be drawn
+    pub(crate) fn shape_gutter(&mut self, dpi: Size2D<u32, DPI>, numbers: &[usize]) {
+        let cfg = &*self.config.borrow();
+        let font_core = &mut *self.font_core.borrow_mut();
+        for (d, gutter, _) in &mut self.dpi_shaped_lines {
+            if *d == dpi {
+                gutter.shape(numbers, dpi, cfg, font_core);
+            }
+        }
+    }
+
+    /// Shape lines highlighted in the background, if any are ready. Returns true if lines were
+    /// re-shaped
+    pub(crate) fn poll_highlight(&mut self) -> bool {
+        let mut budget = POLL_HIGHLIGHT_LINES;
+        let mut ret = false;
+        while budget > 0 {
+            let msg = match self.highlight_job.as_ref().map(|rx| rx.try_recv()) {
+                Some(Ok(msg)) => msg,
+                Some(Err(TryRecvError::Empty)) | None => break,
+                Some(Err(TryRecvError::Disconnected)) => {
+                    self.highlight_job = None;
+                    break;
+                }
+            };
+            match msg {
+                HighlightMsg::Lines(lines) => {
+                    let cfg = &*self.config.borrow();
+                    let font_core = &mut *self.font_core.borrow_mut();
+                    let start = lines.start_linum();
+                    for (dpi, _, t) in &mut self.dpi_shaped_lines {
+                        for i in 0..lines.len() {
+                            if start + i >= t.len() {
+                                continue;
+                            }
+                            // Lines that aren't shaped yet are shaped once they are in view
+                            if t[start + i].is_placeholder() {
+                                t[start + i].set_key(lines.key(i));
+                            } else {
+                                t[start + i] = lines.shape(i, *dpi, cfg, font_core);
+                            }
+                        }
+                    }
+                    let end = start + lines.len();
+                    let len_lines = self.data.len_lines();
+                    self.unsettled = match self.unsettled {
+                        Some((e, l)) => Some((e.max(end), l)),
+                        None => Some((end, len_lines)),
+                    };
+                    budget = budget.saturating_sub(lines.len());
+                    ret = true;
+                }
+                HighlightMsg::Done(syntax) => {
+                    self.syntax = syntax;
+                    self.highlight_job = None;
+                    self.unsettled = None;
+                }
+            }
+        }
+        // Lines left over are handled after the next frame
+        if budget == 0 && self.highlight_job.is_some() {
+            self.wakeup.wake();
+        }
+        ret
+    }
+
+    // Text changed. Mark buffer as edited, and drop decorations that depend on the text
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.line_changes = None;
+        self.decorations.clear(DecorationLayer::Indentation);
+    }
+
+    // Contents of the file were read or written
+    fn set_saved(&mut self, data: Rope) {
+        self.saved = Some(data);
+        self.saved_mtime = self.mtime;
+        self.line_changes = None;
+    }
+
+    /// Recompute how lines differ from the file, if the buffer was edited since they were last
+    /// computed
+    pub(crate) fn update_line_changes(&mut self) {
+        if self.line_changes.is_some() {
+            return;
+        }
+        let saved = match &self.saved {
+            Some(saved) => saved,
+            None => return,
+        };
+        let last_line = self.data.len_lines() - 1;
+        let mut changes = Vec::new();
+        for hunk in line_diff(saved, &self.data) {
+            let (old_len, new_len) = (hunk.old_end - hunk.old_start, hunk.new_end - hunk.new_start);
+            if new_len == 0 {
+                changes.push((hunk.new_start.min(last_line), LineChange::Deleted));
+                continue;
+            }
+            for linum in hunk.new_start..hunk.new_end {
+                let change = if linum - hunk.new_start < old_len {
+                    LineChange::Modified
+                } else {
+                    LineChange::Added
+                };
+                changes.push((linum, change));
+            }
+        }
+        self.line_changes = Some(changes);
+    }
+
+    /// Lines changed since the file was last read or written, in increasing order. This is
+    /// empty until update_line_changes is called after edits
+    pub(crate) fn line_changes(&self) -> &[(usize, LineChange)] {
+        self.line_changes
+            .as_ref()
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // Replace lines in the given hunk with lines from new data, updating cursors and caches
+    fn apply_line_hunk(&mut self, hunk: &LineHunk, new_data: &Rope) {
+        let start_cidx = self.data.line_to_char(hunk.old_start);
+        let end_cidx = self.data.line_to_char(hunk.old_end);
+        let new_start_cidx = new_data.line_to_char(hunk.new_start);
+        let new_end_cidx = new_data.line_to_char(hunk.new_end);
+        let (old_len, new_len) = (end_cidx - start_cidx, new_end_cidx - new_start_cidx);
+
+        // Replace contents
+        self.data.remove(start_cidx..end_cidx);
+        let mut cidx = start_cidx;
+        for chunk in new_data.slice(new_start_cidx..new_end_cidx).chunks() {
+            self.data.insert(cidx, chunk);
+            cidx += chunk.chars().count();
+        }
+
+        // Update cursors after start of hunk
+        for (_, weak) in self.cursors.iter_mut() {
+            let strong = weak.upgrade().unwrap();
+            let inner = &mut *strong.borrow_mut();
+            if inner.char_idx < start_cidx {
+                continue;
+            }
+            if inner.char_idx >= end_cidx {
+                inner.char_idx = inner.char_idx - old_len + new_len;
+            } else {
+                let offset = inner.char_idx - start_cidx;
+                inner.char_idx = start_cidx + offset.min(new_len);
+            }
+        }
+
+        // Update line caches
+        let nlines = hunk.new_end - hunk.new_start;
+        for (_, _, t) in &mut self.dpi_shaped_lines {
+            if hunk.old_end <= t.len() {
+                t.splice(
+                    hunk.old_start..hunk.old_end,
+                    (0..nlines).map(|_| ShapedTextLine::default()),
+                );
+            }
+        }
+        if hunk.old_end > hunk.old_start {
+            self.syntax.remove_lines(hunk.old_start..hunk.old_end);
+        }
+        if nlines > 0 {
+            self.syntax.insert_lines(hunk.old_start, nlines);
+        }
+    }
+}
+
+// Number of lines from the start of an edit which are always re-highlighted synchronously. This
+// should cover the visible part of the buffer
+const SYNC_HIGHLIGHT_LINES: usize = 256;
+// Maximum number of lines highlighted in the background that are shaped per poll
+const POLL_HIGHLIGHT_LINES: usize = 512;
+// Maximum number of chunks of a large file that are appended per poll
+const POLL_STREAM_CHUNKS: usize = 4;
+
+// Lines looked at to guess the indentation of a file
+const DETECT_INDENT_LINES: usize = 1000;
+// Largest number of spaces per indentation level that is detected
+const MAX_INDENT_WIDTH: usize = 8;
+
+// Lines searched for a matching bracket, in either direction
+const MAX_BRACKET_LINES: usize = 2000;
+
+// Upper bound on the size of the table used for line diffs. Larger changes are treated as a
+// single hunk
+const MAX_LINE_DIFF_CELLS: usize = 1 << 22;
+
+// A range of lines in the old text, replaced by a range of lines in the new text
+struct LineHunk {
+    old_start: usize,
+    old_end: usize,
+    new_start: usize,
+    new_end: usize,
+}
+
+// Line-oriented diff between two ropes, returning hunks in increasing order
+fn line_diff(old: &Rope, new: &Rope) -> Vec<LineHunk> {
+    let (old_lines, new_lines) = (old.len_lines(), new.len_lines());
+    // Strip common prefix and suffix
+    let mut prefix = 0;
+    while prefix < old_lines && prefix < new_lines && old.line(prefix) == new.line(prefix) {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < old_lines - prefix
+        && suffix < new_lines - prefix
+        && old.line(old_lines - suffix - 1) == new.line(new_lines - suffix - 1)
+    {
+        suffix += 1;
+    }
+    let (old_end, new_end) = (old_lines - suffix, new_lines - suffix);
+    let (m, n) = (old_end - prefix, new_end - prefix);
+    if m == 0 && n == 0 {
+        return Vec::new();
+    }
+    let whole = LineHunk {
+        old_start: prefix,
+        old_end: old_end,
+        new_start: prefix,
+        new_end: new_end,
+    };
+    if m == 0 || n == 0 || (m + 1).saturating_mul(n + 1) > MAX_LINE_DIFF_CELLS {
+        return vec![whole];
+    }
+
+    // Longest common subsequence over the remaining lines
+    let a = (prefix..old_end)
+        .map(|i| old.line(i).to_string())
+        .collect::<Vec<_>>();
+    let b = (prefix..new_end)
+        .map(|i| new.line(i).to_string())
+        .collect::<Vec<_>>();
+    let w = n + 1;
+    let mut table = vec![0u32; (m + 1) * w];
+    for i in (0..m).rev() {
+        for j in (0..n).rev() {
+            table[i * w + j] = if a[i] == b[j] {
+                table[(i + 1) * w + j + 1] + 1
+            } else {
+                table[(i + 1) * w + j].max(table[i * w + j + 1])
+            };
+        }
+    }
+
+    // Collect runs of unmatched lines into hunks
+    let mut hunks = Vec::new();
+    let (mut i, mut j, mut hi, mut hj) = (0, 0, 0, 0);
+    while i < m || j < n {
+        if i < m && j < n && a[i] == b[j] {
+            if hi < i || hj < j {
+                hunks.push(LineHunk {
+                    old_start: prefix + hi,
+                    old_end: prefix + i,
+                    new_start: prefix + hj,
+                    new_end: prefix + j,
+                });
+            }
+            i += 1;
+            j += 1;
+            hi = i;
+            hj = j;
+        } else if j < n && (i == m || table[i * w + j + 1] >= table[(i + 1) * w + j]) {
+            j += 1;
+        } else {
+            i += 1;
+        }
+    }
+    if hi < m || hj < n {
+        hunks.push(LineHunk {
+            old_start: prefix + hi,
+            old_end: old_end,
+            new_start: prefix + hj,
+            new_end: new_end,
+        });
+    }
+    hunks
+}
+
+// Character classes for word motions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CharClass {
+    Space,
+    Word,
+    Punctuation,
+}
+
+impl CharClass {
+    fn of(c: char) -> CharClass {
+        if c.is_whitespace() {
+            CharClass::Space
+        } else if c.is_alphanumeric() || c == '_' {
+            CharClass::Word
+        } else {
+            CharClass::Punctuation
         }
     }
 }
@@ -858,6 +2723,128 @@ fn next_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> usize {
     }
 }
 
+// Kind of leading whitespace in a line
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Indentation {
+    Tabs,
+    Spaces,
+    Mixed,
+}
+
+// Indentation guessed from leading whitespace
+enum DetectedIndent {
+    Tabs,
+    Spaces(usize),
+}
+
+// Lines indented with tabs are compared with lines indented with spaces. For spaces, the width
+// is the most common increase in indentation from one line to the next. Blank lines are skipped,
+// and so are increases of one space, which are usually block comment continuations
+fn detect_indentation(data: &Rope) -> Option<DetectedIndent> {
+    let (mut ntabs, mut nspaces) = (0, 0);
+    let mut increases = [0; MAX_INDENT_WIDTH + 1];
+    let mut prev = 0;
+    for line in data.lines().take(DETECT_INDENT_LINES) {
+        let mut spaces = 0;
+        let mut first = None;
+        for c in line.chars() {
+            if c != ' ' {
+                first = Some(c);
+                break;
+            }
+            spaces += 1;
+        }
+        match first {
+            None | Some('\n') | Some('\r') => continue,
+            Some('\t') if spaces == 0 => {
+                ntabs += 1;
+                continue;
+            }
+            _ => {}
+        }
+        if spaces > 0 {
+            nspaces += 1;
+        }
+        if spaces > prev + 1 && spaces - prev <= MAX_INDENT_WIDTH {
+            increases[spaces - prev] += 1;
+        }
+        prev = spaces;
+    }
+    if ntabs > nspaces {
+        return Some(DetectedIndent::Tabs);
+    }
+    // Prefer the narrower width on ties
+    let (width, count) = increases
+        .iter()
+        .enumerate()
+        .rev()
+        .max_by_key(|(_, count)| **count)?;
+    if *count == 0 {
+        None
+    } else {
+        Some(DetectedIndent::Spaces(width))
+    }
+}
+
+// Indent every non-empty line by one level, or remove up to one level of indentation
+fn shift_lines(s: &str, tabsize: usize, indent_tabs: bool, right: bool) -> String {
+    let indent = if indent_tabs {
+        "\t".to_owned()
+    } else {
+        " ".repeat(tabsize)
+    };
+    s.split('\n')
+        .map(|line| {
+            if right {
+                if line.trim().is_empty() {
+                    line.to_owned()
+                } else {
+                    format!("{}{}", indent, line)
+                }
+            } else {
+                let mut n = 0;
+                for c in line.chars().take(tabsize) {
+                    match c {
+                        ' ' => n += 1,
+                        '\t' => {
+                            n += 1;
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                line[n..].to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Replace leading whitespace with the same width of tabs or spaces
+fn retab_line(line: &str, tabsize: usize, indent_tabs: bool) -> String {
+    let mut width = 0;
+    let mut len = 0;
+    for c in line.chars() {
+        match c {
+            '\t' => width = (width / tabsize + 1) * tabsize,
+            ' ' => width += 1,
+            _ => break,
+        }
+        len += 1;
+    }
+    let mut ret = if indent_tabs {
+        "\t".repeat(width / tabsize) + &" ".repeat(width % tabsize)
+    } else {
+        " ".repeat(width)
+    };
+    ret.push_str(&line[len..]);
+    ret
+}
+
+fn file_mtime(path: &str) -> Option<SystemTime> {
+    provider(path).mtime(path)
+}
+
 // From https://github.com/cessen/ropey/blob/master/examples/graphemes_step.rs
 fn is_grapheme_boundary(slice: &RopeSlice, char_idx: usize) -> bool {
     // We work with bytes for this, so convert.
@@ -993,13 +2980,12 @@ fn cidx_gidx_from_gidx(
     past_end: bool,
 ) -> (usize, usize) {
     let (mut gcount, mut cidx) = (0, 0);
-    let mut len_chars = slice.len_chars();
-    if !past_end && len_chars > 0 {
-        len_chars -= 1;
-    }
+    let len_chars = slice.len_chars();
     for g in RopeGraphemes::new(slice) {
         let count_here = g.chars().count();
-        if gcount >= gidx || cidx + count_here > len_chars {
+        // Unless past_end is set, the cursor stops at the start of the last grapheme, which
+        // might span multiple chars
+        if gcount >= gidx || (!past_end && cidx + count_here >= len_chars) {
             return (cidx, gcount);
         }
         cidx += count_here;
@@ -1019,13 +3005,10 @@ fn cidx_gidx_from_global_x(
     past_end: bool,
 ) -> (usize, usize) {
     let (mut gidx, mut ccount) = (0, 0);
-    let mut len_chars = slice.len_chars();
-    if !past_end && len_chars > 0 {
-        len_chars -= 1;
-    }
+    let len_chars = slice.len_chars();
     for g in RopeGraphemes::new(slice) {
         let count_here = g.chars().count();
-        if gidx >= global_x || ccount + count_here > len_chars {
+        if gidx >= global_x || (!past_end && ccount + count_here >= len_chars) {
             return (ccount, gidx);
         }
         ccount += count_here;
@@ -1037,3 +3020,179 @@ fn cidx_gidx_from_global_x(
     }
     (ccount, gidx)
 }
+
+#[cfg(test)]
+mod tests {
+    use euclid::size2;
+
+    use super::*;
+
+    // Graphemes that edits are made of: combining marks, tabs, CJK, emoji with modifiers, flags,
+    // and line breaks
+    const PIECES: &[&str] = &[
+        "a",
+        "Z",
+        " ",
+        "\t",
+        "\n",
+        "\r\n",
+        "e\u{301}",
+        "a\u{301}\u{323}",
+        "\u{915}\u{94d}\u{937}",
+        "漢",
+        "字",
+        "😀",
+        "👍🏽",
+        "🇯🇵",
+        "👩\u{200d}💻",
+    ];
+
+    // Xorshift generator, so that a failing run can be repeated from its seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn piece(&mut self) -> &'static str {
+            PIECES[self.below(PIECES.len())]
+        }
+    }
+
+    fn check_cursor(buffer: &Buffer, cursor: &BufferCursor) {
+        let inner = &*cursor.inner.borrow();
+        assert!(
+            inner.char_idx <= buffer.data.len_chars(),
+            "cursor past end of buffer"
+        );
+        inner.debug_check(&buffer.data, buffer.tabsize);
+    }
+
+    // Apply a random edit or cursor movement with the given cursor
+    fn random_step(rng: &mut Rng, buffer: &mut Buffer, cursor: &mut BufferCursor) {
+        let n = 1 + rng.below(4);
+        match rng.below(19) {
+            0 | 1 | 2 => {
+                let piece = rng.piece();
+                buffer.insert_str(cursor, piece);
+            }
+            3 => {
+                let c = rng.piece().chars().next().unwrap();
+                buffer.insert_char(cursor, c);
+            }
+            4 => {
+                let s = (0..n).map(|_| rng.piece()).collect::<String>();
+                buffer.insert_str(cursor, &s);
+            }
+            5 => buffer.delete_left(cursor, n),
+            6 => buffer.delete_right(cursor, n),
+            7 => buffer.delete_around(cursor, 1, 1),
+            8 => buffer.delete_word_left(cursor),
+            9 => buffer.delete_to_line_start(cursor),
+            10 => buffer.delete_to_line_end(cursor),
+            11 => buffer.delete_lines(cursor, n),
+            12 => buffer.move_cursor_left(cursor, n),
+            13 => buffer.move_cursor_right(cursor, n),
+            14 => buffer.move_cursor_up(cursor, n),
+            15 => buffer.move_cursor_down(cursor, n),
+            16 => buffer.move_cursor_start_of_line(cursor),
+            17 => buffer.move_cursor_end_of_line(cursor),
+            _ => {
+                // Switch between normal and insert mode
+                let past_end = !cursor.inner.borrow().past_end;
+                cursor.set_past_end(past_end);
+                let linum = rng.below(buffer.len_lines());
+                buffer.move_cursor_to_line(cursor, linum);
+            }
+        }
+    }
+
+    fn empty_buffer() -> Buffer {
+        // Buffers can't be shaped without fonts
+        let font_core = FontCore::new().expect("no fonts found, which buffers need for shaping");
+        let font_core = Rc::new(RefCell::new(font_core));
+        let config = Rc::new(RefCell::new(Cfg::default(&mut *font_core.borrow_mut())));
+        Buffer::empty(size2(96, 96), font_core, config, Wakeup::none())
+    }
+
+    // Edits with one cursor move the other cursor along with its text
+    #[test]
+    fn edits_move_other_cursors() {
+        let mut buffer = empty_buffer();
+        let pos = buffer.get_pos_at_line(0);
+        let mut first = buffer.add_cursor_at_pos(0, &pos, true);
+        buffer.insert_str(&mut first, "hello\nworld");
+        buffer.assert_state("hello\nworld", &[(&first, 11)]);
+
+        let pos = buffer.get_pos_at_line(1);
+        let second = buffer.add_cursor_at_pos(1, &pos, true);
+        buffer.move_cursor_to_line(&mut first, 0);
+        buffer.move_cursor_start_of_line(&mut first);
+        buffer.insert_str(&mut first, "e\u{301} 漢\n");
+        buffer.assert_state("e\u{301} 漢\nhello\nworld", &[(&first, 5), (&second, 11)]);
+
+        buffer.delete_left(&mut first, 1);
+        buffer.assert_state("e\u{301} 漢hello\nworld", &[(&first, 4), (&second, 10)]);
+
+        buffer.delete_lines(&mut first, 1);
+        buffer.assert_state("world", &[(&first, 0), (&second, 0)]);
+    }
+
+    // Brackets in comments don't match brackets in code, also when the comment started on an
+    // earlier line
+    #[test]
+    fn matching_bracket_skips_comments() {
+        let mut buffer = empty_buffer();
+        buffer.set_syntax_from_path("test.rs");
+        let pos = buffer.get_pos_at_line(0);
+        let mut cursor = buffer.add_cursor_at_pos(0, &pos, false);
+        buffer.insert_str(&mut cursor, "f(/* (\n) */ \"(\",\n)");
+        buffer.move_cursor_to_line(&mut cursor, 0);
+        buffer.move_cursor_start_of_line(&mut cursor);
+        buffer.move_cursor_right(&mut cursor, 1);
+        assert_eq!(buffer.matching_bracket(&cursor), Some((2, 0)));
+        buffer.move_cursor_right(&mut cursor, 4);
+        assert_eq!(buffer.matching_bracket(&cursor), Some((1, 0)));
+    }
+
+    // Random edits and movements with two cursors, like two panes showing the buffer, checking
+    // that both stay consistent with the text after every step
+    #[test]
+    fn random_edits_keep_cursors_consistent() {
+        let dpi = size2(96, 96);
+        let template = empty_buffer();
+        for seed in 1..=20u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut buffer = Buffer::empty(
+                dpi,
+                template.font_core.clone(),
+                template.config.clone(),
+                Wakeup::none(),
+            );
+            let mut cursors = (0..2)
+                .map(|id| {
+                    let pos = buffer.get_pos_at_line(0);
+                    buffer.add_cursor_at_pos(id, &pos, id == 0)
+                })
+                .collect::<Vec<_>>();
+            for step in 0..500 {
+                let idx = rng.below(cursors.len());
+                random_step(&mut rng, &mut buffer, &mut cursors[idx]);
+                for cursor in &cursors {
+                    check_cursor(&buffer, cursor);
+                }
+                assert_eq!(
+                    buffer.dpi_shaped_lines[0].2.len(),
+                    buffer.data.len_lines(),
+                    "shaped lines out of sync at seed {}, step {}",
+                    seed,
+                    step
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::fs::{create_dir_all, remove_file, rename};
+use std::io::{Error as IOError, ErrorKind, Result as IOResult};
+use std::path::{Path, PathBuf};
+
+/// A file that was moved to the trash, which can be moved back
+#[derive(Debug)]
+pub(crate) struct TrashedFile {
+    original: PathBuf,
+    trashed: PathBuf,
+    // Metadata written alongside the file, which is removed on restore
+    info: Option<PathBuf>,
+}
+
+impl TrashedFile {
+    pub(crate) fn original_path(&self) -> &Path {
+        &self.original
+    }
+
+    /// Move file back to where it was. Existing files are not overwritten
+    pub(crate) fn restore(&self) -> IOResult<()> {
+        if self.original.exists() {
+            return Err(IOError::new(ErrorKind::AlreadyExists, "file exists"));
+        }
+        if let Some(parent) = self.original.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(&self.trashed, &self.original)?;
+        if let Some(info) = &self.info {
+            let _ = remove_file(info);
+        }
+        Ok(())
+    }
+}
+
+/// Move file to the trash. Files are never unlinked, so this fails if the platform has no trash,
+/// or the file is on a different filesystem than the trash
+pub(crate) fn trash_file(path: &Path) -> IOResult<TrashedFile> {
+    let path = path.canonicalize()?;
+    if path.is_dir() {
+        return Err(IOError::new(ErrorKind::Other, "is a directory"));
+    }
+    platform_trash_file(path)
+}
+
+// The freedesktop.org trash specification, followed by Linux and BSD desktops
+#[cfg(all(unix, not(target_os = "macos")))]
+fn platform_trash_file(path: PathBuf) -> IOResult<TrashedFile> {
+    use directories::BaseDirs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let trash_dir = BaseDirs::new()
+        .map(|dirs| dirs.data_dir().join("Trash"))
+        .ok_or(IOError::new(ErrorKind::NotFound, "no data directory"))?;
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    create_dir_all(&files_dir)?;
+    create_dir_all(&info_dir)?;
+
+    // Creating the info file reserves the name in the trash
+    let name = file_name(&path)?;
+    let mut i = 1;
+    let (trash_name, info_path, mut info_file) = loop {
+        let trash_name = if i == 1 {
+            name.clone()
+        } else {
+            format!("{}.{}", name, i)
+        };
+        let info_path = info_dir.join(format!("{}.trashinfo", trash_name));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(f) => break (trash_name, info_path, f),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => i += 1,
+            Err(e) => return Err(e),
+        }
+    };
+    let info = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        percent_encode(&path),
+        deletion_date()
+    );
+    let trashed = files_dir.join(trash_name);
+    if let Err(e) = info_file
+        .write_all(info.as_bytes())
+        .and_then(|_| rename(&path, &trashed))
+    {
+        let _ = remove_file(&info_path);
+        return Err(e);
+    }
+    Ok(TrashedFile {
+        original: path,
+        trashed: trashed,
+        info: Some(info_path),
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn platform_trash_file(path: PathBuf) -> IOResult<TrashedFile> {
+    use directories::BaseDirs;
+
+    let trash_dir = BaseDirs::new()
+        .map(|dirs| dirs.home_dir().join(".Trash"))
+        .ok_or(IOError::new(ErrorKind::NotFound, "no home directory"))?;
+    create_dir_all(&trash_dir)?;
+    let name = file_name(&path)?;
+    let mut trashed = trash_dir.join(&name);
+    let mut i = 2;
+    while trashed.exists() {
+        trashed = trash_dir.join(format!("{} {}", name, i));
+        i += 1;
+    }
+    rename(&path, &trashed)?;
+    Ok(TrashedFile {
+        original: path,
+        trashed: trashed,
+        info: None,
+    })
+}
+
+#[cfg(not(unix))]
+fn platform_trash_file(_path: PathBuf) -> IOResult<TrashedFile> {
+    Err(IOError::new(
+        ErrorKind::Other,
+        "trash is not supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn file_name(path: &Path) -> IOResult<String> {
+    path.file_name()
+        .map(|s| s.to_string_lossy().into_owned())
+        .ok_or(IOError::new(
+            ErrorKind::InvalidInput,
+            "path has no file name",
+        ))
+}
+
+// Paths in trash info files are URL-encoded
+#[cfg(all(unix, not(target_os = "macos")))]
+fn percent_encode(path: &Path) -> String {
+    use std::fmt::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut ret = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                ret.push(b as char)
+            }
+            _ => write!(&mut ret, "%{:02X}", b).unwrap(),
+        }
+    }
+    ret
+}
+
+// Current time as YYYY-MM-DDThh:mm:ss. The specification asks for local time, but there's no
+// portable way to get the timezone without a dependency, so UTC is used
+#[cfg(all(unix, not(target_os = "macos")))]
+fn deletion_date() -> String {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // Convert days since epoch to a civil date
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
@@ -125,7 +125,7 @@ impl TextSize {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub(crate) enum TextPitch {
     Fixed,
     Variable,
@@ -0,0 +1,273 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::window::Window;
+
+/// Handler run when a command is invoked
+pub(crate) type CommandHandler = Rc<dyn Fn(&mut Window, &CommandArgs)>;
+
+/// Completion function for the argument of a command. Takes the partially typed argument, and
+/// returns candidates for the whole argument
+pub(crate) type CommandCompleter = Rc<dyn Fn(&Window, &str) -> Vec<String>>;
+
+/// Argument a command accepts. The string describes the argument, e.g. "file"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ArgSpec {
+    None,
+    Optional(&'static str),
+    Required(&'static str),
+}
+
+/// Arguments a command was invoked with
+#[derive(Debug)]
+pub(crate) struct CommandArgs {
+    /// Name the command was invoked with, which may be an alias
+    pub(crate) name: String,
+    /// Line range prefix, e.g. "3,7" or "%"
+    pub(crate) range: Option<String>,
+    /// Whether the command was suffixed with a "!"
+    pub(crate) bang: bool,
+    pub(crate) args: Vec<String>,
+    /// Arguments as typed, for commands that take a shell command or an expression, where
+    /// whitespace and quotes matter
+    pub(crate) raw: String,
+}
+
+impl CommandArgs {
+    /// Parse a command line, like ":3,7upper", ":w! foo.txt", ":!ls -l", ":= 2*3" or
+    /// ":%s/a b/c/g".
+    /// Returns None if the line is empty
+    pub(crate) fn parse(line: &str) -> Option<CommandArgs> {
+        let line = line.trim_start().trim_start_matches(':').trim_start();
+        let mut iter = line.split_whitespace();
+        let s = iter.next()?;
+        let end = s
+            .find(|c: char| !(c.is_ascii_digit() || c == ',' || c == '.' || c == '$' || c == '%'))
+            .unwrap_or(s.len());
+        let range = if end > 0 {
+            Some(s[..end].to_owned())
+        } else {
+            None
+        };
+        let name = &s[end..];
+        // Shell commands and expressions follow "!" or "=" without a space, like ":!ls" or
+        // ":=2*3"
+        for prefix in &['!', '='] {
+            if let Some(first) = name.strip_prefix(*prefix) {
+                let first = Some(first).filter(|s| !s.is_empty());
+                return Some(CommandArgs {
+                    name: format!(":{}", prefix),
+                    range: range,
+                    bang: false,
+                    args: first
+                        .into_iter()
+                        .chain(iter)
+                        .map(|s| s.to_owned())
+                        .collect(),
+                    raw: line[end + 1..].trim().to_owned(),
+                });
+            }
+        }
+        // Substitutions keep their argument as typed, since patterns may contain spaces
+        let mut name_chars = name.chars();
+        if let (Some('s'), Some(delim)) = (name_chars.next(), name_chars.next()) {
+            if !delim.is_alphanumeric() && delim != '!' {
+                return Some(CommandArgs {
+                    name: ":s".to_owned(),
+                    range: range,
+                    bang: false,
+                    args: vec![line[end + 1..].to_owned()],
+                    raw: line[end + 1..].to_owned(),
+                });
+            }
+        }
+        let (name, bang) = match name.strip_suffix('!') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        Some(CommandArgs {
+            name: format!(":{}", name),
+            range: range,
+            bang: bang,
+            args: iter.map(|s| s.to_owned()).collect(),
+            raw: line[s.len()..].trim().to_owned(),
+        })
+    }
+
+    pub(crate) fn arg(&self, idx: usize) -> Option<&str> {
+        self.args.get(idx).map(|s| s.as_str())
+    }
+}
+
+/// A command that can be run from the prompt
+#[derive(Clone)]
+pub(crate) struct CommandDef {
+    name: String,
+    aliases: Vec<String>,
+    arg: ArgSpec,
+    description: String,
+    completer: Option<CommandCompleter>,
+    handler: CommandHandler,
+}
+
+impl CommandDef {
+    /// Command with the given name (e.g. ":write") and handler, which takes no arguments
+    pub(crate) fn new<F>(name: &str, description: &str, handler: F) -> CommandDef
+    where
+        F: Fn(&mut Window, &CommandArgs) + 'static,
+    {
+        CommandDef {
+            name: name.to_owned(),
+            aliases: Vec::new(),
+            arg: ArgSpec::None,
+            description: description.to_owned(),
+            completer: None,
+            handler: Rc::new(handler),
+        }
+    }
+
+    pub(crate) fn alias(mut self, alias: &str) -> CommandDef {
+        self.aliases.push(alias.to_owned());
+        self
+    }
+
+    pub(crate) fn arg(mut self, arg: ArgSpec) -> CommandDef {
+        self.arg = arg;
+        self
+    }
+
+    pub(crate) fn completer<F>(mut self, completer: F) -> CommandDef
+    where
+        F: Fn(&Window, &str) -> Vec<String> + 'static,
+    {
+        self.completer = Some(Rc::new(completer));
+        self
+    }
+
+    pub(crate) fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    pub(crate) fn arg_spec(&self) -> ArgSpec {
+        self.arg
+    }
+
+    pub(crate) fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub(crate) fn handler(&self) -> CommandHandler {
+        self.handler.clone()
+    }
+
+    /// Human-readable usage, e.g. ":write [file]"
+    pub(crate) fn usage(&self) -> String {
+        match self.arg {
+            ArgSpec::None => self.name.clone(),
+            ArgSpec::Optional(arg) => format!("{} [{}]", self.name, arg),
+            ArgSpec::Required(arg) => format!("{} <{}>", self.name, arg),
+        }
+    }
+}
+
+/// Commands by name. Everything that runs commands (the prompt, key bindings, scripts) looks them
+/// up here, so commands registered later are available everywhere
+#[derive(Default)]
+pub(crate) struct CommandRegistry {
+    commands: Vec<CommandDef>,
+    // Index into commands for every name and alias
+    names: HashMap<String, usize>,
+}
+
+impl CommandRegistry {
+    /// Register command. Commands registered later replace earlier ones with the same names
+    pub(crate) fn register(&mut self, command: CommandDef) {
+        let idx = match self.names.get(&command.name) {
+            Some(&idx) => {
+                let old = &self.commands[idx];
+                for alias in &old.aliases {
+                    self.names.remove(alias);
+                }
+                self.commands[idx] = command;
+                idx
+            }
+            None => {
+                self.commands.push(command);
+                self.commands.len() - 1
+            }
+        };
+        let command = &self.commands[idx];
+        for name in std::iter::once(&command.name).chain(command.aliases.iter()) {
+            self.names.insert(name.clone(), idx);
+        }
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&CommandDef> {
+        self.names.get(name).map(|&idx| &self.commands[idx])
+    }
+
+    /// All commands, in the order they were registered
+    pub(crate) fn commands(&self) -> &[CommandDef] {
+        &self.commands
+    }
+
+    /// Candidates for completing command line. Command names are completed if the line has no
+    /// space yet, and the argument is completed with the command's completer. Candidates are
+    /// whole command lines
+    pub(crate) fn complete(&self, window: &Window, line: &str) -> Vec<String> {
+        match line.rfind(char::is_whitespace) {
+            None => {
+                let mut ret = self
+                    .names
+                    .keys()
+                    .filter(|name| name.starts_with(line))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                ret.sort();
+                ret
+            }
+            Some(idx) => {
+                let (head, partial) = line.split_at(idx + 1);
+                // Only the first argument is completed
+                let completer = CommandArgs::parse(head)
+                    .filter(|args| args.args.is_empty())
+                    .and_then(|args| self.get(&args.name))
+                    .and_then(|cmd| cmd.completer.clone());
+                match completer {
+                    Some(completer) => completer(window, partial)
+                        .into_iter()
+                        .map(|s| format!("{}{}", head, s))
+                        .collect(),
+                    None => Vec::new(),
+                }
+            }
+        }
+    }
+}
+
+/// Resolve a line range to 0-based inclusive line numbers, given the cursor line and the last
+/// line
+pub(crate) fn resolve_range(range: &str, cur: usize, last: usize) -> Option<(usize, usize)> {
+    let addr = |s: &str| match s {
+        "." => Some(cur),
+        "$" => Some(last),
+        s => s.parse::<usize>().ok().map(|n| min(n.max(1) - 1, last)),
+    };
+    if range == "%" {
+        return Some((0, last));
+    }
+    let mut iter = range.splitn(2, ',');
+    let start = addr(iter.next()?)?;
+    let end = match iter.next() {
+        Some(s) => addr(s)?,
+        None => start,
+    };
+    if start <= end {
+        Some((start, end))
+    } else {
+        Some((end, start))
+    }
+}
@@ -3,13 +3,14 @@
 use std::ffi::CStr;
 use std::ops::Drop;
 
-use euclid::{point2, Point2D, Rect, SideOffsets2D, Size2D};
+use euclid::{point2, size2, Point2D, Rect, Size2D};
 use glfw::Context;
+use image::RgbaImage;
 
 use super::glyphrender::{ActiveGlyphRenderer, GlyphRenderer};
-use super::opengl::{ElemArr, Framebuffer, Gl, Mat4, ShaderProgram, TexUnit};
+use super::opengl::{ElemArr, Framebuffer, Gl, GlTexture, Mat4, ShaderProgram, TexRGBA, TexUnit};
 use super::quad::{ColorQuad, TexColorQuad, TexQuad};
-use crate::font::{FaceKey, RasterFace};
+use crate::font::{FaceKey, RasterFace, RasterOptions};
 use crate::types::{Color, PixelSize, TextSize, TextStyle, DPI};
 
 pub(super) struct RenderCtx {
@@ -18,13 +19,19 @@ pub(super) struct RenderCtx {
     size: Size2D<u32, PixelSize>,
     pub(super) dpi: Size2D<u32, DPI>,
     clear_color: Color,
+    // GL version and renderer
+    gl_info: (String, String),
     glyph_renderer: GlyphRenderer,
     // Framebuffers
     framebuffers: [Framebuffer; 1],
+    // Everything is drawn here and then copied to the window, so that parts of the window that
+    // didn't change can be kept from the last frame
+    frame: Framebuffer,
     // shaders
     clr_quad_shader: ShaderProgram,
     tex_clr_quad_shader: ShaderProgram,
     shadow_shader: ShaderProgram,
+    image_shader: ShaderProgram,
     // arrays
     clr_quad_arr: ElemArr<ColorQuad>,
     tex_clr_quad_arr: ElemArr<TexColorQuad>,
@@ -36,6 +43,7 @@ impl RenderCtx {
         window: &mut glfw::Window,
         size: Size2D<u32, PixelSize>,
         dpi: Size2D<u32, DPI>,
+        raster_options: RasterOptions,
         clear_color: Color,
     ) -> RenderCtx {
         // Initialize opengl context
@@ -56,25 +64,35 @@ impl RenderCtx {
         let shadow_shader = gl
             .new_shader(shadow_vsrc, shadow_fsrc)
             .expect("failed to compile shader");
+        let image_vsrc = include_str!("opengl/shader_src/image.vert");
+        let image_fsrc = include_str!("opengl/shader_src/image.frag");
+        let image_shader = gl
+            .new_shader(image_vsrc, image_fsrc)
+            .expect("failed to compile shader");
         let clr_quad_arr = gl.new_elem_arr(64);
         let tex_clr_quad_arr = gl.new_elem_arr(4096);
         let tex_quad_arr = gl.new_elem_arr(4);
         let framebuffer = gl.new_framebuffer(TexUnit::Texture1, size);
-        let glyph_renderer = GlyphRenderer::new(&mut gl, dpi);
+        let frame = gl.new_framebuffer(TexUnit::Texture3, size);
+        let glyph_renderer = GlyphRenderer::new(&mut gl, dpi, raster_options);
+        let gl_info = gl.version_info();
         RenderCtx {
             gl: gl,
             projection_matrix: Mat4::projection(size.cast()),
             size: size,
             dpi: dpi,
             clear_color: clear_color,
+            gl_info: gl_info,
             glyph_renderer: glyph_renderer,
             clr_quad_shader: clr_shader,
             tex_clr_quad_shader: tex_clr_shader,
             shadow_shader: shadow_shader,
+            image_shader: image_shader,
             clr_quad_arr: clr_quad_arr,
             tex_clr_quad_arr: tex_clr_quad_arr,
             tex_quad_arr: tex_quad_arr,
             framebuffers: [framebuffer],
+            frame: frame,
         }
     }
 
@@ -82,44 +100,68 @@ impl RenderCtx {
         window.make_current();
         self.gl.viewport(Rect::new(point2(0, 0), self.size.cast()));
         self.framebuffers[0].bind_texture();
+        self.frame.bind();
         let mut ret = ActiveRenderCtx {
             gl: &mut self.gl,
-            size: self.size,
             projection_matrix: &self.projection_matrix,
-            dpi: self.dpi,
             clear_color: self.clear_color,
             clr_quad_shader: &mut self.clr_quad_shader,
             tex_clr_quad_shader: &mut self.tex_clr_quad_shader,
             shadow_shader: &mut self.shadow_shader,
+            image_shader: &mut self.image_shader,
             tex_quad_arr: &mut self.tex_quad_arr,
             clr_quad_arr: &mut self.clr_quad_arr,
-            framebuffers: &mut self.framebuffers,
             active_glyph_renderer: self.glyph_renderer.activate(&mut self.tex_clr_quad_arr),
         };
         ret.set_uniforms();
         ret
     }
 
+    /// Show what was drawn since the last call to activate in the window. The window's buffers
+    /// still have to be swapped
+    pub(super) fn present(&mut self) {
+        self.frame.blit_to_window(self.size);
+    }
+
+    /// Read back the last frame drawn, for comparing it against golden images
+    pub(super) fn read_frame(&mut self) -> RgbaImage {
+        let (width, height) = (self.size.width, self.size.height);
+        let data = self.frame.read_pixels(self.size);
+        // OpenGL rows start at the bottom
+        let row = width as usize * 4;
+        let flipped = data.chunks(row.max(1)).rev().flatten().cloned().collect();
+        RgbaImage::from_raw(width, height, flipped).unwrap()
+    }
+
+    pub(super) fn gl_info(&self) -> &(String, String) {
+        &self.gl_info
+    }
+
+    /// Change the DPI and how glyphs are rasterized, dropping glyphs rasterized so far if either
+    /// changed
+    pub(super) fn set_raster(&mut self, dpi: Size2D<u32, DPI>, options: RasterOptions) {
+        self.dpi = dpi;
+        self.glyph_renderer.set_raster(dpi, options);
+    }
+
     pub(super) fn set_size(&mut self, size: Size2D<u32, PixelSize>) {
         self.size = size;
         self.projection_matrix = Mat4::projection(size);
         self.framebuffers[0].resize(size);
+        self.frame.resize(size);
     }
 }
 
 pub(super) struct ActiveRenderCtx<'a> {
     gl: &'a mut Gl,
-    size: Size2D<u32, PixelSize>,
     projection_matrix: &'a Mat4,
     clear_color: Color,
-    dpi: Size2D<u32, DPI>,
     active_glyph_renderer: ActiveGlyphRenderer<'a, 'a>,
-    // framebuffers
-    framebuffers: &'a mut [Framebuffer; 1],
     // shaders
     clr_quad_shader: &'a mut ShaderProgram,
     tex_clr_quad_shader: &'a mut ShaderProgram,
     shadow_shader: &'a mut ShaderProgram,
+    image_shader: &'a mut ShaderProgram,
     // arrays
     clr_quad_arr: &'a mut ElemArr<ColorQuad>,
     tex_quad_arr: &'a mut ElemArr<TexQuad>,
@@ -145,37 +187,24 @@ impl<'a> ActiveRenderCtx<'a> {
         ret
     }
 
-    pub(super) fn draw_shadow(&mut self, rect: Rect<i32, PixelSize>) {
-        /*
-        let outer_dims = SideOffsets2D::new(5, 5, 5, 5);
-        let outer_rect = rect.outer_rect(outer_dims);
-
-        self.gl.set_stencil_test(false);
-        self.framebuffers[0].bind();
-        self.gl.clear_color(Color::new(0, 0, 0, 255));
-        self.gl.clear();
-        {
-            let active_shader = self.gl.use_shader(self.clr_quad_shader);
-            self.clr_quad_arr
-                .push(ColorQuad::new(rect.cast(), Color::new(255, 0, 0, 255)));
-            self.clr_quad_arr.flush(&active_shader);
-        }
-        self.framebuffers[0].unbind();
-        {
-            let tex = self.framebuffers[0].get_texture();
-            let active_shader = self.gl.use_shader(self.shadow_shader);
-            let trect = tex.get_tex_dimensions(outer_rect);
-            let quad = TexQuad::new(outer_rect.cast(), trect);
-            self.tex_quad_arr.push(quad);
-            self.tex_quad_arr.flush(&active_shader);
-        }
-        */
+    /// Upload RGBA image with given size to a new texture
+    pub(super) fn new_image_texture(
+        &mut self,
+        size: Size2D<u32, PixelSize>,
+        data: &[u8],
+    ) -> GlTexture<TexRGBA> {
+        let mut texture = self.gl.new_texture(TexUnit::Texture2, size);
+        texture.sub_image(Rect::new(point2(0, 0), size), data);
+        texture
     }
 
+    pub(super) fn draw_shadow(&mut self, _rect: Rect<i32, PixelSize>) {}
+
     fn set_uniforms(&mut self) {
         let projection = CStr::from_bytes_with_nul(b"projection\0").unwrap();
         let text = CStr::from_bytes_with_nul(b"text\0").unwrap();
         let tex = CStr::from_bytes_with_nul(b"tex\0").unwrap();
+        let image = CStr::from_bytes_with_nul(b"image\0").unwrap();
         {
             let mut active_shader = self.gl.use_shader(self.clr_quad_shader);
             active_shader.uniform_mat4f(&projection, &self.projection_matrix);
@@ -190,6 +219,11 @@ impl<'a> ActiveRenderCtx<'a> {
             active_shader.uniform_mat4f(&projection, &self.projection_matrix);
             active_shader.uniform_1i(&tex, 1);
         }
+        {
+            let mut active_shader = self.gl.use_shader(self.image_shader);
+            active_shader.uniform_mat4f(&projection, &self.projection_matrix);
+            active_shader.uniform_1i(&image, 2);
+        }
     }
 }
 
@@ -224,6 +258,23 @@ impl<'a, 'b> WidgetRenderCtx<'a, 'b> {
             .render_glyph(pos, face, gid, size, color, style, raster);
     }
 
+    /// Draw texture stretched over rect. Things drawn earlier are flushed first, so that the
+    /// image is drawn over them
+    pub(super) fn image(&mut self, rect: Rect<i32, PixelSize>, texture: &mut GlTexture<TexRGBA>) {
+        self.flush();
+        let tvec = self.rect.origin.to_vector();
+        texture.activate();
+        let active_shader = self
+            .active_ctx
+            .gl
+            .use_shader(&mut self.active_ctx.image_shader);
+        let trect = Rect::new(point2(0.0, 0.0), size2(1.0, 1.0));
+        self.active_ctx
+            .tex_quad_arr
+            .push(TexQuad::new(rect.translate(tvec).cast(), trect));
+        self.active_ctx.tex_quad_arr.flush(&active_shader);
+    }
+
     pub(super) fn flush(&mut self) {
         {
             let active_shader = self
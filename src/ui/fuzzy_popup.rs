@@ -6,36 +6,75 @@ use std::rc::Rc;
 use std::sync::mpsc::{Receiver, TryRecvError};
 
 use euclid::{point2, size2, Rect, SideOffsets2D, Size2D};
-use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
 
-use crate::config::Cfg;
+use crate::config::{Cfg, CfgUiFuzzyInput, CfgUiFuzzyPlacement};
 use crate::font::FontCore;
 use crate::types::{PixelSize, TextPitch, TextStyle, DPI};
 
 use super::context::ActiveRenderCtx;
-use super::text::{ShapedTextLine, TextCursorStyle, TextLine, TextSpan};
+use super::line_input::{LineInput, LineInputStyle};
+use super::text::{ShapedTextLine, TextLine, TextSpan};
+
+// Choices streamed in from another thread. Dropping the receiver cancels the source, since the
+// sending thread stops once sending fails
+struct AsyncSource {
+    // Index into the popup's source names, if the source has a name
+    name_idx: Option<usize>,
+    rx: Receiver<String>,
+    // The source is cancelled after this many choices, if set
+    limit: Option<usize>,
+    count: usize,
+}
+
+impl AsyncSource {
+    // Add the choices received so far. Returns false once the source is finished or reached its
+    // limit
+    fn receive(&mut self, choices: &mut Vec<(String, Option<usize>)>) -> bool {
+        loop {
+            if self.limit.map(|l| self.count >= l).unwrap_or(false) {
+                return false;
+            }
+            match self.rx.try_recv() {
+                Ok(s) => {
+                    choices.push((s, self.name_idx));
+                    self.count += 1;
+                }
+                Err(TryRecvError::Disconnected) => return false,
+                Err(TryRecvError::Empty) => return true,
+            }
+        }
+    }
+}
 
 pub(super) struct FuzzyPopup {
     is_active: bool,
     interacted: bool,
     pub(super) to_refresh: bool,
     window_rect: Rect<u32, PixelSize>,
-    height: u32,
-    input_line: ShapedTextLine,
+    // Where the popup is placed in the window, computed when refreshing
+    rect: Rect<u32, PixelSize>,
+    input: LineInput,
     input_label: ShapedTextLine,
     lines: Vec<ShapedTextLine>,
     dpi: Size2D<u32, DPI>,
     input_label_str: String,
-    user_input: String,
-    choices: Vec<String>,
-    filtered: Vec<(usize, String, Vec<(usize, usize)>)>,
+    // Choices, with the index of the name of the source they came from, if it has a name
+    choices: Vec<(String, Option<usize>)>,
+    filtered: Vec<(usize, String, Vec<(usize, usize)>, Option<usize>)>,
     select_idx: usize,
+    // Index into filtered of the first shaped line. Only lines that fit are shaped
+    scroll_idx: usize,
     default_on_empty: bool,
-    cursor_bidx: usize,
-    cursor_gidx: usize,
+    // Choices are listed in the order they were given until something is typed, for questions
+    // whose first choice is the default answer
+    keep_order: bool,
     font_core: Rc<RefCell<FontCore>>,
     config: Rc<RefCell<Cfg>>,
-    async_source: Option<Receiver<String>>,
+    async_sources: Vec<AsyncSource>,
+    // Where the input line was last drawn, in window coordinates, for clicks
+    input_rect: Rect<i32, PixelSize>,
+    // Names of sources, shown next to the choices from them
+    source_names: Vec<String>,
 }
 
 impl FuzzyPopup {
@@ -47,25 +86,26 @@ impl FuzzyPopup {
     ) -> FuzzyPopup {
         let mut ret = FuzzyPopup {
             window_rect: window_rect,
-            height: 0,
-            input_line: ShapedTextLine::default(),
+            rect: Rect::zero(),
+            input: LineInput::default(),
             input_label: ShapedTextLine::default(),
             lines: Vec::new(),
             dpi: dpi,
             font_core: font_core,
             config: config,
             input_label_str: String::new(),
-            user_input: String::new(),
             choices: Vec::new(),
             filtered: Vec::new(),
             select_idx: 0,
+            scroll_idx: 0,
             is_active: false,
             interacted: false,
             to_refresh: false,
             default_on_empty: false,
-            cursor_bidx: 0,
-            cursor_gidx: 0,
-            async_source: None,
+            keep_order: false,
+            async_sources: Vec::new(),
+            input_rect: Rect::zero(),
+            source_names: Vec::new(),
         };
         ret.refresh();
         ret
@@ -77,22 +117,14 @@ impl FuzzyPopup {
         let cfguifz = &cfg.ui.fuzzy;
         let cfgfztheme = &cfg.ui.theme().fuzzy;
 
-        let width = (self.window_rect.size.width * cfguifz.width_percentage) / 100;
-        let lpad = (self.window_rect.size.width - width) / 2;
-        let origin = point2(
-            self.window_rect.origin.x + lpad,
-            self.window_rect.origin.y + self.window_rect.size.height
-                - self.height
-                - cfguifz.bottom_offset,
-        );
-        let size = size2(width, self.height);
+        let input_above = cfguifz.input_position == CfgUiFuzzyInput::Above;
         let side_offsets = SideOffsets2D::new(
             cfgfztheme.edge_padding,
             cfgfztheme.edge_padding,
             cfgfztheme.edge_padding,
             cfgfztheme.edge_padding,
         );
-        let rect = Rect::new(origin, size);
+        let rect = self.rect;
         let inner_rect = rect.inner_rect(side_offsets);
 
         {
@@ -104,11 +136,19 @@ impl FuzzyPopup {
 
         let font_core = &mut *self.font_core.borrow_mut();
         let mut ctx = actx.get_widget_context(inner_rect.cast(), cfgfztheme.background_color);
-        let mut pos = point2(0, inner_rect.size.height as i32);
-        pos.y += min(
-            self.input_line.metrics.descender,
+        let input_ascender = max(
+            self.input.shaped().metrics.ascender,
+            self.input_label.metrics.ascender,
+        ) as i32;
+        let input_descender = min(
+            self.input.shaped().metrics.descender,
             self.input_label.metrics.descender,
         ) as i32;
+        let mut pos = if input_above {
+            point2(0, input_ascender)
+        } else {
+            point2(0, inner_rect.size.height as i32 + input_descender)
+        };
 
         // Draw input label
         let mut pos_here = self.input_label.draw(
@@ -122,35 +162,45 @@ impl FuzzyPopup {
         );
         pos_here.x += 5;
         let text_padding = pos_here.x;
+        let input_metrics = &self.input.shaped().metrics;
+        self.input_rect = Rect::new(
+            point2(
+                inner_rect.origin.x as i32 + pos_here.x,
+                inner_rect.origin.y as i32 + pos_here.y - input_metrics.ascender,
+            ),
+            size2(
+                inner_rect.size.width as i32 - pos_here.x,
+                input_metrics.height as i32,
+            ),
+        );
 
         // Draw input line
-        self.input_line.draw(
+        self.input.draw(
             &mut ctx,
-            self.input_line.metrics.ascender,
-            self.input_line.metrics.height as i32,
             pos_here,
             font_core,
-            Some((
-                self.cursor_gidx,
-                TextCursorStyle::Beam,
-                cfgfztheme.cursor_color,
-                cfgfztheme.foreground_color,
-            )),
-            100,
+            cfgfztheme.cursor_color,
+            cfgfztheme.foreground_color,
+            cfgfztheme.select_background_color,
         );
-        pos.y -= max(
-            self.input_line.metrics.ascender,
-            self.input_label.metrics.ascender,
-        ) as i32;
 
-        // Draw selection lines
+        // Draw selection lines, going away from the input line
+        if input_above {
+            pos.y += (2 * cfguifz.line_spacing) as i32 - input_descender;
+        } else {
+            pos.y -= input_ascender;
+        }
         if self.lines.len() > 0 {
             for i in 0..self.lines.len() {
                 let line = &self.lines[i];
-                pos.y -= (line.metrics.height + 2 * cfguifz.line_spacing) as i32;
+                let step = (line.metrics.height + 2 * cfguifz.line_spacing) as i32;
+                if !input_above {
+                    pos.y -= step;
+                }
 
-                if i == self.select_idx {
-                    let rect = Rect::new(pos, size2(width, self.lines[i].metrics.height).cast());
+                if self.scroll_idx + i == self.select_idx {
+                    let size = size2(rect.size.width, self.lines[i].metrics.height);
+                    let rect = Rect::new(pos, size.cast());
                     ctx.color_quad(rect, cfgfztheme.select_background_color);
                 }
 
@@ -166,46 +216,60 @@ impl FuzzyPopup {
                     None,
                     100,
                 );
+                if input_above {
+                    pos.y += step;
+                }
             }
         }
     }
 
+    /// Stream choices from a single source, without labelling them
     pub(super) fn set_async_source(&mut self, source: Receiver<String>) {
-        self.async_source = Some(source);
+        self.async_sources.clear();
+        self.add_async_source("", source, None);
+    }
+
+    /// Stream choices from another source alongside the existing ones. Choices from it are
+    /// labelled with name unless it is empty, and it is cancelled after limit choices, if there
+    /// is a limit
+    pub(super) fn add_async_source(
+        &mut self,
+        name: &str,
+        source: Receiver<String>,
+        limit: Option<usize>,
+    ) {
+        let name_idx = if name.len() == 0 {
+            None
+        } else {
+            self.source_names.push(name.to_owned());
+            Some(self.source_names.len() - 1)
+        };
+        self.async_sources.push(AsyncSource {
+            name_idx: name_idx,
+            rx: source,
+            limit: limit,
+            count: 0,
+        });
     }
 
     pub(super) fn update_from_async(&mut self) {
-        let mut found = false;
         let start = self.choices.len();
-        if let Some(source) = &self.async_source {
-            loop {
-                match source.try_recv() {
-                    Ok(s) => {
-                        self.choices.push(s);
-                        found = true;
-                    }
-                    Err(TryRecvError::Disconnected) => {
-                        self.async_source = None;
-                        break;
-                    }
-                    _ => break,
-                }
+        let mut i = 0;
+        while i < self.async_sources.len() {
+            if self.async_sources[i].receive(&mut self.choices) {
+                i += 1;
+            } else {
+                self.async_sources.remove(i);
             }
         }
-        if found {
-            for choice in &self.choices[start..] {
-                if let Some((score, indices)) = fuzzy_search(choice, &self.user_input) {
-                    self.filtered.push((score, choice.to_owned(), indices));
+        if self.choices.len() > start {
+            for (choice, source) in &self.choices[start..] {
+                if let Some((score, indices)) = fuzzy_search(choice, self.input.text()) {
+                    self.filtered
+                        .push((score, choice.to_owned(), indices, *source));
                 }
             }
-            self.filtered.sort_by(|a, b| {
-                if a.0 == b.0 {
-                    //a.1.len().cmp(&b.1.len())
-                    a.1.cmp(&b.1)
-                } else {
-                    a.0.cmp(&b.0)
-                }
-            });
+            self.sort_filtered();
             self.refresh();
             self.to_refresh = true;
         }
@@ -218,13 +282,14 @@ impl FuzzyPopup {
     }
 
     pub(super) fn push_string_choices(&mut self, choices: &[String]) {
-        self.choices.extend_from_slice(choices);
+        self.choices
+            .extend(choices.iter().map(|s| (s.to_owned(), None)));
         self.to_refresh = true;
     }
 
     pub(super) fn push_str_choices(&mut self, choices: &[&str]) {
         for s in choices {
-            self.choices.push(s.to_string());
+            self.choices.push((s.to_string(), None));
         }
         self.to_refresh = true;
     }
@@ -243,16 +308,23 @@ impl FuzzyPopup {
         self.default_on_empty = val;
     }
 
+    /// List choices in the order they were given until something is typed, instead of sorting
+    /// them. Reset when the popup is activated
+    pub(super) fn set_keep_order(&mut self, val: bool) {
+        self.keep_order = val;
+    }
+
     pub(super) fn set_active(&mut self, val: bool) {
-        self.async_source = None;
+        self.async_sources.clear();
+        self.source_names.clear();
         self.is_active = val;
         self.interacted = false;
+        self.keep_order = false;
         self.choices.clear();
-        self.user_input.clear();
+        self.input.clear();
         self.filtered.clear();
         self.select_idx = 0;
-        self.cursor_bidx = 0;
-        self.cursor_gidx = 0;
+        self.scroll_idx = 0;
         self.to_refresh = true;
     }
 
@@ -270,58 +342,95 @@ impl FuzzyPopup {
 
     pub(super) fn insert(&mut self, c: char) {
         self.interacted = true;
-        self.user_input.push(c);
-        self.cursor_bidx = next_grapheme_boundary(&self.user_input, self.cursor_bidx);
-        self.cursor_gidx = bidx_to_gidx(&self.user_input, self.cursor_bidx);
+        self.input.insert(c);
         self.to_refresh = true;
     }
 
     pub(super) fn delete_left(&mut self) {
         self.interacted = true;
-        if self.cursor_bidx == 0 {
-            return;
-        }
-        let cur = self.cursor_bidx;
-        self.cursor_bidx = 0;
-        for (i, _) in self.user_input.char_indices() {
-            if i >= cur {
-                break;
-            }
-            self.cursor_bidx = i;
-        }
-        self.user_input.remove(self.cursor_bidx);
-        if !is_grapheme_boundary(&self.user_input, self.cursor_bidx) {
-            self.cursor_bidx = next_grapheme_boundary(&self.user_input, self.cursor_bidx);
-        }
-        self.cursor_gidx = bidx_to_gidx(&self.user_input, self.cursor_bidx);
+        self.input.delete_left();
         self.to_refresh = true;
     }
 
-    pub(super) fn up_key(&mut self) {
+    pub(super) fn delete_right(&mut self) {
         self.interacted = true;
-        self.select_idx += 1;
-        if self.select_idx >= self.filtered.len() {
-            self.select_idx = self.filtered.len() - 1;
-        }
+        self.input.delete_right();
+        self.to_refresh = true;
+    }
+
+    pub(super) fn left_key(&mut self, extend: bool) {
+        self.input.move_left(extend);
+        self.to_refresh = true;
+    }
+
+    pub(super) fn right_key(&mut self, extend: bool) {
+        self.input.move_right(extend);
         self.to_refresh = true;
     }
 
+    pub(super) fn up_key(&mut self) {
+        if self.input_above() {
+            self.select(self.select_idx.saturating_sub(1));
+        } else {
+            self.select(self.select_idx + 1);
+        }
+    }
+
     pub(super) fn down_key(&mut self) {
-        self.interacted = true;
-        if self.select_idx > 0 {
-            self.select_idx -= 1;
+        if self.input_above() {
+            self.select(self.select_idx + 1);
+        } else {
+            self.select(self.select_idx.saturating_sub(1));
         }
-        self.to_refresh = true;
+    }
+
+    pub(super) fn page_up_key(&mut self) {
+        let page = max(self.lines.len(), 1);
+        if self.input_above() {
+            self.select(self.select_idx.saturating_sub(page));
+        } else {
+            self.select(self.select_idx + page);
+        }
+    }
+
+    pub(super) fn page_down_key(&mut self) {
+        let page = max(self.lines.len(), 1);
+        if self.input_above() {
+            self.select(self.select_idx + page);
+        } else {
+            self.select(self.select_idx.saturating_sub(page));
+        }
+    }
+
+    /// Select best match
+    pub(super) fn home_key(&mut self) {
+        self.select(0);
+    }
+
+    /// Select worst match
+    pub(super) fn end_key(&mut self) {
+        self.select(self.filtered.len().saturating_sub(1));
     }
 
     pub(super) fn tab_key(&mut self) {
         self.interacted = true;
         if self.filtered.len() > 0 {
-            self.user_input = self.filtered[self.select_idx].1.clone();
-            self.cursor_bidx = self.user_input.len();
-            self.cursor_gidx = bidx_to_gidx(&self.user_input, self.cursor_bidx);
+            let choice = self.filtered[self.select_idx].1.clone();
+            self.input.set_text(&choice);
+        }
+        self.to_refresh = true;
+    }
+
+    /// Handle a click at point, in window coordinates, with count for double and triple clicks.
+    /// Returns false if the point isn't on the input line
+    pub(super) fn click(&mut self, point: (i32, i32), count: usize) -> bool {
+        if !self.is_active || !self.input_rect.contains(point2(point.0, point.1)) {
+            return false;
         }
+        self.input
+            .click(point.0 - self.input_rect.origin.x, count, 0);
         self.to_refresh = true;
+        true
     }
 
     pub(super) fn set_window_rect(&mut self, window_rect: Rect<u32, PixelSize>) {
@@ -330,14 +439,52 @@ impl FuzzyPopup {
         self.to_refresh = true;
     }
 
+    pub(super) fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.dpi = dpi;
+        self.refresh();
+        self.to_refresh = true;
+    }
+
+    // Results go downwards from the input line if it's above them, so up and down keys swap
+    fn input_above(&self) -> bool {
+        self.config.borrow().ui.fuzzy.input_position == CfgUiFuzzyInput::Above
+    }
+
+    // Select choice at given index into filtered choices, and scroll so that it is visible
+    fn select(&mut self, idx: usize) {
+        self.interacted = true;
+        if self.filtered.len() == 0 {
+            return;
+        }
+        self.select_idx = min(idx, self.filtered.len() - 1);
+        let page = max(self.lines.len(), 1);
+        if self.select_idx < self.scroll_idx {
+            self.scroll_idx = self.select_idx;
+        } else if self.select_idx >= self.scroll_idx + page {
+            self.scroll_idx = self.select_idx + 1 - page;
+        }
+        self.refresh();
+        self.to_refresh = true;
+    }
+
     fn filter(&mut self) {
         self.filtered.clear();
         self.select_idx = 0;
-        for choice in &self.choices {
-            if let Some((score, indices)) = fuzzy_search(choice, &self.user_input) {
-                self.filtered.push((score, choice.to_owned(), indices));
+        self.scroll_idx = 0;
+        for (choice, source) in &self.choices {
+            if let Some((score, indices)) = fuzzy_search(choice, self.input.text()) {
+                self.filtered
+                    .push((score, choice.to_owned(), indices, *source));
             }
         }
+        self.sort_filtered();
+    }
+
+    // Best matches first, with ties sorted by name
+    fn sort_filtered(&mut self) {
+        if self.keep_order && self.input.text().is_empty() {
+            return;
+        }
         self.filtered.sort_by(|a, b| {
             if a.0 == b.0 {
                 //a.1.len().cmp(&b.1.len())
@@ -354,40 +501,23 @@ impl FuzzyPopup {
         let cfgfztheme = &cfg.ui.theme().fuzzy;
 
         let max_height = (cfguifz.max_height_percentage * self.window_rect.size.height) / 100;
+        let edge_offset = match cfguifz.placement {
+            CfgUiFuzzyPlacement::Center => 0,
+            _ => cfguifz.edge_offset,
+        };
         self.lines.clear();
         let font_core = &mut *self.font_core.borrow_mut();
 
-        self.input_line = if self.user_input.len() == 0 {
-            ShapedTextLine::from_textstr(
-                TextSpan::new(
-                    " ",
-                    cfguifz.text_size,
-                    TextStyle::default(),
-                    cfgfztheme.foreground_color,
-                    TextPitch::Variable,
-                    None,
-                ),
-                cfguifz.fixed_face,
-                cfguifz.variable_face,
-                font_core,
-                self.dpi,
-            )
-        } else {
-            ShapedTextLine::from_textstr(
-                TextSpan::new(
-                    &self.user_input,
-                    cfguifz.text_size,
-                    TextStyle::default(),
-                    cfgfztheme.foreground_color,
-                    TextPitch::Variable,
-                    None,
-                ),
-                cfguifz.fixed_face,
-                cfguifz.variable_face,
-                font_core,
-                self.dpi,
-            )
-        };
+        self.input.shape(
+            LineInputStyle {
+                text_size: cfguifz.text_size,
+                fixed_face: cfguifz.fixed_face,
+                variable_face: cfguifz.variable_face,
+                color: cfgfztheme.foreground_color,
+            },
+            font_core,
+            self.dpi,
+        );
 
         self.input_label = if self.input_label_str.len() == 0 {
             ShapedTextLine::from_textstr(
@@ -421,14 +551,15 @@ impl FuzzyPopup {
             )
         };
 
-        self.height = max(
-            self.input_line.metrics.height,
+        let mut height = max(
+            self.input.shaped().metrics.height,
             self.input_label.metrics.height,
         ) + cfgfztheme.edge_padding * 2
             + cfguifz.line_spacing;
 
-        let mut i = 0;
-        for (_, line, indices) in &self.filtered {
+        for (i, (_, line, indices, source)) in
+            self.filtered.iter().enumerate().skip(self.scroll_idx)
+        {
             let match_color = if i == self.select_idx {
                 cfgfztheme.select_match_color
             } else {
@@ -440,6 +571,7 @@ impl FuzzyPopup {
                 cfgfztheme.foreground_color
             };
 
+            let label = source.map(|i| format!("  {}", self.source_names[i]));
             let mut textline = TextLine::default();
             let mut j = 0;
             for (start, end) in indices {
@@ -473,6 +605,16 @@ impl FuzzyPopup {
                     None,
                 ));
             }
+            if let Some(label) = &label {
+                textline.0.push(TextSpan::new(
+                    label,
+                    cfguifz.text_size,
+                    TextStyle::default(),
+                    cfgfztheme.label_color,
+                    TextPitch::Variable,
+                    None,
+                ));
+            }
 
             let fmtline = ShapedTextLine::from_textline(
                 textline,
@@ -481,40 +623,33 @@ impl FuzzyPopup {
                 font_core,
                 self.dpi,
             );
-            if self.height
-                + cfguifz.bottom_offset
-                + cfguifz.line_spacing * 2
-                + fmtline.metrics.height
-                > max_height
+            if height + edge_offset + cfguifz.line_spacing * 2 + fmtline.metrics.height > max_height
             {
                 break;
             }
-            self.height += fmtline.metrics.height + cfguifz.line_spacing * 2;
+            height += fmtline.metrics.height + cfguifz.line_spacing * 2;
             self.lines.push(fmtline);
-            i += 1;
+            if cfguifz.max_results == Some(self.lines.len()) {
+                break;
+            }
         }
-    }
-}
 
-fn is_grapheme_boundary(s: &str, idx: usize) -> bool {
-    let mut gc = GraphemeCursor::new(idx, s.len(), true);
-    gc.is_boundary(s, 0).unwrap()
-}
-
-fn next_grapheme_boundary(s: &str, idx: usize) -> usize {
-    let mut gc = GraphemeCursor::new(idx, s.len(), true);
-    gc.next_boundary(s, 0).unwrap().unwrap_or(s.len())
-}
-
-fn bidx_to_gidx(s: &str, bidx: usize) -> usize {
-    let mut gidx = 0;
-    for (i, _) in s.grapheme_indices(true) {
-        if i >= bidx {
-            return gidx;
-        }
-        gidx += 1;
+        let width = (self.window_rect.size.width * cfguifz.width_percentage) / 100;
+        let lpad = (self.window_rect.size.width - width) / 2;
+        let free_height = self.window_rect.size.height.saturating_sub(height);
+        let top = match cfguifz.placement {
+            CfgUiFuzzyPlacement::Top => min(edge_offset, free_height),
+            CfgUiFuzzyPlacement::Center => free_height / 2,
+            CfgUiFuzzyPlacement::Bottom => free_height.saturating_sub(edge_offset),
+        };
+        self.rect = Rect::new(
+            point2(
+                self.window_rect.origin.x + lpad,
+                self.window_rect.origin.y + top,
+            ),
+            size2(width, height),
+        );
     }
-    gidx
 }
 
 fn fuzzy_search(haystack: &str, needle: &str) -> Option<(usize, Vec<(usize, usize)>)> {
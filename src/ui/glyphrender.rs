@@ -6,7 +6,7 @@ use euclid::{point2, size2, Point2D, Rect, Size2D};
 use fnv::FnvHashMap;
 use guillotiere::{AllocId, AllocatorOptions, AtlasAllocator};
 
-use crate::font::{FaceKey, RasterFace};
+use crate::font::{FaceKey, RasterFace, RasterOptions};
 use crate::types::{Color, PixelSize, TextSize, TextStyle, DPI};
 
 use super::opengl::{ActiveShaderProgram, ElemArr, Gl, GlTexture, TexRed, TexUnit};
@@ -80,12 +80,17 @@ pub(super) struct GlyphRenderer {
     atlas: GlTexture<TexRed>,
     glyph_map: FnvHashMap<GlyphKey, Option<RenderedGlyph>>,
     dpi: Size2D<u32, DPI>,
+    options: RasterOptions,
     allocator: AtlasAllocator,
 }
 
 impl GlyphRenderer {
     /// Initialize a new glyph renderer
-    pub(super) fn new(gl: &mut Gl, dpi: Size2D<u32, DPI>) -> GlyphRenderer {
+    pub(super) fn new(
+        gl: &mut Gl,
+        dpi: Size2D<u32, DPI>,
+        raster_options: RasterOptions,
+    ) -> GlyphRenderer {
         let options = AllocatorOptions {
             snap_size: 1,
             small_size_threshold: 8,
@@ -95,6 +100,7 @@ impl GlyphRenderer {
             atlas: gl.new_texture(TexUnit::Texture0, size2(GL_TEX_SIZE, GL_TEX_SIZE)),
             glyph_map: FnvHashMap::default(),
             dpi: dpi,
+            options: raster_options,
             allocator: AtlasAllocator::with_options(
                 (GL_TEX_SIZE as i32, GL_TEX_SIZE as i32).into(),
                 &options,
@@ -102,6 +108,17 @@ impl GlyphRenderer {
         }
     }
 
+    /// Change how glyphs are rasterized. Glyphs rasterized so far are dropped if anything changed
+    pub(super) fn set_raster(&mut self, dpi: Size2D<u32, DPI>, options: RasterOptions) {
+        if dpi == self.dpi && options == self.options {
+            return;
+        }
+        self.dpi = dpi;
+        self.options = options;
+        self.glyph_map.clear();
+        self.allocator.clear();
+    }
+
     /// Activate renderer
     pub(super) fn activate<'a, 'b>(
         &'a mut self,
@@ -112,6 +129,7 @@ impl GlyphRenderer {
             atlas: &mut self.atlas,
             glyph_map: &mut self.glyph_map,
             dpi: self.dpi,
+            options: self.options,
             allocator: &mut self.allocator,
             vert_buf: vert_buf,
         }
@@ -123,6 +141,7 @@ pub(super) struct ActiveGlyphRenderer<'a, 'b> {
     atlas: &'a mut GlTexture<TexRed>,
     glyph_map: &'a mut FnvHashMap<GlyphKey, Option<RenderedGlyph>>,
     dpi: Size2D<u32, DPI>,
+    options: RasterOptions,
     allocator: &'a mut AtlasAllocator,
     vert_buf: &'b mut ElemArr<TexColorQuad>,
 }
@@ -148,7 +167,7 @@ impl<'a, 'b> ActiveGlyphRenderer<'a, 'b> {
         let optrg = if let Some(optrg) = self.glyph_map.get(&key) {
             optrg
         } else {
-            if let Some(rast_glyph) = raster.raster(gid, size, self.dpi) {
+            if let Some(rast_glyph) = raster.raster(gid, size, self.dpi, self.options) {
                 // TODO: Free LRU if allocation fails, and flush text
                 // In that case, use bg shader to flush bg quads before flushing text
                 // It's better to do that inside TextView. So, indicate the need to flush, using
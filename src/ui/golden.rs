@@ -0,0 +1,103 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::path::{Path, PathBuf};
+
+use image::RgbaImage;
+
+/// Compare a rendered frame against the golden image at path. Pixels match if no channel
+/// differs by more than tolerance. If there is no golden image yet, the frame is saved as one.
+/// On a mismatch, the frame is saved next to the golden image with ".actual" added to the name,
+/// for inspecting it. Returns a reply for the debug server
+pub(super) fn check(frame: &RgbaImage, path: &Path, tolerance: u8) -> String {
+    if !path.exists() {
+        return match frame.save(path) {
+            Ok(()) => format!("written: {}", path.display()),
+            Err(e) => format!("failed to write golden image: {}", e),
+        };
+    }
+    let golden = match image::open(path) {
+        Ok(img) => img.to_rgba8(),
+        Err(e) => return format!("failed to read golden image: {}", e),
+    };
+    if golden.dimensions() != frame.dimensions() {
+        let (gw, gh) = golden.dimensions();
+        let (fw, fh) = frame.dimensions();
+        return save_actual(
+            frame,
+            path,
+            format!("size {}x{}, expected {}x{}", fw, fh, gw, gh),
+        );
+    }
+    let (mut count, mut worst) = (0, 0);
+    for (a, b) in frame.pixels().zip(golden.pixels()) {
+        let diff =
+            a.0.iter()
+                .zip(b.0.iter())
+                .map(|(x, y)| if x > y { x - y } else { y - x })
+                .max()
+                .unwrap_or(0);
+        if diff > tolerance {
+            count += 1;
+            worst = worst.max(diff);
+        }
+    }
+    if count == 0 {
+        return "ok".to_owned();
+    }
+    save_actual(
+        frame,
+        path,
+        format!("{} pixels differ, by up to {}", count, worst),
+    )
+}
+
+fn save_actual(frame: &RgbaImage, path: &Path, reason: String) -> String {
+    let actual = actual_path(path);
+    match frame.save(&actual) {
+        Ok(()) => format!("mismatch: {} (saved {})", reason, actual.display()),
+        Err(e) => format!("mismatch: {} (failed to save frame: {})", reason, e),
+    }
+}
+
+// foo.png becomes foo.actual.png
+fn actual_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("frame");
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("png");
+    path.with_file_name(format!("{}.actual.{}", stem, ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::Rgba;
+
+    use super::*;
+
+    fn frame(color: [u8; 4]) -> RgbaImage {
+        RgbaImage::from_pixel(4, 3, Rgba(color))
+    }
+
+    // Only the comparison. Rendering is checked against the images in tests/golden by its run.sh
+    #[test]
+    fn golden_written_then_compared() {
+        let dir = std::env::temp_dir().join(format!("bed-golden-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("frame.png");
+        let actual = dir.join("frame.actual.png");
+
+        let reply = check(&frame([10, 20, 30, 255]), &path, 0);
+        assert_eq!(reply, format!("written: {}", path.display()));
+        assert_eq!(check(&frame([10, 20, 30, 255]), &path, 0), "ok");
+        assert_eq!(check(&frame([12, 18, 30, 255]), &path, 2), "ok");
+        assert!(!actual.exists());
+
+        let reply = check(&frame([13, 20, 30, 255]), &path, 2);
+        assert!(reply.starts_with("mismatch: 12 pixels differ, by up to 3"));
+        assert!(actual.exists());
+        let reply = check(&RgbaImage::new(2, 2), &path, 2);
+        assert!(reply.starts_with("mismatch: size 2x2, expected 4x3"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
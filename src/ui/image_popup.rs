@@ -0,0 +1,132 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use euclid::{point2, size2, Rect, Size2D};
+use glfw::{Action, WindowEvent};
+use image::GenericImageView;
+
+use crate::config::Cfg;
+use crate::types::PixelSize;
+
+use super::context::ActiveRenderCtx;
+use super::opengl::{GlTexture, TexRGBA};
+
+// Larger images are scaled down when decoded, to keep the texture within what GPUs support
+const MAX_IMAGE_SIZE: u32 = 2048;
+
+// Percentage of the window the image can take up
+const MAX_SIZE_PERCENTAGE: u32 = 80;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PopupState {
+    Hidden,
+    Shown,
+    // A key was pressed to close the popup, and events are swallowed until it is released
+    Closing,
+}
+
+/// Popup showing a decoded image, scaled to fit the window. Closes on any key
+pub(super) struct ImagePopup {
+    state: PopupState,
+    window_rect: Rect<u32, PixelSize>,
+    size: Size2D<u32, PixelSize>,
+    // RGBA data, which is uploaded to a texture the first time the popup is drawn
+    data: Vec<u8>,
+    texture: Option<GlTexture<TexRGBA>>,
+    config: Rc<RefCell<Cfg>>,
+}
+
+impl ImagePopup {
+    pub(super) fn new(window_rect: Rect<u32, PixelSize>, config: Rc<RefCell<Cfg>>) -> ImagePopup {
+        ImagePopup {
+            state: PopupState::Hidden,
+            window_rect: window_rect,
+            size: size2(0, 0),
+            data: Vec::new(),
+            texture: None,
+            config: config,
+        }
+    }
+
+    pub(super) fn draw(&mut self, actx: &mut ActiveRenderCtx) {
+        let cfg = &*self.config.borrow();
+        let cfgfztheme = &cfg.ui.theme().fuzzy;
+
+        if self.texture.is_none() {
+            self.texture = Some(actx.new_image_texture(self.size, &self.data));
+            self.data = Vec::new();
+        }
+
+        // Scale image to fit, preserving the aspect ratio. Images are never scaled up
+        let max_width = self.window_rect.size.width * MAX_SIZE_PERCENTAGE / 100;
+        let max_height = self.window_rect.size.height * MAX_SIZE_PERCENTAGE / 100;
+        let scale = (max_width as f64 / self.size.width as f64)
+            .min(max_height as f64 / self.size.height as f64)
+            .min(1.0);
+        let img_size = size2(
+            ((self.size.width as f64 * scale) as u32).max(1),
+            ((self.size.height as f64 * scale) as u32).max(1),
+        );
+
+        let pad = cfgfztheme.edge_padding;
+        let size = size2(img_size.width + pad * 2, img_size.height + pad * 2);
+        let origin = point2(
+            self.window_rect.origin.x + self.window_rect.size.width.saturating_sub(size.width) / 2,
+            self.window_rect.origin.y
+                + self.window_rect.size.height.saturating_sub(size.height) / 2,
+        );
+        let rect = Rect::new(origin, size);
+
+        let shadow_rect = Rect::new(rect.origin, size2(size.width + 3, size.height + 3));
+        actx.draw_shadow(shadow_rect.cast());
+        let mut ctx = actx.get_widget_context(rect.cast(), cfgfztheme.background_color);
+        let img_rect = Rect::new(point2(pad, pad), img_size);
+        ctx.image(img_rect.cast(), self.texture.as_mut().unwrap());
+    }
+
+    pub(super) fn set_window_rect(&mut self, window_rect: Rect<u32, PixelSize>) {
+        self.window_rect = window_rect;
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.state != PopupState::Hidden
+    }
+
+    /// Decode image file and show it. Returns an error message if the image can't be decoded
+    pub(super) fn show(&mut self, path: &Path) -> Result<(), String> {
+        let img = image::open(path).map_err(|e| e.to_string())?;
+        let (width, height) = img.dimensions();
+        if width == 0 || height == 0 {
+            return Err("image is empty".to_owned());
+        }
+        let img = if width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+            img.thumbnail(MAX_IMAGE_SIZE, MAX_IMAGE_SIZE)
+        } else {
+            img
+        };
+        let img = img.to_rgba8();
+        self.size = size2(img.width(), img.height());
+        self.data = img.into_raw();
+        self.texture = None;
+        self.state = PopupState::Shown;
+        Ok(())
+    }
+
+    /// Handle event while the popup is active. Every event is consumed, so that the key closing
+    /// the popup doesn't reach the text view
+    pub(super) fn handle_event(&mut self, event: &WindowEvent) {
+        match (self.state, event) {
+            (PopupState::Shown, WindowEvent::Key(_, _, Action::Press, _)) => {
+                self.state = PopupState::Closing;
+            }
+            (PopupState::Closing, WindowEvent::Key(_, _, Action::Release, _)) => {
+                self.state = PopupState::Hidden;
+                self.texture = None;
+            }
+            _ => {}
+        }
+    }
+}
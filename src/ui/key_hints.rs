@@ -0,0 +1,183 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cell::RefCell;
+use std::cmp::max;
+use std::rc::Rc;
+
+use euclid::{point2, size2, Rect, SideOffsets2D, Size2D};
+
+use crate::config::Cfg;
+use crate::font::FontCore;
+use crate::types::{Color, PixelSize, TextPitch, TextStyle, DPI};
+
+use super::context::ActiveRenderCtx;
+use super::text::{ShapedTextLine, TextSpan};
+
+// Space between the key and description columns
+const COLUMN_GAP: u32 = 20;
+
+/// Popup listing the keys that can follow a pending prefix key, and what they do
+pub(super) struct KeyHints {
+    is_active: bool,
+    window_rect: Rect<u32, PixelSize>,
+    height: u32,
+    key_width: u32,
+    title: ShapedTextLine,
+    lines: Vec<(ShapedTextLine, ShapedTextLine)>,
+    font_core: Rc<RefCell<FontCore>>,
+    config: Rc<RefCell<Cfg>>,
+    dpi: Size2D<u32, DPI>,
+}
+
+impl KeyHints {
+    pub(super) fn new(
+        window_rect: Rect<u32, PixelSize>,
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        dpi: Size2D<u32, DPI>,
+    ) -> KeyHints {
+        KeyHints {
+            is_active: false,
+            window_rect: window_rect,
+            height: 0,
+            key_width: 0,
+            title: ShapedTextLine::default(),
+            lines: Vec::new(),
+            font_core: font_core,
+            config: config,
+            dpi: dpi,
+        }
+    }
+
+    pub(super) fn draw(&mut self, actx: &mut ActiveRenderCtx) {
+        let cfg = &*self.config.borrow();
+        let cfguifz = &cfg.ui.fuzzy;
+        let cfgfztheme = &cfg.ui.theme().fuzzy;
+
+        let width = (self.window_rect.size.width * cfguifz.width_percentage) / 100;
+        let lpad = (self.window_rect.size.width - width) / 2;
+        let origin = point2(
+            self.window_rect.origin.x + lpad,
+            self.window_rect.origin.y + self.window_rect.size.height
+                - self.height
+                - cfguifz.edge_offset,
+        );
+        let size = size2(width, self.height);
+        let side_offsets = SideOffsets2D::new(
+            cfgfztheme.edge_padding,
+            cfgfztheme.edge_padding,
+            cfgfztheme.edge_padding,
+            cfgfztheme.edge_padding,
+        );
+        let rect = Rect::new(origin, size);
+        let inner_rect = rect.inner_rect(side_offsets);
+
+        {
+            let size = size2(rect.size.width + 3, rect.size.height + 3);
+            let shadow_rect = Rect::new(rect.origin, size);
+            actx.draw_shadow(shadow_rect.cast());
+            let _ctx = actx.get_widget_context(rect.cast(), cfgfztheme.background_color);
+        }
+
+        let font_core = &mut *self.font_core.borrow_mut();
+        let mut ctx = actx.get_widget_context(inner_rect.cast(), cfgfztheme.background_color);
+
+        // Draw title, followed by one line per key
+        let mut pos = point2(0, self.title.metrics.ascender);
+        self.title.draw(
+            &mut ctx,
+            self.title.metrics.ascender,
+            self.title.metrics.height as i32,
+            pos,
+            font_core,
+            None,
+            100,
+        );
+        pos.y += self.title.metrics.height as i32 - self.title.metrics.ascender;
+
+        for (key, desc) in &self.lines {
+            let ascender = max(key.metrics.ascender, desc.metrics.ascender);
+            let height = max(key.metrics.height, desc.metrics.height);
+            pos.y += (cfguifz.line_spacing * 2) as i32 + ascender;
+            key.draw(&mut ctx, ascender, height as i32, pos, font_core, None, 100);
+            let desc_pos = point2(pos.x + (self.key_width + COLUMN_GAP) as i32, pos.y);
+            desc.draw(
+                &mut ctx,
+                ascender,
+                height as i32,
+                desc_pos,
+                font_core,
+                None,
+                100,
+            );
+            pos.y += height as i32 - ascender;
+        }
+    }
+
+    pub(super) fn set_window_rect(&mut self, window_rect: Rect<u32, PixelSize>) {
+        self.window_rect = window_rect;
+    }
+
+    // Hints that are showing keep the old DPI until they are shown again
+    pub(super) fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.dpi = dpi;
+    }
+
+    pub(super) fn is_active(&self) -> bool {
+        self.is_active
+    }
+
+    /// Show popup with title, and a list of keys with their descriptions
+    pub(super) fn show(&mut self, title: &str, hints: &[(&str, &str)]) {
+        let cfg = &*self.config.borrow();
+        let cfguifz = &cfg.ui.fuzzy;
+        let cfgfztheme = &cfg.ui.theme().fuzzy;
+        let font_core = &mut *self.font_core.borrow_mut();
+        let dpi = self.dpi;
+
+        let mut shape = |s: &str, color: Color, pitch: TextPitch| {
+            ShapedTextLine::from_textstr(
+                TextSpan::new(
+                    s,
+                    cfguifz.text_size,
+                    TextStyle::default(),
+                    color,
+                    pitch,
+                    None,
+                ),
+                cfguifz.fixed_face,
+                cfguifz.variable_face,
+                font_core,
+                dpi,
+            )
+        };
+
+        self.title = shape(title, cfgfztheme.label_color, TextPitch::Variable);
+        self.lines = hints
+            .iter()
+            .map(|(key, desc)| {
+                (
+                    shape(key, cfgfztheme.match_color, TextPitch::Fixed),
+                    shape(desc, cfgfztheme.select_color, TextPitch::Variable),
+                )
+            })
+            .collect();
+
+        self.key_width = self
+            .lines
+            .iter()
+            .map(|(key, _)| key.metrics.width)
+            .max()
+            .unwrap_or(0);
+        self.height = self.title.metrics.height + cfgfztheme.edge_padding * 2;
+        for (key, desc) in &self.lines {
+            self.height += max(key.metrics.height, desc.metrics.height) + cfguifz.line_spacing * 2;
+        }
+        self.is_active = true;
+    }
+
+    pub(super) fn hide(&mut self) {
+        self.is_active = false;
+        self.lines.clear();
+    }
+}
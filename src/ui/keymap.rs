@@ -0,0 +1,186 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use glfw::{Action, Key, Modifiers, Scancode, WindowEvent};
+
+use crate::config::{CfgKeyBinding, CfgUiKeymap};
+
+/// Translates key and character events before they reach the input handlers, so that bindings
+/// resolve predictably on alternate layouts (Dvorak, Colemak, etc.)
+#[derive(Default)]
+pub(super) struct Keymap {
+    last_key: Option<(Key, Modifiers)>,
+}
+
+impl Keymap {
+    /// Translate event according to config. `text_input` should be set when characters are
+    /// inserted as text, in which case character events are left alone
+    pub(super) fn translate(
+        &mut self,
+        cfg: &CfgUiKeymap,
+        event: WindowEvent,
+        text_input: bool,
+    ) -> WindowEvent {
+        match event {
+            WindowEvent::Key(key, scancode, action, mods) => {
+                let key = keypad_key(key, mods);
+                if action != Action::Release {
+                    self.last_key = Some((key, mods));
+                }
+                if cfg.modifier_keys == CfgKeyBinding::Logical
+                    && mods.intersects(Modifiers::Control | Modifiers::Alt)
+                {
+                    if let Some(logical) = logical_key(key, scancode) {
+                        return WindowEvent::Key(logical, scancode, action, mods);
+                    }
+                }
+                WindowEvent::Key(key, scancode, action, mods)
+            }
+            WindowEvent::Char(c) if !text_input && cfg.command_keys == CfgKeyBinding::Physical => {
+                // Character events carry no key information. Use the key that was last pressed
+                let physical = self
+                    .last_key
+                    .and_then(|(key, mods)| qwerty_char(key, mods.contains(Modifiers::Shift)));
+                WindowEvent::Char(physical.unwrap_or(c))
+            }
+            e => e,
+        }
+    }
+}
+
+// Logical equivalent of keypad keys. Digits and the decimal point act as navigation keys when
+// Num Lock is off. When it is on, they are left alone, since they also produce character events
+fn keypad_key(key: Key, mods: Modifiers) -> Key {
+    if key == Key::KpEnter {
+        return Key::Enter;
+    }
+    if mods.contains(Modifiers::NumLock) {
+        return key;
+    }
+    match key {
+        Key::Kp0 => Key::Insert,
+        Key::Kp1 => Key::End,
+        Key::Kp2 => Key::Down,
+        Key::Kp3 => Key::PageDown,
+        Key::Kp4 => Key::Left,
+        Key::Kp6 => Key::Right,
+        Key::Kp7 => Key::Home,
+        Key::Kp8 => Key::Up,
+        Key::Kp9 => Key::PageUp,
+        Key::KpDecimal => Key::Delete,
+        k => k,
+    }
+}
+
+// Key for the character the given key produces in the active layout. Returns None if the layout
+// doesn't produce an ASCII letter or digit, so that the physical key is used as a fallback
+fn logical_key(key: Key, scancode: Scancode) -> Option<Key> {
+    let name = glfw::get_key_name(Some(key), Some(scancode))?;
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => key_for_char(c.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+fn key_for_char(c: char) -> Option<Key> {
+    let key = match c {
+        'a' => Key::A,
+        'b' => Key::B,
+        'c' => Key::C,
+        'd' => Key::D,
+        'e' => Key::E,
+        'f' => Key::F,
+        'g' => Key::G,
+        'h' => Key::H,
+        'i' => Key::I,
+        'j' => Key::J,
+        'k' => Key::K,
+        'l' => Key::L,
+        'm' => Key::M,
+        'n' => Key::N,
+        'o' => Key::O,
+        'p' => Key::P,
+        'q' => Key::Q,
+        'r' => Key::R,
+        's' => Key::S,
+        't' => Key::T,
+        'u' => Key::U,
+        'v' => Key::V,
+        'w' => Key::W,
+        'x' => Key::X,
+        'y' => Key::Y,
+        'z' => Key::Z,
+        '0' => Key::Num0,
+        '1' => Key::Num1,
+        '2' => Key::Num2,
+        '3' => Key::Num3,
+        '4' => Key::Num4,
+        '5' => Key::Num5,
+        '6' => Key::Num6,
+        '7' => Key::Num7,
+        '8' => Key::Num8,
+        '9' => Key::Num9,
+        _ => return None,
+    };
+    Some(key)
+}
+
+// Character produced by key on a US QWERTY layout
+fn qwerty_char(key: Key, shift: bool) -> Option<char> {
+    let (lower, upper) = match key {
+        Key::A => ('a', 'A'),
+        Key::B => ('b', 'B'),
+        Key::C => ('c', 'C'),
+        Key::D => ('d', 'D'),
+        Key::E => ('e', 'E'),
+        Key::F => ('f', 'F'),
+        Key::G => ('g', 'G'),
+        Key::H => ('h', 'H'),
+        Key::I => ('i', 'I'),
+        Key::J => ('j', 'J'),
+        Key::K => ('k', 'K'),
+        Key::L => ('l', 'L'),
+        Key::M => ('m', 'M'),
+        Key::N => ('n', 'N'),
+        Key::O => ('o', 'O'),
+        Key::P => ('p', 'P'),
+        Key::Q => ('q', 'Q'),
+        Key::R => ('r', 'R'),
+        Key::S => ('s', 'S'),
+        Key::T => ('t', 'T'),
+        Key::U => ('u', 'U'),
+        Key::V => ('v', 'V'),
+        Key::W => ('w', 'W'),
+        Key::X => ('x', 'X'),
+        Key::Y => ('y', 'Y'),
+        Key::Z => ('z', 'Z'),
+        Key::Num0 => ('0', ')'),
+        Key::Num1 => ('1', '!'),
+        Key::Num2 => ('2', '@'),
+        Key::Num3 => ('3', '#'),
+        Key::Num4 => ('4', '$'),
+        Key::Num5 => ('5', '%'),
+        Key::Num6 => ('6', '^'),
+        Key::Num7 => ('7', '&'),
+        Key::Num8 => ('8', '*'),
+        Key::Num9 => ('9', '('),
+        Key::Space => (' ', ' '),
+        Key::Apostrophe => ('\'', '"'),
+        Key::Comma => (',', '<'),
+        Key::Minus => ('-', '_'),
+        Key::Period => ('.', '>'),
+        Key::Slash => ('/', '?'),
+        Key::Semicolon => (';', ':'),
+        Key::Equal => ('=', '+'),
+        Key::LeftBracket => ('[', '{'),
+        Key::Backslash => ('\\', '|'),
+        Key::RightBracket => (']', '}'),
+        Key::GraveAccent => ('`', '~'),
+        _ => return None,
+    };
+    if shift {
+        Some(upper)
+    } else {
+        Some(lower)
+    }
+}
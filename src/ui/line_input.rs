@@ -0,0 +1,347 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cmp::{max, min};
+
+use euclid::{point2, size2, Point2D, Rect, Size2D};
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+
+use crate::font::{FaceKey, FontCore};
+use crate::types::{Color, PixelSize, TextPitch, TextSize, TextStyle, DPI};
+
+use super::context::WidgetRenderCtx;
+use super::text::{ShapedTextLine, TextCursorStyle, TextSpan};
+
+/// Font and color that a line input is shaped with
+#[derive(Clone, Copy)]
+pub(super) struct LineInputStyle {
+    pub(super) text_size: TextSize,
+    pub(super) fixed_face: FaceKey,
+    pub(super) variable_face: FaceKey,
+    pub(super) color: Color,
+}
+
+/// Single line of editable text with a cursor, for prompts and popups. Text can be selected
+/// from an anchor to the cursor, and earlier input can be recalled from a history. The owner
+/// decides where the line is drawn, and shapes it again after it changes
+pub(super) struct LineInput {
+    text: String,
+    shaped: ShapedTextLine,
+    cursor_bidx: usize,
+    cursor_gidx: usize,
+    // Selection runs from the anchor to the cursor, as byte indices
+    anchor: Option<usize>,
+    history: Vec<String>,
+    hist_idx: usize,
+}
+
+impl Default for LineInput {
+    fn default() -> LineInput {
+        LineInput {
+            text: String::new(),
+            shaped: ShapedTextLine::default(),
+            cursor_bidx: 0,
+            cursor_gidx: 0,
+            anchor: None,
+            history: Vec::new(),
+            hist_idx: 0,
+        }
+    }
+}
+
+impl LineInput {
+    pub(super) fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub(super) fn shaped(&self) -> &ShapedTextLine {
+        &self.shaped
+    }
+
+    pub(super) fn cursor_gidx(&self) -> usize {
+        self.cursor_gidx
+    }
+
+    pub(super) fn has_selection(&self) -> bool {
+        self.selection_gidx().is_some()
+    }
+
+    /// Replace the text, with the cursor at the end
+    pub(super) fn set_text(&mut self, s: &str) {
+        self.text.replace_range(.., s);
+        self.anchor = None;
+        self.hist_idx = self.history.len();
+        self.set_cursor(self.text.len());
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.set_text("");
+    }
+
+    /// Insert character at the cursor, replacing the selection if there is one
+    pub(super) fn insert(&mut self, c: char) {
+        self.delete_selection();
+        self.text.insert(self.cursor_bidx, c);
+        let bidx = next_grapheme_boundary(&self.text, self.cursor_bidx);
+        self.set_cursor(bidx);
+    }
+
+    /// Delete the selection, or the character before the cursor
+    pub(super) fn delete_left(&mut self) {
+        if self.delete_selection() || self.cursor_bidx == 0 {
+            return;
+        }
+        let cur = self.cursor_bidx;
+        let mut bidx = 0;
+        for (i, _) in self.text.char_indices() {
+            if i >= cur {
+                break;
+            }
+            bidx = i;
+        }
+        self.text.remove(bidx);
+        if !is_grapheme_boundary(&self.text, bidx) {
+            bidx = next_grapheme_boundary(&self.text, bidx);
+        }
+        self.set_cursor(bidx);
+    }
+
+    /// Delete the selection, or the grapheme after the cursor
+    pub(super) fn delete_right(&mut self) {
+        if self.delete_selection() || self.cursor_bidx == self.text.len() {
+            return;
+        }
+        let end = next_grapheme_boundary(&self.text, self.cursor_bidx);
+        self.text.replace_range(self.cursor_bidx..end, "");
+    }
+
+    /// Move the cursor a grapheme left. The selection is extended if set, and cleared otherwise
+    pub(super) fn move_left(&mut self, extend: bool) {
+        let bidx = prev_grapheme_boundary(&self.text, self.cursor_bidx);
+        self.move_to(bidx, extend);
+    }
+
+    /// Move the cursor a grapheme right. The selection is extended if set, and cleared otherwise
+    pub(super) fn move_right(&mut self, extend: bool) {
+        let bidx = next_grapheme_boundary(&self.text, self.cursor_bidx);
+        self.move_to(bidx, extend);
+    }
+
+    pub(super) fn move_to_start(&mut self, extend: bool) {
+        self.move_to(0, extend);
+    }
+
+    pub(super) fn move_to_end(&mut self, extend: bool) {
+        self.move_to(self.text.len(), extend);
+    }
+
+    /// Handle a click at x, relative to where the text is drawn. A click moves the cursor to the
+    /// closest grapheme boundary, a double click selects the word there, and a triple click
+    /// selects all text. Text before byte index start is left out, like the mode of a prompt
+    pub(super) fn click(&mut self, x: i32, count: usize, start: usize) {
+        let start = min(start, self.text.len());
+        let bidx = max(gidx_to_bidx(&self.text, self.shaped.x_gidx(x)), start);
+        match count {
+            1 => self.move_to(bidx, false),
+            2 => {
+                let (word_start, word_end) = word_at(&self.text, bidx);
+                self.anchor = Some(max(word_start, start));
+                self.set_cursor(max(word_end, start));
+            }
+            _ => {
+                self.anchor = Some(start);
+                self.set_cursor(self.text.len());
+            }
+        }
+    }
+
+    /// Save the text to the history, and reset recalling to start from the newest entry
+    pub(super) fn push_history(&mut self) {
+        self.history.push(self.text.clone());
+        self.hist_idx = self.history.len();
+    }
+
+    /// Add an entry to the history without changing the text
+    pub(super) fn add_history(&mut self, entry: String) {
+        self.history.push(entry);
+        self.hist_idx = self.history.len();
+    }
+
+    /// Stop recalling history, so that the next recall starts from the newest entry
+    pub(super) fn reset_history(&mut self) {
+        self.hist_idx = self.history.len();
+    }
+
+    /// Recall the previous history entry that starts with prefix
+    pub(super) fn history_prev(&mut self, prefix: &str) {
+        let before = &self.history[..self.hist_idx];
+        if let Some(i) = before.iter().rposition(|h| h.starts_with(prefix)) {
+            self.hist_idx = i;
+            self.recall();
+        }
+    }
+
+    /// Recall the next history entry that starts with prefix
+    pub(super) fn history_next(&mut self, prefix: &str) {
+        let start = min(self.hist_idx + 1, self.history.len());
+        if let Some(i) = self.history[start..]
+            .iter()
+            .position(|h| h.starts_with(prefix))
+        {
+            self.hist_idx = start + i;
+            self.recall();
+        }
+    }
+
+    /// Shape the text. Empty text is shaped as a space, so that it still has a height
+    pub(super) fn shape(
+        &mut self,
+        style: LineInputStyle,
+        font_core: &mut FontCore,
+        dpi: Size2D<u32, DPI>,
+    ) {
+        let text = if self.text.len() == 0 {
+            " "
+        } else {
+            &self.text
+        };
+        self.shaped = ShapedTextLine::from_textstr(
+            TextSpan::new(
+                text,
+                style.text_size,
+                TextStyle::default(),
+                style.color,
+                TextPitch::Variable,
+                None,
+            ),
+            style.fixed_face,
+            style.variable_face,
+            font_core,
+            dpi,
+        );
+    }
+
+    /// Draw the text with its baseline at pos, with the selection highlighted and a beam cursor.
+    /// Returns the position after the text
+    pub(super) fn draw(
+        &self,
+        ctx: &mut WidgetRenderCtx,
+        pos: Point2D<i32, PixelSize>,
+        font_core: &mut FontCore,
+        cursor_color: Color,
+        text_color: Color,
+        selection_color: Color,
+    ) -> Point2D<i32, PixelSize> {
+        let metrics = &self.shaped.metrics;
+        if let Some((start, end)) = self.selection_gidx() {
+            let (startx, endx) = (self.shaped.gidx_x(start), self.shaped.gidx_x(end));
+            let rect = Rect::new(
+                point2(pos.x + startx, pos.y - metrics.ascender),
+                size2(endx - startx, metrics.height as i32),
+            );
+            ctx.color_quad(rect, selection_color);
+        }
+        self.shaped.draw(
+            ctx,
+            metrics.ascender,
+            metrics.height as i32,
+            pos,
+            font_core,
+            Some((
+                self.cursor_gidx,
+                TextCursorStyle::Beam,
+                cursor_color,
+                text_color,
+            )),
+            100,
+        )
+    }
+
+    // Selected graphemes, as a start and end index
+    fn selection_gidx(&self) -> Option<(usize, usize)> {
+        let anchor = self.anchor?;
+        if anchor == self.cursor_bidx {
+            return None;
+        }
+        let start = bidx_to_gidx(&self.text, min(anchor, self.cursor_bidx));
+        let end = bidx_to_gidx(&self.text, max(anchor, self.cursor_bidx));
+        Some((start, end))
+    }
+
+    // Returns true if there was a selection to delete
+    fn delete_selection(&mut self) -> bool {
+        let anchor = match self.anchor.take() {
+            Some(anchor) if anchor != self.cursor_bidx => anchor,
+            _ => return false,
+        };
+        let (start, end) = (min(anchor, self.cursor_bidx), max(anchor, self.cursor_bidx));
+        self.text.replace_range(start..end, "");
+        self.set_cursor(start);
+        true
+    }
+
+    fn move_to(&mut self, bidx: usize, extend: bool) {
+        if !extend {
+            self.anchor = None;
+        } else if self.anchor.is_none() {
+            self.anchor = Some(self.cursor_bidx);
+        }
+        self.set_cursor(bidx);
+    }
+
+    fn set_cursor(&mut self, bidx: usize) {
+        self.cursor_bidx = bidx;
+        self.cursor_gidx = bidx_to_gidx(&self.text, bidx);
+    }
+
+    fn recall(&mut self) {
+        self.text = self.history[self.hist_idx].clone();
+        self.anchor = None;
+        self.set_cursor(self.text.len());
+    }
+}
+
+fn is_grapheme_boundary(s: &str, idx: usize) -> bool {
+    let mut gc = GraphemeCursor::new(idx, s.len(), true);
+    gc.is_boundary(s, 0).unwrap()
+}
+
+fn next_grapheme_boundary(s: &str, idx: usize) -> usize {
+    let mut gc = GraphemeCursor::new(idx, s.len(), true);
+    gc.next_boundary(s, 0).unwrap().unwrap_or(s.len())
+}
+
+fn prev_grapheme_boundary(s: &str, idx: usize) -> usize {
+    let mut gc = GraphemeCursor::new(idx, s.len(), true);
+    gc.prev_boundary(s, 0).unwrap().unwrap_or(0)
+}
+
+fn gidx_to_bidx(s: &str, gidx: usize) -> usize {
+    s.grapheme_indices(true)
+        .nth(gidx)
+        .map(|(i, _)| i)
+        .unwrap_or(s.len())
+}
+
+// Byte range of the word, or run of whitespace or punctuation, at byte index bidx. The last
+// word is used at the end of the text
+fn word_at(s: &str, bidx: usize) -> (usize, usize) {
+    let mut last = (s.len(), s.len());
+    for (i, word) in s.split_word_bound_indices() {
+        last = (i, i + word.len());
+        if bidx < i + word.len() {
+            break;
+        }
+    }
+    last
+}
+
+fn bidx_to_gidx(s: &str, bidx: usize) -> usize {
+    let mut gidx = 0;
+    for (i, _) in s.grapheme_indices(true) {
+        if i >= bidx {
+            return gidx;
+        }
+        gidx += 1;
+    }
+    gidx
+}
@@ -2,18 +2,26 @@
 
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::time::Duration;
 
 use glfw::{Glfw, OpenGlProfileHint, WindowEvent, WindowHint};
 
 use crate::core::Core;
 
+pub(crate) mod commands;
 mod context;
 mod fuzzy_popup;
 mod glyphrender;
+mod golden;
+mod image_popup;
+mod key_hints;
+mod keymap;
+mod line_input;
 mod opengl;
 mod prompt;
 mod quad;
+mod tabs;
 pub(crate) mod text;
 mod textview;
 mod textview_tree;
@@ -21,6 +29,7 @@ mod window;
 
 use crate::config::Cfg;
 use crate::font::FontCore;
+use crate::session::Session;
 use window::Window;
 
 #[derive(Clone)]
@@ -45,8 +54,27 @@ impl UICore {
         glfw.window_hint(WindowHint::Visible(false));
         glfw.window_hint(WindowHint::ContextVersion(3, 3));
         glfw.window_hint(WindowHint::OpenGlProfile(OpenGlProfileHint::Core));
+        // Load session, and move to its working directory before the core loads anything
+        // relative to it
+        let session = args
+            .value_of("session")
+            .and_then(|name| match Session::load(name) {
+                Ok(session) => Some(session),
+                Err(e) => {
+                    error!("failed to load session: {}: {}", name, e);
+                    None
+                }
+            });
+        if let Some(session) = &session {
+            if let Err(e) = std::env::set_current_dir(&session.working_directory) {
+                error!(
+                    "failed to change directory: {:?}: {}",
+                    session.working_directory, e
+                );
+            }
+        }
         // Initialize editor core
-        let core = Core::new(font_core.clone(), config.clone());
+        let core = Core::new(font_core.clone(), config.clone(), Wakeup(true));
         let first_buffer_path = args.value_of("FILE");
         // Create core and first window
         let ui_core = UICore {
@@ -61,6 +89,7 @@ impl UICore {
             ui_core.font_core.clone(),
             ui_core.config.clone(),
             first_buffer_path,
+            session.as_ref(),
             width,
             height,
             title,
@@ -68,8 +97,77 @@ impl UICore {
         (ui_core, window, events)
     }
 
-    pub(crate) fn poll_events(&mut self) {
+    /// Process events, waiting for one to arrive if none are queued. Waiting ends once the
+    /// timeout passes, if there is one, or when a background thread wakes the loop up
+    pub(crate) fn wait_events(&mut self, timeout: Option<Duration>) {
         let glfw = &mut *self.glfw.borrow_mut();
-        glfw.poll_events();
+        match timeout {
+            Some(timeout) if timeout == Duration::from_secs(0) => glfw.poll_events(),
+            Some(timeout) => glfw.wait_events_timeout(timeout.as_secs_f64()),
+            None => glfw.wait_events(),
+        }
+    }
+
+    pub(crate) fn wakeup(&self) -> Wakeup {
+        Wakeup(true)
+    }
+}
+
+/// Handle for waking the main loop up while it waits for events. It can be sent to background
+/// threads, which wake the loop up after sending it something to handle
+#[derive(Clone)]
+pub(crate) struct Wakeup(bool);
+
+impl Wakeup {
+    /// Handle that wakes nothing up, for when there is no main loop, like in batch mode
+    pub(crate) fn none() -> Wakeup {
+        Wakeup(false)
+    }
+
+    pub(crate) fn wake(&self) {
+        // GLFW allows posting an empty event from any thread
+        if self.0 {
+            unsafe { glfw::ffi::glfwPostEmptyEvent() }
+        }
+    }
+
+    /// Channel for a background thread to send results to the main loop with
+    pub(crate) fn channel<T>(&self) -> (WakingSender<T>, Receiver<T>) {
+        let (tx, rx) = channel();
+        let tx = WakingSender {
+            tx: tx,
+            wakeup: self.clone(),
+        };
+        (tx, rx)
+    }
+}
+
+/// Sending end of a channel that wakes the main loop up after every message, and once it is
+/// dropped, so that the loop also notices when the channel is closed
+pub(crate) struct WakingSender<T> {
+    tx: Sender<T>,
+    wakeup: Wakeup,
+}
+
+impl<T> WakingSender<T> {
+    pub(crate) fn send(&self, t: T) -> Result<(), SendError<T>> {
+        let ret = self.tx.send(t);
+        self.wakeup.wake();
+        ret
+    }
+}
+
+impl<T> Clone for WakingSender<T> {
+    fn clone(&self) -> WakingSender<T> {
+        WakingSender {
+            tx: self.tx.clone(),
+            wakeup: self.wakeup.clone(),
+        }
+    }
+}
+
+impl<T> Drop for WakingSender<T> {
+    fn drop(&mut self) {
+        self.wakeup.wake();
     }
 }
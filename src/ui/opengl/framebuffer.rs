@@ -41,8 +41,9 @@ impl Framebuffer {
                 rbo.id,
             );
 
+            // Nothing is drawn then, but the editor keeps running so that files can be saved
             if gl.CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
-                panic!("framebuffer is not complete");
+                error!("OpenGL framebuffer of size {} is not complete", size);
             }
             let mut ret = Framebuffer {
                 tex: tex,
@@ -78,7 +79,7 @@ impl Framebuffer {
             );
 
             if self.gl.CheckFramebufferStatus(gl::FRAMEBUFFER) != gl::FRAMEBUFFER_COMPLETE {
-                panic!("framebuffer is not complete");
+                error!("OpenGL framebuffer of size {} is not complete", size);
             }
             self.unbind();
         }
@@ -94,8 +95,47 @@ impl Framebuffer {
         self.tex.activate();
     }
 
-    pub(in crate::ui) fn get_texture(&self) -> &GlTexture<TexRGB> {
-        &self.tex
+    /// Copy the contents to the window, which has the given size
+    pub(in crate::ui) fn blit_to_window(&mut self, size: Size2D<u32, PixelSize>) {
+        let (width, height) = (size.width as i32, size.height as i32);
+        unsafe {
+            self.gl.BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo);
+            self.gl.BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0);
+            self.gl.BlitFramebuffer(
+                0,
+                0,
+                width,
+                height,
+                0,
+                0,
+                width,
+                height,
+                gl::COLOR_BUFFER_BIT,
+                gl::NEAREST,
+            );
+        }
+        self.unbind();
+    }
+
+    /// Read back the contents, which have the given size, as rows of RGBA pixels from the bottom
+    /// up
+    pub(in crate::ui) fn read_pixels(&mut self, size: Size2D<u32, PixelSize>) -> Vec<u8> {
+        let mut ret = vec![0u8; size.width as usize * size.height as usize * 4];
+        self.bind();
+        unsafe {
+            self.gl.PixelStorei(gl::PACK_ALIGNMENT, 1);
+            self.gl.ReadPixels(
+                0,
+                0,
+                size.width as i32,
+                size.height as i32,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                ret.as_mut_ptr() as *mut _,
+            );
+        }
+        self.unbind();
+        ret
     }
 
     pub(in crate::ui) fn unbind(&mut self) {
@@ -1,6 +1,8 @@
 // (C) 2019 Srimanta Barua <srimanta.barua1@gmail.com>
 
+use std::ffi::CStr;
 use std::fmt;
+use std::os::raw::c_char;
 use std::rc::Rc;
 
 use euclid::{Rect, Size2D};
@@ -15,7 +17,7 @@ mod vert_array;
 
 pub(super) use framebuffer::Framebuffer;
 pub(super) use shader::{ActiveShaderProgram, ShaderProgram};
-pub(super) use texture::{GlTexture, TexRGB, TexRed, TexUnit};
+pub(super) use texture::{GlTexture, TexRGB, TexRGBA, TexRed, TexUnit};
 pub(super) use vert_array::{ElemArr, Element};
 
 mod gl {
@@ -42,6 +44,23 @@ impl Gl {
         Gl { gl }
     }
 
+    /// GL version and renderer strings
+    pub(super) fn version_info(&self) -> (String, String) {
+        (self.get_string(gl::VERSION), self.get_string(gl::RENDERER))
+    }
+
+    fn get_string(&self, name: gl::types::GLenum) -> String {
+        unsafe {
+            let ptr = self.gl.GetString(name);
+            if ptr.is_null() {
+                return "unknown".to_owned();
+            }
+            CStr::from_ptr(ptr as *const c_char)
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+
     pub(super) fn viewport(&mut self, rect: Rect<i32, PixelSize>) {
         unsafe {
             self.gl.Viewport(
@@ -233,17 +252,17 @@ impl fmt::Display for GlErrTyp {
 }
 
 macro_rules! gl_error_check {
-    (gl:tt) => {
+    ($gl:expr) => {
         {
-            if let Some(err) = gl.get_error() {
-                panic!("OpenGL error: {}", err);
+            if let Some(err) = $gl.get_error() {
+                error!("OpenGL error: {}", err);
             }
         }
     };
-    (gl:tt, $($arg:tt)*) => {
+    ($gl:expr, $($arg:tt)*) => {
         {
-            if let Some(err) = gl.get_error() {
-                panic!("OpenGL error: {}: {}", format_args!($($arg)*), err);
+            if let Some(err) = $gl.get_error() {
+                error!("OpenGL error: {}: {}", format_args!($($arg)*), err);
             }
         }
     };
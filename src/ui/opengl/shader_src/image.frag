@@ -0,0 +1,11 @@
+#version 330 core
+
+out vec4 out_color;
+
+uniform sampler2D image;
+
+in vec2 tex_coord;
+
+void main() {
+	out_color = texture(image, tex_coord);
+}
@@ -19,6 +19,7 @@ pub(in crate::ui) enum TexUnit {
     Texture0,
     Texture1,
     Texture2,
+    Texture3,
 }
 
 impl TexUnit {
@@ -27,6 +28,7 @@ impl TexUnit {
             TexUnit::Texture0 => gl::TEXTURE0,
             TexUnit::Texture1 => gl::TEXTURE1,
             TexUnit::Texture2 => gl::TEXTURE2,
+            TexUnit::Texture3 => gl::TEXTURE3,
         }
     }
 }
@@ -43,6 +45,14 @@ impl TexFormat for TexRGB {
     }
 }
 
+pub(in crate::ui) struct TexRGBA;
+
+impl TexFormat for TexRGBA {
+    fn format() -> GLenum {
+        gl::RGBA
+    }
+}
+
 pub(in crate::ui) struct TexRed;
 
 impl TexFormat for TexRed {
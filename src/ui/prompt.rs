@@ -1,30 +1,31 @@
 // (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::rc::Rc;
 
 use euclid::{point2, size2, Rect, SideOffsets2D, Size2D};
-use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
 
 use crate::config::Cfg;
 use crate::font::FontCore;
+use crate::messages::{Message, Severity};
 use crate::types::{PixelSize, TextPitch, TextStyle, DPI};
 
 use super::context::ActiveRenderCtx;
-use super::text::{ShapedTextLine, TextCursorStyle, TextSpan};
+use super::line_input::{LineInput, LineInputStyle};
+use super::text::{ShapedTextLine, TextSpan};
 
+/// Line input for commands and searches, in a bar along the bottom of the window. While the
+/// prompt isn't active, the bar shows the latest message
 pub(super) struct Prompt {
     is_active: bool,
     window_rect: Rect<u32, PixelSize>,
     height: u32,
     font_core: Rc<RefCell<FontCore>>,
     config: Rc<RefCell<Cfg>>,
-    history: Vec<String>,
-    hist_idx: usize,
-    buffer: String,
-    shaped: ShapedTextLine,
-    cursor_bidx: usize,
-    cursor_gidx: usize,
+    input: LineInput,
+    message: Option<Message>,
+    shaped_message: ShapedTextLine,
     dpi: Size2D<u32, DPI>,
 }
 
@@ -41,12 +42,9 @@ impl Prompt {
             font_core: font_core,
             config: config,
             is_active: false,
-            history: vec![],
-            hist_idx: 0,
-            buffer: String::new(),
-            shaped: ShapedTextLine::default(),
-            cursor_bidx: 0,
-            cursor_gidx: 0,
+            input: LineInput::default(),
+            message: None,
+            shaped_message: ShapedTextLine::default(),
             dpi: dpi,
         };
         ret.refresh();
@@ -55,145 +53,185 @@ impl Prompt {
 
     pub(super) fn draw(&mut self, actx: &mut ActiveRenderCtx) {
         let cfg = &*self.config.borrow();
-        let cfguipr = &cfg.ui.fuzzy;
-        let cfgprtheme = &cfg.ui.theme().fuzzy;
+        let cfgprtheme = &cfg.ui.theme().prompt;
 
-        let width = (self.window_rect.size.width * cfguipr.width_percentage) / 100;
-        let lpad = (self.window_rect.size.width - width) / 2;
-        let origin = point2(
-            self.window_rect.origin.x + lpad,
-            self.window_rect.origin.y + self.window_rect.size.height
-                - self.height
-                - cfguipr.bottom_offset,
-        );
-        let size = size2(width, self.height);
+        let rect = self.bar_rect();
         let side_offsets = SideOffsets2D::new(
             cfgprtheme.edge_padding,
             cfgprtheme.edge_padding,
             cfgprtheme.edge_padding,
             cfgprtheme.edge_padding,
         );
-        let rect = Rect::new(origin, size);
         let inner_rect = rect.inner_rect(side_offsets);
 
         {
-            let size = size2(rect.size.width + 3, rect.size.height + 3);
-            let shadow_rect = Rect::new(rect.origin, size);
-            actx.draw_shadow(shadow_rect.cast());
             let _ctx = actx.get_widget_context(rect.cast(), cfgprtheme.background_color);
         }
-
+        // An inactive bar shows the latest message, if any
         let font_core = &mut *self.font_core.borrow_mut();
+        if !self.is_active {
+            if self.message.is_some() {
+                let mut ctx =
+                    actx.get_widget_context(inner_rect.cast(), cfgprtheme.background_color);
+                let metrics = &self.shaped_message.metrics;
+                let pos = point2(0, inner_rect.size.height as i32 + metrics.descender);
+                self.shaped_message.draw(
+                    &mut ctx,
+                    metrics.ascender,
+                    metrics.height as i32,
+                    pos,
+                    font_core,
+                    None,
+                    100,
+                );
+            }
+            return;
+        }
+
         let mut ctx = actx.get_widget_context(inner_rect.cast(), cfgprtheme.background_color);
         let mut pos = point2(0, inner_rect.size.height as i32);
-        pos.y += self.shaped.metrics.descender;
+        pos.y += self.input.shaped().metrics.descender;
 
         // Draw buffer
-        self.shaped.draw(
+        self.input.draw(
             &mut ctx,
-            self.shaped.metrics.ascender,
-            self.shaped.metrics.height as i32,
             pos,
             font_core,
-            Some((
-                self.cursor_gidx,
-                TextCursorStyle::Beam,
-                cfgprtheme.cursor_color,
-                cfgprtheme.foreground_color,
-            )),
-            100,
+            cfgprtheme.cursor_color,
+            cfgprtheme.foreground_color,
+            cfg.ui.theme().fuzzy.select_background_color,
         );
     }
 
+    /// Handle a click at point, in window coordinates, with count for double and triple clicks.
+    /// Returns false if the point isn't on the active prompt
+    pub(super) fn click(&mut self, point: (i32, i32), count: usize) -> bool {
+        let rect = self.bar_rect().cast::<i32>();
+        if !self.is_active || !rect.contains(point2(point.0, point.1)) {
+            return false;
+        }
+        let padding = self.config.borrow().ui.theme().prompt.edge_padding as i32;
+        let start = self.mode_prefix().len();
+        self.input
+            .click(point.0 - rect.origin.x - padding, count, start);
+        true
+    }
+
+    fn bar_rect(&self) -> Rect<u32, PixelSize> {
+        let height = min(self.height, self.window_rect.size.height);
+        let origin = point2(
+            self.window_rect.origin.x,
+            self.window_rect.origin.y + self.window_rect.size.height - height,
+        );
+        Rect::new(origin, size2(self.window_rect.size.width, height))
+    }
+
+    /// Height of the bar at the bottom of the window, or 0 if it is hidden
+    pub(super) fn bar_height(&self) -> u32 {
+        if self.is_active || self.message.is_some() || self.config.borrow().ui.prompt.always_visible
+        {
+            self.height
+        } else {
+            0
+        }
+    }
+
     pub(super) fn set_window_rect(&mut self, window_rect: Rect<u32, PixelSize>) {
         self.window_rect = window_rect;
     }
 
-    pub(super) fn is_active(&self) -> bool {
-        self.is_active
+    pub(super) fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.dpi = dpi;
+        self.refresh();
     }
 
     pub(super) fn set_active(&mut self, val: bool) {
         self.is_active = val;
-        self.hist_idx = self.history.len();
+        self.input.reset_history();
+    }
+
+    /// Show a message while the prompt isn't active, or hide it
+    pub(super) fn set_message(&mut self, message: Option<Message>) {
+        if message.is_none() && self.message.is_none() {
+            return;
+        }
+        self.message = message;
+        self.refresh();
     }
 
     pub(super) fn set_string(&mut self, s: &str) {
-        self.buffer.replace_range(.., s);
-        self.cursor_bidx = s.len();
-        self.cursor_gidx = bidx_to_gidx(&self.buffer, self.cursor_bidx);
-        self.hist_idx = self.history.len();
+        self.input.set_text(s);
         self.refresh();
     }
 
     pub(super) fn push_to_history(&mut self) {
-        self.history.push(self.buffer.clone());
-        self.hist_idx = self.history.len();
+        self.input.push_history();
+    }
+
+    /// Add an earlier input to the history, such as a search pattern from an earlier session
+    pub(super) fn add_to_history(&mut self, s: String) {
+        self.input.add_history(s);
     }
 
     pub(super) fn get_string(&self) -> &str {
-        &self.buffer
+        self.input.text()
     }
 
+    // Commands and searches share the history, so only entries for the prompt's mode are
+    // recalled
     pub(super) fn up_key(&mut self) {
-        if self.hist_idx > 0 {
-            self.hist_idx -= 1;
-            self.buffer = self.history[self.hist_idx].clone();
-            self.cursor_bidx = self.buffer.len();
-            self.cursor_gidx = bidx_to_gidx(&self.buffer, self.cursor_bidx);
-            self.refresh();
-        }
+        let mode = self.mode_prefix();
+        self.input.history_prev(&mode);
+        self.refresh();
     }
 
     pub(super) fn down_key(&mut self) {
-        if self.hist_idx + 1 < self.history.len() {
-            self.hist_idx += 1;
-            self.buffer = self.history[self.hist_idx].clone();
-            self.cursor_bidx = self.buffer.len();
-            self.cursor_gidx = bidx_to_gidx(&self.buffer, self.cursor_bidx);
-            self.refresh();
-        }
+        let mode = self.mode_prefix();
+        self.input.history_next(&mode);
+        self.refresh();
+    }
+
+    fn mode_prefix(&self) -> String {
+        self.input.text().chars().take(1).collect()
     }
 
-    pub(super) fn left_key(&mut self) {
-        let i = prev_grapheme_boundary(&self.buffer, self.cursor_bidx);
-        if i > 0 {
-            self.cursor_bidx = i;
-            self.cursor_gidx = bidx_to_gidx(&self.buffer, self.cursor_bidx);
+    // The first character is the prompt's mode, like ':' or '/', so the cursor stays after it
+    pub(super) fn left_key(&mut self, extend: bool) {
+        if self.input.cursor_gidx() > 1 {
+            self.input.move_left(extend);
         }
     }
 
-    pub(super) fn right_key(&mut self) {
-        let i = next_grapheme_boundary(&self.buffer, self.cursor_bidx);
-        self.cursor_bidx = i;
-        self.cursor_gidx = bidx_to_gidx(&self.buffer, self.cursor_bidx);
+    pub(super) fn right_key(&mut self, extend: bool) {
+        self.input.move_right(extend);
+    }
+
+    pub(super) fn home_key(&mut self, extend: bool) {
+        self.input.move_to_start(extend);
+        self.input.move_right(extend);
+    }
+
+    pub(super) fn end_key(&mut self, extend: bool) {
+        self.input.move_to_end(extend);
     }
 
     pub(super) fn insert(&mut self, c: char) {
-        self.buffer.insert(self.cursor_bidx, c);
-        self.cursor_bidx = next_grapheme_boundary(&self.buffer, self.cursor_bidx);
-        self.cursor_gidx = bidx_to_gidx(&self.buffer, self.cursor_bidx);
+        self.input.insert(c);
         self.refresh();
     }
 
+    // The mode at the start is only deleted once nothing follows it, which closes the prompt
     pub(super) fn delete_left(&mut self) {
-        if self.cursor_bidx == 0 {
+        let at_mode = self.input.cursor_gidx() <= 1 && !self.input.has_selection();
+        if at_mode && self.input.text().len() > self.mode_prefix().len() {
             return;
         }
-        let cur = self.cursor_bidx;
-        self.cursor_bidx = 0;
-        for (i, _) in self.buffer.char_indices() {
-            if i >= cur {
-                break;
-            }
-            self.cursor_bidx = i;
-        }
-        self.buffer.remove(self.cursor_bidx);
-        let buf = &self.buffer;
-        if !is_grapheme_boundary(&self.buffer, self.cursor_bidx) {
-            self.cursor_bidx = next_grapheme_boundary(buf, self.cursor_bidx);
-        }
-        self.cursor_gidx = bidx_to_gidx(buf, self.cursor_bidx);
+        self.input.delete_left();
+        self.refresh();
+    }
+
+    pub(super) fn delete_right(&mut self) {
+        self.input.delete_right();
         self.refresh();
     }
 
@@ -202,29 +240,27 @@ impl Prompt {
         let cfguipr = &cfg.ui.prompt;
         let cfgprtheme = &cfg.ui.theme().prompt;
         let font_core = &mut *self.font_core.borrow_mut();
-
-        self.shaped = if self.buffer.len() == 0 {
-            ShapedTextLine::from_textstr(
-                TextSpan::new(
-                    " ",
-                    cfguipr.text_size,
-                    TextStyle::default(),
-                    cfgprtheme.foreground_color,
-                    TextPitch::Variable,
-                    None,
-                ),
-                cfguipr.fixed_face,
-                cfguipr.variable_face,
-                font_core,
-                self.dpi,
-            )
-        } else {
-            ShapedTextLine::from_textstr(
+        let style = LineInputStyle {
+            text_size: cfguipr.text_size,
+            fixed_face: cfguipr.fixed_face,
+            variable_face: cfguipr.variable_face,
+            color: cfgprtheme.foreground_color,
+        };
+        self.input.shape(style, font_core, self.dpi);
+        self.height = self.input.shaped().metrics.height + cfgprtheme.edge_padding * 2;
+        // Only the first line of a message fits in the bar
+        if let Some(message) = &self.message {
+            let color = match message.severity {
+                Severity::Info => cfgprtheme.foreground_color,
+                Severity::Warning => cfgprtheme.warning_color,
+                Severity::Error => cfgprtheme.error_color,
+            };
+            self.shaped_message = ShapedTextLine::from_textstr(
                 TextSpan::new(
-                    &self.buffer,
+                    message.text.lines().next().unwrap_or(" "),
                     cfguipr.text_size,
                     TextStyle::default(),
-                    cfgprtheme.foreground_color,
+                    color,
                     TextPitch::Variable,
                     None,
                 ),
@@ -232,34 +268,7 @@ impl Prompt {
                 cfguipr.variable_face,
                 font_core,
                 self.dpi,
-            )
-        };
-        self.height = self.shaped.metrics.height + cfgprtheme.edge_padding * 2;
-    }
-}
-
-fn is_grapheme_boundary(s: &str, idx: usize) -> bool {
-    let mut gc = GraphemeCursor::new(idx, s.len(), true);
-    gc.is_boundary(s, 0).unwrap()
-}
-
-fn next_grapheme_boundary(s: &str, idx: usize) -> usize {
-    let mut gc = GraphemeCursor::new(idx, s.len(), true);
-    gc.next_boundary(s, 0).unwrap().unwrap_or(s.len())
-}
-
-fn prev_grapheme_boundary(s: &str, idx: usize) -> usize {
-    let mut gc = GraphemeCursor::new(idx, s.len(), true);
-    gc.prev_boundary(s, 0).unwrap().unwrap_or(0)
-}
-
-fn bidx_to_gidx(s: &str, bidx: usize) -> usize {
-    let mut gidx = 0;
-    for (i, _) in s.grapheme_indices(true) {
-        if i >= bidx {
-            return gidx;
+            );
         }
-        gidx += 1;
     }
-    gidx
 }
@@ -0,0 +1,250 @@
+// (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::path::Path;
+use std::rc::Rc;
+
+use euclid::{point2, size2, Rect, Size2D};
+
+use crate::config::Cfg;
+use crate::font::FontCore;
+use crate::textbuffer::Buffer;
+use crate::types::{PixelSize, TextPitch, TextStyle, DPI};
+
+use super::context::ActiveRenderCtx;
+use super::text::{ShapedTextLine, TextSpan};
+use super::textview_tree::TextViewTree;
+
+/// Tab pages, each with its own tree of panes, and the bar listing them along the top of the
+/// window. The window holds the tree of the active tab, and the others are kept here
+pub(super) struct Tabs {
+    // Trees of the other tabs, in order. The active tab would be at index active
+    others: Vec<TextViewTree>,
+    active: usize,
+    window_rect: Rect<u32, PixelSize>,
+    height: u32,
+    // Horizontal extent of each tab in the bar, in window coordinates, as of the last draw
+    extents: Vec<(i32, i32)>,
+    font_core: Rc<RefCell<FontCore>>,
+    config: Rc<RefCell<Cfg>>,
+    dpi: Size2D<u32, DPI>,
+}
+
+impl Tabs {
+    pub(super) fn new(
+        window_rect: Rect<u32, PixelSize>,
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        dpi: Size2D<u32, DPI>,
+    ) -> Tabs {
+        let mut ret = Tabs {
+            others: Vec::new(),
+            active: 0,
+            window_rect: window_rect,
+            height: 0,
+            extents: Vec::new(),
+            font_core: font_core,
+            config: config,
+            dpi: dpi,
+        };
+        ret.refresh();
+        ret
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.others.len() + 1
+    }
+
+    pub(super) fn active(&self) -> usize {
+        self.active
+    }
+
+    /// Open tab showing tree after the active one, and make it active. current is the tree of the
+    /// active tab, which is swapped for the new one
+    pub(super) fn open(&mut self, current: &mut TextViewTree, tree: TextViewTree) {
+        let old = std::mem::replace(current, tree);
+        self.others.insert(self.active, old);
+        self.active += 1;
+    }
+
+    /// Make tab at index active, swapping its tree with current
+    pub(super) fn switch_to(&mut self, current: &mut TextViewTree, idx: usize) {
+        if idx == self.active || idx >= self.len() {
+            return;
+        }
+        let i = if idx > self.active { idx - 1 } else { idx };
+        std::mem::swap(current, &mut self.others[i]);
+        let old = self.others.remove(i);
+        let pos = if idx < self.active {
+            self.active - 1
+        } else {
+            self.active
+        };
+        self.others.insert(pos, old);
+        self.active = idx;
+    }
+
+    /// Close the active tab, replacing current with the tree of the tab after it, or the one
+    /// before it if it was the last. Returns false if it is the only tab
+    pub(super) fn close(&mut self, current: &mut TextViewTree) -> bool {
+        if self.others.is_empty() {
+            return false;
+        }
+        if self.active == self.others.len() {
+            self.active -= 1;
+        }
+        *current = self.others.remove(self.active);
+        true
+    }
+
+    /// Trees of tabs other than the active one
+    pub(super) fn others_mut(&mut self) -> &mut [TextViewTree] {
+        &mut self.others
+    }
+
+    /// Buffers shown in panes of tabs other than the active one
+    pub(super) fn visible_buffers(&self) -> Vec<Rc<RefCell<Buffer>>> {
+        let mut ret = Vec::new();
+        for tree in &self.others {
+            for buffer in tree.visible_buffers() {
+                if !ret.iter().any(|b| Rc::ptr_eq(b, &buffer)) {
+                    ret.push(buffer);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Height of the bar at the top of the window. The bar is hidden if there is only one tab
+    pub(super) fn bar_height(&self) -> u32 {
+        if self.others.is_empty() {
+            0
+        } else {
+            min(self.height, self.window_rect.size.height)
+        }
+    }
+
+    pub(super) fn set_window_rect(&mut self, window_rect: Rect<u32, PixelSize>) {
+        self.window_rect = window_rect;
+    }
+
+    pub(super) fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.dpi = dpi;
+        for tree in &mut self.others {
+            tree.set_dpi(dpi);
+        }
+        self.refresh();
+    }
+
+    /// Index of the tab at point, in window coordinates
+    pub(super) fn tab_at(&self, point: (i32, i32)) -> Option<usize> {
+        let rect = self.bar_rect().cast::<i32>();
+        if self.bar_height() == 0 || !rect.contains(point2(point.0, point.1)) {
+            return None;
+        }
+        self.extents
+            .iter()
+            .position(|(start, end)| point.0 >= *start && point.0 < *end)
+    }
+
+    pub(super) fn draw(&mut self, actx: &mut ActiveRenderCtx, current: &mut TextViewTree) {
+        let cfg = &*self.config.borrow();
+        let cfgsl = &cfg.ui.statusline;
+        let cfgthemesl = &cfg.ui.theme().statusline;
+        let font_core = &mut *self.font_core.borrow_mut();
+
+        let rect = self.bar_rect();
+        let mut ctx = actx.get_widget_context(rect.cast(), cfgthemesl.background_color);
+        let mut x = cfgsl.padding as i32;
+        self.extents.clear();
+        for i in 0..self.len() {
+            let tree = if i < self.active {
+                &mut self.others[i]
+            } else if i == self.active {
+                &mut *current
+            } else {
+                &mut self.others[i - 1]
+            };
+            let label = format!("{}: {}", i + 1, tab_name(tree));
+            let (color, opacity) = if i == self.active {
+                (cfgthemesl.mode_color, 100)
+            } else {
+                (cfgthemesl.foreground_color, cfgthemesl.inactive_opacity)
+            };
+            let shaped = ShapedTextLine::from_textstr(
+                TextSpan::new(
+                    &label,
+                    cfgsl.text_size,
+                    TextStyle::default(),
+                    color,
+                    TextPitch::Variable,
+                    None,
+                ),
+                cfgsl.fixed_face,
+                cfgsl.variable_face,
+                font_core,
+                self.dpi,
+            );
+            let ascender = shaped.metrics.ascender;
+            let height = shaped.metrics.height as i32;
+            let baseline = point2(x, cfgsl.padding as i32 + ascender);
+            shaped.draw(
+                &mut ctx, ascender, height, baseline, font_core, None, opacity,
+            );
+            let start = rect.origin.x as i32 + x - cfgsl.padding as i32;
+            x += shaped.metrics.width as i32 + cfgsl.padding as i32 * 2;
+            self.extents
+                .push((start, rect.origin.x as i32 + x - cfgsl.padding as i32));
+        }
+    }
+
+    fn bar_rect(&self) -> Rect<u32, PixelSize> {
+        Rect::new(
+            self.window_rect.origin,
+            size2(self.window_rect.size.width, self.bar_height()),
+        )
+    }
+
+    // The bar is as tall as the statusline
+    fn refresh(&mut self) {
+        let cfg = &*self.config.borrow();
+        let cfgsl = &cfg.ui.statusline;
+        let font_core = &mut *self.font_core.borrow_mut();
+        let shaped = ShapedTextLine::from_textstr(
+            TextSpan::new(
+                "1",
+                cfgsl.text_size,
+                TextStyle::default(),
+                cfg.ui.theme().statusline.foreground_color,
+                TextPitch::Variable,
+                None,
+            ),
+            cfgsl.fixed_face,
+            cfgsl.variable_face,
+            font_core,
+            self.dpi,
+        );
+        self.height = shaped.metrics.height + cfgsl.padding * 2;
+    }
+}
+
+// Name of the buffer in the active pane of a tab
+fn tab_name(tree: &mut TextViewTree) -> String {
+    let buffer = tree.active_mut().buffer();
+    let buffer = &*buffer.borrow();
+    let name = match buffer.path() {
+        _ if buffer.is_scratch() => buffer.title().unwrap_or("[scratch]").to_owned(),
+        Some(path) => Path::new(path)
+            .file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or(path)
+            .to_owned(),
+        None => "[no name]".to_owned(),
+    };
+    if buffer.is_dirty() {
+        format!("{} [+]", name)
+    } else {
+        name
+    }
+}
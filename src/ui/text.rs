@@ -1,6 +1,7 @@
 // (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
 
 use std::cmp::min;
+use std::mem::size_of;
 
 use euclid::{point2, size2, Point2D, Rect, Size2D};
 use unicode_segmentation::UnicodeSegmentation;
@@ -223,9 +224,14 @@ impl<'a> Iterator for ShapedClusterIter<'a> {
         if self.cpi == self.cursor_positions.len() || self.gii == self.glyph_infos.len() {
             return None;
         }
+        // Glyphs are grouped until one that starts a grapheme. Characters have clusters of
+        // their own, so those of combining marks are grouped with their base
         let mut i = self.gii + 1;
         while i < self.glyph_infos.len()
-            && self.glyph_infos[i].cluster == self.glyph_infos[self.gii].cluster
+            && (self.glyph_infos[i].cluster == self.glyph_infos[self.gii].cluster
+                || self.cursor_positions[self.cpi + 1..]
+                    .binary_search(&(self.glyph_infos[i].cluster as usize))
+                    .is_err())
         {
             i += 1;
         }
@@ -261,6 +267,28 @@ pub(super) struct ShapedCluster<'a> {
     pub(super) glyph_infos: &'a [harfbuzz::GlyphInfo],
 }
 
+impl<'a> ShapedCluster<'a> {
+    pub(super) fn width(&self) -> i32 {
+        self.glyph_infos.iter().map(|gi| gi.advance.width).sum()
+    }
+
+    // Horizontal offset of the start of the i'th grapheme from the start of the cluster. If the
+    // glyphs can be split evenly among the graphemes, each grapheme spans its own glyphs.
+    // Otherwise, as for a ligature, the graphemes share the width evenly
+    pub(super) fn grapheme_x(&self, i: usize) -> i32 {
+        let num_glyphs = self.glyph_infos.len();
+        if num_glyphs % self.num_graphemes == 0 {
+            let end = i * num_glyphs / self.num_graphemes;
+            self.glyph_infos[..end]
+                .iter()
+                .map(|gi| gi.advance.width)
+                .sum()
+        } else {
+            self.width() * i as i32 / self.num_graphemes as i32
+        }
+    }
+}
+
 #[derive(Debug, Default, Eq, PartialEq)]
 pub(super) struct ShapedTextLineMetrics {
     pub(super) ascender: i32,
@@ -273,9 +301,54 @@ pub(super) struct ShapedTextLineMetrics {
 pub(crate) struct ShapedTextLine {
     pub(super) metrics: ShapedTextLineMetrics,
     pub(super) spans: Vec<ShapedTextSpan>,
+    // Hash of the highlighted text, for lines of a buffer. Lines are only re-shaped if it changes
+    key: u64,
 }
 
 impl ShapedTextLine {
+    /// Approximate memory used by this line, in bytes
+    pub(crate) fn approx_size(&self) -> usize {
+        let spans_size: usize = self
+            .spans
+            .iter()
+            .map(|span| {
+                size_of::<ShapedTextSpan>()
+                    + span.cursor_positions.capacity() * size_of::<usize>()
+                    + span.glyph_infos.capacity() * size_of::<harfbuzz::GlyphInfo>()
+            })
+            .sum();
+        size_of::<ShapedTextLine>() + spans_size
+    }
+
+    /// Line as tall as this one, but without glyphs. These stand in for lines of a buffer that
+    /// haven't been shaped yet, so that the view can still be laid out. key is the hash of the
+    /// highlighted text of the line
+    pub(crate) fn placeholder(&self, key: u64) -> ShapedTextLine {
+        ShapedTextLine {
+            metrics: ShapedTextLineMetrics {
+                ascender: self.metrics.ascender,
+                descender: self.metrics.descender,
+                height: self.metrics.height,
+                width: 0,
+            },
+            spans: Vec::new(),
+            key: key,
+        }
+    }
+
+    pub(crate) fn key(&self) -> u64 {
+        self.key
+    }
+
+    pub(crate) fn set_key(&mut self, key: u64) {
+        self.key = key;
+    }
+
+    /// Shaped lines always have a span, even if the line is empty
+    pub(crate) fn is_placeholder(&self) -> bool {
+        self.spans.is_empty()
+    }
+
     pub(crate) fn from_textline(
         line: TextLine,
         fixed_face: FaceKey,
@@ -311,6 +384,7 @@ impl ShapedTextLine {
         ShapedTextLine {
             spans: spans,
             metrics: metrics,
+            key: 0,
         }
     }
 
@@ -346,10 +420,69 @@ impl ShapedTextLine {
         ShapedTextLine {
             spans: spans,
             metrics: metrics,
+            key: 0,
+        }
+    }
+
+    // Horizontal offset of the start of grapheme from the start of the line
+    pub(super) fn gidx_x(&self, gidx: usize) -> i32 {
+        let (mut x, mut grapheme) = (0, 0);
+        for span in self.spans.iter() {
+            for cluster in span.clusters() {
+                if gidx < grapheme + cluster.num_graphemes {
+                    return x + cluster.grapheme_x(gidx - grapheme);
+                }
+                x += cluster.width();
+                grapheme += cluster.num_graphemes;
+            }
+        }
+        x
+    }
+
+    // Index of the grapheme boundary closest to x
+    pub(super) fn x_gidx(&self, x: i32) -> usize {
+        let (mut start, mut grapheme) = (0, 0);
+        for span in self.spans.iter() {
+            for cluster in span.clusters() {
+                let width = cluster.width();
+                if x < start + width && width > 0 {
+                    let i = (0..=cluster.num_graphemes)
+                        .min_by_key(|i| (start + cluster.grapheme_x(*i) - x).abs())
+                        .unwrap();
+                    return grapheme + i;
+                }
+                start += width;
+                grapheme += cluster.num_graphemes;
+            }
         }
+        grapheme
     }
 
     pub(super) fn draw(
+        &self,
+        ctx: &mut WidgetRenderCtx,
+        ascender: i32,
+        height: i32,
+        baseline: Point2D<i32, PixelSize>,
+        font_core: &mut FontCore,
+        cursor: Option<(usize, TextCursorStyle, Color, Color)>,
+        opacity: u8,
+    ) -> Point2D<i32, PixelSize> {
+        self.draw_colored(
+            ctx,
+            ascender,
+            height,
+            baseline,
+            font_core,
+            cursor,
+            opacity,
+            &[],
+        )
+    }
+
+    /// Draw line, overriding text color in the given grapheme ranges. Later ranges take
+    /// precedence over earlier ones
+    pub(super) fn draw_colored(
         &self,
         ctx: &mut WidgetRenderCtx,
         ascender: i32,
@@ -358,7 +491,16 @@ impl ShapedTextLine {
         font_core: &mut FontCore,
         cursor: Option<(usize, TextCursorStyle, Color, Color)>,
         opacity: u8,
+        colors: &[(usize, usize, Color)],
     ) -> Point2D<i32, PixelSize> {
+        let color_at = |grapheme: usize, default: Color| {
+            colors
+                .iter()
+                .rev()
+                .find(|(start, end, _)| grapheme >= *start && grapheme < *end)
+                .map(|(_, _, color)| *color)
+                .unwrap_or(default)
+        };
         let mut grapheme = 0;
         let mut block_cursor_width = 10;
         let mut underline_y = baseline.y;
@@ -376,7 +518,7 @@ impl ShapedTextLine {
                         let glyph_color = if style == TextCursorStyle::Block {
                             cursor_text_color.opacity(opacity)
                         } else {
-                            span.color.opacity(opacity)
+                            color_at(grapheme, span.color).opacity(opacity)
                         };
                         let num_glyphs = cluster.glyph_infos.len();
                         if num_glyphs % cluster.num_graphemes != 0 {
@@ -393,9 +535,9 @@ impl ShapedTextLine {
                                 );
                                 baseline.x += gi.advance.width;
                             }
-                            let width = baseline.x - startx;
-                            let grapheme_width = width / cluster.num_graphemes as i32;
-                            let cursor_x = startx + ((gidx - grapheme) as i32) * grapheme_width;
+                            let offset = cluster.grapheme_x(gidx - grapheme);
+                            let grapheme_width = cluster.grapheme_x(gidx - grapheme + 1) - offset;
+                            let cursor_x = startx + offset;
                             let (cursor_y, cursor_size) = match style {
                                 TextCursorStyle::Beam => (baseline.y - ascender, size2(2, height)),
                                 TextCursorStyle::Block => {
@@ -454,7 +596,7 @@ impl ShapedTextLine {
                         continue;
                     }
                 }
-                let glyph_col = span.color.opacity(opacity);
+                let glyph_col = color_at(grapheme, span.color).opacity(opacity);
                 for gi in cluster.glyph_infos {
                     ctx.glyph(
                         baseline + gi.offset,
@@ -3,22 +3,49 @@
 use std::cell::RefCell;
 use std::cmp::{max, min};
 use std::io::Result as IOResult;
+use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
 
 use euclid::{point2, size2, Rect, Size2D};
 
+use crate::annotations::AnnotationKind;
 use crate::config::Cfg;
+use crate::decorations::DecorationStyle;
+use crate::encoding::Encoding;
 use crate::font::FontCore;
-use crate::textbuffer::{Buffer, BufferCursor};
-use crate::types::{PixelSize, DPI};
+use crate::gutter::ShapedGutter;
+use crate::search::Searcher;
+use crate::session::{SessionNode, SessionView};
+use crate::textbuffer::{Buffer, BufferCursor, LineChange};
+use crate::types::{PixelSize, TextPitch, TextStyle, DPI};
 
 use super::context::ActiveRenderCtx;
-use super::text::{ShapedTextLine, TextCursorStyle};
-
-const M: f64 = 0.5;
-const G: f64 = 9.8;
-const COEFF: f64 = 0.3;
-const FRICTION_A: f64 = M * G * COEFF;
+use super::text::{ShapedTextLine, TextCursorStyle, TextLine, TextSpan};
+
+// Scrolling stops once it slows below this many pixels per second
+const MIN_SCROLL_SPEED: f64 = 5.0;
+
+// Lines above and below the view that are shaped ahead of scrolling to them
+const SHAPE_MARGIN_LINES: usize = 32;
+// Width of the bar drawn in the gutter next to lines with notes
+const NOTE_SIGN_WIDTH: i32 = 3;
+// Size of signs along the right edge of the gutter for changed lines. Deleted lines are shown
+// as a short bar at the top of the line below them
+const CHANGE_SIGN_WIDTH: i32 = 3;
+const DELETED_SIGN_HEIGHT: i32 = 3;
+
+// Height of the bar drawn under underline decorations
+const UNDERLINE_HEIGHT: i32 = 3;
+// Size of annotation tick marks at the right edge of the view
+const TICK_WIDTH: i32 = 6;
+const TICK_HEIGHT: i32 = 2;
+// Scrollbar thumbs are at least this tall, so that they can be grabbed in long files
+const MIN_THUMB_HEIGHT: i32 = 16;
+// Height of the bar flashed where lines were deleted
+const DELETE_FLASH_HEIGHT: i32 = 3;
+// Width of the rule drawn at the color column
+const COLOR_COLUMN_WIDTH: i32 = 2;
 
 #[derive(Clone)]
 struct View {
@@ -29,20 +56,84 @@ struct View {
     relative_number: bool,
     buffer: Rc<RefCell<Buffer>>,
     cursor: BufferCursor,
+    id: usize,
+}
+
+// Lines highlighted for a moment after they were yanked, or where lines were deleted
+#[derive(Clone)]
+struct Flash {
+    start_line: usize,
+    end_line: usize,
+    // Deleted lines are gone, so a bar is drawn above start_line instead
+    deleted: bool,
+    elapsed: Duration,
+}
+
+// Selected text runs from the anchor to the cursor, including the graphemes under both.
+// Linewise selections cover whole lines
+#[derive(Clone, Copy)]
+struct Selection {
+    anchor: (usize, usize),
+    linewise: bool,
 }
 
 #[derive(Clone)]
 pub(super) struct TextView {
     views: Vec<View>,
     cur_view_idx: usize,
+    // View shown before the current one, for toggling back to it
+    alt_view_idx: Option<usize>,
     rect: Rect<u32, PixelSize>,
+    // Area for text and gutter, above the statusline
+    text_rect: Rect<u32, PixelSize>,
     line_numbers: bool,
     relative_number: bool,
     dpi: Size2D<u32, DPI>,
+    // Scroll velocity in pixels per second, and the part of a pixel scrolled but not yet applied
     scroll_v: (f64, f64),
+    scroll_rem: (f64, f64),
     font_core: Rc<RefCell<FontCore>>,
     config: Rc<RefCell<Cfg>>,
     cursor_style: TextCursorStyle,
+    // Identifies the pane, so that other panes can follow it
+    id: usize,
+    // Pane this one mirrors, if it is following another pane
+    leader: Option<usize>,
+    flash: Option<Flash>,
+    selection: Option<Selection>,
+    // Whether the view changed on its own, through a scroll or flash animation, since it was last
+    // drawn
+    damaged: bool,
+    // Height of lines as a multiple of the height of their text
+    line_height: f64,
+    presentation: Option<Presentation>,
+    // Largest number in the gutter when it only has relative numbers, found when it is shaped
+    relative_widest: usize,
+    // Column a rule is drawn after, if any
+    color_column: Option<usize>,
+    // Whether the line with the cursor is highlighted
+    cursor_line: bool,
+}
+
+// What presentation mode changed, to restore it afterwards
+#[derive(Clone)]
+struct Presentation {
+    // DPI of the window. Text is shaped at a multiple of it while presenting
+    dpi: Size2D<u32, DPI>,
+    line_numbers: bool,
+    relative_number: bool,
+    // Line number settings of each view, by view ID
+    views: Vec<(usize, bool, bool)>,
+}
+
+/// What a pane is showing, copied over to panes following it
+pub(super) struct Viewport {
+    buffer: Rc<RefCell<Buffer>>,
+    start_line: usize,
+    xbase: u32,
+    ybase: u32,
+    cursor: (usize, usize),
+    cursor_style: TextCursorStyle,
 }
 
 impl TextView {
@@ -69,19 +160,42 @@ impl TextView {
             relative_number: relative_number,
             buffer: buffer,
             cursor: cursor,
+            id: view_id,
         }];
-        TextView {
+        let (color_column, cursor_line) = {
+            let cfgtv = &config.borrow().ui.textview;
+            (
+                Some(cfgtv.color_column).filter(|c| *c > 0),
+                cfgtv.cursor_line,
+            )
+        };
+        let mut ret = TextView {
             views: views,
             cur_view_idx: 0,
+            alt_view_idx: None,
             rect: rect,
+            text_rect: rect,
             scroll_v: (0.0, 0.0),
+            scroll_rem: (0.0, 0.0),
             font_core: font_core,
             dpi: dpi,
             line_numbers: line_numbers,
             relative_number: relative_number,
             cursor_style: TextCursorStyle::Block,
             config: config,
-        }
+            id: view_id,
+            leader: None,
+            flash: None,
+            selection: None,
+            damaged: true,
+            line_height: 1.0,
+            presentation: None,
+            relative_widest: 0,
+            color_column: color_column,
+            cursor_line: cursor_line,
+        };
+        ret.update_text_rect();
+        ret
     }
 
     pub(super) fn split(&self, view_id: usize) -> TextView {
@@ -100,24 +214,39 @@ impl TextView {
             relative_number: view.relative_number,
             buffer: buffer,
             cursor: cursor,
+            id: view_id,
         }];
         TextView {
             views: views,
             cur_view_idx: 0,
+            alt_view_idx: None,
             rect: self.rect,
+            text_rect: self.text_rect,
             scroll_v: (0.0, 0.0),
+            scroll_rem: (0.0, 0.0),
             font_core: self.font_core.clone(),
             dpi: self.dpi,
             line_numbers: view.line_numbers,
             relative_number: view.relative_number,
             cursor_style: TextCursorStyle::Block,
             config: self.config.clone(),
+            id: view_id,
+            leader: None,
+            flash: None,
+            selection: None,
+            damaged: true,
+            line_height: self.line_height,
+            presentation: self.presentation.clone(),
+            relative_widest: self.relative_widest,
+            color_column: self.color_column,
+            cursor_line: self.cursor_line,
         }
     }
 
     pub(super) fn add_buffer(&mut self, buffer: Rc<RefCell<Buffer>>, view_id: usize) {
         let cursor = {
             let borrow = &mut *buffer.borrow_mut();
+            borrow.ensure_shaped(self.dpi);
             let pos = borrow.get_pos_at_line(0);
             borrow.add_cursor_at_pos(view_id, &pos, false)
         };
@@ -129,9 +258,256 @@ impl TextView {
             relative_number: self.relative_number,
             buffer: buffer,
             cursor: cursor,
+            id: view_id,
         });
-        self.cur_view_idx += 1;
-        self.scroll_v = (0.0, 0.0);
+        self.alt_view_idx = Some(self.cur_view_idx);
+        self.cur_view_idx = self.views.len() - 1;
+        self.stop_scrolling();
+        self.flash = None;
+        self.selection = None;
+    }
+
+    /// Switch to buffer, adding a view into it with the given ID if this pane has none
+    pub(super) fn show_buffer(&mut self, buffer: Rc<RefCell<Buffer>>, view_id: usize) {
+        match self
+            .views
+            .iter()
+            .position(|v| Rc::ptr_eq(&v.buffer, &buffer))
+        {
+            Some(idx) => self.switch_view(idx),
+            None => self.add_buffer(buffer, view_id),
+        }
+    }
+
+    pub(super) fn buffer(&self) -> Rc<RefCell<Buffer>> {
+        self.views[self.cur_view_idx].buffer.clone()
+    }
+
+    pub(super) fn is_scratch(&self) -> bool {
+        self.views[self.cur_view_idx].buffer.borrow().is_scratch()
+    }
+
+    pub(super) fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Identifies the buffer shown in this pane, as opposed to the pane itself
+    pub(super) fn view_id(&self) -> usize {
+        self.views[self.cur_view_idx].id
+    }
+
+    // Views into files, for saving in a session, and the index of the one shown. Scratch and
+    // unnamed buffers are left out
+    pub(super) fn session_pane(&self) -> SessionNode {
+        let mut views = Vec::new();
+        let mut current = 0;
+        for (i, view) in self.views.iter().enumerate() {
+            let buffer = &*view.buffer.borrow();
+            let path = match buffer.path() {
+                Some(path) if !buffer.is_scratch() => path,
+                _ => continue,
+            };
+            if i == self.cur_view_idx {
+                current = views.len();
+            }
+            views.push(SessionView {
+                path: path.to_owned(),
+                line: view.cursor.line_num(),
+                gidx: view.cursor.line_gidx(),
+                start_line: view.start_line,
+            });
+        }
+        SessionNode::Pane(views, current)
+    }
+
+    // Move the cursor and scroll the view at idx to where they were saved in a session
+    pub(super) fn restore_view(
+        &mut self,
+        idx: usize,
+        linum: usize,
+        gidx: usize,
+        start_line: usize,
+    ) {
+        self.switch_view(idx);
+        self.go_to_line_gidx(linum, gidx);
+        let view = &mut self.views[self.cur_view_idx];
+        let len_lines = view.buffer.borrow().len_lines();
+        view.start_line = min(start_line, len_lines - 1);
+        view.ybase = 0;
+        self.snap_to_cursor();
+    }
+
+    pub(super) fn leader(&self) -> Option<usize> {
+        self.leader
+    }
+
+    pub(super) fn set_leader(&mut self, leader: Option<usize>) {
+        self.leader = leader;
+        self.stop_scrolling();
+    }
+
+    pub(super) fn viewport(&self) -> Viewport {
+        let view = &self.views[self.cur_view_idx];
+        Viewport {
+            buffer: view.buffer.clone(),
+            start_line: view.start_line,
+            xbase: view.xbase,
+            ybase: view.ybase,
+            cursor: (view.cursor.line_num(), view.cursor.line_gidx()),
+            cursor_style: self.cursor_style,
+        }
+    }
+
+    // Show the same buffer, scroll position and cursor as the leader. New views are given IDs
+    // from view_id. Returns true if anything changed
+    pub(super) fn follow(&mut self, leader: &Viewport, view_id: &mut dyn FnMut() -> usize) -> bool {
+        let mut changed = false;
+        if !Rc::ptr_eq(&self.views[self.cur_view_idx].buffer, &leader.buffer) {
+            match self
+                .views
+                .iter()
+                .position(|v| Rc::ptr_eq(&v.buffer, &leader.buffer))
+            {
+                Some(idx) => {
+                    self.alt_view_idx = Some(self.cur_view_idx);
+                    self.cur_view_idx = idx;
+                    self.ensure_shaped();
+                }
+                None => self.add_buffer(leader.buffer.clone(), view_id()),
+            }
+            changed = true;
+        }
+        let view = &mut self.views[self.cur_view_idx];
+        if view.start_line != leader.start_line
+            || view.xbase != leader.xbase
+            || view.ybase != leader.ybase
+        {
+            view.start_line = leader.start_line;
+            view.xbase = leader.xbase;
+            view.ybase = leader.ybase;
+            changed = true;
+        }
+        if (view.cursor.line_num(), view.cursor.line_gidx()) != leader.cursor {
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.move_cursor_to_linum_gidx(&mut view.cursor, leader.cursor.0, leader.cursor.1);
+            changed = true;
+        }
+        if self.cursor_style != leader.cursor_style {
+            view.cursor
+                .set_past_end(leader.cursor_style == TextCursorStyle::Beam);
+            self.cursor_style = leader.cursor_style;
+            changed = true;
+        }
+        changed
+    }
+
+    // Remove all views into given buffer. Returns true if there are no views left
+    pub(super) fn remove_buffer(&mut self, buffer: &Rc<RefCell<Buffer>>) -> bool {
+        let count_before = |views: &[View], idx: usize| {
+            views[..idx]
+                .iter()
+                .filter(|v| Rc::ptr_eq(&v.buffer, buffer))
+                .count()
+        };
+        let before = count_before(&self.views, self.cur_view_idx);
+        let cur_removed = Rc::ptr_eq(&self.views[self.cur_view_idx].buffer, buffer);
+        self.alt_view_idx = match self.alt_view_idx {
+            Some(idx) if !Rc::ptr_eq(&self.views[idx].buffer, buffer) => {
+                Some(idx - count_before(&self.views, idx))
+            }
+            _ => None,
+        };
+        self.views.retain(|v| !Rc::ptr_eq(&v.buffer, buffer));
+        if self.views.len() == 0 {
+            self.cur_view_idx = 0;
+            return true;
+        }
+        if cur_removed {
+            // Fall back to the alternate view if there is one
+            if let Some(idx) = self.alt_view_idx.take() {
+                self.cur_view_idx = idx;
+            } else {
+                self.cur_view_idx -= before;
+            }
+        } else {
+            self.cur_view_idx -= before;
+        }
+        if self.cur_view_idx >= self.views.len() {
+            self.cur_view_idx = self.views.len() - 1;
+        }
+        if self.alt_view_idx == Some(self.cur_view_idx) {
+            self.alt_view_idx = None;
+        }
+        self.stop_scrolling();
+        self.ensure_shaped();
+        self.snap_to_cursor();
+        false
+    }
+
+    /// Highlight nlines lines from the cursor line for a moment, e.g. after they were yanked
+    pub(super) fn flash_lines(&mut self, nlines: usize) {
+        let start = self.views[self.cur_view_idx].cursor.line_num();
+        let end = min(start + max(nlines, 1), self.len_lines()) - 1;
+        self.start_flash(start, end, false);
+    }
+
+    // Mark where lines were deleted, above the cursor line
+    fn flash_deleted(&mut self) {
+        let linum = self.views[self.cur_view_idx].cursor.line_num();
+        self.start_flash(linum, linum, true);
+    }
+
+    fn start_flash(&mut self, start_line: usize, end_line: usize, deleted: bool) {
+        if (&*self.config.borrow()).ui.textview.flash_duration == 0 {
+            return;
+        }
+        self.flash = Some(Flash {
+            start_line: start_line,
+            end_line: end_line,
+            deleted: deleted,
+            elapsed: Duration::from_secs(0),
+        });
+    }
+
+    /// Whether a flash animation is running
+    pub(super) fn is_flashing(&self) -> bool {
+        self.flash.is_some()
+    }
+
+    /// Advance the flash animation. Returns true if the view has to be redrawn
+    pub(super) fn update_flash(&mut self, duration: Duration) -> bool {
+        let total = (&*self.config.borrow()).ui.textview.flash_duration;
+        let done = match &mut self.flash {
+            Some(flash) => {
+                flash.elapsed += duration;
+                flash.elapsed >= Duration::from_millis(total)
+            }
+            None => return false,
+        };
+        if done {
+            self.flash = None;
+        }
+        self.damaged = true;
+        true
+    }
+
+    // Re-generate shaped lines for the current buffer if they were evicted. Lines are only
+    // shaped once they are near the view, or the cursor is on them
+    fn ensure_shaped(&mut self) {
+        {
+            let buffer = &mut *self.views[self.cur_view_idx].buffer.borrow_mut();
+            buffer.ensure_shaped(self.dpi);
+        }
+        let (first_line, last_line) = self.visible_lines();
+        let view = &self.views[self.cur_view_idx];
+        let cursor_linum = view.cursor.line_num();
+        let buffer = &mut *view.buffer.borrow_mut();
+        buffer.shape_visible(
+            self.dpi,
+            first_line.saturating_sub(SHAPE_MARGIN_LINES),
+            last_line + 1 + SHAPE_MARGIN_LINES,
+        );
+        buffer.shape_visible(self.dpi, cursor_linum, cursor_linum + 1);
     }
 
     pub(super) fn reload_buffer(&mut self) -> IOResult<()> {
@@ -145,18 +521,36 @@ impl TextView {
     }
 
     pub(super) fn prev_buffer(&mut self) {
-        if self.cur_view_idx == 0 {
-            self.cur_view_idx = self.views.len() - 1;
+        let idx = if self.cur_view_idx == 0 {
+            self.views.len() - 1
         } else {
-            self.cur_view_idx -= 1;
-        }
-        self.scroll_v = (0.0, 0.0);
-        self.snap_to_cursor();
+            self.cur_view_idx - 1
+        };
+        self.switch_view(idx);
     }
 
     pub(super) fn next_buffer(&mut self) {
-        self.cur_view_idx = (self.cur_view_idx + 1) % self.views.len();
-        self.scroll_v = (0.0, 0.0);
+        self.switch_view((self.cur_view_idx + 1) % self.views.len());
+    }
+
+    /// Switch to the buffer shown before the current one in this pane, if there is one
+    pub(super) fn alternate_buffer(&mut self) {
+        if let Some(idx) = self.alt_view_idx {
+            self.switch_view(idx);
+        }
+    }
+
+    // Show view at idx, remembering the current view as the alternate one
+    fn switch_view(&mut self, idx: usize) {
+        if idx == self.cur_view_idx {
+            return;
+        }
+        self.alt_view_idx = Some(self.cur_view_idx);
+        self.cur_view_idx = idx;
+        self.stop_scrolling();
+        self.flash = None;
+        self.selection = None;
+        self.ensure_shaped();
         self.snap_to_cursor();
     }
 
@@ -187,20 +581,17 @@ impl TextView {
 
             if point.0 < 0 {
                 point.0 = 0;
-            } else if point.0 > self.rect.size.width as i32 {
-                point.0 = self.rect.size.width as i32;
+            } else if point.0 > self.text_rect.size.width as i32 {
+                point.0 = self.text_rect.size.width as i32;
             }
             if point.1 < 0 {
                 point.1 = 0;
-            } else if point.1 > self.rect.size.height as i32 {
-                point.1 = self.rect.size.height as i32;
+            } else if point.1 > self.text_rect.size.height as i32 {
+                point.1 = self.text_rect.size.height as i32;
             }
 
-            let gutter_width = if view.line_numbers || view.relative_number {
-                shaped_linums[shaped_linums.len() - 1].metrics.width + cfggtr.padding * 2
-            } else {
-                cfggtr.padding * 2
-            };
+            let gutter_width =
+                gutter_width(view, shaped_linums, self.relative_widest, cfggtr.padding);
 
             point.0 += view.xbase as i32 - gutter_width as i32;
             point.1 += view.ybase as i32;
@@ -215,6 +606,7 @@ impl TextView {
                 cursor_linum,
                 view.line_numbers,
                 view.relative_number,
+                self.line_height,
             ) {
                 total_height += height as i32;
                 if total_height >= point.1 {
@@ -233,31 +625,17 @@ impl TextView {
             let mut gidx = 0;
             'outer: for span in &shaped_text[linum].spans {
                 for cluster in span.clusters() {
-                    let num_glyphs = cluster.glyph_infos.len();
-                    if num_glyphs % cluster.num_graphemes != 0 {
-                        let startx = x;
-                        for gi in cluster.glyph_infos {
-                            x += gi.advance.width;
-                        }
-                        if x < point.0 {
-                            continue;
-                        }
-                        let width = x - startx;
-                        let grapheme_width = width / cluster.num_graphemes as i32;
-                        gidx += width / grapheme_width;
-                        break 'outer;
-                    } else {
-                        let glyphs_per_grapheme = num_glyphs / cluster.num_graphemes;
-                        for i in (0..num_glyphs).step_by(glyphs_per_grapheme) {
-                            for gi in &cluster.glyph_infos[i..(i + glyphs_per_grapheme)] {
-                                x += gi.advance.width;
-                                if x >= point.0 {
-                                    break 'outer;
-                                }
-                            }
-                            gidx += 1;
-                        }
+                    let width = cluster.width();
+                    if x + width < point.0 {
+                        x += width;
+                        gidx += cluster.num_graphemes;
+                        continue;
                     }
+                    // Grapheme in the cluster under the point
+                    gidx += (1..cluster.num_graphemes)
+                        .take_while(|i| x + cluster.grapheme_x(*i) < point.0)
+                        .count();
+                    break 'outer;
                 }
             }
 
@@ -266,6 +644,131 @@ impl TextView {
         self.snap_to_cursor();
     }
 
+    /// Start selecting text from the cursor position
+    pub(super) fn start_selection(&mut self, linewise: bool) {
+        let cursor = &self.views[self.cur_view_idx].cursor;
+        self.selection = Some(Selection {
+            anchor: (cursor.line_num(), cursor.line_gidx()),
+            linewise: linewise,
+        });
+    }
+
+    pub(super) fn set_selection_linewise(&mut self, linewise: bool) {
+        if let Some(selection) = &mut self.selection {
+            selection.linewise = linewise;
+        }
+    }
+
+    pub(super) fn clear_selection(&mut self) {
+        self.selection = None;
+    }
+
+    /// Select the word under the cursor, moving the cursor to its last grapheme
+    pub(super) fn select_word(&mut self) {
+        let word = {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            let linum = view.cursor.line_num();
+            let word = buffer.word_at(linum, view.cursor.line_gidx());
+            if let Some((_, last)) = word {
+                buffer.move_cursor_to_linum_gidx(&mut view.cursor, linum, last);
+            }
+            word.map(|(first, _)| (linum, first))
+        };
+        self.selection = word.map(|anchor| Selection {
+            anchor: anchor,
+            linewise: false,
+        });
+        self.snap_to_cursor();
+    }
+
+    /// Word under the cursor, if the cursor is on letters, digits or underscores
+    pub(super) fn word_under_cursor(&self) -> Option<String> {
+        let view = &self.views[self.cur_view_idx];
+        let buffer = &*view.buffer.borrow();
+        let linum = view.cursor.line_num();
+        let (first, last) = buffer.word_at(linum, view.cursor.line_gidx())?;
+        let word = buffer.range_text((linum, first), (linum, last + 1));
+        if !word.is_empty() && word.chars().all(is_word_char) {
+            Some(word)
+        } else {
+            None
+        }
+    }
+
+    /// Move the cursor to point while the mouse is dragged, selecting text from where the drag
+    /// started. Returns true if any text is selected
+    pub(super) fn drag_to_point(&mut self, point: (i32, i32)) -> bool {
+        if self.selection.is_none() {
+            self.start_selection(false);
+        }
+        self.move_cursor_to_point(point);
+        let cursor = &self.views[self.cur_view_idx].cursor;
+        let pos = (cursor.line_num(), cursor.line_gidx());
+        match self.selection {
+            Some(selection) if selection.anchor == pos && !selection.linewise => {
+                self.selection = None;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Selected text, and whether it is linewise
+    pub(super) fn selection_text(&self) -> Option<(String, bool)> {
+        let (start, end, linewise) = self.selection_range()?;
+        let buffer = &*self.views[self.cur_view_idx].buffer.borrow();
+        if linewise {
+            Some((buffer.lines_text(start.0, end.0 - start.0), true))
+        } else {
+            Some((buffer.range_text(start, end), false))
+        }
+    }
+
+    /// Delete selected text, and clear the selection
+    pub(super) fn delete_selection(&mut self) {
+        let (start, end, linewise) = match self.selection_range() {
+            Some(range) => range,
+            None => return,
+        };
+        self.selection = None;
+        if linewise {
+            {
+                let view = &mut self.views[self.cur_view_idx];
+                let buffer = &mut *view.buffer.borrow_mut();
+                buffer.move_cursor_to_line(&mut view.cursor, start.0);
+            }
+            self.delete_lines(end.0 - start.0);
+        } else {
+            {
+                let view = &mut self.views[self.cur_view_idx];
+                let buffer = &mut *view.buffer.borrow_mut();
+                buffer.delete_range(&mut view.cursor, start, end);
+            }
+            self.snap_to_cursor();
+        }
+    }
+
+    // Start and end of the selection as line numbers and columns, with the end exclusive, and
+    // whether the selection is linewise. Linewise selections end at the start of the line after
+    // the last selected line
+    fn selection_range(&self) -> Option<((usize, usize), (usize, usize), bool)> {
+        let selection = self.selection?;
+        let view = &self.views[self.cur_view_idx];
+        let cursor = (view.cursor.line_num(), view.cursor.line_gidx());
+        let (start, last) = if selection.anchor <= cursor {
+            (selection.anchor, cursor)
+        } else {
+            (cursor, selection.anchor)
+        };
+        if selection.linewise {
+            Some(((start.0, 0), (last.0 + 1, 0), true))
+        } else {
+            let end = view.buffer.borrow().next_gidx(last.0, last.1);
+            Some((start, (last.0, end), false))
+        }
+    }
+
     pub(super) fn move_cursor_down(&mut self, n: usize) {
         {
             let view = &mut self.views[self.cur_view_idx];
@@ -320,7 +823,14 @@ impl TextView {
         self.snap_to_cursor();
     }
 
+    /// Move the view up a page. The cursor moves with it, or only as far as needed to stay in
+    /// view, depending on the config
     pub(super) fn page_up(&mut self) {
+        if !self.config.borrow().ui.textview.page_moves_cursor {
+            self.scroll_page(false);
+            self.keep_cursor_in_view();
+            return;
+        }
         let view = &mut self.views[self.cur_view_idx];
         let buffer = &mut *view.buffer.borrow_mut();
         let cursor_linum = view.cursor.line_num();
@@ -330,7 +840,7 @@ impl TextView {
         let linum = if view.start_line == 0 {
             0
         } else {
-            let mut total_height = self.rect.size.height;
+            let mut total_height = self.text_rect.size.height;
             let mut iter = LinumTextIter::new(
                 shaped_linums,
                 shaped_text,
@@ -338,6 +848,7 @@ impl TextView {
                 cursor_linum,
                 view.line_numbers,
                 view.relative_number,
+                self.line_height,
             );
             while let Some((_, _, height, _, _)) = iter.prev() {
                 if height > total_height {
@@ -355,8 +866,9 @@ impl TextView {
                 cursor_linum,
                 view.line_numbers,
                 view.relative_number,
+                self.line_height,
             ) {
-                if linum >= cursor_linum || height + total_height >= self.rect.size.height {
+                if linum >= cursor_linum || height + total_height >= self.text_rect.size.height {
                     break;
                 }
                 total_height += height;
@@ -367,7 +879,14 @@ impl TextView {
         buffer.move_cursor_to_line(&mut view.cursor, linum);
     }
 
+    /// Move the view down a page. The cursor moves with it, or only as far as needed to stay in
+    /// view, depending on the config
     pub(super) fn page_down(&mut self) {
+        if !self.config.borrow().ui.textview.page_moves_cursor {
+            self.scroll_page(true);
+            self.keep_cursor_in_view();
+            return;
+        }
         let view = &mut self.views[self.cur_view_idx];
         let buffer = &mut *view.buffer.borrow_mut();
         let cursor_linum = view.cursor.line_num();
@@ -382,8 +901,9 @@ impl TextView {
             cursor_linum,
             view.line_numbers,
             view.relative_number,
+            self.line_height,
         ) {
-            if height + total_height >= self.rect.size.height {
+            if height + total_height >= self.text_rect.size.height {
                 break;
             }
             total_height += height;
@@ -395,6 +915,106 @@ impl TextView {
         buffer.move_cursor_to_line(&mut view.cursor, view.start_line);
     }
 
+    /// Scroll the view a page up or down without moving the cursor
+    pub(super) fn scroll_page(&mut self, down: bool) {
+        let start_line = {
+            let view = &self.views[self.cur_view_idx];
+            let buffer = &*view.buffer.borrow();
+            let cursor_linum = view.cursor.line_num();
+            let (shaped_linums, shaped_text) = buffer.shaped_data(self.dpi).unwrap();
+            let mut iter = LinumTextIter::new(
+                shaped_linums,
+                shaped_text,
+                view.start_line,
+                cursor_linum,
+                view.line_numbers,
+                view.relative_number,
+                self.line_height,
+            );
+            let mut start_line = view.start_line;
+            let mut total_height = 0;
+            if down {
+                for (_, _, height, _, _) in iter {
+                    if height + total_height >= self.text_rect.size.height {
+                        break;
+                    }
+                    total_height += height;
+                    start_line += 1;
+                }
+                if start_line > 0 && start_line == shaped_text.len() {
+                    start_line -= 1;
+                }
+            } else {
+                while let Some((_, _, height, _, _)) = iter.prev() {
+                    if height + total_height > self.text_rect.size.height {
+                        break;
+                    }
+                    total_height += height;
+                    start_line -= 1;
+                }
+            }
+            start_line
+        };
+        let view = &mut self.views[self.cur_view_idx];
+        view.start_line = start_line;
+        view.ybase = 0;
+        self.stop_scrolling();
+    }
+
+    /// Scroll the view by a number of lines without moving the cursor
+    pub(super) fn scroll_lines(&mut self, n: usize, down: bool) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let len_lines = view.buffer.borrow().len_lines();
+            view.start_line = if down {
+                min(view.start_line + n, len_lines - 1)
+            } else {
+                view.start_line.saturating_sub(n)
+            };
+            view.ybase = 0;
+        }
+        self.stop_scrolling();
+    }
+
+    /// First and last lines that are fully in view
+    pub(super) fn visible_lines(&self) -> (usize, usize) {
+        let view = &self.views[self.cur_view_idx];
+        let buffer = &*view.buffer.borrow();
+        let cursor_linum = view.cursor.line_num();
+        let (shaped_linums, shaped_text) = buffer.shaped_data(self.dpi).unwrap();
+        let mut last_line = view.start_line;
+        let mut total_height = 0;
+        for (_, _, height, _, _) in LinumTextIter::new(
+            shaped_linums,
+            shaped_text,
+            view.start_line,
+            cursor_linum,
+            view.line_numbers,
+            view.relative_number,
+            self.line_height,
+        ) {
+            total_height += height;
+            if total_height > self.text_rect.size.height {
+                break;
+            }
+            last_line += 1;
+        }
+        (view.start_line, max(last_line, view.start_line + 1) - 1)
+    }
+
+    // Move the cursor to the first or last line that is fully in view, if it is out of view
+    fn keep_cursor_in_view(&mut self) {
+        let (first_line, last_line) = self.visible_lines();
+        let view = &mut self.views[self.cur_view_idx];
+        let buffer = &mut *view.buffer.borrow_mut();
+        let cursor_linum = view.cursor.line_num();
+        if cursor_linum < first_line {
+            buffer.move_cursor_to_line(&mut view.cursor, first_line);
+        } else if cursor_linum > last_line {
+            buffer.move_cursor_to_line(&mut view.cursor, last_line);
+        }
+    }
+
     pub(super) fn go_to_line(&mut self, linum: usize) {
         {
             let view = &mut self.views[self.cur_view_idx];
@@ -404,6 +1024,48 @@ impl TextView {
         self.snap_to_cursor();
     }
 
+    pub(super) fn go_to_line_gidx(&mut self, linum: usize, gidx: usize) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.move_cursor_to_linum_gidx(&mut view.cursor, linum, gidx);
+        }
+        self.snap_to_cursor();
+    }
+
+    // Move cursor to the bracket matching the one under it, if any
+    pub(super) fn go_to_matching_bracket(&mut self) {
+        let pos = {
+            let view = &self.views[self.cur_view_idx];
+            let buffer = &*view.buffer.borrow();
+            buffer.matching_bracket(&view.cursor)
+        };
+        if let Some((linum, gidx)) = pos {
+            self.go_to_line_gidx(linum, gidx);
+        }
+    }
+
+    // Move cursor to the next match of pattern. Returns false if there is no match
+    pub(super) fn search(&mut self, pattern: &str, forward: bool, inclusive: bool) -> bool {
+        let options = self.config.borrow().ui.textview.search;
+        let searcher = match Searcher::new(pattern, options) {
+            Ok(searcher) => searcher,
+            Err(_) => return false,
+        };
+        let found = {
+            let view = &self.views[self.cur_view_idx];
+            let buffer = &*view.buffer.borrow();
+            buffer.find(&view.cursor, &searcher, forward, inclusive)
+        };
+        match found {
+            Some((linum, gidx)) => {
+                self.go_to_line_gidx(linum, gidx);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub(super) fn go_to_last_line(&mut self) {
         {
             let view = &mut self.views[self.cur_view_idx];
@@ -431,12 +1093,22 @@ impl TextView {
         self.snap_to_cursor();
     }
 
+    pub(super) fn delete_around(&mut self, left: usize, right: usize) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.delete_around(&mut view.cursor, left, right);
+        }
+        self.snap_to_cursor();
+    }
+
     pub(super) fn delete_lines(&mut self, nlines: usize) {
         {
             let view = &mut self.views[self.cur_view_idx];
             let buffer = &mut *view.buffer.borrow_mut();
             buffer.delete_lines(&mut view.cursor, nlines);
         }
+        self.flash_deleted();
         self.snap_to_cursor();
     }
 
@@ -446,6 +1118,7 @@ impl TextView {
             let buffer = &mut *view.buffer.borrow_mut();
             buffer.delete_lines_up(&mut view.cursor, nlines);
         }
+        self.flash_deleted();
         self.snap_to_cursor();
     }
 
@@ -455,6 +1128,7 @@ impl TextView {
             let buffer = &mut *view.buffer.borrow_mut();
             buffer.delete_lines_down(&mut view.cursor, nlines);
         }
+        self.flash_deleted();
         self.snap_to_cursor();
     }
 
@@ -464,6 +1138,7 @@ impl TextView {
             let buffer = &mut *view.buffer.borrow_mut();
             buffer.delete_to_line(&mut view.cursor, linum);
         }
+        self.flash_deleted();
         self.snap_to_cursor();
     }
 
@@ -473,6 +1148,22 @@ impl TextView {
             let buffer = &mut *view.buffer.borrow_mut();
             buffer.delete_to_last_line(&mut view.cursor);
         }
+        self.flash_deleted();
+        self.snap_to_cursor();
+    }
+
+    // Replace nlines lines starting at start with a single empty line, and move the cursor to it
+    pub(super) fn clear_lines(&mut self, start: usize, nlines: usize) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.move_cursor_to_line(&mut view.cursor, start);
+            if nlines > 1 {
+                buffer.delete_lines(&mut view.cursor, nlines - 1);
+            }
+            buffer.move_cursor_start_of_line(&mut view.cursor);
+            buffer.delete_to_line_end(&mut view.cursor);
+        }
         self.snap_to_cursor();
     }
 
@@ -494,6 +1185,42 @@ impl TextView {
         self.snap_to_cursor();
     }
 
+    pub(super) fn delete_word_left(&mut self) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.delete_word_left(&mut view.cursor);
+        }
+        self.snap_to_cursor();
+    }
+
+    pub(super) fn indent_line(&mut self) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.indent_line(&mut view.cursor);
+        }
+        self.snap_to_cursor();
+    }
+
+    pub(super) fn dedent_line(&mut self) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.dedent_line(&mut view.cursor);
+        }
+        self.snap_to_cursor();
+    }
+
+    pub(super) fn shift_lines(&mut self, start: usize, end: usize, right: bool) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.shift_lines(&mut view.cursor, start, end, right);
+        }
+        self.snap_to_cursor();
+    }
+
     pub(super) fn insert_char(&mut self, c: char) {
         {
             let view = &mut self.views[self.cur_view_idx];
@@ -512,46 +1239,100 @@ impl TextView {
         self.snap_to_cursor();
     }
 
-    pub(super) fn scroll(&mut self, force: (f64, f64), time: f64) -> bool {
-        fn apply_friction(v: f64, fv: f64) -> f64 {
-            if v < 0.0 {
-                if v >= -fv {
-                    0.0
-                } else {
-                    v + fv
-                }
-            } else if v > 0.0 {
-                if v <= fv {
-                    0.0
-                } else {
-                    v - fv
-                }
-            } else {
-                0.0
-            }
+    // Text of nlines lines starting from the cursor line
+    pub(super) fn lines_text(&self, nlines: usize) -> String {
+        let view = &self.views[self.cur_view_idx];
+        let buffer = &*view.buffer.borrow();
+        buffer.lines_text(view.cursor.line_num(), nlines)
+    }
+
+    pub(super) fn paste(&mut self, text: &str, linewise: bool, after: bool) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.paste(&mut view.cursor, text, linewise, after);
         }
+        self.snap_to_cursor();
+    }
 
-        // Apply friction
-        let friction_v = FRICTION_A * time;
-        self.scroll_v.0 = apply_friction(self.scroll_v.0, friction_v);
-        self.scroll_v.1 = apply_friction(self.scroll_v.1, friction_v);
-        // Compute and apply acceleration
-        let a = (force.0 / M, force.1 / M);
-        self.scroll_v.0 += time * a.0;
-        self.scroll_v.1 += time * a.1;
+    pub(super) fn transform_lines<F>(&mut self, start: usize, end: usize, f: F)
+    where
+        F: Fn(&str) -> String,
+    {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.transform_lines(start, end, f);
+        }
+        self.snap_to_cursor();
+    }
+
+    pub(super) fn sort_lines(&mut self, start: usize, end: usize, reverse: bool) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.sort_lines(start, end, reverse);
+        }
+        self.snap_to_cursor();
+    }
 
-        // Calculate delta
-        fn round_delta(s: f64) -> i32 {
-            if s <= 0.0 {
-                s.floor() as i32
+    pub(super) fn retab(&mut self) {
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.retab();
+        }
+        self.snap_to_cursor();
+    }
+
+    pub(super) fn text_around_cursor(&self) -> (String, Option<char>) {
+        let view = &self.views[self.cur_view_idx];
+        view.buffer.borrow().text_around_cursor(&view.cursor)
+    }
+
+    pub(super) fn cursor_line_num(&self) -> usize {
+        self.views[self.cur_view_idx].cursor.line_num()
+    }
+
+    pub(super) fn cursor_gidx(&self) -> usize {
+        self.views[self.cur_view_idx].cursor.line_gidx()
+    }
+
+    pub(super) fn len_lines(&self) -> usize {
+        self.views[self.cur_view_idx].buffer.borrow().len_lines()
+    }
+
+    /// Scroll by the distance travelled in the given number of seconds. Force, from the scroll
+    /// wheel, adds to the velocity which then decays with friction, so that large deltas are
+    /// spread over several frames. Returns true if the view moved
+    pub(super) fn scroll(&mut self, force: (f64, f64), time: f64) -> bool {
+        let (acceleration, friction) = {
+            let mouse = &(&*self.config.borrow()).ui.mouse;
+            (mouse.scroll_acceleration, mouse.scroll_friction)
+        };
+        self.scroll_v.0 += force.0 * acceleration;
+        self.scroll_v.1 += force.1 * acceleration;
+
+        // Velocity decays exponentially, so the distance travelled is its integral over time
+        let decay = (-friction * time).exp();
+        let distance = |v: f64| {
+            if friction > 0.0 {
+                v * (1.0 - decay) / friction
             } else {
-                s.ceil() as i32
+                v * time
             }
-        }
-        let amts = (
-            round_delta(time * self.scroll_v.0),
-            round_delta(time * self.scroll_v.1),
+        };
+        let delta = (
+            self.scroll_rem.0 + distance(self.scroll_v.0),
+            self.scroll_rem.1 + distance(self.scroll_v.1),
         );
+        let amts = (delta.0.trunc() as i32, delta.1.trunc() as i32);
+        self.scroll_rem = (delta.0 - amts.0 as f64, delta.1 - amts.1 as f64);
+        self.scroll_v.0 *= decay;
+        self.scroll_v.1 *= decay;
+        if self.scroll_v.0.hypot(self.scroll_v.1) < MIN_SCROLL_SPEED {
+            self.stop_scrolling();
+        }
 
         let view = &mut self.views[self.cur_view_idx];
         let buffer = &*view.buffer.borrow();
@@ -575,8 +1356,10 @@ impl TextView {
             cursor_linum,
             view.line_numbers,
             view.relative_number,
+            self.line_height,
         );
 
+        let mut at_edge = false;
         view.ybase = if y < 0 {
             while let Some((_, _, height, _, _)) = iter.prev() {
                 y += height as i32;
@@ -586,6 +1369,7 @@ impl TextView {
                 }
             }
             if y < 0 {
+                at_edge = true;
                 0
             } else {
                 y as u32
@@ -604,46 +1388,203 @@ impl TextView {
                     view.start_line -= 1;
                 }
                 y = 0;
+                at_edge = true;
             }
             y as u32
         };
 
-        amts.0 != 0 || amts.1 != 0
+        // Don't keep pushing against the start or end of the buffer
+        if at_edge {
+            self.scroll_v = (0.0, 0.0);
+            self.scroll_rem = (0.0, 0.0);
+        }
+        let moved = amts.0 != 0 || amts.1 != 0;
+        self.damaged |= moved;
+        moved
+    }
+
+    /// Whether the view has to be redrawn because it scrolled or flashed on its own
+    pub(super) fn is_damaged(&self) -> bool {
+        self.damaged
+    }
+
+    /// Whether a scroll animation is still running
+    pub(super) fn is_scrolling(&self) -> bool {
+        self.scroll_v != (0.0, 0.0)
+    }
+
+    fn stop_scrolling(&mut self) {
+        self.scroll_v = (0.0, 0.0);
+        self.scroll_rem = (0.0, 0.0);
+    }
+
+    // Scrollbar along the right edge of the text, relative to the view
+    fn scrollbar_rect(&self) -> Option<Rect<i32, PixelSize>> {
+        let cfg = &*self.config.borrow();
+        if !cfg.ui.textview.scrollbar {
+            return None;
+        }
+        let width = min(cfg.ui.theme().scrollbar.width, self.text_rect.size.width) as i32;
+        Some(Rect::new(
+            point2(self.text_rect.size.width as i32 - width, 0),
+            size2(width, self.text_rect.size.height as i32),
+        ))
+    }
+
+    /// Whether point, relative to the view, is on the scrollbar
+    pub(super) fn scrollbar_contains(&self, point: (i32, i32)) -> bool {
+        self.scrollbar_rect()
+            .map(|rect| rect.contains(point2(point.0, point.1)))
+            .unwrap_or(false)
+    }
+
+    /// Whether point, relative to the view, is on the statusline
+    pub(super) fn statusline_contains(&self, point: (i32, i32)) -> bool {
+        let rect: Rect<i32, PixelSize> = Rect::new(
+            point2(0, self.text_rect.size.height as i32),
+            size2(
+                self.rect.size.width as i32,
+                (self.rect.size.height - self.text_rect.size.height) as i32,
+            ),
+        );
+        rect.contains(point2(point.0, point.1))
+    }
+
+    /// Scroll so that the line at the given height along the scrollbar is at the top of the view
+    pub(super) fn scroll_to_scrollbar_point(&mut self, y: i32) {
+        let height = match self.scrollbar_rect() {
+            Some(rect) if rect.size.height > 0 => rect.size.height as i64,
+            _ => return,
+        };
+        {
+            let view = &mut self.views[self.cur_view_idx];
+            let nlines = view.buffer.borrow().len_lines();
+            let y = max(0, min(y as i64, height - 1));
+            view.start_line = min((y * nlines as i64 / height) as usize, nlines - 1);
+            view.ybase = 0;
+        }
+        self.stop_scrolling();
+    }
+
+    // Shape the current buffer for the new DPI. Other buffers are shaped when they are shown
+    pub(super) fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.dpi = match &mut self.presentation {
+            Some(presentation) => {
+                presentation.dpi = dpi;
+                let scale = self.config.borrow().ui.presentation.text_scale;
+                size2(
+                    (dpi.width as f64 * scale).round() as u32,
+                    (dpi.height as f64 * scale).round() as u32,
+                )
+            }
+            None => dpi,
+        };
+        self.ensure_shaped();
+        self.update_text_rect();
+        self.snap_to_cursor();
     }
 
     pub(super) fn set_rect(&mut self, rect: Rect<u32, PixelSize>) {
         self.rect = rect;
+        self.update_text_rect();
         self.snap_to_cursor();
     }
 
+    // The statusline keeps its size in presentation mode
+    fn chrome_dpi(&self) -> Size2D<u32, DPI> {
+        match &self.presentation {
+            Some(presentation) => presentation.dpi,
+            None => self.dpi,
+        }
+    }
+
+    // Leave space for the statusline at the bottom of the view
+    fn update_text_rect(&mut self) {
+        let height = {
+            let cfg = &*self.config.borrow();
+            let cfgsl = &cfg.ui.statusline;
+            if cfgsl.enabled {
+                let font_core = &mut *self.font_core.borrow_mut();
+                let line = ShapedTextLine::from_textstr(
+                    TextSpan::new(
+                        " ",
+                        cfgsl.text_size,
+                        TextStyle::default(),
+                        cfg.ui.theme().statusline.foreground_color,
+                        TextPitch::Variable,
+                        None,
+                    ),
+                    cfgsl.fixed_face,
+                    cfgsl.variable_face,
+                    font_core,
+                    self.chrome_dpi(),
+                );
+                line.metrics.height + cfgsl.padding * 2
+            } else {
+                0
+            }
+        };
+        self.text_rect = self.rect;
+        self.text_rect.size.height -= min(height, self.rect.size.height);
+    }
+
     pub(super) fn get_rect(&self) -> Rect<u32, PixelSize> {
         self.rect
     }
 
-    pub(super) fn draw(&mut self, actx: &mut ActiveRenderCtx, is_active: bool) {
+    pub(super) fn draw(
+        &mut self,
+        actx: &mut ActiveRenderCtx,
+        is_active: bool,
+        search: Option<&str>,
+        mode: &str,
+    ) {
+        self.damaged = false;
+        self.draw_statusline(actx, is_active, mode);
+        self.ensure_shaped();
+        {
+            let view = &self.views[self.cur_view_idx];
+            let buffer = &mut *view.buffer.borrow_mut();
+            buffer.highlight_search(search);
+            buffer.update_line_changes();
+        }
+        self.shape_gutter();
+
         let cfg = &*self.config.borrow();
         let cfggtr = &cfg.ui.gutter;
         let cfgtheme = cfg.ui.theme();
         let cfgthemetv = &cfgtheme.textview;
         let cfgthemegtr = &cfgtheme.gutter;
 
+        let selection = self.selection_range();
         let view = &mut self.views[self.cur_view_idx];
         let start_line = view.start_line;
         let cursor_linum = view.cursor.line_num();
         let buffer = &*view.buffer.borrow();
         let font_core = &mut *self.font_core.borrow_mut();
         let (shaped_linums, shaped_text) = buffer.shaped_data(self.dpi).unwrap();
+        // Bracket under the cursor and the one matching it
+        let mut brackets = Vec::new();
+        if is_active {
+            if let Some(pos) = buffer.matching_bracket(&view.cursor) {
+                brackets.push((cursor_linum, view.cursor.line_gidx()));
+                brackets.push(pos);
+            }
+        }
 
-        let gutter_width = if view.line_numbers || view.relative_number {
-            shaped_linums[shaped_linums.len() - 1].metrics.width + cfggtr.padding * 2
-        } else {
-            cfggtr.padding * 2
-        };
+        let gutter_width = gutter_width(view, shaped_linums, self.relative_widest, cfggtr.padding);
 
-        let mut textview_rect = self.rect.cast();
+        let mut textview_rect = self.text_rect.cast();
         textview_rect.origin.x += gutter_width as i32;
         textview_rect.size.width -= gutter_width as i32;
 
+        // Flash fades out linearly
+        let flash = self.flash.as_ref().map(|flash| {
+            let total = cfg.ui.textview.flash_duration.max(1) as u128;
+            let left = total.saturating_sub(flash.elapsed.as_millis());
+            (flash, (left * 100 / total) as u8)
+        });
+
         let mut pos = point2(-(view.xbase as i32), -(view.ybase as i32));
         {
             let mut linum = start_line;
@@ -653,6 +1594,30 @@ impl TextView {
             } else {
                 cfgthemetv.inactive_opacity
             };
+            // The rule is placed by the width of the fixed-pitch face, so it lines up with code
+            if let Some(column) = self.color_column {
+                let advance = TextSpan::new(
+                    " ",
+                    cfg.ui.textview.text_size,
+                    TextStyle::default(),
+                    cfgthemetv.foreground_color,
+                    TextPitch::Fixed,
+                    None,
+                )
+                .base_face_metrics(
+                    cfg.ui.textview.fixed_face,
+                    cfg.ui.textview.variable_face,
+                    font_core,
+                    self.dpi,
+                )
+                .advance_width;
+                let x = pos.x + column as i32 * advance;
+                let rect = Rect::new(
+                    point2(x, 0),
+                    size2(COLOR_COLUMN_WIDTH, textview_rect.size.height),
+                );
+                ctx.color_quad(rect, cfgthemetv.color_column_color.opacity(op));
+            }
             for (ascender, _, height, line, _) in LinumTextIter::new(
                 shaped_linums,
                 shaped_text,
@@ -660,6 +1625,7 @@ impl TextView {
                 cursor_linum,
                 view.line_numbers,
                 view.relative_number,
+                self.line_height,
             ) {
                 if pos.y >= textview_rect.size.height {
                     break;
@@ -677,13 +1643,118 @@ impl TextView {
                 } else {
                     None
                 };
-                line.draw(&mut ctx, ascender, height, baseline, font_core, cursor, op);
+                if self.cursor_line && linum == cursor_linum {
+                    let rect = Rect::new(point2(0, pos.y), size2(textview_rect.size.width, height));
+                    ctx.color_quad(rect, cfgthemetv.cursor_line_color.opacity(op));
+                }
+                let mut colors = Vec::new();
+                for (start, end, style) in buffer.line_decorations(linum) {
+                    let startx = line.gidx_x(start);
+                    let endx = line.gidx_x(end);
+                    match style {
+                        DecorationStyle::Foreground(color) => colors.push((start, end, color)),
+                        DecorationStyle::Background(color) => {
+                            let rect = Rect::new(
+                                point2(pos.x + startx, pos.y),
+                                size2(endx - startx, height),
+                            );
+                            ctx.color_quad(rect, color.opacity(op));
+                        }
+                        DecorationStyle::Underline(color) => {
+                            let rect = Rect::new(
+                                point2(pos.x + startx, pos.y + height - UNDERLINE_HEIGHT),
+                                size2(endx - startx, UNDERLINE_HEIGHT),
+                            );
+                            ctx.color_quad(rect, color.opacity(op));
+                        }
+                    }
+                }
+                if let Some((start, end, _)) = selection {
+                    if linum >= start.0 && linum <= end.0 {
+                        let startx = if linum == start.0 {
+                            line.gidx_x(start.1)
+                        } else {
+                            0
+                        };
+                        // Lines selected up to their end are highlighted across the view
+                        let width = if linum == end.0 {
+                            line.gidx_x(end.1) - startx
+                        } else {
+                            textview_rect.size.width - pos.x - startx
+                        };
+                        let rect = Rect::new(point2(pos.x + startx, pos.y), size2(width, height));
+                        ctx.color_quad(rect, cfgthemetv.selection_color.opacity(op));
+                    }
+                }
+                if let Some((flash, fade)) = flash {
+                    let color = cfgthemetv.flash_color.opacity(fade).opacity(op);
+                    if flash.deleted && linum == flash.start_line {
+                        let rect = Rect::new(
+                            point2(0, pos.y),
+                            size2(textview_rect.size.width, DELETE_FLASH_HEIGHT),
+                        );
+                        ctx.color_quad(rect, color);
+                    } else if !flash.deleted && linum >= flash.start_line && linum <= flash.end_line
+                    {
+                        let rect =
+                            Rect::new(point2(0, pos.y), size2(textview_rect.size.width, height));
+                        ctx.color_quad(rect, color);
+                    }
+                }
+                for (_, gidx) in brackets.iter().filter(|(l, _)| *l == linum) {
+                    let startx = line.gidx_x(*gidx);
+                    let endx = line.gidx_x(gidx + 1);
+                    let rect =
+                        Rect::new(point2(pos.x + startx, pos.y), size2(endx - startx, height));
+                    ctx.color_quad(rect, cfgthemetv.bracket_color.opacity(op));
+                }
+                line.draw_colored(
+                    &mut ctx, ascender, height, baseline, font_core, cursor, op, &colors,
+                );
                 pos.y += height;
                 linum += 1;
             }
+            // Scrollbar, with the thumb covering the lines in view
+            let cfgthemesb = &cfgtheme.scrollbar;
+            if cfg.ui.textview.scrollbar {
+                let nlines = max(buffer.len_lines(), 1) as i64;
+                let height = textview_rect.size.height;
+                let width = min(cfgthemesb.width as i32, textview_rect.size.width);
+                let x = textview_rect.size.width - width;
+                let rect = Rect::new(point2(x, 0), size2(width, height));
+                ctx.color_quad(rect, cfgthemesb.track_color.opacity(op));
+                let start = (start_line as i64 * height as i64 / nlines) as i32;
+                let end = (linum as i64 * height as i64 / nlines) as i32;
+                let thumb_height = min(max(end - start, MIN_THUMB_HEIGHT), height);
+                let y = min(start, height - thumb_height);
+                let rect = Rect::new(point2(x, y), size2(width, thumb_height));
+                ctx.color_quad(rect, cfgthemesb.thumb_color.opacity(op));
+            }
+            // Tick marks along the right edge, showing where annotated lines are in the file
+            let annotations = buffer.annotations();
+            if !annotations.is_empty() {
+                let nlines = buffer.len_lines() as i32;
+                let height = textview_rect.size.height;
+                let tick_height = max(TICK_HEIGHT, height / nlines);
+                let x = textview_rect.size.width - TICK_WIDTH;
+                for (kind, lines) in annotations.iter() {
+                    let color = match kind {
+                        AnnotationKind::Search => cfgthemetv.search_color,
+                    };
+                    for linum in lines {
+                        let y = (*linum as i64 * height as i64 / nlines as i64) as i32;
+                        let rect = Rect::new(point2(x, y), size2(TICK_WIDTH, tick_height));
+                        ctx.color_quad(rect, color.opacity(op));
+                    }
+                }
+            }
         }
 
-        let rect = Rect::new(self.rect.origin, size2(gutter_width, self.rect.size.height)).cast();
+        let rect = Rect::new(
+            self.text_rect.origin,
+            size2(gutter_width, self.text_rect.size.height),
+        )
+        .cast();
         if view.xbase > 0 {
             let vec = point2(3, 0).to_vector();
             actx.draw_shadow(rect.translate(vec));
@@ -698,20 +1769,52 @@ impl TextView {
             } else {
                 cfgthemegtr.inactive_opacity
             };
-            if view.line_numbers || view.relative_number {
-                for (ascender, _, height, _, gline) in LinumTextIter::new(
-                    shaped_linums,
-                    shaped_text,
-                    start_line,
-                    cursor_linum,
-                    view.line_numbers,
-                    view.relative_number,
-                ) {
-                    if pos.y >= textview_rect.size.height {
-                        break;
-                    }
-                    let gline = gline.unwrap();
-                    let height = height as i32;
+            let note_lines = buffer
+                .notes()
+                .into_iter()
+                .map(|(linum, _)| linum)
+                .collect::<Vec<_>>();
+            let line_changes = buffer.line_changes();
+            for (ascender, _, height, _, gline) in LinumTextIter::new(
+                shaped_linums,
+                shaped_text,
+                start_line,
+                cursor_linum,
+                view.line_numbers,
+                view.relative_number,
+                self.line_height,
+            ) {
+                if pos.y >= textview_rect.size.height {
+                    break;
+                }
+                let height = height as i32;
+                // Lines with notes have a bar along the left edge of the gutter
+                if note_lines.contains(&linum) {
+                    let rect = Rect::new(point2(0, pos.y), size2(NOTE_SIGN_WIDTH, height));
+                    ctx.color_quad(rect, cfgthemegtr.note_color.opacity(op));
+                }
+                if let Ok(idx) = line_changes.binary_search_by_key(&linum, |(l, _)| *l) {
+                    let x = gutter_width as i32 - CHANGE_SIGN_WIDTH;
+                    let (rect, color) = match line_changes[idx].1 {
+                        LineChange::Added => (
+                            Rect::new(point2(x, pos.y), size2(CHANGE_SIGN_WIDTH, height)),
+                            cfgthemegtr.added_color,
+                        ),
+                        LineChange::Modified => (
+                            Rect::new(point2(x, pos.y), size2(CHANGE_SIGN_WIDTH, height)),
+                            cfgthemegtr.modified_color,
+                        ),
+                        LineChange::Deleted => (
+                            Rect::new(
+                                point2(x - CHANGE_SIGN_WIDTH, pos.y),
+                                size2(CHANGE_SIGN_WIDTH * 2, DELETED_SIGN_HEIGHT),
+                            ),
+                            cfgthemegtr.deleted_color,
+                        ),
+                    };
+                    ctx.color_quad(rect, color.opacity(op));
+                }
+                if let Some(gline) = gline {
                     let mut baseline = pos;
                     baseline.y += ascender;
                     baseline.x -= gline.metrics.width as i32;
@@ -719,11 +1822,252 @@ impl TextView {
                         baseline.x = cfggtr.padding as i32;
                     }
                     gline.draw(&mut ctx, ascender, height, baseline, font_core, None, op);
-                    pos.y += height;
-                    linum += 1;
                 }
+                pos.y += height;
+                linum += 1;
+            }
+        }
+    }
+
+    // Shape the numbers of lines in view. Relative numbers are distances from the cursor line,
+    // which itself shows its line number
+    fn shape_gutter(&mut self) {
+        let (first, last) = self.visible_lines();
+        let view = &self.views[self.cur_view_idx];
+        if !view.line_numbers && !view.relative_number {
+            return;
+        }
+        let cursor_linum = view.cursor.line_num();
+        // The line after the last fully visible one may be partly in view
+        let lines = first..(last + 2);
+        let numbers = if view.relative_number {
+            let mut numbers = lines
+                .map(|l| max(l, cursor_linum) - min(l, cursor_linum))
+                .collect::<Vec<_>>();
+            if view.line_numbers {
+                numbers.push(cursor_linum + 1);
+            } else {
+                // Distances are at most the number of lines in view while the cursor is in view,
+                // so the gutter stays that wide unless the cursor is scrolled far out of view
+                let widest = numbers.iter().cloned().max().unwrap_or(0);
+                let len_lines = view.buffer.borrow().len_lines();
+                self.relative_widest = min(max(widest, last + 2 - first), len_lines);
+                numbers.push(self.relative_widest);
             }
+            numbers
+        } else {
+            lines.map(|l| l + 1).collect()
+        };
+        let buffer = &mut *view.buffer.borrow_mut();
+        buffer.shape_gutter(self.dpi, &numbers);
+    }
+
+    // File name and input mode on the left, syntax and cursor position on the right. The input
+    // mode is only shown for the active view
+    fn draw_statusline(&mut self, actx: &mut ActiveRenderCtx, is_active: bool, mode: &str) {
+        let cfg = &*self.config.borrow();
+        let cfgsl = &cfg.ui.statusline;
+        let cfgthemesl = &cfg.ui.theme().statusline;
+        if !cfgsl.enabled || self.text_rect.size.height == self.rect.size.height {
+            return;
+        }
+
+        let chrome_dpi = self.chrome_dpi();
+        let view = &self.views[self.cur_view_idx];
+        let buffer = &*view.buffer.borrow();
+        let name = match buffer.path() {
+            _ if buffer.is_scratch() => buffer.title().unwrap_or("[scratch]").to_owned(),
+            // Files in a project are shown relative to its root
+            Some(path) => match buffer
+                .project_root()
+                .and_then(|root| Path::new(path).strip_prefix(root).ok())
+            {
+                Some(rel) => rel.to_string_lossy().into_owned(),
+                None => Path::new(path)
+                    .file_name()
+                    .and_then(|s| s.to_str())
+                    .unwrap_or(path)
+                    .to_owned(),
+            },
+            None => "[no name]".to_owned(),
+        };
+        let name = if buffer.is_new_file() {
+            format!("{} [new file]", name)
+        } else {
+            name
+        };
+        let name = if buffer.is_dirty() {
+            format!("{} [+]", name)
+        } else {
+            name
+        };
+        let name = if self.leader.is_some() {
+            format!("{} [following]", name)
+        } else {
+            name
+        };
+        // Show the note on the cursor line, if any
+        let name = match buffer.note(view.cursor.line_num()) {
+            Some(note) => format!("{}  \u{2014} {}", name, note),
+            None => name,
+        };
+        // Only encodings other than UTF-8 are shown
+        let encoding = match buffer.encoding() {
+            Encoding::Utf8 => String::new(),
+            encoding => format!("{}  ", encoding.name()),
+        };
+        let position = format!(
+            "{}  {}  {}{}:{}",
+            buffer.syntax_name(),
+            buffer.indent_style(),
+            encoding,
+            view.cursor.line_num() + 1,
+            view.cursor.line_gidx() + 1
+        );
+
+        let font_core = &mut *self.font_core.borrow_mut();
+        let mut spans = Vec::new();
+        if is_active {
+            spans.push(TextSpan::new(
+                mode,
+                cfgsl.text_size,
+                TextStyle::default(),
+                cfgthemesl.mode_color,
+                TextPitch::Variable,
+                None,
+            ));
+            spans.push(TextSpan::new(
+                "  ",
+                cfgsl.text_size,
+                TextStyle::default(),
+                cfgthemesl.foreground_color,
+                TextPitch::Variable,
+                None,
+            ));
+        }
+        spans.push(TextSpan::new(
+            &name,
+            cfgsl.text_size,
+            TextStyle::default(),
+            cfgthemesl.foreground_color,
+            TextPitch::Variable,
+            None,
+        ));
+        let left = ShapedTextLine::from_textline(
+            TextLine(spans),
+            cfgsl.fixed_face,
+            cfgsl.variable_face,
+            font_core,
+            chrome_dpi,
+        );
+        let right = ShapedTextLine::from_textstr(
+            TextSpan::new(
+                &position,
+                cfgsl.text_size,
+                TextStyle::default(),
+                cfgthemesl.foreground_color,
+                TextPitch::Variable,
+                None,
+            ),
+            cfgsl.fixed_face,
+            cfgsl.variable_face,
+            font_core,
+            chrome_dpi,
+        );
+
+        let rect = Rect::new(
+            point2(
+                self.rect.origin.x,
+                self.rect.origin.y + self.text_rect.size.height,
+            ),
+            size2(
+                self.rect.size.width,
+                self.rect.size.height - self.text_rect.size.height,
+            ),
+        );
+        let mut op = if is_active {
+            100
+        } else {
+            cfgthemesl.inactive_opacity
+        };
+        if self.presentation.is_some() {
+            op = min(op, cfg.ui.presentation.chrome_opacity);
         }
+        let mut ctx = actx.get_widget_context(rect.cast(), cfgthemesl.background_color);
+        let ascender = max(left.metrics.ascender, right.metrics.ascender);
+        let height = max(left.metrics.height, right.metrics.height) as i32;
+        let baseline = point2(cfgsl.padding as i32, cfgsl.padding as i32 + ascender);
+        left.draw(&mut ctx, ascender, height, baseline, font_core, None, op);
+        let x = rect.size.width as i32 - cfgsl.padding as i32 - right.metrics.width as i32;
+        let baseline = point2(max(x, 0), baseline.y);
+        right.draw(&mut ctx, ascender, height, baseline, font_core, None, op);
+    }
+
+    pub(super) fn is_presenting(&self) -> bool {
+        self.presentation.is_some()
+    }
+
+    /// Presentation mode scales text up, spaces lines out, hides the gutter and dims the
+    /// statusline, as set in the config. Leaving it restores the line number settings from
+    /// before
+    pub(super) fn set_presenting(&mut self, val: bool) {
+        if val == self.presentation.is_some() {
+            return;
+        }
+        let dpi = match self.presentation.take() {
+            Some(presentation) => {
+                for view in &mut self.views {
+                    let saved = presentation.views.iter().find(|(id, _, _)| *id == view.id);
+                    let (numbers, relative) = match saved {
+                        Some((_, numbers, relative)) => (*numbers, *relative),
+                        None => (presentation.line_numbers, presentation.relative_number),
+                    };
+                    view.line_numbers = numbers;
+                    view.relative_number = relative;
+                }
+                self.line_numbers = presentation.line_numbers;
+                self.relative_number = presentation.relative_number;
+                self.line_height = 1.0;
+                presentation.dpi
+            }
+            None => {
+                self.presentation = Some(Presentation {
+                    dpi: self.dpi,
+                    line_numbers: self.line_numbers,
+                    relative_number: self.relative_number,
+                    views: self
+                        .views
+                        .iter()
+                        .map(|v| (v.id, v.line_numbers, v.relative_number))
+                        .collect(),
+                });
+                for view in &mut self.views {
+                    view.line_numbers = false;
+                    view.relative_number = false;
+                }
+                self.line_numbers = false;
+                self.relative_number = false;
+                self.line_height = self.config.borrow().ui.presentation.line_height;
+                self.dpi
+            }
+        };
+        self.set_dpi(dpi);
+    }
+
+    pub(super) fn color_column(&self) -> Option<usize> {
+        self.color_column
+    }
+
+    pub(super) fn set_color_column(&mut self, column: Option<usize>) {
+        self.color_column = column;
+    }
+
+    pub(super) fn cursor_line(&self) -> bool {
+        self.cursor_line
+    }
+
+    pub(super) fn set_cursor_line(&mut self, val: bool) {
+        self.cursor_line = val;
     }
 
     pub(super) fn set_line_numbers(&mut self, val: bool) {
@@ -747,6 +2091,7 @@ impl TextView {
     }
 
     fn snap_to_cursor(&mut self) {
+        self.ensure_shaped();
         let cfg = &*self.config.borrow();
         let cfggtr = &cfg.ui.gutter;
 
@@ -755,11 +2100,7 @@ impl TextView {
         let cursor_linum = view.cursor.line_num();
         let (shaped_linums, shaped_text) = buffer.shaped_data(self.dpi).unwrap();
 
-        let gutter_width = if view.line_numbers || view.relative_number {
-            shaped_linums[shaped_linums.len() - 1].metrics.width + cfggtr.padding * 2
-        } else {
-            cfggtr.padding * 2
-        };
+        let gutter_width = gutter_width(view, shaped_linums, self.relative_widest, cfggtr.padding);
 
         // Snap to y
         if cursor_linum <= view.start_line {
@@ -775,11 +2116,12 @@ impl TextView {
                 cursor_linum,
                 view.line_numbers,
                 view.relative_number,
+                self.line_height,
             );
             while let Some((_, _, height, _, _)) = iter.prev() {
                 total_height += height;
-                if total_height >= self.rect.size.height {
-                    view.ybase = total_height - self.rect.size.height;
+                if total_height >= self.text_rect.size.height {
+                    view.ybase = total_height - self.text_rect.size.height;
                     view.start_line = linum;
                     break;
                 }
@@ -795,28 +2137,17 @@ impl TextView {
         let line = &shaped_text[cursor_linum];
         let mut grapheme = 0;
         let mut cursor_x = 0;
-        let width = self.rect.size.width - gutter_width;
+        let width = self.text_rect.size.width - gutter_width;
         for span in &line.spans {
             for cluster in span.clusters() {
                 if grapheme > gidx || grapheme + cluster.num_graphemes <= gidx {
-                    for gi in cluster.glyph_infos {
-                        cursor_x += gi.advance.width;
-                    }
+                    cursor_x += cluster.width();
                     grapheme += cluster.num_graphemes;
                     continue;
                 }
                 let diff = gidx - grapheme;
-                let num_glyphs = cluster.glyph_infos.len();
-                let glyphs_per_grapheme = num_glyphs / cluster.num_graphemes;
-                let start = diff * glyphs_per_grapheme;
-                let end = start + glyphs_per_grapheme;
-                for i in 0..start {
-                    cursor_x += cluster.glyph_infos[i].advance.width;
-                }
-                let mut cursor_width = 0;
-                for i in start..end {
-                    cursor_width += cluster.glyph_infos[i].advance.width;
-                }
+                cursor_x += cluster.grapheme_x(diff);
+                let cursor_width = cluster.grapheme_x(diff + 1) - cluster.grapheme_x(diff);
                 let cursor_x = if cursor_x < 0 { 0 } else { cursor_x as u32 };
                 let cursor_width = if cursor_width < 0 {
                     0
@@ -835,22 +2166,24 @@ impl TextView {
 }
 
 struct LinumTextIter<'a> {
-    linums: &'a [ShapedTextLine],
+    linums: &'a ShapedGutter,
     textlines: &'a [ShapedTextLine],
     i: usize,
     cursor_line: usize,
     numbers: bool,
     rela: bool,
+    line_height: f64,
 }
 
 impl<'a> LinumTextIter<'a> {
     fn new(
-        linums: &'a [ShapedTextLine],
+        linums: &'a ShapedGutter,
         textlines: &'a [ShapedTextLine],
         start_line: usize,
         cursor_line: usize,
         line_numbers: bool,
         relative_line_numbers: bool,
+        line_height: f64,
     ) -> LinumTextIter<'a> {
         LinumTextIter {
             linums: linums,
@@ -859,9 +2192,22 @@ impl<'a> LinumTextIter<'a> {
             cursor_line: cursor_line,
             numbers: line_numbers,
             rela: relative_line_numbers,
+            line_height: line_height,
         }
     }
 
+    // Ascender, descender and height of a line spaced out to line_height, with the text centered
+    // in the extra space
+    fn spaced(&self, ascender: i32, descender: i32, height: u32) -> (i32, i32, u32) {
+        let extra = (height as f64 * (self.line_height - 1.0)).round() as u32;
+        let above = (extra / 2) as i32;
+        (
+            ascender + above,
+            descender - (extra as i32 - above),
+            height + extra,
+        )
+    }
+
     fn prev(
         &mut self,
     ) -> Option<(
@@ -887,13 +2233,13 @@ impl<'a> LinumTextIter<'a> {
                 } else {
                     self.i - self.cursor_line
                 };
-                let lline = &self.linums[idx];
+                let lline = self.linums.get(idx);
                 height = max(height, lline.metrics.height);
                 ascender = max(ascender, lline.metrics.ascender);
                 descender = min(ascender, lline.metrics.descender);
                 Some(lline)
             } else if self.numbers {
-                let lline = &self.linums[self.i + 1];
+                let lline = self.linums.get(self.i + 1);
                 height = max(height, lline.metrics.height);
                 ascender = max(ascender, lline.metrics.ascender);
                 descender = min(ascender, lline.metrics.descender);
@@ -901,6 +2247,7 @@ impl<'a> LinumTextIter<'a> {
             } else {
                 None
             };
+            let (ascender, descender, height) = self.spaced(ascender, descender, height);
             Some((ascender, descender, height, tline, lline))
         }
     }
@@ -932,13 +2279,13 @@ impl<'a> Iterator for LinumTextIter<'a> {
                 } else {
                     self.i - self.cursor_line
                 };
-                let lline = &self.linums[idx];
+                let lline = self.linums.get(idx);
                 height = max(height, lline.metrics.height);
                 ascender = max(ascender, lline.metrics.ascender);
                 descender = min(ascender, lline.metrics.descender);
                 Some(lline)
             } else if self.numbers {
-                let lline = &self.linums[self.i + 1];
+                let lline = self.linums.get(self.i + 1);
                 height = max(height, lline.metrics.height);
                 ascender = max(ascender, lline.metrics.ascender);
                 descender = min(ascender, lline.metrics.descender);
@@ -948,7 +2295,30 @@ impl<'a> Iterator for LinumTextIter<'a> {
             };
             //println!("line: {}: height: {}", self.i, height);
             self.i += 1;
+            let (ascender, descender, height) = self.spaced(ascender, descender, height);
             Some((ascender, descender, height, tline, lline))
         }
     }
 }
+
+// Width of the gutter, with padding. It fits the largest line number, or with only relative
+// numbers, relative_widest, instead of the number of lines in the buffer
+fn gutter_width(
+    view: &View,
+    shaped_linums: &ShapedGutter,
+    relative_widest: usize,
+    padding: u32,
+) -> u32 {
+    if view.relative_number && !view.line_numbers {
+        shaped_linums.get(relative_widest).metrics.width + padding * 2
+    } else if view.line_numbers {
+        shaped_linums.widest().metrics.width + padding * 2
+    } else {
+        padding * 2
+    }
+}
+
+/// Words are made of letters, digits and underscores
+pub(super) fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
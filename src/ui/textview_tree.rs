@@ -1,22 +1,42 @@
 // (C) 2019 Srimanta Barua <srimanta.barua1@gmail.com>
 
 use std::cell::RefCell;
+use std::cmp::min;
 use std::rc::Rc;
+use std::time::Duration;
 
-use euclid::{size2, Rect, Size2D};
+use euclid::{point2, size2, Rect, Size2D};
 
 use crate::config::Cfg;
 use crate::font::FontCore;
+use crate::session::SessionNode;
 use crate::textbuffer::Buffer;
 use crate::types::{PixelSize, DPI};
 
 use super::context::ActiveRenderCtx;
-use super::textview::TextView;
+use super::textview::{TextView, Viewport};
+
+// Views dropped this close to an edge of the window are moved to a new split along that edge
+const DROP_EDGE_SIZE: i32 = 24;
+// Panes can't be resized below this share of their split
+const MIN_SHARE: f64 = 0.05;
+
+/// Edge of the window, where a dragged view is moved to a new split, or of a pane, to move focus
+/// across
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Edge {
+    Left,
+    Right,
+    Top,
+    Bottom,
+}
 
 pub(super) struct TextViewTree {
     config: Rc<RefCell<Cfg>>,
     rect: Rect<u32, PixelSize>,
     root: Node,
+    // Geometry of the active pane before it was zoomed
+    zoomed: Option<Rect<u32, PixelSize>>,
 }
 
 impl TextViewTree {
@@ -44,12 +64,50 @@ impl TextViewTree {
             root: leaf,
             config: config,
             rect: rect,
+            zoomed: None,
         }
     }
 
+    // Rebuild the layout of panes saved in a session. Buffers and view IDs for saved files are
+    // returned by open, which is called with None for panes left without any file
+    pub(super) fn from_session(
+        layout: &SessionNode,
+        rect: Rect<u32, PixelSize>,
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        dpi: Size2D<u32, DPI>,
+        line_numbers: bool,
+        relative_number: bool,
+        open: &mut dyn FnMut(Option<&str>) -> Option<(Rc<RefCell<Buffer>>, usize)>,
+    ) -> TextViewTree {
+        let mut root = Node::from_session(
+            layout,
+            rect,
+            font_core,
+            config.clone(),
+            dpi,
+            line_numbers,
+            relative_number,
+            open,
+        );
+        let borderwidth = config.borrow().ui.theme().textview.border_width;
+        root.set_rect(rect, borderwidth);
+        TextViewTree {
+            root: root,
+            config: config,
+            rect: rect,
+            zoomed: None,
+        }
+    }
+
+    pub(super) fn session_layout(&self) -> SessionNode {
+        self.root.session_layout()
+    }
+
     // Kill the current active pane. Return true if that was the last pane, false
     // otherwise
     pub(super) fn kill_active(&mut self) -> bool {
+        self.zoomed = None;
         if !self.root.kill_active() {
             let cfg = &*self.config.borrow();
             let borderwidth = cfg.ui.theme().textview.border_width;
@@ -59,8 +117,16 @@ impl TextViewTree {
         true
     }
 
-    pub(super) fn draw(&mut self, active_ctx: &mut ActiveRenderCtx) {
-        {
+    /// Draw the panes. Unless full is set, only panes that changed on their own since they were
+    /// last drawn are redrawn, and the rest of the frame is assumed to be intact
+    pub(super) fn draw(
+        &mut self,
+        active_ctx: &mut ActiveRenderCtx,
+        search: Option<&str>,
+        mode: &str,
+        full: bool,
+    ) {
+        if full {
             let cfg = &*self.config.borrow();
             let theme = &cfg.ui.theme().textview;
             let bgcol = theme.background_color;
@@ -69,11 +135,221 @@ impl TextViewTree {
             let mut ctx = active_ctx.get_widget_context(rect, bgcol);
             ctx.color_quad(rect, border_color);
         }
-        self.root.draw(active_ctx, true)
+        if self.zoomed.is_some() {
+            let active = self.root.active_mut();
+            if full || active.is_damaged() {
+                active.draw(active_ctx, true, search, mode)
+            }
+        } else {
+            self.root.draw(active_ctx, true, search, mode, full)
+        }
     }
 
     pub(super) fn move_cursor_to_point(&mut self, point: (i32, i32)) {
-        self.root.move_cursor_to_point(point);
+        if self.zoomed.is_some() {
+            self.root.active_mut().move_cursor_to_point(point);
+        } else {
+            self.root.move_cursor_to_point(point);
+        }
+    }
+
+    /// Extend the active pane's selection to point, in window coordinates, while the mouse is
+    /// dragged. Returns true if any text is selected
+    pub(super) fn drag_to_point(&mut self, point: (i32, i32)) -> bool {
+        let active = self.root.active_mut();
+        let origin = active.get_rect().origin;
+        active.drag_to_point((point.0 - origin.x as i32, point.1 - origin.y as i32))
+    }
+
+    /// Focus the pane at point, in window coordinates, and jump to the position on its scrollbar
+    /// if the point is on one. Returns true if the scrollbar was clicked
+    pub(super) fn click_scrollbar(&mut self, point: (i32, i32)) -> bool {
+        self.focus_point(point);
+        let active = self.root.active_mut();
+        let origin = active.get_rect().origin;
+        let point = (point.0 - origin.x as i32, point.1 - origin.y as i32);
+        if !active.scrollbar_contains(point) {
+            return false;
+        }
+        active.scroll_to_scrollbar_point(point.1);
+        true
+    }
+
+    /// Jump to point, in window coordinates, while the active pane's scrollbar is dragged
+    pub(super) fn drag_scrollbar(&mut self, point: (i32, i32)) {
+        let active = self.root.active_mut();
+        let origin = active.get_rect().origin;
+        active.scroll_to_scrollbar_point(point.1 - origin.y as i32);
+    }
+
+    /// ID of the pane with its statusline at point, in window coordinates
+    pub(super) fn statusline_at(&self, point: (i32, i32)) -> Option<usize> {
+        let t = self.pane_at(point)?;
+        let origin = t.get_rect().origin;
+        if t.statusline_contains((point.0 - origin.x as i32, point.1 - origin.y as i32)) {
+            Some(t.id())
+        } else {
+            None
+        }
+    }
+
+    /// Edge of the window that point, in window coordinates, is close to
+    pub(super) fn edge_at(&self, point: (i32, i32)) -> Option<Edge> {
+        let rbox = self.rect.to_box2d().cast::<i32>();
+        if point.0 < rbox.min.x + DROP_EDGE_SIZE {
+            Some(Edge::Left)
+        } else if point.0 >= rbox.max.x - DROP_EDGE_SIZE {
+            Some(Edge::Right)
+        } else if point.1 < rbox.min.y + DROP_EDGE_SIZE {
+            Some(Edge::Top)
+        } else if point.1 >= rbox.max.y - DROP_EDGE_SIZE {
+            Some(Edge::Bottom)
+        } else {
+            None
+        }
+    }
+
+    /// ID of the pane at point, in window coordinates
+    pub(super) fn pane_id_at(&self, point: (i32, i32)) -> Option<usize> {
+        self.pane_at(point).map(|t| t.id())
+    }
+
+    /// Focus the pane across the given edge of the active pane, next to the middle of that edge.
+    /// Returns false if there is no such pane
+    pub(super) fn focus_neighbor(&mut self, edge: Edge) -> bool {
+        if self.zoomed.is_some() {
+            return false;
+        }
+        let rbox = self.root.active().get_rect().to_box2d().cast::<i32>();
+        let border = self.config.borrow().ui.theme().textview.border_width as i32;
+        let center = rbox.center();
+        let point = match edge {
+            Edge::Left => (rbox.min.x - border - 1, center.y),
+            Edge::Right => (rbox.max.x + border, center.y),
+            Edge::Top => (center.x, rbox.min.y - border - 1),
+            Edge::Bottom => (center.x, rbox.max.y + border),
+        };
+        match self.root.pane_at(point).map(|t| t.id()) {
+            Some(id) => self.root.focus_id(id),
+            None => false,
+        }
+    }
+
+    /// Focus the pane after the active one, in layout order, wrapping around to the first
+    pub(super) fn focus_next(&mut self) {
+        if self.zoomed.is_some() {
+            return;
+        }
+        let mut ids = Vec::new();
+        self.root.pane_ids(&mut ids);
+        let active = self.root.active().id();
+        if let Some(i) = ids.iter().position(|id| *id == active) {
+            self.root.focus_id(ids[(i + 1) % ids.len()]);
+        }
+    }
+
+    // Only the active pane is visible while it is zoomed
+    fn pane_at(&self, point: (i32, i32)) -> Option<&TextView> {
+        if self.zoomed.is_some() {
+            Some(self.root.active())
+        } else {
+            self.root.pane_at(point)
+        }
+    }
+
+    /// Show the buffer of pane from in pane to, with the cursor at the same position. Unless
+    /// copy is set, the buffer is removed from pane from, which is closed if it has nothing
+    /// else to show
+    pub(super) fn move_view(&mut self, from: usize, to: usize, copy: bool, view_id: usize) {
+        if from == to {
+            return;
+        }
+        let (buffer, linum, gidx) = match self.root.find(from) {
+            Some(t) => (t.buffer(), t.cursor_line_num(), t.cursor_gidx()),
+            None => return,
+        };
+        match self.root.find_mut(to) {
+            Some(t) => {
+                t.show_buffer(buffer.clone(), view_id);
+                t.go_to_line_gidx(linum, gidx);
+            }
+            None => return,
+        }
+        if !copy {
+            self.remove_view(from, &buffer);
+        }
+        self.root.focus_id(to);
+    }
+
+    /// Show the buffer of pane from in a new pane along an edge of the window, with the cursor
+    /// at the same position. Unless copy is set, the buffer is removed from pane from, which is
+    /// closed if it has nothing else to show
+    pub(super) fn move_view_to_edge(
+        &mut self,
+        from: usize,
+        edge: Edge,
+        copy: bool,
+        view_id: usize,
+    ) {
+        let (buffer, other) = match self.root.find(from) {
+            Some(t) => {
+                let mut other = t.split(view_id);
+                other.go_to_line_gidx(t.cursor_line_num(), t.cursor_gidx());
+                (t.buffer(), other)
+            }
+            None => return,
+        };
+        let new = Node::Leaf(other);
+        self.zoomed = None;
+        match (&mut self.root, edge) {
+            (Node::InnerH(v, _, i, w), Edge::Left) | (Node::InnerV(v, _, i, w), Edge::Top) => {
+                v.insert(0, new);
+                w.insert(0, mean_weight(w));
+                *i = Some(0);
+            }
+            (Node::InnerH(v, _, i, w), Edge::Right) | (Node::InnerV(v, _, i, w), Edge::Bottom) => {
+                v.push(new);
+                w.push(mean_weight(w));
+                *i = Some(v.len() - 1);
+            }
+            (root, _) => {
+                let old =
+                    std::mem::replace(root, Node::InnerH(Vec::new(), self.rect, None, Vec::new()));
+                let (v, i) = match edge {
+                    Edge::Left | Edge::Top => (vec![new, old], 0),
+                    Edge::Right | Edge::Bottom => (vec![old, new], 1),
+                };
+                *root = match edge {
+                    Edge::Left | Edge::Right => Node::InnerH(v, self.rect, Some(i), vec![1.0; 2]),
+                    Edge::Top | Edge::Bottom => Node::InnerV(v, self.rect, Some(i), vec![1.0; 2]),
+                };
+            }
+        }
+        if !copy {
+            self.remove_view(from, &buffer);
+        }
+        self.root.focus_id(view_id);
+        let cfg = &*self.config.borrow();
+        let borderwidth = cfg.ui.theme().textview.border_width;
+        self.root.compute_rects(borderwidth);
+    }
+
+    // Remove buffer from pane id, closing the pane if it is left empty
+    fn remove_view(&mut self, id: usize, buffer: &Rc<RefCell<Buffer>>) {
+        let empty = match self.root.find_mut(id) {
+            Some(t) => t.remove_buffer(buffer),
+            None => return,
+        };
+        if empty {
+            self.root.focus_id(id);
+            self.kill_active();
+        }
+    }
+
+    pub(super) fn focus_point(&mut self, point: (i32, i32)) {
+        if self.zoomed.is_none() {
+            self.root.focus_point(point);
+        }
     }
 
     pub(super) fn scroll_views(
@@ -82,7 +358,16 @@ impl TextViewTree {
         force: (f64, f64),
         time: f64,
     ) -> bool {
-        self.root.scroll(cursor, force, time)
+        if self.zoomed.is_some() {
+            let force = if cursor.is_some() { force } else { (0.0, 0.0) };
+            self.root.active_mut().scroll(force, time)
+        } else {
+            self.root.scroll(cursor, force, time)
+        }
+    }
+
+    pub(super) fn is_scrolling(&self) -> bool {
+        self.root.is_scrolling()
     }
 
     pub(super) fn set_rect(&mut self, rect: Rect<u32, PixelSize>) {
@@ -90,30 +375,120 @@ impl TextViewTree {
         let borderwidth = cfg.ui.theme().textview.border_width;
         self.rect = rect;
         self.root.set_rect(rect, borderwidth);
+        if self.zoomed.is_some() {
+            let active = self.root.active_mut();
+            self.zoomed = Some(active.get_rect());
+            active.set_rect(rect);
+        }
+    }
+
+    /// Set the active pane's share of the width of the side-by-side split containing it, given its
+    /// current share. Returns false if there is no such split
+    pub(super) fn resize_active(&mut self, share: &dyn Fn(f64) -> f64) -> bool {
+        if !self.root.resize_active_h(share) {
+            return false;
+        }
+        let cfg = &*self.config.borrow();
+        let borderwidth = cfg.ui.theme().textview.border_width;
+        self.zoomed = None;
+        self.root.compute_rects(borderwidth);
+        true
+    }
+
+    // Toggle between the active pane covering the whole tree, and the split layout
+    pub(super) fn toggle_zoom(&mut self) {
+        let active = self.root.active_mut();
+        if let Some(rect) = self.zoomed.take() {
+            active.set_rect(rect);
+        } else {
+            self.zoomed = Some(active.get_rect());
+            active.set_rect(self.rect);
+        }
     }
 
     pub(super) fn active_mut(&mut self) -> &mut TextView {
         self.root.active_mut()
     }
 
+    // Focus the pane with the given ID. Returns false if there is no such pane
+    pub(super) fn focus_pane(&mut self, id: usize) -> bool {
+        self.root.focus_id(id)
+    }
+
+    // Remove buffer from all panes. Panes left without any buffer are given the buffer and view
+    // ID returned by the replacement function
+    pub(super) fn remove_buffer<F>(&mut self, buffer: &Rc<RefCell<Buffer>>, mut replacement: F)
+    where
+        F: FnMut() -> (Rc<RefCell<Buffer>>, usize),
+    {
+        self.root.remove_buffer(buffer, &mut replacement);
+    }
+
+    /// Whether a flash animation is running in any pane
+    pub(super) fn is_flashing(&self) -> bool {
+        self.root.is_flashing()
+    }
+
+    // Buffers currently shown in any pane
+    /// Advance flash animations in all panes. Returns true if any pane has to be redrawn
+    pub(super) fn update_flashes(&mut self, duration: Duration) -> bool {
+        self.root.update_flashes(duration)
+    }
+
+    pub(super) fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        self.root.set_dpi(dpi);
+    }
+
+    // Turn presentation mode on or off in all panes
+    pub(super) fn set_presenting(&mut self, val: bool) {
+        self.root.set_presenting(val);
+    }
+
+    pub(super) fn visible_buffers(&self) -> Vec<Rc<RefCell<Buffer>>> {
+        let mut ret = Vec::new();
+        self.root.visible_buffers(&mut ret);
+        ret
+    }
+
     pub(super) fn split_h(&mut self, view_id: usize) {
         let cfg = &*self.config.borrow();
         let borderwidth = cfg.ui.theme().textview.border_width;
+        self.zoomed = None;
         self.root.split_h(view_id);
         self.root.compute_rects(borderwidth);
     }
 
+    // Split the active pane side by side. The new pane follows the active pane, which stays
+    // focused
+    pub(super) fn split_follow(&mut self, view_id: usize) {
+        let leader = self.root.active_mut().id();
+        self.split_h(view_id);
+        self.root.active_mut().set_leader(Some(leader));
+        self.root.focus_id(leader);
+    }
+
+    // Copy what leaders show over to panes following them. Panes whose leader was closed stop
+    // following. Returns true if any pane changed
+    pub(super) fn sync_followers(&mut self, view_id: &mut dyn FnMut() -> usize) -> bool {
+        let mut viewports = Vec::new();
+        self.root.viewports(&mut viewports);
+        self.root.sync_followers(&viewports, view_id)
+    }
+
     pub(super) fn split_v(&mut self, view_id: usize) {
         let cfg = &*self.config.borrow();
         let borderwidth = cfg.ui.theme().textview.border_width;
+        self.zoomed = None;
         self.root.split_v(view_id);
         self.root.compute_rects(borderwidth);
     }
 }
 
+// Splits hold their children, their rect, the index of the active child, and a weight for each
+// child. Children get shares of the split proportional to their weights
 enum Node {
-    InnerH(Vec<Node>, Rect<u32, PixelSize>, Option<usize>),
-    InnerV(Vec<Node>, Rect<u32, PixelSize>, Option<usize>),
+    InnerH(Vec<Node>, Rect<u32, PixelSize>, Option<usize>, Vec<f64>),
+    InnerV(Vec<Node>, Rect<u32, PixelSize>, Option<usize>, Vec<f64>),
     Leaf(TextView),
 }
 
@@ -140,22 +515,308 @@ impl Node {
         ))
     }
 
+    fn from_session(
+        layout: &SessionNode,
+        rect: Rect<u32, PixelSize>,
+        font_core: Rc<RefCell<FontCore>>,
+        config: Rc<RefCell<Cfg>>,
+        dpi: Size2D<u32, DPI>,
+        line_numbers: bool,
+        relative_number: bool,
+        open: &mut dyn FnMut(Option<&str>) -> Option<(Rc<RefCell<Buffer>>, usize)>,
+    ) -> Node {
+        match layout {
+            SessionNode::Pane(views, current) => {
+                let mut opened = Vec::new();
+                let mut current_idx = 0;
+                for (i, view) in views.iter().enumerate() {
+                    if let Some((buffer, view_id)) = open(Some(&view.path)) {
+                        if i == *current {
+                            current_idx = opened.len();
+                        }
+                        opened.push((buffer, view_id, view));
+                    }
+                }
+                if opened.is_empty() {
+                    let (buffer, view_id) = open(None).unwrap();
+                    return Node::new_leaf(
+                        buffer,
+                        rect,
+                        font_core,
+                        config,
+                        dpi,
+                        line_numbers,
+                        relative_number,
+                        view_id,
+                    );
+                }
+                let mut iter = opened.iter();
+                let (buffer, view_id, _) = iter.next().unwrap();
+                let mut t = TextView::new(
+                    buffer.clone(),
+                    rect,
+                    font_core,
+                    config,
+                    dpi,
+                    line_numbers,
+                    relative_number,
+                    *view_id,
+                );
+                for (buffer, view_id, _) in iter {
+                    t.add_buffer(buffer.clone(), *view_id);
+                }
+                // Restore the current view last, so that it is the one shown
+                let order = (0..opened.len()).filter(|i| *i != current_idx);
+                for i in order.chain(Some(current_idx)) {
+                    let view = opened[i].2;
+                    t.restore_view(i, view.line, view.gidx, view.start_line);
+                }
+                Node::Leaf(t)
+            }
+            SessionNode::Split(vertical, children, active) => {
+                let v: Vec<Node> = children
+                    .iter()
+                    .map(|child| {
+                        Node::from_session(
+                            child,
+                            rect,
+                            font_core.clone(),
+                            config.clone(),
+                            dpi,
+                            line_numbers,
+                            relative_number,
+                            open,
+                        )
+                    })
+                    .collect();
+                let weights = vec![1.0; v.len()];
+                if *vertical {
+                    Node::InnerV(v, rect, Some(*active), weights)
+                } else {
+                    Node::InnerH(v, rect, Some(*active), weights)
+                }
+            }
+        }
+    }
+
+    fn session_layout(&self) -> SessionNode {
+        match self {
+            Node::Leaf(t) => t.session_pane(),
+            Node::InnerH(v, _, i, _) => SessionNode::Split(
+                false,
+                v.iter().map(|node| node.session_layout()).collect(),
+                i.unwrap(),
+            ),
+            Node::InnerV(v, _, i, _) => SessionNode::Split(
+                true,
+                v.iter().map(|node| node.session_layout()).collect(),
+                i.unwrap(),
+            ),
+        }
+    }
+
     fn kill_active(&mut self) -> bool {
         match self {
             Node::Leaf(_) => true,
-            Node::InnerH(v, _, i) | Node::InnerV(v, _, i) => {
+            Node::InnerH(v, _, i, w) | Node::InnerV(v, _, i, w) => {
                 let j = i.unwrap();
                 if v[j].kill_active() {
                     v.remove(j);
-                }
-                if j > 0 {
-                    *i = Some(j - 1);
+                    w.remove(j);
+                    // Splits are inserted before the active pane, so focus the pane that
+                    // took the killed pane's place
+                    if j == v.len() && j > 0 {
+                        *i = Some(j - 1);
+                    }
                 }
                 v.len() == 0
             }
         }
     }
 
+    fn remove_buffer(
+        &mut self,
+        buffer: &Rc<RefCell<Buffer>>,
+        replacement: &mut dyn FnMut() -> (Rc<RefCell<Buffer>>, usize),
+    ) {
+        match self {
+            Node::Leaf(t) => {
+                if t.remove_buffer(buffer) {
+                    let (buffer, view_id) = replacement();
+                    t.add_buffer(buffer, view_id);
+                }
+            }
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                for node in v {
+                    node.remove_buffer(buffer, replacement);
+                }
+            }
+        }
+    }
+
+    fn set_dpi(&mut self, dpi: Size2D<u32, DPI>) {
+        match self {
+            Node::Leaf(t) => t.set_dpi(dpi),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                for node in v {
+                    node.set_dpi(dpi);
+                }
+            }
+        }
+    }
+
+    fn set_presenting(&mut self, val: bool) {
+        match self {
+            Node::Leaf(t) => t.set_presenting(val),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                for node in v {
+                    node.set_presenting(val);
+                }
+            }
+        }
+    }
+
+    fn is_flashing(&self) -> bool {
+        match self {
+            Node::Leaf(t) => t.is_flashing(),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                v.iter().any(|n| n.is_flashing())
+            }
+        }
+    }
+
+    fn update_flashes(&mut self, duration: Duration) -> bool {
+        match self {
+            Node::Leaf(t) => t.update_flash(duration),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                let mut ret = false;
+                for node in v {
+                    ret |= node.update_flashes(duration);
+                }
+                ret
+            }
+        }
+    }
+
+    fn visible_buffers(&self, ret: &mut Vec<Rc<RefCell<Buffer>>>) {
+        match self {
+            Node::Leaf(t) => ret.push(t.buffer()),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                for node in v {
+                    node.visible_buffers(ret);
+                }
+            }
+        }
+    }
+
+    fn pane_ids(&self, ret: &mut Vec<usize>) {
+        match self {
+            Node::Leaf(t) => ret.push(t.id()),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                for node in v {
+                    node.pane_ids(ret);
+                }
+            }
+        }
+    }
+
+    fn viewports(&self, ret: &mut Vec<(usize, Viewport)>) {
+        match self {
+            Node::Leaf(t) => ret.push((t.id(), t.viewport())),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                for node in v {
+                    node.viewports(ret);
+                }
+            }
+        }
+    }
+
+    fn sync_followers(
+        &mut self,
+        viewports: &[(usize, Viewport)],
+        view_id: &mut dyn FnMut() -> usize,
+    ) -> bool {
+        match self {
+            Node::Leaf(t) => match t.leader() {
+                Some(leader) => match viewports.iter().find(|(id, _)| *id == leader) {
+                    Some((_, viewport)) => t.follow(viewport, view_id),
+                    None => {
+                        t.set_leader(None);
+                        true
+                    }
+                },
+                None => false,
+            },
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                let mut ret = false;
+                for node in v {
+                    ret |= node.sync_followers(viewports, view_id);
+                }
+                ret
+            }
+        }
+    }
+
+    fn find(&self, id: usize) -> Option<&TextView> {
+        match self {
+            Node::Leaf(t) if t.id() == id => Some(t),
+            Node::Leaf(_) => None,
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                v.iter().find_map(|n| n.find(id))
+            }
+        }
+    }
+
+    fn find_mut(&mut self, id: usize) -> Option<&mut TextView> {
+        match self {
+            Node::Leaf(t) if t.id() == id => Some(t),
+            Node::Leaf(_) => None,
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                v.iter_mut().find_map(|n| n.find_mut(id))
+            }
+        }
+    }
+
+    // Pane at point, in window coordinates
+    fn pane_at(&self, point: (i32, i32)) -> Option<&TextView> {
+        match self {
+            Node::Leaf(t) => {
+                let rect = t.get_rect().cast::<i32>();
+                if rect.contains(point2(point.0, point.1)) {
+                    Some(t)
+                } else {
+                    None
+                }
+            }
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                v.iter().find_map(|n| n.pane_at(point))
+            }
+        }
+    }
+
+    fn active(&self) -> &TextView {
+        match self {
+            Node::Leaf(t) => t,
+            Node::InnerH(v, _, i, _) | Node::InnerV(v, _, i, _) => v[i.unwrap()].active(),
+        }
+    }
+
+    // Focus the pane with the given ID. Returns false if there is no such pane
+    fn focus_id(&mut self, id: usize) -> bool {
+        match self {
+            Node::Leaf(t) => t.id() == id,
+            Node::InnerH(v, _, i, _) | Node::InnerV(v, _, i, _) => {
+                for j in 0..v.len() {
+                    if v[j].focus_id(id) {
+                        *i = Some(j);
+                        return true;
+                    }
+                }
+                false
+            }
+        }
+    }
+
     fn split_h(&mut self, view_id: usize) {
         match self {
             Node::Leaf(t) => {
@@ -165,19 +826,21 @@ impl Node {
                     vec![Node::Leaf(other), Node::Leaf(t.clone())],
                     rect,
                     Some(0),
+                    vec![1.0; 2],
                 );
             }
-            Node::InnerH(v, _, i) => {
+            Node::InnerH(v, _, i, w) => {
                 let i = i.unwrap();
                 match &mut v[i] {
                     Node::Leaf(t) => {
                         let other = t.split(view_id);
                         v.insert(i, Node::Leaf(other));
+                        w.insert(i, mean_weight(w));
                     }
                     _ => v[i].split_h(view_id),
                 }
             }
-            Node::InnerV(v, _, i) => v[i.unwrap()].split_h(view_id),
+            Node::InnerV(v, _, i, _) => v[i.unwrap()].split_h(view_id),
         }
     }
 
@@ -190,26 +853,28 @@ impl Node {
                     vec![Node::Leaf(other), Node::Leaf(t.clone())],
                     rect,
                     Some(0),
+                    vec![1.0; 2],
                 );
             }
-            Node::InnerV(v, _, i) => {
+            Node::InnerV(v, _, i, w) => {
                 let i = i.unwrap();
                 match &mut v[i] {
                     Node::Leaf(t) => {
                         let other = t.split(view_id);
                         v.insert(i, Node::Leaf(other));
+                        w.insert(i, mean_weight(w));
                     }
                     _ => v[i].split_v(view_id),
                 }
             }
-            Node::InnerH(v, _, i) => v[i.unwrap()].split_v(view_id),
+            Node::InnerH(v, _, i, _) => v[i.unwrap()].split_v(view_id),
         }
     }
 
     fn move_cursor_to_point(&mut self, point: (i32, i32)) {
         match self {
             Node::Leaf(t) => t.move_cursor_to_point(point),
-            Node::InnerH(v, _, i) => {
+            Node::InnerH(v, _, i, _) => {
                 for j in 0..v.len() {
                     let rbox = v[j].get_rect().to_box2d().cast().to_untyped();
                     if point.0 < rbox.max.x {
@@ -219,7 +884,7 @@ impl Node {
                     }
                 }
             }
-            Node::InnerV(v, _, i) => {
+            Node::InnerV(v, _, i, _) => {
                 for j in 0..v.len() {
                     let rbox = v[j].get_rect().to_box2d().cast().to_untyped();
                     if point.1 < rbox.max.y {
@@ -232,6 +897,41 @@ impl Node {
         }
     }
 
+    fn focus_point(&mut self, point: (i32, i32)) {
+        match self {
+            Node::Leaf(_) => {}
+            Node::InnerH(v, _, i, _) => {
+                for j in 0..v.len() {
+                    let rbox = v[j].get_rect().to_box2d().cast().to_untyped();
+                    if point.0 < rbox.max.x {
+                        v[j].focus_point((point.0 - rbox.min.x, point.1));
+                        *i = Some(j);
+                        break;
+                    }
+                }
+            }
+            Node::InnerV(v, _, i, _) => {
+                for j in 0..v.len() {
+                    let rbox = v[j].get_rect().to_box2d().cast().to_untyped();
+                    if point.1 < rbox.max.y {
+                        v[j].focus_point((point.0, point.1 - rbox.min.y));
+                        *i = Some(j);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn is_scrolling(&self) -> bool {
+        match self {
+            Node::Leaf(t) => t.is_scrolling(),
+            Node::InnerH(v, _, _, _) | Node::InnerV(v, _, _, _) => {
+                v.iter().any(|n| n.is_scrolling())
+            }
+        }
+    }
+
     fn scroll(&mut self, mut cursor: Option<(i32, i32)>, force: (f64, f64), time: f64) -> bool {
         match self {
             Node::Leaf(t) => {
@@ -241,7 +941,7 @@ impl Node {
                     t.scroll((0.0, 0.0), time)
                 }
             }
-            Node::InnerH(v, _, _) => {
+            Node::InnerH(v, _, _, _) => {
                 let mut ret = false;
                 for j in 0..v.len() {
                     let rbox = v[j].get_rect().to_box2d().cast().to_untyped();
@@ -256,7 +956,7 @@ impl Node {
                 }
                 ret
             }
-            Node::InnerV(v, _, _) => {
+            Node::InnerV(v, _, _, _) => {
                 let mut ret = false;
                 for j in 0..v.len() {
                     let rbox = v[j].get_rect().to_box2d().cast().to_untyped();
@@ -277,7 +977,7 @@ impl Node {
     fn set_rect(&mut self, rect: Rect<u32, PixelSize>, border_width: u32) {
         match self {
             Node::Leaf(t) => t.set_rect(rect),
-            Node::InnerH(_, r, _) | Node::InnerV(_, r, _) => *r = rect,
+            Node::InnerH(_, r, _, _) | Node::InnerV(_, r, _, _) => *r = rect,
         }
         self.compute_rects(border_width);
     }
@@ -285,32 +985,20 @@ impl Node {
     fn compute_rects(&mut self, border_width: u32) {
         match self {
             Node::Leaf(_) => {}
-            Node::InnerH(v, r, _) => {
+            Node::InnerH(v, r, _, w) => {
                 let mut pos = r.origin;
                 let height = r.size.height;
                 let total_width = r.size.width - (v.len() as u32 - 1) * border_width;
-                let width = total_width / v.len() as u32;
-                let j = total_width as usize % v.len();
-                for i in 0..j {
-                    v[i].set_rect(Rect::new(pos, size2(width + 1, height)), border_width);
-                    pos.x += width + 1 + border_width;
-                }
-                for i in j..v.len() {
+                for (i, width) in split_sizes(total_width, w).into_iter().enumerate() {
                     v[i].set_rect(Rect::new(pos, size2(width, height)), border_width);
                     pos.x += width + border_width;
                 }
             }
-            Node::InnerV(v, r, _) => {
+            Node::InnerV(v, r, _, w) => {
                 let mut pos = r.origin;
                 let width = r.size.width;
                 let total_height = r.size.height - (v.len() as u32 - 1) * border_width;
-                let height = total_height / v.len() as u32;
-                let j = total_height as usize % v.len();
-                for i in 0..j {
-                    v[i].set_rect(Rect::new(pos, size2(width, height + 1)), border_width);
-                    pos.y += height + 1 + border_width;
-                }
-                for i in j..v.len() {
+                for (i, height) in split_sizes(total_height, w).into_iter().enumerate() {
                     v[i].set_rect(Rect::new(pos, size2(width, height)), border_width);
                     pos.y += height + border_width;
                 }
@@ -318,24 +1006,66 @@ impl Node {
         }
     }
 
+    // Set the share of the innermost side-by-side split containing the active pane, given its
+    // current share. Returns false if there is no such split
+    fn resize_active_h(&mut self, share: &dyn Fn(f64) -> f64) -> bool {
+        match self {
+            Node::Leaf(_) => false,
+            Node::InnerV(v, _, i, _) => v[i.unwrap()].resize_active_h(share),
+            Node::InnerH(v, _, i, w) => {
+                let i = i.unwrap();
+                if v[i].resize_active_h(share) {
+                    return true;
+                }
+                if v.len() < 2 {
+                    return false;
+                }
+                let total = w.iter().sum::<f64>();
+                let max_share = 1.0 - MIN_SHARE * (v.len() - 1) as f64;
+                let new = share(w[i] / total).max(MIN_SHARE).min(max_share) * total;
+                // Other panes keep their proportions to each other
+                let scale = (total - new) / (total - w[i]);
+                for (j, weight) in w.iter_mut().enumerate() {
+                    if j == i {
+                        *weight = new;
+                    } else {
+                        *weight *= scale;
+                    }
+                }
+                true
+            }
+        }
+    }
+
     fn get_rect(&self) -> Rect<u32, PixelSize> {
         match self {
             Node::Leaf(t) => t.get_rect(),
-            Node::InnerH(_, r, _) | Node::InnerV(_, r, _) => *r,
+            Node::InnerH(_, r, _, _) | Node::InnerV(_, r, _, _) => *r,
         }
     }
 
     fn active_mut(&mut self) -> &mut TextView {
         match self {
             Node::Leaf(t) => t,
-            Node::InnerH(v, _, i) | Node::InnerV(v, _, i) => v[i.unwrap()].active_mut(),
+            Node::InnerH(v, _, i, _) | Node::InnerV(v, _, i, _) => v[i.unwrap()].active_mut(),
         }
     }
 
-    fn draw(&mut self, active_ctx: &mut ActiveRenderCtx, is_active: bool) {
+    fn draw(
+        &mut self,
+        active_ctx: &mut ActiveRenderCtx,
+        is_active: bool,
+        search: Option<&str>,
+        mode: &str,
+        full: bool,
+    ) {
         match self {
-            Node::Leaf(t) => t.draw(active_ctx, is_active),
-            Node::InnerH(v, _, i) | Node::InnerV(v, _, i) => {
+            Node::Leaf(t) => {
+                if full || t.is_damaged() {
+                    t.draw(active_ctx, is_active, search, mode)
+                }
+            }
+            Node::InnerH(v, _, i, _) | Node::InnerV(v, _, i, _) => {
                 for j in 0..v.len() {
                     v[j].draw(
                         active_ctx,
@@ -344,9 +1074,37 @@ impl Node {
                         } else {
                             false
                         },
+                        search,
+                        mode,
+                        full,
                     );
                 }
             }
         }
     }
 }
+
+// Weight for a new child of a split, which gets an average share
+fn mean_weight(weights: &[f64]) -> f64 {
+    if weights.is_empty() {
+        1.0
+    } else {
+        weights.iter().sum::<f64>() / weights.len() as f64
+    }
+}
+
+// Split total pixels in proportion to weights. Pixels left over from rounding down go to the
+// first children
+fn split_sizes(total: u32, weights: &[f64]) -> Vec<u32> {
+    let sum = weights.iter().sum::<f64>();
+    let mut sizes = weights
+        .iter()
+        .map(|w| (total as f64 * w / sum).floor() as u32)
+        .collect::<Vec<_>>();
+    let left = total - min(sizes.iter().sum::<u32>(), total);
+    let n = sizes.len();
+    for i in 0..left as usize {
+        sizes[i % n] += 1;
+    }
+    sizes
+}
@@ -1,7 +1,11 @@
 // (C) 2020 Srimanta Barua <srimanta.barua1@gmail.com>
 
 use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::rc::Rc;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
@@ -12,21 +16,59 @@ use directories::BaseDirs;
 use euclid::SideOffsets2D;
 use euclid::{point2, size2, Rect, Size2D};
 use glfw::{Action, Context, Glfw, Key, Modifiers, WindowEvent, WindowMode};
+use regex::{NoExpand, Regex};
+use unicode_segmentation::UnicodeSegmentation;
 use walkdir::WalkDir;
 
-use crate::config::Cfg;
-use crate::core::Core;
-use crate::types::{Color, PixelSize};
+use crate::build::Build;
+use crate::config::{Cfg, CfgSource, CfgUiClickAction, CfgUiWriteBeforeBuild};
+use crate::core::{warn_mixed_indentation, Core};
+use crate::decorations::DecorationLayer;
+use crate::encoding::{Encoding, ENCODING_NAMES};
+use crate::expr::{eval, format_value};
+use crate::filecheck::{check_file, hex_dump, FileCheck, HEX_PREVIEW};
+use crate::git;
+use crate::marks::FileMark;
+use crate::messages;
+use crate::provider::{provider, write_error_message};
+use crate::registers::Register;
+use crate::session::{Session, DEFAULT_SESSION};
+use crate::substitute::{preview_lines, substitute_lines, Substitution};
+use crate::textbuffer::Buffer;
+use crate::trash::trash_file;
+use crate::types::{Color, PixelSize, DPI};
 
+use super::commands::{resolve_range, ArgSpec, CommandArgs, CommandDef, CommandRegistry};
 use super::context::RenderCtx;
 use super::fuzzy_popup::FuzzyPopup;
+use super::golden;
+use super::image_popup::ImagePopup;
+use super::key_hints::KeyHints;
+use super::keymap::Keymap;
 use super::prompt::Prompt;
+use super::tabs::Tabs;
 use super::text::TextCursorStyle;
-use super::textview_tree::TextViewTree;
+use super::textview::{is_word_char, TextView};
+use super::textview_tree::{Edge, TextViewTree};
 use crate::font::FontCore;
 
 static CLEAR_COLOR: Color = Color::new(255, 255, 255, 255);
 
+// Clicks closer together than this, in time and in pixels, are double or triple clicks
+const MULTI_CLICK_TIME: time::Duration = time::Duration::from_millis(400);
+const MULTI_CLICK_DISTANCE: i32 = 4;
+
+// Matching lines listed by :grep are cut off after this many characters
+const GREP_LINE_CHARS: usize = 200;
+// :grep stops searching after this many matching lines
+const GREP_MAX_MATCHES: usize = 10000;
+// Percentage of the width of a split that Ctrl-W < and > resize panes by
+const RESIZE_STEP: usize = 5;
+// Lines looked at for fences before the cursor, when deciding whether to close a code block
+const FENCE_SCAN_LINES: usize = 2000;
+// Build output and dependencies, skipped along with hidden files when walking a project
+const IGNORED_DIRS: &[&str] = &["target", "node_modules"];
+
 // Because windows messes things up, we have to get viewable region
 #[cfg(not(target_os = "windows"))]
 fn get_viewable_rect(window: &glfw::Window) -> Rect<u32, PixelSize> {
@@ -43,6 +85,34 @@ fn get_viewable_rect(window: &glfw::Window) -> Rect<u32, PixelSize> {
     rect.inner_rect(off).cast()
 }
 
+fn monitor_dpi(m: &glfw::Monitor) -> Option<Size2D<u32, DPI>> {
+    const MM_IN: f32 = 0.0393701;
+    let (width_mm, height_mm) = m.get_physical_size();
+    let (width_in, height_in) = (width_mm as f32 * MM_IN, height_mm as f32 * MM_IN);
+    m.get_video_mode().map(|vm| {
+        let (width_p, height_p) = (vm.width as f32, vm.height as f32);
+        size2((width_p / width_in) as u32, (height_p / height_in) as u32)
+    })
+}
+
+// DPI of the monitor the center of the window is on
+fn window_monitor_dpi(glfw: &mut Glfw, window: &glfw::Window) -> Option<Size2D<u32, DPI>> {
+    let (x, y) = window.get_pos();
+    let (w, h) = window.get_size();
+    let (cx, cy) = (x + w / 2, y + h / 2);
+    glfw.with_connected_monitors(|_, monitors| {
+        monitors
+            .iter()
+            .find(|m| {
+                let (mx, my) = m.get_pos();
+                m.get_video_mode().map_or(false, |vm| {
+                    cx >= mx && cx < mx + vm.width as i32 && cy >= my && cy < my + vm.height as i32
+                })
+            })
+            .and_then(monitor_dpi)
+    })
+}
+
 #[cfg(not(target_os = "windows"))]
 fn scale_point_to_viewable(_window: &glfw::Window, point: (f64, f64)) -> (f64, f64) {
     point
@@ -63,12 +133,69 @@ pub(crate) struct Window {
     render_ctx: RenderCtx,
     glfw: Rc<RefCell<Glfw>>,
     core: Rc<RefCell<Core>>,
+    // Panes of the active tab
     textview_tree: TextViewTree,
+    tabs: Tabs,
     prompt: Prompt,
+    // Height taken from the bottom of the window for the prompt, as of the last layout
+    prompt_bar_height: u32,
+    // Messages reported as of the last one shown in the prompt bar
+    message_count: u64,
     fuzzy_popup: FuzzyPopup,
+    key_hints: KeyHints,
+    image_popup: ImagePopup,
     input_state: InputState,
+    // View whose input state is in input_state, and the saved input state of other views
+    input_view: usize,
+    view_input_states: HashMap<usize, ViewInputState>,
+    keymap: Keymap,
+    // Prefix key that is waiting for the next key, and how long it has been pending
+    pending_key: Option<(InputMode, time::Duration)>,
+    focused: bool,
+    iconified: bool,
+    // Whether the next refresh has to redraw everything, rather than just the panes that scrolled
+    // or flashed on their own
+    full_redraw: bool,
+    search_pattern: Option<String>,
+    // Cursor position and pattern to restore if an incremental search is cancelled
+    search_restore: Option<(usize, usize, Option<String>)>,
     font_core: Rc<RefCell<FontCore>>,
+    config: Rc<RefCell<Cfg>>,
     working_directory: PathBuf,
+    fuzzy_target: FuzzyTarget,
+    // Directory that paths in the fuzzy popup are relative to
+    fuzzy_directory: PathBuf,
+    // File that failed the checks before opening, and where to open it, while asking how to
+    // open it
+    pending_open: Option<(String, FuzzyTarget)>,
+    // Build command started by :make, while asking whether to write modified buffers first
+    pending_build: Option<String>,
+    // Buffer that couldn't be written for lack of permission, while asking whether to write it
+    // as root
+    pending_root_write: Option<Rc<RefCell<Buffer>>>,
+    // Buffer whose file was changed by something else, while asking whether to overwrite it
+    pending_overwrite: Option<Rc<RefCell<Buffer>>>,
+    // Project-wide rename started by :rename-symbol, while asking about each file
+    pending_rename: Option<PendingRename>,
+    // Project-wide rename started by :rename-symbol, while files are searched in the background
+    rename_search: Option<(PendingRename, Receiver<Vec<(String, usize)>>)>,
+    // Substitution started by :s with the c or p flag, while asking about each match or whether
+    // to apply the preview
+    pending_substitution: Option<PendingSubstitution>,
+    build: Option<Build>,
+    // Shell command started by :! or :read, and where its output goes
+    shell: Option<(Build, ShellOutput)>,
+    // Errors and warnings found in the output of the last build, as "message [path:line]"
+    build_locations: Vec<String>,
+    // Time, position and count of the last left click, to detect double and triple clicks
+    last_click: Option<(time::Instant, (i32, i32), usize)>,
+    // Left mouse button is held down after a click that moved the cursor
+    dragging: bool,
+    // Left mouse button is held down after a click on a scrollbar
+    scrollbar_dragging: bool,
+    // Pane whose statusline is being dragged, to move its buffer to another pane
+    pane_dragging: Option<usize>,
+    commands: CommandRegistry,
 }
 
 impl Window {
@@ -78,6 +205,7 @@ impl Window {
         font_core: Rc<RefCell<FontCore>>,
         config: Rc<RefCell<Cfg>>,
         first_buffer_path: Option<&str>,
+        session: Option<&Session>,
         width: u32,
         height: u32,
         title: &str,
@@ -89,20 +217,11 @@ impl Window {
                 let (window, events) = glfw
                     .create_window(width, height, title, WindowMode::Windowed)
                     .expect("failed to create GLFW window");
-                let dpi = m
-                    .and_then(|m| {
-                        const MM_IN: f32 = 0.0393701;
-                        let (width_mm, height_mm) = m.get_physical_size();
-                        let (width_in, height_in) =
-                            (width_mm as f32 * MM_IN, height_mm as f32 * MM_IN);
-                        m.get_video_mode().map(|vm| {
-                            let (width_p, height_p) = (vm.width as f32, vm.height as f32);
-                            size2((width_p / width_in) as u32, (height_p / height_in) as u32)
-                        })
-                    })
-                    .unwrap_or(size2(96, 96));
+                let dpi = m.and_then(monitor_dpi).unwrap_or(size2(96, 96));
                 (window, events, dpi)
             });
+            // The window isn't necessarily on the primary monitor
+            let dpi = window_monitor_dpi(glfw, &window).unwrap_or(dpi);
             // Make window the current GL context and load OpenGL function pointers
             window.make_current();
             window.set_key_polling(true);
@@ -110,31 +229,50 @@ impl Window {
             window.set_scroll_polling(true);
             window.set_refresh_polling(true);
             window.set_framebuffer_size_polling(true);
+            window.set_pos_polling(true);
             window.set_mouse_button_polling(true);
+            window.set_cursor_pos_polling(true);
+            window.set_focus_polling(true);
+            window.set_iconify_polling(true);
+            // Report Num Lock state, so keypad keys can be decoded
+            window.set_store_lock_key_mods(true);
             // Return stuff
             (window, events, dpi)
         };
-        // Open first buffer
+        // Open first buffer. Files that fail the checks before opening start with an empty
+        // buffer, and ask how to open the file once the window is up
+        let first_buffer_path = first_buffer_path.map(|spath| {
+            let path = Path::new(spath);
+            if path.is_absolute() || provider(spath).is_remote() {
+                spath.to_owned()
+            } else {
+                let mut working_directory =
+                    std::env::current_dir().expect("failed to get current directory");
+                working_directory.push(path);
+                working_directory
+                    .to_str()
+                    .expect("failed to convert path to string")
+                    .to_owned()
+            }
+        });
+        let large_file_size = config.borrow().ui.textview.large_file_size;
+        let first_check = first_buffer_path
+            .as_ref()
+            .map(|spath| (spath.clone(), check_file(spath, large_file_size)))
+            .filter(|(_, check)| *check != FileCheck::Ok);
         let buffer = {
             let core = &mut *core.borrow_mut();
-            match first_buffer_path {
-                Some(spath) => {
-                    let path = Path::new(spath);
-                    if path.is_absolute() {
-                        core.new_buffer_from_file(spath, dpi)
-                            .expect("failed to open file")
-                    } else {
-                        let mut working_directory =
-                            std::env::current_dir().expect("failed to get current directory");
-                        working_directory.push(path);
-                        let spath = working_directory
-                            .to_str()
-                            .expect("failed to convert path to string");
-                        core.new_buffer_from_file(spath, dpi)
-                            .expect("failed to open file")
+            match &first_buffer_path {
+                Some(spath) if first_check.is_none() => {
+                    match core.new_buffer_from_file(spath, dpi) {
+                        Ok(buffer) => buffer,
+                        Err(e) => {
+                            error!("failed to open file: {}: {}", spath, e);
+                            core.new_empty_buffer(dpi)
+                        }
                     }
                 }
-                None => core.new_empty_buffer(dpi),
+                _ => core.new_empty_buffer(dpi),
             }
         };
         // Request view ID from core
@@ -153,28 +291,85 @@ impl Window {
         );
         // Initialize fuzzy search popup
         let fuzzy_popup = FuzzyPopup::new(inner_rect, font_core.clone(), config.clone(), dpi);
-        // Initialize editor prompt
-        let prompt = Prompt::new(inner_rect, font_core.clone(), config, dpi);
+        // Initialize editor prompt, with searches from earlier sessions
+        let mut prompt = Prompt::new(inner_rect, font_core.clone(), config.clone(), dpi);
+        for pattern in core.borrow().search_history().patterns() {
+            prompt.add_to_history(format!("/{}", pattern));
+        }
+        // Initialize key hints popup
+        let key_hints = KeyHints::new(inner_rect, font_core.clone(), config.clone(), dpi);
+        // Initialize image preview popup
+        let image_popup = ImagePopup::new(inner_rect, config.clone());
+        // Initialize tab pages, with the first one holding the panes created above
+        let tabs = Tabs::new(inner_rect, font_core.clone(), config.clone(), dpi);
         // Make window visible
         window.show();
         // Return window wrapper
-        let ctx = RenderCtx::new(&mut window, size2(width, height), dpi, CLEAR_COLOR);
-        (
-            Window {
-                window: window,
-                render_ctx: ctx,
-                glfw: glfw,
-                core: core,
-                textview_tree: textview_tree,
-                fuzzy_popup: fuzzy_popup,
-                prompt: prompt,
-                input_state: InputState::default(),
-                font_core: font_core,
-                working_directory: std::env::current_dir()
-                    .expect("failed to get current directory"),
-            },
-            events,
-        )
+        let raster_options = config.borrow().ui.font;
+        let ctx = RenderCtx::new(
+            &mut window,
+            size2(width, height),
+            dpi,
+            raster_options,
+            CLEAR_COLOR,
+        );
+        let mut ret = Window {
+            window: window,
+            render_ctx: ctx,
+            glfw: glfw,
+            core: core,
+            textview_tree: textview_tree,
+            tabs: tabs,
+            fuzzy_popup: fuzzy_popup,
+            prompt: prompt,
+            prompt_bar_height: 0,
+            message_count: 0,
+            key_hints: key_hints,
+            image_popup: image_popup,
+            input_state: InputState::default(),
+            input_view: view_id,
+            view_input_states: HashMap::new(),
+            keymap: Keymap::default(),
+            pending_key: None,
+            focused: true,
+            iconified: false,
+            full_redraw: true,
+            search_pattern: None,
+            search_restore: None,
+            font_core: font_core,
+            config: config,
+            working_directory: std::env::current_dir().expect("failed to get current directory"),
+            fuzzy_target: FuzzyTarget::Current,
+            fuzzy_directory: PathBuf::new(),
+            pending_open: None,
+            pending_build: None,
+            pending_root_write: None,
+            pending_overwrite: None,
+            pending_rename: None,
+            rename_search: None,
+            pending_substitution: None,
+            build: None,
+            shell: None,
+            build_locations: Vec::new(),
+            last_click: None,
+            dragging: false,
+            scrollbar_dragging: false,
+            pane_dragging: None,
+            commands: builtin_commands(),
+        };
+        // A file given along with a session is shown in the active pane of the session
+        if let Some(session) = session {
+            let first = ret.textview_tree.active_mut().buffer();
+            ret.restore_session(session);
+            if first_buffer_path.is_some() && first_check.is_none() {
+                let view_id = (&mut *ret.core.borrow_mut()).next_view_id();
+                ret.textview_tree.active_mut().show_buffer(first, view_id);
+            }
+        }
+        if let Some((spath, check)) = first_check {
+            ret.ask_open_mode(&spath, check, FuzzyTarget::Current);
+        }
+        (ret, events)
     }
 
     pub(crate) fn handle_events(
@@ -183,20 +378,72 @@ impl Window {
         duration: time::Duration,
     ) -> bool {
         let mut to_refresh = false;
+        // Only some panes have to be redrawn
+        let mut damaged = false;
         let mut scroll_force = (0.0, 0.0);
         let mut cursor_position = None;
-        let time = duration.as_secs_f64() * 100.0;
+        let time = duration.as_secs_f64();
 
         for (_, event) in glfw::flush_messages(events) {
-            to_refresh = true;
+            // Mouse motion only matters while dragging a selection or a scrollbar
+            if let WindowEvent::CursorPos(_, _) = event {
+                if !self.dragging && !self.scrollbar_dragging {
+                    continue;
+                }
+            }
+            // Scrolling only moves the views, which redraw themselves
+            if let WindowEvent::Scroll(_, _) = event {
+            } else {
+                to_refresh = true;
+            }
             match event {
                 WindowEvent::FramebufferSize(w, h) => self.resize(size2(w as u32, h as u32)),
-                WindowEvent::MouseButton(glfw::MouseButtonLeft, Action::Press, _) => {
+                WindowEvent::Pos(_, _) => self.update_dpi(),
+                WindowEvent::Focus(focused) => self.set_focused(focused),
+                WindowEvent::Iconify(iconified) => self.iconified = iconified,
+                WindowEvent::MouseButton(glfw::MouseButtonLeft, Action::Press, m) => {
                     let point = self.window.get_cursor_pos();
                     // windows-only scale
                     let (x, y) = scale_point_to_viewable(&self.window, point);
-                    self.textview_tree
-                        .move_cursor_to_point((x as i32, y as i32));
+                    let action = {
+                        let mouse = &(&*self.config.borrow()).ui.mouse;
+                        if m.contains(Modifiers::Control) {
+                            mouse.ctrl_click
+                        } else if m.contains(Modifiers::Shift) {
+                            mouse.shift_click
+                        } else if m.contains(Modifiers::Alt) {
+                            mouse.alt_click
+                        } else {
+                            mouse.click
+                        }
+                    };
+                    match action {
+                        CfgUiClickAction::MoveCursor => self.click((x as i32, y as i32)),
+                        CfgUiClickAction::Focus => {
+                            self.textview_tree.focus_point((x as i32, y as i32))
+                        }
+                        CfgUiClickAction::None => {}
+                    }
+                }
+                WindowEvent::MouseButton(glfw::MouseButtonLeft, Action::Release, m) => {
+                    self.dragging = false;
+                    self.scrollbar_dragging = false;
+                    if let Some(from) = self.pane_dragging.take() {
+                        let point = self.window.get_cursor_pos();
+                        let (x, y) = scale_point_to_viewable(&self.window, point);
+                        self.drop_pane((x as i32, y as i32), from, m.contains(Modifiers::Control));
+                    }
+                }
+                WindowEvent::CursorPos(x, y) => {
+                    let (x, y) = scale_point_to_viewable(&self.window, (x, y));
+                    if self.scrollbar_dragging {
+                        self.textview_tree.drag_scrollbar((x as i32, y as i32));
+                    } else if self.textview_tree.drag_to_point((x as i32, y as i32)) {
+                        if let InputMode::Visual(_) = self.input_state.mode {
+                        } else {
+                            self.input_state.mode = InputMode::Visual(false);
+                        }
+                    }
                 }
                 WindowEvent::Scroll(ax, ay) => {
                     // Get cursor position
@@ -204,20 +451,55 @@ impl Window {
                     let (x, y) = scale_point_to_viewable(&self.window, point);
                     cursor_position = Some((x as i32, y as i32));
                     // Scroll acceleration accumulation
+                    let (ax, ay) = {
+                        let mouse = &(&*self.config.borrow()).ui.mouse;
+                        let mul = mouse.scroll_multiplier;
+                        if mouse.natural_scroll {
+                            (-ax * mul, -ay * mul)
+                        } else {
+                            (ax * mul, ay * mul)
+                        }
+                    };
                     scroll_force.0 -= ax;
                     scroll_force.1 -= ay;
                 }
-                e => self.handle_event(e),
+                e => {
+                    // The message in the prompt bar is dismissed by the next key
+                    if matches!(e, WindowEvent::Key(_, _, Action::Press, _)) {
+                        self.prompt.set_message(None);
+                    }
+                    let text_input = match self.input_state.mode {
+                        InputMode::Normal
+                        | InputMode::DeleteMotion
+                        | InputMode::ChangeMotion
+                        | InputMode::YankMotion
+                        | InputMode::ShiftMotion(_)
+                        | InputMode::Visual(_)
+                        | InputMode::Leader
+                        | InputMode::WindowCommand
+                        | InputMode::GoPrefix => false,
+                        _ => true,
+                    };
+                    let e = {
+                        let cfg = &*self.config.borrow();
+                        self.keymap.translate(&cfg.ui.keymap, e, text_input)
+                    };
+                    self.handle_event(e)
+                }
             }
+            self.switch_input_view();
             if self.should_close() {
                 break;
             }
         }
 
-        // If any view was scrolled, refresh
-        to_refresh |= self
-            .textview_tree
-            .scroll_views(cursor_position, scroll_force, time);
+        // If any view was scrolled, refresh. Scroll animations that are running finish even when
+        // the window is idle
+        if !self.is_idle() || self.textview_tree.is_scrolling() {
+            damaged |= self
+                .textview_tree
+                .scroll_views(cursor_position, scroll_force, time);
+        }
 
         // Update fuzzy finder async if required
         if self.fuzzy_popup.is_active() {
@@ -225,21 +507,194 @@ impl Window {
             to_refresh |= self.fuzzy_popup.to_refresh;
         }
 
-        to_refresh
+        // Fill in files read in the background, and report files written in the background
+        let buffers = (&*self.core.borrow()).buffers();
+        for buffer in buffers {
+            let buffer = &mut *buffer.borrow_mut();
+            if buffer.poll_load(self.render_ctx.dpi) {
+                warn_mixed_indentation(buffer);
+                to_refresh = true;
+            }
+            match buffer.poll_write() {
+                Some((path, Ok(()))) => info!("written: {}", path),
+                Some((path, Err(e))) => error!("{}", write_error_message(&path, &e)),
+                None => {}
+            }
+        }
+
+        // Shape lines highlighted in the background, and add text of large files read in the
+        // background
+        for buffer in self.textview_tree.visible_buffers() {
+            let buffer = &mut *buffer.borrow_mut();
+            to_refresh |= buffer.poll_highlight();
+            to_refresh |= buffer.poll_stream();
+        }
+
+        // Mirror leader panes in the panes following them
+        {
+            let core = &mut *self.core.borrow_mut();
+            to_refresh |= self
+                .textview_tree
+                .sync_followers(&mut || core.next_view_id());
+        }
+
+        // Show output of a finished build
+        let finished = match &mut self.build {
+            Some(build) => build.poll(),
+            None => false,
+        };
+        if finished {
+            self.finish_build();
+            to_refresh = true;
+        }
+        let finished = match &mut self.shell {
+            Some((shell, _)) => shell.poll(),
+            None => false,
+        };
+        if finished {
+            self.finish_shell();
+            to_refresh = true;
+        }
+        // Files to rename in are asked about once nothing else is going on
+        let found = match &self.rename_search {
+            Some((_, rx)) if self.input_state.mode == InputMode::Normal => rx.try_recv().ok(),
+            _ => None,
+        };
+        if let Some(files) = found {
+            self.finish_rename_search(files);
+            to_refresh = true;
+        }
+
+        damaged |= self.textview_tree.update_flashes(duration);
+        to_refresh |= self.update_pending_key(duration);
+
+        // Show the latest message in the prompt bar
+        let message_count = messages::count();
+        if message_count != self.message_count {
+            self.message_count = message_count;
+            self.prompt.set_message(messages::latest());
+            to_refresh = true;
+        }
+
+        self.full_redraw |= to_refresh;
+        (to_refresh || damaged) && !self.iconified
     }
 
-    pub(crate) fn refresh(&mut self) {
-        let mut active_ctx = self.render_ctx.activate(&mut self.window);
-        active_ctx.clear();
-        self.textview_tree.draw(&mut active_ctx);
+    // Show hints once a prefix key has been pending for a while, and cancel it on timeout.
+    // Returns true if the hints popup was shown or hidden
+    fn update_pending_key(&mut self, duration: time::Duration) -> bool {
+        let mode = self.input_state.mode;
+        let leader_hints = if mode == InputMode::Leader {
+            self.leader_hints()
+        } else {
+            Vec::new()
+        };
+        let (title, hints) = match mode.key_hints() {
+            Some((title, hints)) => (title, hints),
+            None if mode == InputMode::Leader => {
+                let hints = leader_hints.iter();
+                (
+                    "leader",
+                    hints.map(|(k, c)| (k.as_str(), c.as_str())).collect(),
+                )
+            }
+            None => {
+                self.pending_key = None;
+                if self.key_hints.is_active() {
+                    self.key_hints.hide();
+                    return true;
+                }
+                return false;
+            }
+        };
+        let elapsed = match self.pending_key {
+            Some((pending, elapsed)) if pending == mode => elapsed + duration,
+            _ => time::Duration::from_secs(0),
+        };
+        self.pending_key = Some((mode, elapsed));
 
-        if self.fuzzy_popup.is_active() {
-            self.fuzzy_popup.draw(&mut active_ctx);
+        let (delay, timeout) = {
+            let keymap = &(&*self.config.borrow()).ui.keymap;
+            (keymap.hint_delay, keymap.timeout)
+        };
+        if timeout > 0 && elapsed >= time::Duration::from_millis(timeout) {
+            if mode == InputMode::Leader {
+                let command = self.leader_command();
+                self.finish_leader(command);
+                self.pending_key = None;
+                self.key_hints.hide();
+                return true;
+            }
+            // All pending modes return to normal mode on Escape
+            self.handle_event(WindowEvent::Key(
+                Key::Escape,
+                0,
+                Action::Press,
+                Modifiers::empty(),
+            ));
+            self.pending_key = None;
+            self.key_hints.hide();
+            return true;
+        }
+        if !self.key_hints.is_active() && elapsed >= time::Duration::from_millis(delay) {
+            self.key_hints.show(title, &hints);
+            return true;
+        }
+        false
+    }
+
+    // Window is unfocused or iconified
+    fn is_idle(&self) -> bool {
+        !self.focused || self.iconified
+    }
+
+    /// Something is animating, and the window should be redrawn every frame until it settles.
+    /// Key hints are shown after a delay, so a pending key counts too
+    pub(crate) fn is_animating(&self) -> bool {
+        self.textview_tree.is_scrolling()
+            || self.textview_tree.is_flashing()
+            || self.pending_key.is_some()
+    }
+
+    pub(crate) fn refresh(&mut self) {
+        // The prompt bar appears and disappears as the prompt is activated
+        if self.prompt.bar_height() != self.prompt_bar_height {
+            self.layout();
         }
-        if self.prompt.is_active() {
-            self.prompt.draw(&mut active_ctx);
+        // The last frame is kept, so unless something other than the panes changed, only panes
+        // that scrolled or flashed on their own are redrawn. Popups are drawn over the panes, so
+        // everything is redrawn while they are shown
+        let full = std::mem::replace(&mut self.full_redraw, false)
+            || self.fuzzy_popup.is_active()
+            || self.key_hints.is_active()
+            || self.image_popup.is_active();
+        {
+            let mut active_ctx = self.render_ctx.activate(&mut self.window);
+            if full {
+                active_ctx.clear();
+            }
+            let search = self.search_pattern.as_ref().map(|s| s.as_str());
+            let mode = self.input_state.mode.name();
+            self.textview_tree.draw(&mut active_ctx, search, mode, full);
+            if full && self.tabs.bar_height() > 0 {
+                self.tabs.draw(&mut active_ctx, &mut self.textview_tree);
+            }
+
+            if self.fuzzy_popup.is_active() {
+                self.fuzzy_popup.draw(&mut active_ctx);
+            }
+            if full && self.prompt_bar_height > 0 {
+                self.prompt.draw(&mut active_ctx);
+            }
+            if self.key_hints.is_active() {
+                self.key_hints.draw(&mut active_ctx);
+            }
+            if self.image_popup.is_active() {
+                self.image_popup.draw(&mut active_ctx);
+            }
         }
 
+        self.render_ctx.present();
         self.window.swap_buffers();
     }
 
@@ -252,214 +707,2328 @@ impl Window {
     }
 
     fn handle_command(&mut self) {
-        let prompt_s = self.prompt.get_string().trim();
-        let mut iter = prompt_s.split_whitespace();
-        match iter.next() {
-            Some(":q") | Some(":quit") => {
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Normal;
-                if self.textview_tree.kill_active() {
-                    self.set_should_close(true);
-                }
+        let line = self.prompt.get_string().trim().to_owned();
+        self.run_command(&line);
+    }
+
+    /// Run command line, like ":w foo.txt", with the matching registered command
+    pub(crate) fn run_command(&mut self, line: &str) {
+        let args = match CommandArgs::parse(line) {
+            Some(args) => args,
+            None => return,
+        };
+        let (handler, missing_arg) = match self.commands.get(&args.name) {
+            Some(command) => match command.arg_spec() {
+                ArgSpec::Required(arg) if args.args.is_empty() => (None, Some(arg)),
+                _ => (Some(command.handler()), None),
+            },
+            None => {
+                error!("unknown command: {}", args.name);
+                return;
             }
-            Some(":bn") | Some(":bnext") => {
-                self.textview_tree.active_mut().next_buffer();
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Normal;
+        };
+        // Commands that open a popup switch to its mode themselves
+        self.prompt.set_active(false);
+        self.input_state.mode = InputMode::Normal;
+        match (handler, missing_arg) {
+            (Some(handler), _) => handler(self, &args),
+            (_, Some(arg)) => error!("{}: {} required", args.name, arg),
+            _ => {}
+        }
+    }
+
+    /// Answer a request from the debug server. Lines starting with ':' are run as commands,
+    /// "keys" types keys into the window, "golden" compares the window against a golden image,
+    /// and the rest query the editor's state
+    pub(crate) fn debug_request(&mut self, line: &str) -> String {
+        // The window is refreshed after every request
+        self.full_redraw = true;
+        let line = line.trim();
+        if line.starts_with(':') {
+            self.run_command(line);
+            return "ok".to_owned();
+        }
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next().unwrap(), parts.next()) {
+            ("mode", None) => self.input_state.mode.name().to_owned(),
+            ("cursor", None) => {
+                let textview = self.textview_tree.active_mut();
+                format!(
+                    "{}:{}",
+                    textview.cursor_line_num() + 1,
+                    textview.cursor_gidx() + 1
+                )
             }
-            Some(":bp") | Some(":bprevious") => {
-                self.textview_tree.active_mut().prev_buffer();
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Normal;
+            ("buffer", None) => {
+                let buffer = self.textview_tree.active_mut().buffer();
+                let buffer = &*buffer.borrow();
+                let name = buffer.title().or(buffer.path()).unwrap_or("[no name]");
+                let dirty = if buffer.is_dirty() { " [+]" } else { "" };
+                format!("{}{}\n{} lines", name, dirty, buffer.len_lines())
             }
-            Some(":e") | Some(":edit") => match iter.next() {
-                Some(fname) => {
-                    let core = &mut *self.core.borrow_mut();
-                    let path = Path::new(fname);
-                    let path = if path.has_root() {
-                        path.to_path_buf()
-                    } else if path.starts_with("~") {
-                        let path = path.strip_prefix("~").unwrap();
-                        let mut buf = BaseDirs::new()
-                            .expect("failed to get base dirs")
-                            .home_dir()
-                            .to_path_buf();
-                        buf.push(path);
-                        buf
+            ("buffers", None) => (&*self.core.borrow())
+                .buffer_list()
+                .into_iter()
+                .map(|(id, path, size)| format!("{}: {} [{}]", id, path, format_size(size)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ("metrics", None) => {
+                let buffers = self.all_buffers();
+                let (memory, cache) = buffers.iter().fold((0, 0), |(m, c), b| {
+                    let b = &*b.borrow();
+                    (m + b.approx_memory_usage(), c + b.approx_cache_size())
+                });
+                format!(
+                    "buffers {}\nmemory {}\ncache {}\ntabs {}",
+                    buffers.len(),
+                    memory,
+                    cache,
+                    self.tabs.len()
+                )
+            }
+            // Keys are typed as text, except for <Esc> and <CR>
+            ("keys", Some(keys)) => {
+                let mut rest = keys;
+                while let Some(c) = rest.chars().next() {
+                    let (event, len) = if rest.starts_with("<Esc>") {
+                        let key =
+                            WindowEvent::Key(Key::Escape, 0, Action::Press, Modifiers::empty());
+                        (key, 5)
+                    } else if rest.starts_with("<CR>") {
+                        let key =
+                            WindowEvent::Key(Key::Enter, 0, Action::Press, Modifiers::empty());
+                        (key, 4)
                     } else {
-                        let mut buf = self.working_directory.clone();
-                        buf.push(path);
-                        buf
+                        (WindowEvent::Char(c), c.len_utf8())
                     };
-                    match core.new_buffer_from_file(path.to_str().unwrap(), self.render_ctx.dpi) {
-                        Ok(buffer) => {
-                            let view_id = core.next_view_id();
-                            self.textview_tree.active_mut().add_buffer(buffer, view_id);
-                        }
-                        Err(e) => {
-                            eprintln!("failed to open file: {:?}: {}", path, e);
-                        }
-                    }
-                    self.prompt.set_active(false);
-                    self.input_state.mode = InputMode::Normal;
-                }
-                _ => {
-                    self.textview_tree
-                        .active_mut()
-                        .reload_buffer()
-                        .expect("failed to reload buffer");
-                    self.prompt.set_active(false);
-                    self.input_state.mode = InputMode::Normal;
+                    self.handle_event(event);
+                    rest = &rest[len..];
                 }
-            },
-            Some(":vsp") | Some(":vsplit") => {
-                let core = &mut *self.core.borrow_mut();
-                self.textview_tree.split_h(core.next_view_id());
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Normal;
+                "ok".to_owned()
             }
-            Some(":sp") | Some(":split") => {
-                let core = &mut *self.core.borrow_mut();
-                self.textview_tree.split_v(core.next_view_id());
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Normal;
+            // Redraw everything, and compare against the image at path, within an optional
+            // tolerance for each color channel
+            ("golden", Some(args)) => {
+                let mut args = args.split_whitespace();
+                let path = args.next().unwrap_or("");
+                let tolerance = match args.next().map(|t| t.parse::<u8>()) {
+                    Some(Ok(tolerance)) => tolerance,
+                    Some(Err(e)) => return format!("invalid tolerance: {}", e),
+                    None => 0,
+                };
+                self.refresh();
+                let frame = self.render_ctx.read_frame();
+                golden::check(&frame, Path::new(path), tolerance)
             }
-            Some(":w") | Some(":write") => {
-                let res = if let Some(fname) = iter.next() {
-                    let path = Path::new(fname);
-                    let path = if path.has_root() {
-                        path.to_path_buf()
-                    } else if path.starts_with("~") {
-                        let path = path.strip_prefix("~").unwrap();
-                        let mut buf = BaseDirs::new()
-                            .expect("failed to get base dirs")
-                            .home_dir()
-                            .to_path_buf();
-                        buf.push(path);
-                        buf
-                    } else {
-                        let mut buf = self.working_directory.clone();
-                        buf.push(path);
-                        buf
-                    };
-                    self.textview_tree.active_mut().write_buffer(Some(
-                        path.to_str()
-                            .expect("failed to get text representation of path"),
-                    ))
-                } else {
-                    self.textview_tree.active_mut().write_buffer(None)
+            _ => format!("unknown request: {}", line),
+        }
+    }
+
+    // Complete command line in the prompt, as far as all candidates agree
+    fn complete_command(&mut self) {
+        let line = self.prompt.get_string().to_owned();
+        let candidates = self.commands.complete(self, &line);
+        if let Some(prefix) = common_prefix(&candidates) {
+            if prefix.len() > line.len() {
+                self.prompt.set_string(&prefix);
+            }
+        }
+    }
+
+    // Lines in range, or the cursor line if there is no range
+    fn line_range(&mut self, range: Option<&str>) -> (usize, usize) {
+        let textview = self.textview_tree.active_mut();
+        let cur = textview.cursor_line_num();
+        let last = textview.len_lines() - 1;
+        range
+            .and_then(|r| resolve_range(r, cur, last))
+            .unwrap_or((cur, cur))
+    }
+
+    // Open fuzzy popup listing buffers
+    fn start_buffer_fuzzy(&mut self) {
+        self.fuzzy_target = FuzzyTarget::Buffer;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label("buffers");
+        let choices = (&*self.core.borrow())
+            .buffer_list()
+            .into_iter()
+            .map(|(id, path, size)| format!("{}: {} [{}]", id, path, format_size(size)))
+            .collect::<Vec<_>>();
+        self.fuzzy_popup.push_string_choices(&choices);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Each view remembers its input mode, so that switching panes or buffers doesn't carry
+    // insert mode or a pending operator over to another view
+    fn switch_input_view(&mut self) {
+        let textview = self.textview_tree.active_mut();
+        let view_id = textview.view_id();
+        if view_id == self.input_view {
+            return;
+        }
+        let saved = self.input_state.take_view_state();
+        self.view_input_states.insert(self.input_view, saved);
+        self.input_view = view_id;
+        let saved = self.view_input_states.remove(&view_id).unwrap_or_default();
+        // Popups started by whatever switched views stay open
+        let keep_mode = !saved.mode.is_view_mode() || !self.input_state.mode.is_view_mode();
+        let mode = match saved.mode {
+            InputMode::Visual(_) if textview.selection_text().is_none() => InputMode::Normal,
+            mode => mode,
+        };
+        self.input_state.restore_view_state(saved);
+        if keep_mode {
+            return;
+        }
+        self.input_state.mode = mode;
+        textview.set_cursor_style(mode.cursor_style());
+    }
+
+    // Move the cursor to a left click. In normal and visual mode, dragging from a click selects
+    // text, a double click selects a word and a triple click selects a line
+    fn click(&mut self, point: (i32, i32)) {
+        let count = self.click_count(point);
+        if self.prompt.click(point, count) || self.fuzzy_popup.click(point, count) {
+            return;
+        }
+        if let Some(idx) = self.tabs.tab_at(point) {
+            return self.switch_tab(idx);
+        }
+        if self.textview_tree.click_scrollbar(point) {
+            self.scrollbar_dragging = true;
+            return;
+        }
+        if let Some(id) = self.textview_tree.statusline_at(point) {
+            self.pane_dragging = Some(id);
+            return;
+        }
+        match self.input_state.mode {
+            InputMode::Normal | InputMode::Visual(_) => {}
+            _ => return self.textview_tree.move_cursor_to_point(point),
+        }
+        self.textview_tree.active_mut().clear_selection();
+        self.input_state.mode = InputMode::Normal;
+        self.textview_tree.move_cursor_to_point(point);
+        let textview = self.textview_tree.active_mut();
+        match count {
+            1 => self.dragging = true,
+            2 => {
+                textview.select_word();
+                self.input_state.mode = InputMode::Visual(false);
+            }
+            _ => {
+                textview.start_selection(true);
+                self.input_state.mode = InputMode::Visual(true);
+            }
+        }
+    }
+
+    // Number of clicks in a row at about the same point, cycling from 1 to 3
+    fn click_count(&mut self, point: (i32, i32)) -> usize {
+        let now = time::Instant::now();
+        let count = match self.last_click {
+            Some((t, p, count))
+                if now - t < MULTI_CLICK_TIME
+                    && (p.0 - point.0).abs() <= MULTI_CLICK_DISTANCE
+                    && (p.1 - point.1).abs() <= MULTI_CLICK_DISTANCE =>
+            {
+                count % 3 + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some((now, point, count));
+        count
+    }
+
+    // Move the buffer of the pane whose statusline was dragged to the pane at point, or to a new
+    // split if point is near an edge of the window. The buffer is copied instead if copy is set
+    fn drop_pane(&mut self, point: (i32, i32), from: usize, copy: bool) {
+        let view_id = (&mut *self.core.borrow_mut()).next_view_id();
+        if let Some(edge) = self.textview_tree.edge_at(point) {
+            self.textview_tree
+                .move_view_to_edge(from, edge, copy, view_id);
+        } else if let Some(to) = self.textview_tree.pane_id_at(point) {
+            self.textview_tree.move_view(from, to, copy, view_id);
+        }
+    }
+
+    // Show buffer with given number in buffer list in the active pane
+    fn switch_to_buffer(&mut self, id: usize) {
+        let core = &mut *self.core.borrow_mut();
+        match core.buffer_by_id(id) {
+            Some(buffer) => {
+                let view_id = core.next_view_id();
+                self.textview_tree.active_mut().show_buffer(buffer, view_id);
+            }
+            None => error!("no such buffer: {}", id),
+        }
+    }
+
+    // Open fuzzy popup listing lines matching pattern in files under the working directory.
+    // Files are searched in the background, and matches are added to the list as they are found
+    fn start_grep(&mut self, pattern: &str) {
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                error!("invalid pattern: {}: {}", pattern, e);
+                return;
+            }
+        };
+        let wdir = self.project_directory();
+        self.fuzzy_directory = wdir.clone();
+        let large_file_size = self.config.borrow().ui.textview.large_file_size;
+        let (tx, rx) = self.core.borrow().wakeup().channel();
+        thread::spawn(move || {
+            for path in project_files(&wdir) {
+                let spath = match path.to_str() {
+                    Some(spath) => spath,
+                    None => continue,
+                };
+                if check_file(spath, large_file_size) != FileCheck::Ok {
+                    continue;
+                }
+                let text = match std::fs::read_to_string(&path) {
+                    Ok(text) => text,
+                    Err(_) => continue,
                 };
-                match res {
-                    Some(Err(e)) => {
-                        eprintln!("failed to write buffer: {}", e);
+                let rel = path.strip_prefix(&wdir).unwrap_or(&path).to_string_lossy();
+                for (i, line) in text.lines().enumerate() {
+                    if !regex.is_match(line) {
+                        continue;
                     }
-                    None => {
-                        eprintln!("no path provided for writing buffer");
+                    let line = line.trim();
+                    let line = match line.char_indices().nth(GREP_LINE_CHARS) {
+                        Some((idx, _)) => &line[..idx],
+                        None => line,
+                    };
+                    if tx.send(format!("{} [{}:{}]", line, rel, i + 1)).is_err() {
+                        return;
                     }
-                    _ => {}
                 }
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Normal;
             }
-            Some(":fzf") => {
-                self.fuzzy_popup.set_active(true);
-                self.fuzzy_popup.set_default_on_empty(true);
-                let wdir = self.working_directory.clone();
-                let basename = wdir.file_name().and_then(|p| p.to_str()).unwrap_or("/");
-                self.fuzzy_popup.set_input_label(basename);
-                let (tx, rx) = channel();
-                thread::spawn(move || {
-                    for e in WalkDir::new(&wdir)
-                        .into_iter()
-                        .filter_entry(|e| {
-                            e.file_name()
-                                .to_str()
-                                .map(|s| !s.starts_with("."))
-                                .unwrap_or(true)
-                        })
-                        .filter_map(|e| e.ok())
-                    {
-                        let mut path = e.path();
-                        if path.is_file() {
-                            path = path.strip_prefix(&wdir).unwrap();
-                            if let Some(path) = path.to_str().map(|s| s.to_string()) {
-                                if tx.send(path).is_err() {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                });
-                self.fuzzy_popup.set_async_source(rx);
-                self.fuzzy_popup.update_from_async();
-                self.prompt.set_active(false);
-                self.input_state.mode = InputMode::Fuzzy;
+        });
+        self.fuzzy_target = FuzzyTarget::Location;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup
+            .set_input_label(&format!("grep {}", pattern));
+        self.fuzzy_popup
+            .add_async_source("", rx, Some(GREP_MAX_MATCHES));
+        self.fuzzy_popup.update_from_async();
+        self.prompt.set_active(false);
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Open fuzzy popup listing bookmarks
+    fn start_bookmark_fuzzy(&mut self) {
+        self.fuzzy_target = FuzzyTarget::Bookmark;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label("bookmarks");
+        let choices = (&*self.core.borrow())
+            .marks()
+            .bookmarks()
+            .iter()
+            .map(|(name, mark)| format!("{} [{}:{}]", name, mark.path, mark.line + 1))
+            .collect::<Vec<_>>();
+        self.fuzzy_popup.push_string_choices(&choices);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Open fuzzy popup listing symbols in files under the project directory. Files are indexed
+    // in the background, and symbols are added to the list as they are found
+    fn start_symbol_fuzzy(&mut self) {
+        let dir = self.project_directory();
+        self.fuzzy_target = FuzzyTarget::Location;
+        self.fuzzy_directory = dir.clone();
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label("symbols");
+        let core = &*self.core.borrow();
+        let rx = core.symbols().search(&dir, core.wakeup());
+        self.fuzzy_popup.set_async_source(rx);
+        self.fuzzy_popup.update_from_async();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Bookmark the cursor position in the active buffer
+    fn add_bookmark(&mut self, name: &str) {
+        let textview = self.textview_tree.active_mut();
+        match textview.buffer().borrow().path() {
+            Some(path) => {
+                let mark = FileMark {
+                    path: path.to_owned(),
+                    line: textview.cursor_line_num(),
+                    gidx: textview.cursor_gidx(),
+                };
+                (&mut *self.core.borrow_mut())
+                    .marks_mut()
+                    .add_bookmark(name, mark);
             }
-            _ => {}
+            None => error!("buffer has no path"),
         }
     }
 
-    fn handle_fuzzy(&mut self) {
-        if let Some(selection) = self.fuzzy_popup.get_selection() {
-            let core = &mut *self.core.borrow_mut();
-            let mut path = self.working_directory.clone();
-            path.push(&selection);
-            match core.new_buffer_from_file(path.to_str().unwrap(), self.render_ctx.dpi) {
-                Ok(buffer) => {
-                    let view_id = core.next_view_id();
-                    self.textview_tree.active_mut().add_buffer(buffer, view_id);
-                }
-                Err(e) => {
-                    println!("failed to open file: {:?}: {}", path, e);
-                }
+    // Attach note to the cursor line in the active buffer
+    fn set_note(&mut self, text: &str) {
+        let core = &mut *self.core.borrow_mut();
+        let textview = self.textview_tree.active_mut();
+        let linum = textview.cursor_line_num();
+        let buffer = textview.buffer();
+        let buffer = &mut *buffer.borrow_mut();
+        if buffer.path().is_none() {
+            error!("buffer has no path");
+            return;
+        }
+        buffer.set_note(core.next_view_id(), linum, text);
+        core.save_notes(buffer);
+    }
+
+    fn delete_note(&mut self) {
+        let core = &mut *self.core.borrow_mut();
+        let textview = self.textview_tree.active_mut();
+        let linum = textview.cursor_line_num();
+        let buffer = textview.buffer();
+        let buffer = &mut *buffer.borrow_mut();
+        if !buffer.remove_note(linum) {
+            error!("no note on line {}", linum + 1);
+            return;
+        }
+        core.save_notes(buffer);
+    }
+
+    // Pick note to jump to, from all notes in the project
+    fn start_notes_fuzzy(&mut self) {
+        let choices = (&*self.core.borrow())
+            .note_list()
+            .into_iter()
+            .map(|note| {
+                let path = Path::new(&note.path);
+                let path = path.strip_prefix(&self.working_directory).unwrap_or(path);
+                format!("{} [{}:{}]", note.text, path.display(), note.line + 1)
+            })
+            .collect::<Vec<_>>();
+        self.fuzzy_target = FuzzyTarget::Location;
+        self.fuzzy_directory = self.working_directory.clone();
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label("notes");
+        self.fuzzy_popup.push_string_choices(&choices);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Open file in the active pane
+    fn edit_file(&mut self, fname: &str) {
+        let path = self.absolute_path(fname);
+        if !self.check_before_open(path.to_str().unwrap(), FuzzyTarget::Current) {
+            return;
+        }
+        let core = &mut *self.core.borrow_mut();
+        match core.new_buffer_from_file(path.to_str().unwrap(), self.render_ctx.dpi) {
+            Ok(buffer) => {
+                let view_id = core.next_view_id();
+                self.textview_tree.active_mut().add_buffer(buffer, view_id);
+            }
+            Err(e) => {
+                error!("failed to open file: {:?}: {}", path, e);
             }
         }
-        self.fuzzy_popup.set_active(false);
-        self.input_state.mode = InputMode::Normal;
     }
 
-    fn resize(&mut self, size: Size2D<u32, PixelSize>) {
-        let vrect = get_viewable_rect(&self.window);
-        self.render_ctx.set_size(size);
-        self.textview_tree.set_rect(vrect);
-        self.fuzzy_popup.set_window_rect(vrect);
-        self.prompt.set_window_rect(vrect);
+    // Ask whether to write buffer as root, after writing it failed for lack of permission
+    fn ask_write_as_root(&mut self, buffer: Rc<RefCell<Buffer>>) {
+        let label = format!(
+            "can't write {}",
+            file_name(buffer.borrow().path().unwrap_or(""))
+        );
+        self.pending_root_write = Some(buffer);
+        self.fuzzy_target = FuzzyTarget::WriteAsRoot;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label(&label);
+        self.fuzzy_popup
+            .push_string_choices(&["write as root (pkexec)".to_owned(), "cancel".to_owned()]);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
     }
 
-    fn handle_event(&mut self, event: WindowEvent) {
-        let mut state = &mut self.input_state;
-        let textview = self.textview_tree.active_mut();
-        match state.mode {
-            InputMode::Insert => match event {
-                WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
-                    let mult = state.get_action_multiplier();
-                    state.movement_multiplier.clear();
-                    state.mode = InputMode::Normal;
-                    state.last_edit = EditOp::Insert(mult, Insert(state.cur_insert_ops.clone()));
-                    for _ in 0..(mult - 1) {
-                        for op in &state.cur_insert_ops {
-                            match op {
-                                InsertOp::Str(s) => textview.insert_str(s),
-                                InsertOp::Backspace => textview.delete_left(1),
-                                InsertOp::Delete => textview.delete_right(1),
-                                InsertOp::Left => textview.move_cursor_left(1),
-                                InsertOp::Right => textview.move_cursor_right(1),
-                                InsertOp::Up => textview.move_cursor_up(1),
-                                InsertOp::Down => textview.move_cursor_down(1),
-                                InsertOp::Home => textview.move_cursor_start_of_line(),
-                                InsertOp::End => textview.move_cursor_end_of_line(),
-                                InsertOp::PageUp => textview.page_up(),
-                                InsertOp::PageDown => textview.page_down(),
-                            }
-                        }
-                    }
-                    self.input_state.cur_insert_ops.clear();
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                }
-                WindowEvent::Key(Key::Down, _, Action::Press, _)
-                | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
-                    state.cur_insert_ops.push(InsertOp::Down);
-                    textview.move_cursor_down(1);
+    // Evaluate arithmetic expression, and show the result or insert it at the cursor
+    fn eval_expression(&mut self, expr: &str, insert: bool) {
+        match eval(expr) {
+            Ok(val) if insert => self
+                .textview_tree
+                .active_mut()
+                .insert_str(&format_value(val)),
+            Ok(val) => info!("{}", format_value(val)),
+            Err(e) => error!("{}: {}", expr.trim(), e),
+        }
+    }
+
+    // Ask whether to overwrite the file of buffer, which was changed by something else
+    fn ask_overwrite(&mut self, buffer: Rc<RefCell<Buffer>>) {
+        let label = format!(
+            "{} changed on disk",
+            file_name(buffer.borrow().path().unwrap_or(""))
+        );
+        self.pending_overwrite = Some(buffer);
+        self.fuzzy_target = FuzzyTarget::Overwrite;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label(&label);
+        self.fuzzy_popup.push_string_choices(&[
+            "cancel".to_owned(),
+            "overwrite".to_owned(),
+            "reload, discarding changes".to_owned(),
+        ]);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Ask whether to reload the active buffer, whose file was changed by something else. Keeping
+    // the buffer is the default if it has unwritten changes
+    fn ask_reload(&mut self, buffer: Rc<RefCell<Buffer>>) {
+        let (label, choices) = {
+            let buffer = &*buffer.borrow();
+            let label = format!("{} changed on disk", file_name(buffer.path().unwrap_or("")));
+            let choices = if buffer.is_dirty() {
+                ["keep buffer", "reload, discarding changes"]
+            } else {
+                ["reload", "keep buffer"]
+            };
+            (label, choices)
+        };
+        self.pending_overwrite = Some(buffer);
+        self.fuzzy_target = FuzzyTarget::Overwrite;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_keep_order(true);
+        self.fuzzy_popup.set_input_label(&label);
+        let choices = choices.iter().map(|c| (*c).to_owned()).collect::<Vec<_>>();
+        self.fuzzy_popup.push_string_choices(&choices);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // List registered commands
+    fn command_list(&self) -> Vec<String> {
+        self.commands
+            .commands()
+            .iter()
+            .map(|cmd| {
+                let mut line = format!("{:24} {}", cmd.usage(), cmd.description());
+                if !cmd.aliases().is_empty() {
+                    line.push_str(&format!(" (also {})", cmd.aliases().join(", ")));
+                }
+                line
+            })
+            .collect()
+    }
+
+    // Write the active buffer, to the given file if any. If the buffer's file was changed by
+    // something else since it was read, the user is asked whether to overwrite it, unless force
+    // is set. Returns true on success
+    fn write_active(&mut self, fname: Option<&str>, force: bool) -> bool {
+        if fname.is_none() && !force {
+            let buffer = self.textview_tree.active_mut().buffer();
+            if buffer.borrow().write_conflict() {
+                self.ask_overwrite(buffer);
+                return false;
+            }
+        }
+        let path = match fname {
+            Some(fname) => match self.absolute_path(fname).to_str() {
+                Some(path) => Some(path.to_owned()),
+                None => {
+                    error!("invalid path: {}", fname);
+                    return false;
+                }
+            },
+            None => None,
+        };
+        let res = self
+            .textview_tree
+            .active_mut()
+            .write_buffer(path.as_ref().map(|p| p.as_str()));
+        match res {
+            Some(Err(e)) => {
+                let buffer = self.textview_tree.active_mut().buffer();
+                let path = path.or(buffer.borrow().path().map(|p| p.to_owned()));
+                let path = path.unwrap_or_default();
+                error!("{}", write_error_message(&path, &e));
+                // Only the buffer's own file can be written as root
+                if e.kind() == ErrorKind::PermissionDenied
+                    && fname.is_none()
+                    && !provider(&path).is_remote()
+                {
+                    self.ask_write_as_root(buffer);
+                }
+                false
+            }
+            None => {
+                error!("no path provided for writing buffer");
+                false
+            }
+            _ => {
+                {
+                    let buffer = self.textview_tree.active_mut().buffer();
+                    let buffer = &mut *buffer.borrow_mut();
+                    warn_mixed_indentation(buffer);
+                    // Notes are saved where they are in the file as written
+                    (&mut *self.core.borrow_mut()).save_notes(buffer);
+                }
+                // Apply config changes as soon as they are written
+                let path = self
+                    .textview_tree
+                    .active_mut()
+                    .buffer()
+                    .borrow()
+                    .path()
+                    .map(PathBuf::from);
+                if path.is_some() && path == Cfg::path() {
+                    self.reload_config();
+                }
+                let build_on_save = {
+                    let cfg = &*self.config.borrow();
+                    let buffer = self.textview_tree.active_mut().buffer();
+                    let syntax = cfg.syntax(buffer.borrow().syntax_name());
+                    syntax.build_on_save
+                };
+                if build_on_save && self.build.is_none() {
+                    if let Some(command) = self.active_build_command() {
+                        self.start_build(&command);
+                    }
+                }
+                true
+            }
+        }
+    }
+
+    // Open the config file in a split, creating it from a template if it doesn't exist
+    fn open_config(&mut self) {
+        let path = match Cfg::path() {
+            Some(path) => path,
+            None => {
+                error!("no config directory on this platform");
+                return;
+            }
+        };
+        if let Err(e) = Cfg::create_if_missing(&path) {
+            error!("failed to create config: {:?}: {}", path, e);
+            return;
+        }
+        let spath = match path.to_str() {
+            Some(spath) => spath,
+            None => {
+                error!("invalid path: {:?}", path);
+                return;
+            }
+        };
+        let core = &mut *self.core.borrow_mut();
+        // Don't reload the buffer if it's already open, since that would drop unwritten changes
+        let buffer = match core.buffer(spath) {
+            Some(buffer) => Ok(buffer),
+            None => core.new_buffer_from_file(spath, self.render_ctx.dpi),
+        };
+        match buffer {
+            Ok(buffer) => {
+                self.textview_tree.split_v(core.next_view_id());
+                let view_id = core.next_view_id();
+                self.textview_tree.active_mut().add_buffer(buffer, view_id);
+            }
+            Err(e) => error!("failed to open config: {:?}: {}", path, e),
+        }
+    }
+
+    // Show image file in a popup. Without a file, the markdown image link under the cursor is
+    // shown. Relative paths are relative to the buffer's directory
+    fn preview_image(&mut self, fname: Option<&str>) {
+        let textview = self.textview_tree.active_mut();
+        let fname = match fname {
+            Some(fname) => fname.to_owned(),
+            None => match image_link_at_cursor(textview) {
+                Some(fname) => fname,
+                None => {
+                    error!("no image link under cursor");
+                    return;
+                }
+            },
+        };
+        if fname.contains("://") {
+            error!("only local images can be previewed: {}", fname);
+            return;
+        }
+        let buffer_dir = {
+            let buffer = &*textview.buffer();
+            let buffer = &*buffer.borrow();
+            buffer
+                .path()
+                .filter(|path| !provider(path).is_remote())
+                .and_then(|path| Path::new(path).parent().map(|p| p.to_path_buf()))
+        };
+        let path = match buffer_dir {
+            Some(dir) if Path::new(&fname).is_relative() && !fname.starts_with('~') => {
+                dir.join(&fname)
+            }
+            _ => self.absolute_path(&fname),
+        };
+        if let Err(e) = self.image_popup.show(&path) {
+            error!("failed to open image: {:?}: {}", path, e);
+        }
+    }
+
+    // Re-read the config file, and apply it to buffers and views. The current config is kept if
+    // the file can't be parsed
+    fn reload_config(&mut self) {
+        let cfg = {
+            let font_core = &mut *self.font_core.borrow_mut();
+            Cfg::load(font_core)
+        };
+        if let CfgSource::Invalid(path, e) = &cfg.source {
+            warning!("keeping current config: {:?}: {}", path, e);
+            return;
+        }
+        *self.config.borrow_mut() = cfg;
+
+        for buffer in self.all_buffers() {
+            buffer.borrow_mut().reload_config();
+        }
+        let dpi = self.render_ctx.dpi;
+        let options = self.config.borrow().ui.font;
+        self.render_ctx.set_raster(dpi, options);
+        // Borders, statuslines and the prompt bar may have changed size
+        self.layout();
+    }
+
+    // Switch to the DPI of the monitor the window is on, after it moved. Glyphs are rasterized
+    // and text is shaped again if it changed
+    fn update_dpi(&mut self) {
+        let dpi = {
+            let glfw = &mut *self.glfw.borrow_mut();
+            window_monitor_dpi(glfw, &self.window)
+        };
+        let dpi = match dpi {
+            Some(dpi) if dpi != self.render_ctx.dpi => dpi,
+            _ => return,
+        };
+        let options = self.config.borrow().ui.font;
+        self.render_ctx.set_raster(dpi, options);
+        self.textview_tree.set_dpi(dpi);
+        self.tabs.set_dpi(dpi);
+        self.fuzzy_popup.set_dpi(dpi);
+        self.prompt.set_dpi(dpi);
+        self.key_hints.set_dpi(dpi);
+        // The tab bar's height depends on the DPI
+        self.layout();
+    }
+
+    // Toggle presentation mode in the active pane, or in all panes of the tab if all is set. All
+    // panes follow the active one, so that they end up in the same mode
+    fn toggle_presentation(&mut self, all: bool) {
+        let val = !self.textview_tree.active_mut().is_presenting();
+        if all {
+            self.textview_tree.set_presenting(val);
+        } else {
+            self.textview_tree.active_mut().set_presenting(val);
+        }
+    }
+
+    // Set the active pane's width to a percentage of the split containing it, or change it by a
+    // percentage if arg starts with + or -
+    fn resize_pane(&mut self, arg: &str) {
+        let n = match arg.parse::<i32>() {
+            Ok(n) => n as f64 / 100.0,
+            Err(_) => {
+                error!("invalid size: {}", arg);
+                return;
+            }
+        };
+        let relative = arg.starts_with('+') || arg.starts_with('-');
+        let share = |old: f64| if relative { old + n } else { n };
+        if !self.textview_tree.resize_active(&share) {
+            error!("pane isn't in a side-by-side split");
+        }
+    }
+
+    // Close the active pane, and the window along with the last pane. Refuses to close a pane
+    // showing a buffer with unwritten changes, unless forced
+    fn quit_active(&mut self, force: bool) {
+        if !force && self.textview_tree.active_mut().buffer().borrow().is_dirty() {
+            warning!("buffer has unwritten changes (add ! to override)");
+            return;
+        }
+        self.close_pane();
+    }
+
+    // Close the active pane. Closing the last pane of a tab closes the tab, and closing the last
+    // tab closes the window
+    fn close_pane(&mut self) {
+        if !self.textview_tree.kill_active() {
+            return;
+        }
+        if self.tabs.close(&mut self.textview_tree) {
+            self.layout();
+        } else {
+            self.set_should_close(true);
+        }
+    }
+
+    // Open tab after the active one, with an empty buffer or the given file
+    fn new_tab(&mut self, fname: Option<&str>) {
+        let dpi = self.render_ctx.dpi;
+        let (buffer, view_id) = {
+            let core = &mut *self.core.borrow_mut();
+            (core.new_empty_buffer(dpi), core.next_view_id())
+        };
+        let tree = TextViewTree::new(
+            buffer,
+            get_viewable_rect(&self.window),
+            self.font_core.clone(),
+            self.config.clone(),
+            dpi,
+            true,
+            false,
+            view_id,
+        );
+        self.tabs.open(&mut self.textview_tree, tree);
+        self.layout();
+        if let Some(fname) = fname {
+            self.edit_file(fname);
+        }
+    }
+
+    // Make tab at index active
+    fn switch_tab(&mut self, idx: usize) {
+        if idx >= self.tabs.len() {
+            error!("no tab {}", idx + 1);
+            return;
+        }
+        self.tabs.switch_to(&mut self.textview_tree, idx);
+        // The window may have been resized while the tab was in the background
+        self.layout();
+    }
+
+    // Close the active tab, unless it is the only one. Buffers shown in it stay open
+    fn close_tab(&mut self) {
+        if self.tabs.close(&mut self.textview_tree) {
+            self.layout();
+        } else {
+            error!("can't close the last tab");
+        }
+    }
+
+    // Buffers open in the core, and scratch buffers shown in panes of any tab
+    fn all_buffers(&self) -> Vec<Rc<RefCell<Buffer>>> {
+        let mut buffers = (&*self.core.borrow()).buffers();
+        let mut visible = self.textview_tree.visible_buffers();
+        visible.extend(self.tabs.visible_buffers());
+        for buffer in visible {
+            if !buffers.iter().any(|b| Rc::ptr_eq(b, &buffer)) {
+                buffers.push(buffer);
+            }
+        }
+        buffers
+    }
+
+    // Open fuzzy popup listing files in the project directory. The selected file is opened
+    // according to the target
+    fn start_file_fuzzy(&mut self, target: FuzzyTarget, pattern: Option<String>) {
+        self.fuzzy_target = target;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        let wdir = self.project_directory();
+        self.fuzzy_directory = wdir.clone();
+        let basename = wdir.file_name().and_then(|p| p.to_str()).unwrap_or("/");
+        self.fuzzy_popup.set_input_label(basename);
+        if let Some(pattern) = pattern {
+            for c in pattern.chars() {
+                self.fuzzy_popup.insert(c);
+            }
+        }
+        // Open buffers are listed first, and aren't listed again among the files
+        let open_paths = (&*self.core.borrow())
+            .buffers()
+            .iter()
+            .filter_map(|b| b.borrow().path().map(|p| self.absolute_path(p)))
+            .map(|p| p.strip_prefix(&wdir).map(|p| p.to_path_buf()).unwrap_or(p))
+            .filter_map(|p| p.to_str().map(|s| s.to_owned()))
+            .collect::<HashSet<_>>();
+        let (tx, rx) = channel();
+        for path in &open_paths {
+            let _ = tx.send(path.clone());
+        }
+        drop(tx);
+        self.fuzzy_popup.add_async_source("buffer", rx, None);
+        let (tx, rx) = self.core.borrow().wakeup().channel();
+        thread::spawn(move || {
+            for path in project_files(&wdir) {
+                let path = path.strip_prefix(&wdir).unwrap();
+                if let Some(path) = path.to_str().map(|s| s.to_string()) {
+                    if open_paths.contains(&path) {
+                        continue;
+                    }
+                    if tx.send(path).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        self.fuzzy_popup.add_async_source("file", rx, None);
+        self.fuzzy_popup.update_from_async();
+        self.prompt.set_active(false);
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Detach the active buffer from all panes. The buffer is removed from the core if wipe is
+    // set, and its shaped lines are dropped if it isn't shown anywhere else. Refuses to drop
+    // unwritten changes, unless forced, in which case a buffer not shown anywhere else is removed
+    // from the core too
+    fn delete_active_buffer(&mut self, wipe: bool, force: bool) {
+        let buffer = self.textview_tree.active_mut().buffer();
+        let dirty = buffer.borrow().is_dirty();
+        if !force && dirty {
+            warning!("buffer has unwritten changes (add ! to override)");
+            return;
+        }
+        let dpi = self.render_ctx.dpi;
+        let core = &mut *self.core.borrow_mut();
+        self.textview_tree.remove_buffer(&buffer, || {
+            (core.new_empty_buffer(dpi), core.next_view_id())
+        });
+        for tree in self.tabs.others_mut() {
+            tree.remove_buffer(&buffer, || {
+                (core.new_empty_buffer(dpi), core.next_view_id())
+            });
+        }
+        if buffer.borrow_mut().has_views() {
+            // Still open in another window, which keeps its changes
+            if dirty && !wipe {
+                warning!("buffer is open in another window, changes were kept");
+            }
+            if wipe {
+                core.remove_buffer(&buffer);
+            }
+        } else if wipe || dirty {
+            // Forgetting a modified buffer discards its changes, so they aren't written by
+            // autosave or :make. Opening the file again reads it from disk
+            core.remove_buffer(&buffer);
+        } else {
+            buffer.borrow_mut().unload();
+        }
+    }
+
+    // Move the active buffer's file to a new path
+    fn rename_active(&mut self, fname: &str) {
+        let path = self.absolute_path(fname);
+        let spath = match path.to_str() {
+            Some(spath) => spath,
+            None => {
+                error!("invalid path: {:?}", path);
+                return;
+            }
+        };
+        let buffer = self.textview_tree.active_mut().buffer();
+        if let Err(e) = (&mut *self.core.borrow_mut()).rename_buffer(&buffer, spath) {
+            error!("failed to rename file: {}: {}", spath, e);
+        }
+    }
+
+    // Root of the project the active buffer is in, or the working directory if it isn't in one.
+    // File pickers and :grep start here
+    fn project_directory(&mut self) -> PathBuf {
+        let buffer = self.textview_tree.active_mut().buffer();
+        let buffer = &*buffer.borrow();
+        buffer
+            .project_root()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| self.working_directory.clone())
+    }
+
+    // Change option for this session, given as name=value. Without a value, the current value
+    // is shown
+    fn set_option(&mut self, arg: &str) {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next().unwrap(), parts.next()) {
+            ("rootmarkers", None) => {
+                info!(
+                    "rootmarkers={}",
+                    self.config.borrow().ui.root_markers.join(",")
+                );
+            }
+            ("rootmarkers", Some(value)) => {
+                self.config.borrow_mut().ui.root_markers = value
+                    .split(',')
+                    .filter(|m| !m.is_empty())
+                    .map(|m| m.to_owned())
+                    .collect();
+                for buffer in self.all_buffers() {
+                    buffer.borrow_mut().detect_project_root();
+                }
+            }
+            ("fileencoding", None) => {
+                let buffer = self.textview_tree.active_mut().buffer();
+                info!("fileencoding={}", buffer.borrow().encoding().name());
+            }
+            // The buffer is converted when it is written
+            ("fileencoding", Some(value)) => match Encoding::from_name(value) {
+                Some(encoding) => {
+                    let buffer = self.textview_tree.active_mut().buffer();
+                    buffer.borrow_mut().set_encoding(encoding);
+                }
+                None => error!("unknown encoding: {}", value),
+            },
+            ("colorcolumn", None) => {
+                let column = self.textview_tree.active_mut().color_column();
+                info!("colorcolumn={}", column.unwrap_or(0));
+            }
+            // 0 or an empty value removes the rule
+            ("colorcolumn", Some(value)) => match value.parse::<usize>() {
+                Ok(column) => {
+                    let textview = self.textview_tree.active_mut();
+                    textview.set_color_column(Some(column).filter(|c| *c > 0));
+                }
+                Err(_) if value.is_empty() => {
+                    self.textview_tree.active_mut().set_color_column(None);
+                }
+                Err(_) => error!("invalid column: {}", value),
+            },
+            ("cursorline?", None) => {
+                let val = self.textview_tree.active_mut().cursor_line();
+                info!("{}cursorline", if val { "" } else { "no" });
+            }
+            ("cursorline", None) => self.textview_tree.active_mut().set_cursor_line(true),
+            ("nocursorline", None) => self.textview_tree.active_mut().set_cursor_line(false),
+            (name, _) => error!("unknown option: {}", name),
+        }
+    }
+
+    // Path relative to the working directory, with ~ expanded to the home directory. Remote
+    // paths are left alone
+    fn absolute_path(&self, fname: &str) -> PathBuf {
+        let path = Path::new(fname);
+        if path.has_root() || provider(fname).is_remote() {
+            path.to_path_buf()
+        } else if path.starts_with("~") {
+            let path = path.strip_prefix("~").unwrap();
+            let mut buf = BaseDirs::new()
+                .expect("failed to get base dirs")
+                .home_dir()
+                .to_path_buf();
+            buf.push(path);
+            buf
+        } else {
+            let mut buf = self.working_directory.clone();
+            buf.push(path);
+            buf
+        }
+    }
+
+    // Move the active buffer's file to the trash, and close the buffer
+    fn delete_active_file(&mut self, force: bool) {
+        let buffer = self.textview_tree.active_mut().buffer();
+        let path = match buffer.borrow().path() {
+            Some(path) => path.to_owned(),
+            None => {
+                error!("buffer has no path");
+                return;
+            }
+        };
+        if !force && buffer.borrow().is_dirty() {
+            warning!("buffer has unwritten changes (add ! to override)");
+            return;
+        }
+        match trash_file(Path::new(&path)) {
+            Ok(file) => {
+                (&mut *self.core.borrow_mut()).push_trashed(file);
+                self.delete_active_buffer(true, true);
+            }
+            Err(e) => error!("failed to move file to trash: {}: {}", path, e),
+        }
+    }
+
+    // Move the most recently deleted file back from the trash, and open it
+    fn restore_file(&mut self) {
+        let core = &mut *self.core.borrow_mut();
+        let file = match core.pop_trashed() {
+            Some(file) => file,
+            None => {
+                error!("no deleted files to restore");
+                return;
+            }
+        };
+        if let Err(e) = file.restore() {
+            error!("failed to restore file: {:?}: {}", file.original_path(), e);
+            core.push_trashed(file);
+            return;
+        }
+        let path = match file.original_path().to_str() {
+            Some(path) => path,
+            None => {
+                error!("invalid path: {:?}", file.original_path());
+                return;
+            }
+        };
+        match core.new_buffer_from_file(path, self.render_ctx.dpi) {
+            Ok(buffer) => {
+                let view_id = core.next_view_id();
+                self.textview_tree.active_mut().add_buffer(buffer, view_id);
+            }
+            Err(e) => error!("failed to open file: {}: {}", path, e),
+        }
+    }
+
+    // Show command output in a read-only scratch split. The split is closed with `q`
+    fn show_output(&mut self, lines: &[String]) -> Rc<RefCell<Buffer>> {
+        let core = &mut *self.core.borrow_mut();
+        let buffer = core.new_scratch_buffer(&lines.join("\n"), self.render_ctx.dpi);
+        self.textview_tree.split_v(core.next_view_id());
+        let view_id = core.next_view_id();
+        self.textview_tree
+            .active_mut()
+            .add_buffer(buffer.clone(), view_id);
+        buffer
+    }
+
+    // Show earlier messages in a split, or forget them if clear is set
+    fn show_messages(&mut self, clear: bool) {
+        if clear {
+            messages::clear();
+            self.prompt.set_message(None);
+            return;
+        }
+        let lines = messages::history()
+            .iter()
+            .map(|message| message.to_string())
+            .collect::<Vec<_>>();
+        if lines.is_empty() {
+            info!("no messages");
+            return;
+        }
+        let buffer = self.show_output(&lines);
+        buffer.borrow_mut().set_title("messages");
+    }
+
+    // Run command with the shell in the background. Its output is shown in a split, or inserted
+    // at the cursor if insert is set
+    fn run_shell(&mut self, command: &str, insert: bool) {
+        if let Some((shell, _)) = &self.shell {
+            warning!("command is already running: {}", shell.command());
+            return;
+        }
+        let output = if insert {
+            ShellOutput::Insert(self.textview_tree.active_mut().view_id())
+        } else {
+            ShellOutput::Show
+        };
+        let wakeup = self.core.borrow().wakeup();
+        let shell = Build::start_separate(command, &self.working_directory, wakeup);
+        self.shell = Some((shell, output));
+    }
+
+    fn finish_shell(&mut self) {
+        let (shell, output) = match self.shell.take() {
+            Some(shell) => shell,
+            None => return,
+        };
+        match output {
+            ShellOutput::Show if shell.output().is_empty() && shell.errors().is_empty() => {
+                info!("!{}: {}", shell.command(), shell.status());
+            }
+            ShellOutput::Show => {
+                let buffer = self.show_output(&shell.report());
+                buffer
+                    .borrow_mut()
+                    .set_title(&format!("!{}", shell.command()));
+            }
+            ShellOutput::Insert(view_id) => {
+                for line in shell.errors() {
+                    error!("{}", line);
+                }
+                if !shell.succeeded() {
+                    error!("!{}: {}", shell.command(), shell.status());
+                }
+                let textview = self.textview_tree.active_mut();
+                if textview.view_id() != view_id {
+                    warning!("buffer changed while running {}", shell.command());
+                } else if !shell.output().is_empty() {
+                    textview.insert_str(&shell.output().join("\n"));
+                }
+            }
+        }
+    }
+
+    // Insert the contents of a file at the cursor
+    fn read_file(&mut self, fname: &str) {
+        let path = self.absolute_path(fname);
+        match std::fs::read_to_string(&path) {
+            Ok(text) => self.textview_tree.active_mut().insert_str(&text),
+            Err(e) => error!("failed to read file: {:?}: {}", path, e),
+        }
+    }
+
+    // Show the active buffer's file, or the given file, as of a git revision in a read-only
+    // split. Objects are "<rev>" for the active buffer's file, or "<rev>:<path>"
+    fn git_show(&mut self, object: &str) {
+        let (spec, dir, path) = match object.find(':') {
+            Some(idx) => {
+                let path = &object[idx + 1..];
+                (
+                    object.to_owned(),
+                    self.working_directory.clone(),
+                    path.to_owned(),
+                )
+            }
+            None => {
+                let buffer = self.textview_tree.active_mut().buffer();
+                let buffer = &*buffer.borrow();
+                let path = match buffer.path() {
+                    Some(path) if !provider(path).is_remote() => path.to_owned(),
+                    _ => {
+                        error!("buffer has no local file: use :gitshow <rev>:<path>");
+                        return;
+                    }
+                };
+                // Paths starting with "./" are resolved from git's working directory
+                let abs = self.absolute_path(&path);
+                let dir = match abs.parent() {
+                    Some(dir) => dir.to_path_buf(),
+                    None => self.working_directory.clone(),
+                };
+                let spec = format!("{}:./{}", object, file_name(&path));
+                (spec, dir, path)
+            }
+        };
+        let text = match git::show(&spec, &dir) {
+            Ok(text) => text,
+            Err(e) => {
+                error!("failed to show {}: {}", spec, e);
+                return;
+            }
+        };
+        let core = &mut *self.core.borrow_mut();
+        let buffer = core.new_scratch_buffer(&text, self.render_ctx.dpi);
+        {
+            let buffer = &mut *buffer.borrow_mut();
+            buffer.set_syntax_from_path(&path);
+            buffer.set_title(&format!("{} @ {}", file_name(&path), object));
+        }
+        self.textview_tree.split_v(core.next_view_id());
+        let view_id = core.next_view_id();
+        self.textview_tree.active_mut().add_buffer(buffer, view_id);
+    }
+
+    // Rename the word under the cursor in open buffers and files under the working directory,
+    // asking before changing each file. Changed files are left as modified buffers
+    fn rename_symbol(&mut self, new: &str) {
+        let old = match self.textview_tree.active_mut().word_under_cursor() {
+            Some(old) => old,
+            None => {
+                error!("no word under cursor");
+                return;
+            }
+        };
+        if !new.chars().all(is_word_char) {
+            error!("invalid name: {}", new);
+            return;
+        }
+        let regex = Regex::new(&format!(r"\b{}\b", regex::escape(&old))).unwrap();
+        info!("looking for {}", old);
+        let rx = self.search_rename_candidates(&regex);
+        let rename = PendingRename {
+            regex: regex,
+            old: old,
+            new: new.to_owned(),
+            files: Vec::new(),
+            renamed: 0,
+        };
+        self.rename_search = Some((rename, rx));
+    }
+
+    // Search files for regex in the background. The files with matches, and the number of
+    // matches in each, are sent once all files were searched. Open buffers are searched instead
+    // of the files they are for, so that unsaved changes are included. Binary and large files
+    // are skipped
+    fn search_rename_candidates(&self, regex: &Regex) -> Receiver<Vec<(String, usize)>> {
+        let large_file_size = self.config.borrow().ui.textview.large_file_size;
+        let mut open = Vec::new();
+        for buffer in (&*self.core.borrow()).buffers() {
+            let buffer = &*buffer.borrow();
+            match buffer.path() {
+                Some(path) if !buffer.is_scratch() => {
+                    open.push((path.to_owned(), buffer.lines_text(0, buffer.len_lines())));
+                }
+                _ => {}
+            }
+        }
+        let wdir = self.working_directory.clone();
+        let regex = regex.clone();
+        let (tx, rx) = self.core.borrow().wakeup().channel();
+        thread::spawn(move || {
+            let mut ret = Vec::new();
+            let mut seen = HashSet::new();
+            for (path, text) in open {
+                let count = regex.find_iter(&text).count();
+                if count > 0 {
+                    ret.push((path.clone(), count));
+                }
+                seen.insert(path);
+            }
+            for path in project_files(&wdir) {
+                let path = match path.to_str() {
+                    Some(path) if !seen.contains(path) => path,
+                    _ => continue,
+                };
+                if check_file(path, large_file_size) != FileCheck::Ok {
+                    continue;
+                }
+                let text = match std::fs::read_to_string(path) {
+                    Ok(text) => text,
+                    Err(_) => continue,
+                };
+                let count = regex.find_iter(&text).count();
+                if count > 0 {
+                    ret.push((path.to_owned(), count));
+                }
+            }
+            ret.sort();
+            let _ = tx.send(ret);
+        });
+        rx
+    }
+
+    // Start asking about files found by search_rename_candidates
+    fn finish_rename_search(&mut self, files: Vec<(String, usize)>) {
+        let mut rename = match self.rename_search.take() {
+            Some((rename, _)) => rename,
+            None => return,
+        };
+        if files.is_empty() {
+            error!("no occurrences of {}", rename.old);
+            return;
+        }
+        rename.files = files;
+        self.pending_rename = Some(rename);
+        self.ask_rename();
+    }
+
+    // Ask whether to rename in the next file, or report how many files were changed once all
+    // files were asked about
+    fn ask_rename(&mut self) {
+        let rename = match &self.pending_rename {
+            Some(rename) => rename,
+            None => return,
+        };
+        let (path, count) = match rename.files.first() {
+            Some(file) => file,
+            None => {
+                info!(
+                    "renamed {} to {} in {} files",
+                    rename.old, rename.new, rename.renamed
+                );
+                self.pending_rename = None;
+                return;
+            }
+        };
+        let path = Path::new(path);
+        let label = format!(
+            "{} -> {}: {} ({})",
+            rename.old,
+            rename.new,
+            path.strip_prefix(&self.working_directory)
+                .unwrap_or(path)
+                .to_string_lossy(),
+            count
+        );
+        self.fuzzy_target = FuzzyTarget::Rename;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label(&label);
+        self.fuzzy_popup.push_string_choices(&[
+            "replace in this file".to_owned(),
+            "skip this file".to_owned(),
+            "replace in all remaining files".to_owned(),
+            "cancel".to_owned(),
+        ]);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Carry on with the rename according to the choice picked for the current file
+    fn continue_rename(&mut self, choice: &str) {
+        let mut rename = match self.pending_rename.take() {
+            Some(rename) => rename,
+            None => return,
+        };
+        let nfiles = match choice {
+            "replace in this file" => 1,
+            "skip this file" => {
+                rename.files.remove(0);
+                0
+            }
+            "replace in all remaining files" => rename.files.len(),
+            _ => return,
+        };
+        for (path, _) in rename.files.drain(..nfiles) {
+            if self.rename_in_file(&path, &rename.regex, &rename.new) {
+                rename.renamed += 1;
+            }
+        }
+        self.pending_rename = Some(rename);
+        self.ask_rename();
+    }
+
+    // Replace matches for regex in the buffer for path, opening it if it isn't open
+    fn rename_in_file(&mut self, path: &str, regex: &Regex, new: &str) -> bool {
+        let core = &mut *self.core.borrow_mut();
+        let buffer = match core.buffer(path) {
+            Some(buffer) => buffer,
+            None => match core.new_buffer_from_file(path, self.render_ctx.dpi) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    error!("failed to open file: {}: {}", path, e);
+                    return false;
+                }
+            },
+        };
+        let buffer = &mut *buffer.borrow_mut();
+        let len_lines = buffer.len_lines();
+        buffer.transform_lines(0, len_lines - 1, |text| {
+            regex.replace_all(text, NoExpand(new)).into_owned()
+        });
+        true
+    }
+
+    // Replace matches in a range of lines, like ":%s/foo/bar/g". With the c flag, each match is
+    // asked about first. With the p flag, the changes are shown in a split first
+    fn substitute(&mut self, range: Option<&str>, arg: &str) {
+        let substitution = {
+            let core = &*self.core.borrow();
+            match Substitution::parse(arg, core.registers().last_search()) {
+                Ok(substitution) => substitution,
+                Err(e) => {
+                    error!("invalid substitution: {}", e);
+                    return;
+                }
+            }
+        };
+        self.core
+            .borrow_mut()
+            .add_search(&substitution.searcher.pattern());
+        let (start, end) = self.line_range(range);
+        if substitution.preview {
+            self.preview_substitute(substitution, start, end);
+        } else if substitution.confirm {
+            self.pending_substitution = Some(PendingSubstitution {
+                substitution: substitution,
+                line: start,
+                offset: 0,
+                end: end,
+                current: None,
+                count: 0,
+                preview: None,
+            });
+            self.ask_substitute();
+        } else {
+            self.substitute_all(&substitution, start, end);
+        }
+    }
+
+    fn substitute_all(&mut self, substitution: &Substitution, start: usize, end: usize) {
+        let textview = self.textview_tree.active_mut();
+        let (changes, count) = {
+            let buffer = &*textview.buffer();
+            let buffer = &*buffer.borrow();
+            substitute_lines(buffer, substitution, start, end, 0)
+        };
+        match changes.last() {
+            Some((last, _)) => {
+                let last = *last;
+                textview.buffer().borrow_mut().replace_lines(&changes);
+                textview.go_to_line(last);
+                info!("{} substitutions on {} lines", count, changes.len());
+            }
+            None => error!("pattern not found: {}", substitution.searcher.pattern()),
+        }
+    }
+
+    // Show the lines the substitution changes before and after in a split, keeping the active
+    // pane focused. Then ask whether to apply all of it, or step through the
+    // matches like the c flag. With the c flag too, matches are stepped through right away
+    fn preview_substitute(&mut self, substitution: Substitution, start: usize, end: usize) {
+        let preview = {
+            let cfg = &*self.config.borrow();
+            let gutter = &cfg.ui.theme().gutter;
+            let buffer = self.textview_tree.active_mut().buffer();
+            let buffer = &*buffer.borrow();
+            let (removed, added) = (gutter.deleted_color, gutter.added_color);
+            preview_lines(buffer, &substitution, start, end, removed, added)
+        };
+        let (text, decorations) = match preview {
+            Some(preview) => preview,
+            None => {
+                error!("pattern not found: {}", substitution.searcher.pattern());
+                return;
+            }
+        };
+        let active = self.textview_tree.active_mut().id();
+        let preview_pane = {
+            let core = &mut *self.core.borrow_mut();
+            let buffer = core.new_scratch_buffer(&text, self.render_ctx.dpi);
+            {
+                let buffer = &mut *buffer.borrow_mut();
+                buffer.set_title("substitution preview");
+                buffer
+                    .decorations_mut()
+                    .set(DecorationLayer::SubstitutePreview, decorations);
+            }
+            self.textview_tree.split_v(core.next_view_id());
+            let view_id = core.next_view_id();
+            let textview = self.textview_tree.active_mut();
+            textview.add_buffer(buffer, view_id);
+            textview.id()
+        };
+        self.textview_tree.focus_pane(active);
+        let confirm = substitution.confirm;
+        self.pending_substitution = Some(PendingSubstitution {
+            substitution: substitution,
+            line: start,
+            offset: 0,
+            end: end,
+            current: None,
+            count: 0,
+            preview: Some(preview_pane),
+        });
+        if confirm {
+            self.ask_substitute();
+            return;
+        }
+        let nlines = text.lines().count() / 2;
+        self.fuzzy_target = FuzzyTarget::Substitute;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup
+            .set_input_label(&format!("substitute on {} lines", nlines));
+        self.fuzzy_popup.push_string_choices(&[
+            "apply all".to_owned(),
+            "step through".to_owned(),
+            "cancel".to_owned(),
+        ]);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Close the preview of a substitution that is done or cancelled, if it is still open
+    fn close_substitute_preview(&mut self, preview: Option<usize>) {
+        let preview = match preview {
+            Some(preview) => preview,
+            None => return,
+        };
+        let active = self.textview_tree.active_mut().id();
+        if self.textview_tree.focus_pane(preview) {
+            self.close_pane();
+            self.textview_tree.focus_pane(active);
+        }
+    }
+
+    // Move to the next match of the pending substitution and ask whether to replace it, or
+    // report how many matches were replaced once there are no more
+    fn ask_substitute(&mut self) {
+        let pending = match &mut self.pending_substitution {
+            Some(pending) => pending,
+            None => return,
+        };
+        let textview = self.textview_tree.active_mut();
+        let mut found = None;
+        {
+            let buffer = textview.buffer();
+            let buffer = &*buffer.borrow();
+            let last = min(pending.end, buffer.len_lines() - 1);
+            while pending.line <= last {
+                let text = buffer.line_text(pending.line);
+                let from = pending.offset;
+                if let Some((start, end, replacement)) =
+                    pending.substitution.next_match(&text, from)
+                {
+                    found = Some((text, start, end, replacement));
+                    break;
+                }
+                pending.line += 1;
+                pending.offset = 0;
+            }
+        }
+        let (text, start, end, replacement) = match found {
+            Some(found) => found,
+            None => {
+                info!("{} substitutions", pending.count);
+                let preview = pending.preview;
+                self.pending_substitution = None;
+                self.close_substitute_preview(preview);
+                return;
+            }
+        };
+        let gidx = text[..start].graphemes(true).count();
+        textview.go_to_line_gidx(pending.line, gidx);
+        let label = format!("replace \"{}\" with \"{}\"", &text[start..end], replacement);
+        pending.current = Some((start, end, replacement));
+        self.fuzzy_target = FuzzyTarget::Substitute;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        // Enter on its own answers yes, for this match only
+        self.fuzzy_popup.set_keep_order(true);
+        self.fuzzy_popup.set_input_label(&label);
+        self.fuzzy_popup.push_string_choices(&[
+            "yes".to_owned(),
+            "no".to_owned(),
+            "all remaining".to_owned(),
+            "quit".to_owned(),
+        ]);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Carry on with the pending substitution according to the choice picked for the current
+    // match, or for its preview if no match has been asked about yet
+    fn continue_substitute(&mut self, choice: &str) {
+        let mut pending = match self.pending_substitution.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let (start, end, replacement) = match (pending.current.take(), choice) {
+            (Some(current), _) => current,
+            (None, "apply all") => {
+                self.substitute_all(&pending.substitution, pending.line, pending.end);
+                self.close_substitute_preview(pending.preview);
+                return;
+            }
+            (None, "step through") => {
+                self.pending_substitution = Some(pending);
+                self.ask_substitute();
+                return;
+            }
+            (None, _) => {
+                self.close_substitute_preview(pending.preview);
+                return;
+            }
+        };
+        let buffer = self.textview_tree.active_mut().buffer();
+        let buffer = &mut *buffer.borrow_mut();
+        let line = pending.line;
+        let mut text = buffer.line_text(line);
+        let mut next = end;
+        if choice == "yes" || choice == "all remaining" {
+            text.replace_range(start..end, &replacement);
+            next = start + replacement.len();
+            pending.count += 1;
+        }
+        // An empty match would be found again at the same place
+        if start == end {
+            next += text[next..]
+                .chars()
+                .next()
+                .map(|c| c.len_utf8())
+                .unwrap_or(1);
+        }
+        if !pending.substitution.global || next > text.len() {
+            pending.line += 1;
+            next = 0;
+        }
+        pending.offset = next;
+        match choice {
+            "yes" => buffer.replace_lines(&[(line, text)]),
+            "no" => {}
+            "all remaining" => {
+                let mut changes = Vec::new();
+                let mut count = pending.count;
+                if pending.line == line {
+                    // Rest of the current line, after the replacement
+                    match pending.substitution.replace_from(&text, next) {
+                        Some((replaced, n)) => {
+                            changes.push((line, replaced));
+                            count += n;
+                        }
+                        None => changes.push((line, text)),
+                    }
+                    pending.line += 1;
+                } else {
+                    changes.push((line, text));
+                }
+                let (rest, n) =
+                    substitute_lines(buffer, &pending.substitution, pending.line, pending.end, 0);
+                changes.extend(rest);
+                buffer.replace_lines(&changes);
+                info!("{} substitutions", count + n);
+                self.close_substitute_preview(pending.preview);
+                return;
+            }
+            _ => {
+                info!("{} substitutions", pending.count);
+                self.close_substitute_preview(pending.preview);
+                return;
+            }
+        }
+        self.pending_substitution = Some(pending);
+        self.ask_substitute();
+    }
+
+    // Save the files open in each pane, cursor positions, the layout of panes and the working
+    // directory as a session
+    fn make_session(&mut self, name: Option<&str>) {
+        let name = name.unwrap_or(DEFAULT_SESSION);
+        let session = Session {
+            working_directory: self.working_directory.clone(),
+            root: self.textview_tree.session_layout(),
+        };
+        if let Err(e) = session.save(name) {
+            error!("failed to save session: {}: {}", name, e);
+        }
+    }
+
+    // Replace all panes with the layout saved in session. Files that no longer exist are
+    // skipped
+    fn restore_session(&mut self, session: &Session) {
+        if session.working_directory.is_dir() {
+            self.working_directory = session.working_directory.clone();
+        }
+        let dpi = self.render_ctx.dpi;
+        let core = &mut *self.core.borrow_mut();
+        let mut open = |path: Option<&str>| {
+            let buffer = match path {
+                Some(path) if !provider(path).is_remote() && !Path::new(path).exists() => {
+                    return None;
+                }
+                Some(path) => match core.new_buffer_from_file(path, dpi) {
+                    Ok(buffer) => buffer,
+                    Err(e) => {
+                        error!("failed to open file: {}: {}", path, e);
+                        return None;
+                    }
+                },
+                None => core.new_empty_buffer(dpi),
+            };
+            Some((buffer, core.next_view_id()))
+        };
+        self.textview_tree = TextViewTree::from_session(
+            &session.root,
+            get_viewable_rect(&self.window),
+            self.font_core.clone(),
+            self.config.clone(),
+            dpi,
+            true,
+            false,
+            &mut open,
+        );
+    }
+
+    // Run the given build command, or the one for the active buffer's language. Modified
+    // buffers are written first, or the user is asked whether to write them, depending on the
+    // config
+    fn make(&mut self, command: Option<&str>) {
+        if let Some(build) = &self.build {
+            warning!("build is already running: {}", build.command());
+            return;
+        }
+        let command = match command {
+            Some(command) => command.to_owned(),
+            None => match self.active_build_command() {
+                Some(command) => command,
+                None => {
+                    error!("no build command for this language");
+                    return;
+                }
+            },
+        };
+        let write = (&*self.config.borrow()).ui.write_before_build;
+        match write {
+            CfgUiWriteBeforeBuild::Always => self.core.borrow_mut().write_modified_buffers(),
+            CfgUiWriteBeforeBuild::Ask if self.core.borrow().has_modified_buffers() => {
+                self.pending_build = Some(command);
+                self.fuzzy_target = FuzzyTarget::WriteBeforeBuild;
+                self.fuzzy_popup.set_active(true);
+                self.fuzzy_popup.set_default_on_empty(true);
+                self.fuzzy_popup.set_keep_order(true);
+                self.fuzzy_popup.set_input_label("modified buffers");
+                self.fuzzy_popup.push_string_choices(&[
+                    "write modified buffers, then build".to_owned(),
+                    "build without writing".to_owned(),
+                    "cancel".to_owned(),
+                ]);
+                self.fuzzy_popup.re_filter();
+                self.input_state.mode = InputMode::Fuzzy;
+                return;
+            }
+            _ => {}
+        }
+        self.start_build(&command);
+    }
+
+    // Build command configured for the active buffer's language
+    fn active_build_command(&mut self) -> Option<String> {
+        let cfg = &*self.config.borrow();
+        let buffer = self.textview_tree.active_mut().buffer();
+        let syntax = cfg.syntax(buffer.borrow().syntax_name());
+        syntax.build_command.clone()
+    }
+
+    fn start_build(&mut self, command: &str) {
+        let wakeup = self.core.borrow().wakeup();
+        self.build = Some(Build::start(command, &self.working_directory, wakeup));
+    }
+
+    // Show the output of the finished build, and remember the errors in it for :errors
+    fn finish_build(&mut self) {
+        let build = match self.build.take() {
+            Some(build) => build,
+            None => return,
+        };
+        self.build_locations = build.locations();
+        let title = if build.succeeded() {
+            format!("make: {}", build.command())
+        } else {
+            format!(
+                "make: {} [failed, {} locations]",
+                build.command(),
+                self.build_locations.len()
+            )
+        };
+        let buffer = self.show_output(&build.report());
+        buffer.borrow_mut().set_title(&title);
+    }
+
+    // Open fuzzy popup listing errors and warnings from the last build
+    fn start_build_errors_fuzzy(&mut self) {
+        if self.build_locations.is_empty() {
+            info!("no errors from the last build");
+            return;
+        }
+        self.fuzzy_target = FuzzyTarget::Location;
+        self.fuzzy_directory = self.working_directory.clone();
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label("errors");
+        self.fuzzy_popup.push_string_choices(&self.build_locations);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Information about the environment, for bug reports
+    fn health_report(&self) -> Vec<String> {
+        let mut lines = vec!["Config".to_owned()];
+        {
+            let cfg = &*self.config.borrow();
+            lines.push(match &cfg.source {
+                CfgSource::Default => "  no config directory, using defaults".to_owned(),
+                CfgSource::Missing(path) => format!("  {:?}: not found, using defaults", path),
+                CfgSource::Invalid(path, e) => format!("  {:?}: failed to parse: {}", path, e),
+                CfgSource::File(path) => format!("  {:?}: ok", path),
+            });
+
+            lines.push("".to_owned());
+            lines.push("Fonts".to_owned());
+            let font_core = &*self.font_core.borrow();
+            let face_name = |key| font_core.family_name(key).unwrap_or("unknown");
+            lines.push(format!(
+                "  fixed face: {}",
+                face_name(cfg.ui.textview.fixed_face)
+            ));
+            lines.push(format!(
+                "  variable face: {}",
+                face_name(cfg.ui.textview.variable_face)
+            ));
+            let fallbacks = cfg
+                .ui
+                .textview
+                .fallback_faces
+                .iter()
+                .map(|key| face_name(*key))
+                .collect::<Vec<_>>();
+            lines.push(format!("  fallback faces: {}", fallbacks.join(", ")));
+            lines.push(format!(
+                "  loaded: {}",
+                font_core.loaded_families().join(", ")
+            ));
+        }
+
+        lines.push("".to_owned());
+        lines.push("Graphics".to_owned());
+        let (version, renderer) = self.render_ctx.gl_info();
+        lines.push(format!("  GL version: {}", version));
+        lines.push(format!("  GL renderer: {}", renderer));
+        lines.push(format!(
+            "  window DPI: {}x{}",
+            self.render_ctx.dpi.width, self.render_ctx.dpi.height
+        ));
+        let monitors = self
+            .glfw
+            .borrow_mut()
+            .with_connected_monitors(|_, monitors| {
+                monitors
+                    .iter()
+                    .map(|m| {
+                        let name = m.get_name().unwrap_or("unknown".to_owned());
+                        match monitor_dpi(m) {
+                            Some(dpi) => format!("  {}: {}x{} DPI", name, dpi.width, dpi.height),
+                            None => format!("  {}: unknown DPI", name),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            });
+        lines.extend(monitors);
+
+        lines.push("".to_owned());
+        lines.push("Components".to_owned());
+        lines.push(match self.window.get_clipboard_string() {
+            Some(_) => "  clipboard: ok".to_owned(),
+            None => "  clipboard: empty or unavailable".to_owned(),
+        });
+        lines.push(match Command::new("git").arg("--version").output() {
+            Ok(output) if output.status.success() => {
+                format!("  git: {}", String::from_utf8_lossy(&output.stdout).trim())
+            }
+            _ => "  git: not found".to_owned(),
+        });
+        lines
+    }
+
+    fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+        if !focused {
+            if self.input_state.mode == InputMode::Command {
+                self.prompt.set_active(false);
+                self.input_state.mode = InputMode::Normal;
+            }
+            if self.config.borrow().ui.autosave_on_focus_loss {
+                self.core.borrow_mut().write_modified_buffers();
+            }
+            return;
+        }
+        // Offer to reload the active buffer if its file was changed externally
+        let active = self.textview_tree.active_mut().buffer();
+        let mut reload = None;
+        let changed = self.core.borrow_mut().check_disk_changes();
+        for buffer in changed {
+            if Rc::ptr_eq(&buffer, &active) && self.input_state.mode == InputMode::Normal {
+                reload = Some(buffer);
+            } else if let Some(path) = buffer.borrow().path() {
+                warning!("file changed on disk: {}", path);
+            }
+        }
+        if let Some(buffer) = reload {
+            self.ask_reload(buffer);
+        }
+    }
+
+    fn enforce_cache_budget(&mut self) {
+        let mut visible = self.textview_tree.visible_buffers();
+        visible.extend(self.tabs.visible_buffers());
+        let core = &mut *self.core.borrow_mut();
+        core.enforce_cache_budget(self.render_ctx.dpi, &visible);
+    }
+
+    fn handle_fuzzy(&mut self) {
+        if self.fuzzy_target == FuzzyTarget::Bookmark {
+            if let Some(selection) = self.fuzzy_popup.get_selection() {
+                let core = &mut *self.core.borrow_mut();
+                let name = selection.splitn(2, ' ').next().unwrap();
+                if let Some(mark) = core.marks().bookmark(name).cloned() {
+                    let textview = self.textview_tree.active_mut();
+                    jump_to_file_mark(core, textview, &mark, false, self.render_ctx.dpi);
+                }
+            }
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::Location {
+            if let Some(selection) = self.fuzzy_popup.get_selection() {
+                let location = selection.rsplitn(2, " [").next().unwrap();
+                let mut parts = location.trim_end_matches(']').rsplitn(2, ':');
+                let line = parts.next().and_then(|s| s.parse::<usize>().ok());
+                if let (Some(line), Some(path)) = (line, parts.next()) {
+                    let mark = FileMark {
+                        path: self
+                            .fuzzy_directory
+                            .join(path)
+                            .to_string_lossy()
+                            .into_owned(),
+                        line: line.saturating_sub(1),
+                        gidx: 0,
+                    };
+                    let core = &mut *self.core.borrow_mut();
+                    let textview = self.textview_tree.active_mut();
+                    jump_to_file_mark(core, textview, &mark, false, self.render_ctx.dpi);
+                }
+            }
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::Buffer {
+            if let Some(selection) = self.fuzzy_popup.get_selection() {
+                let id = selection.splitn(2, ':').next().unwrap();
+                if let Ok(id) = id.parse() {
+                    self.switch_to_buffer(id);
+                }
+            }
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::WriteBeforeBuild {
+            let selection = self.fuzzy_popup.get_selection();
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            if let (Some(selection), Some(command)) = (selection, self.pending_build.take()) {
+                if selection.starts_with("write") {
+                    self.core.borrow_mut().write_modified_buffers();
+                }
+                if selection != "cancel" {
+                    self.start_build(&command);
+                }
+            }
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::Rename {
+            let selection = self.fuzzy_popup.get_selection();
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            match selection {
+                Some(selection) => self.continue_rename(&selection),
+                None => self.pending_rename = None,
+            }
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::Substitute {
+            let selection = self.fuzzy_popup.get_selection();
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            match selection {
+                Some(selection) => self.continue_substitute(&selection),
+                None => {
+                    if let Some(pending) = self.pending_substitution.take() {
+                        self.close_substitute_preview(pending.preview);
+                    }
+                }
+            }
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::WriteAsRoot {
+            let selection = self.fuzzy_popup.get_selection();
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            let buffer = self.pending_root_write.take();
+            if let (Some(selection), Some(buffer)) = (selection, buffer) {
+                if selection.starts_with("write") {
+                    let buffer = &mut *buffer.borrow_mut();
+                    if let Err(e) = buffer.write_to_file_as_root() {
+                        let path = buffer.path().unwrap_or("");
+                        error!("failed to write {} as root: {}", path, e);
+                    }
+                }
+            }
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::Overwrite {
+            let selection = self.fuzzy_popup.get_selection();
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            let buffer = match self.pending_overwrite.take() {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            // The buffer is still active, since the popup was open
+            if !Rc::ptr_eq(&buffer, &self.textview_tree.active_mut().buffer()) {
+                return;
+            }
+            match selection {
+                Some(s) if s.starts_with("overwrite") => {
+                    self.write_active(None, true);
+                }
+                Some(s) if s.starts_with("reload") => {
+                    if let Err(e) = self.textview_tree.active_mut().reload_buffer() {
+                        error!("failed to reload buffer: {}", e);
+                    }
+                }
+                _ => {}
+            }
+            return;
+        }
+        if self.fuzzy_target == FuzzyTarget::OpenMode {
+            let selection = self.fuzzy_popup.get_selection();
+            self.fuzzy_popup.set_active(false);
+            self.input_state.mode = InputMode::Normal;
+            if let Some(selection) = selection {
+                self.open_pending(&selection);
+            }
+            return;
+        }
+        let selection = self.fuzzy_popup.get_selection();
+        self.fuzzy_popup.set_active(false);
+        self.input_state.mode = InputMode::Normal;
+        if let Some(selection) = selection {
+            let mut path = self.fuzzy_directory.clone();
+            path.push(&selection);
+            if !self.check_before_open(path.to_str().unwrap(), self.fuzzy_target) {
+                return;
+            }
+            let core = &mut *self.core.borrow_mut();
+            match core.new_buffer_from_file(path.to_str().unwrap(), self.render_ctx.dpi) {
+                Ok(buffer) => {
+                    split_for_target(&mut self.textview_tree, core, self.fuzzy_target);
+                    let view_id = core.next_view_id();
+                    self.textview_tree.active_mut().add_buffer(buffer, view_id);
+                }
+                Err(e) => {
+                    error!("failed to open file: {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    // Check file before opening it, unless it is already open. Returns true if the file can be
+    // opened. Otherwise the user is asked how to open it
+    fn check_before_open(&mut self, path: &str, target: FuzzyTarget) -> bool {
+        if self.core.borrow().buffer(path).is_some() {
+            return true;
+        }
+        let large_file_size = self.config.borrow().ui.textview.large_file_size;
+        match check_file(path, large_file_size) {
+            FileCheck::Ok => true,
+            check => {
+                self.ask_open_mode(path, check, target);
+                false
+            }
+        }
+    }
+
+    // Open fuzzy popup asking whether to open file in large-file mode, hex mode, or not at all
+    fn ask_open_mode(&mut self, path: &str, check: FileCheck, target: FuzzyTarget) {
+        let name = file_name(path);
+        let large = "large-file mode: read-only, no highlighting".to_owned();
+        let hex = format!("hex mode: first {}, read-only", format_size(HEX_PREVIEW));
+        let (label, mut choices) = match check {
+            FileCheck::Binary => (format!("{} is binary", name), vec![hex, large]),
+            FileCheck::Large(size) => (
+                format!("{} is {}", name, format_size(size as usize)),
+                vec![large, hex],
+            ),
+            FileCheck::Ok => return,
+        };
+        self.pending_open = Some((path.to_owned(), target));
+        self.fuzzy_target = FuzzyTarget::OpenMode;
+        self.fuzzy_popup.set_active(true);
+        self.fuzzy_popup.set_default_on_empty(true);
+        self.fuzzy_popup.set_input_label(&label);
+        choices.push("cancel".to_owned());
+        self.fuzzy_popup.push_string_choices(&choices);
+        self.fuzzy_popup.re_filter();
+        self.input_state.mode = InputMode::Fuzzy;
+    }
+
+    // Open the file that failed the checks before opening, in the mode picked from the fuzzy
+    // popup. Large-file mode reads the whole file into a scratch buffer in the background, and
+    // hex mode shows the start of the file in a scratch buffer
+    fn open_pending(&mut self, choice: &str) {
+        let (path, target) = match self.pending_open.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        if choice.starts_with("large-file") {
+            let core = &mut *self.core.borrow_mut();
+            let buffer = core.new_large_file_buffer(&path, self.render_ctx.dpi);
+            buffer
+                .borrow_mut()
+                .set_title(&format!("{} [large file]", file_name(&path)));
+            split_for_target(&mut self.textview_tree, core, target);
+            let view_id = core.next_view_id();
+            self.textview_tree.active_mut().add_buffer(buffer, view_id);
+            return;
+        } else if !choice.starts_with("hex") {
+            return;
+        }
+        let (text, truncated) = match hex_dump(&path) {
+            Ok(res) => res,
+            Err(e) => {
+                error!("failed to open file: {}: {}", path, e);
+                return;
+            }
+        };
+        let title = if truncated {
+            format!(
+                "{} [hex, first {}]",
+                file_name(&path),
+                format_size(HEX_PREVIEW)
+            )
+        } else {
+            format!("{} [hex]", file_name(&path))
+        };
+        let core = &mut *self.core.borrow_mut();
+        let buffer = core.new_scratch_buffer(&text, self.render_ctx.dpi);
+        buffer.borrow_mut().set_title(&title);
+        split_for_target(&mut self.textview_tree, core, target);
+        let view_id = core.next_view_id();
+        self.textview_tree.active_mut().add_buffer(buffer, view_id);
+    }
+
+    fn resize(&mut self, size: Size2D<u32, PixelSize>) {
+        self.render_ctx.set_size(size);
+        self.layout();
+        self.full_redraw = true;
+    }
+
+    // Give panes and popups the window, except for the prompt bar at the bottom if it is shown
+    fn layout(&mut self) {
+        let vrect = get_viewable_rect(&self.window);
+        self.prompt_bar_height = min(self.prompt.bar_height(), vrect.size.height);
+        self.tabs.set_window_rect(vrect);
+        let tab_bar_height = min(
+            self.tabs.bar_height(),
+            vrect.size.height - self.prompt_bar_height,
+        );
+        let mut rect = vrect;
+        rect.origin.y += tab_bar_height;
+        rect.size.height -= self.prompt_bar_height + tab_bar_height;
+        self.textview_tree.set_rect(rect);
+        self.fuzzy_popup.set_window_rect(rect);
+        self.prompt.set_window_rect(vrect);
+        self.key_hints.set_window_rect(rect);
+        self.image_popup.set_window_rect(rect);
+    }
+
+    // The leader key only starts a sequence if there are mappings for it, so that it can still be
+    // used for anything else otherwise
+    fn is_leader_key(&self, c: char) -> bool {
+        let keymap = &(&*self.config.borrow()).ui.keymap;
+        keymap.leader == c && !keymap.leader_mappings.is_empty()
+    }
+
+    // Command mapped to exactly the keys typed after the leader key
+    fn leader_command(&self) -> Option<String> {
+        let keymap = &(&*self.config.borrow()).ui.keymap;
+        let keys = &self.input_state.leader_keys;
+        keymap
+            .leader_mappings
+            .iter()
+            .find(|(seq, _)| seq == keys)
+            .map(|(_, command)| command.clone())
+    }
+
+    // Mappings continuing the keys typed after the leader key, as the rest of the sequence and the
+    // command
+    fn leader_hints(&self) -> Vec<(String, String)> {
+        let keymap = &(&*self.config.borrow()).ui.keymap;
+        let keys = &self.input_state.leader_keys;
+        keymap
+            .leader_mappings
+            .iter()
+            .filter(|(seq, _)| seq.len() > keys.len() && seq.starts_with(keys.as_str()))
+            .map(|(seq, command)| (seq[keys.len()..].to_owned(), command.clone()))
+            .collect()
+    }
+
+    // Keys typed after the leader key. A mapping runs as soon as it is the only one matching the
+    // keys typed so far. If a longer mapping also matches, it runs when the keymap times out
+    fn handle_leader_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::Char(c) => {
+                self.input_state.leader_keys.push(*c);
+                // Restart the timeout, and show hints for the following key
+                self.pending_key = None;
+                self.key_hints.hide();
+                if !self.leader_hints().is_empty() {
+                    return;
+                }
+                let command = self.leader_command();
+                if command.is_none() {
+                    error!("no leader mapping: {}", self.input_state.leader_keys);
+                }
+                self.finish_leader(command);
+            }
+            WindowEvent::Key(Key::Escape, _, Action::Press, _) => self.finish_leader(None),
+            _ => {}
+        }
+    }
+
+    fn finish_leader(&mut self, command: Option<String>) {
+        self.input_state.leader_keys.clear();
+        self.input_state.mode = InputMode::Normal;
+        if let Some(command) = command {
+            self.run_command(&command);
+        }
+    }
+
+    // Panes following another pane are read-only. Only the prompt, window commands and q (which
+    // closes the pane) work in them. Returns true if the event was consumed
+    fn handle_follower_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::Char(':')
+            | WindowEvent::Key(Key::W, _, Action::Press, Modifiers::Control) => false,
+            WindowEvent::Char('q') => {
+                self.close_pane();
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn handle_event(&mut self, event: WindowEvent) {
+        if self.image_popup.is_active() {
+            self.image_popup.handle_event(&event);
+            return;
+        }
+        if self.input_state.mode == InputMode::Normal
+            && self.textview_tree.active_mut().leader().is_some()
+            && self.handle_follower_event(&event)
+        {
+            return;
+        }
+        if let WindowEvent::Char(c) = event {
+            if self.input_state.mode == InputMode::Normal && self.is_leader_key(c) {
+                self.input_state.action_multiplier.clear();
+                self.input_state.movement_multiplier.clear();
+                self.input_state.leader_keys.clear();
+                self.input_state.mode = InputMode::Leader;
+                return;
+            }
+        }
+        if self.input_state.mode == InputMode::Leader {
+            self.handle_leader_event(&event);
+            return;
+        }
+        let mut state = &mut self.input_state;
+        let textview = self.textview_tree.active_mut();
+        let mut close_scratch = false;
+        let mut cancel_substitute = false;
+        let mut toggle_zoom = false;
+        let mut resize_by = None;
+        let mut focus_edge = None;
+        let mut focus_next = false;
+        let mut switch_tab = None;
+        match state.mode {
+            InputMode::Insert => match event {
+                WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Normal;
+                    let insert = Insert(state.cur_insert_ops.clone());
+                    state.last_edit = match state.pending_change.take() {
+                        Some(EditOp::Change(amul, movop, _)) => EditOp::Change(amul, movop, insert),
+                        Some(EditOp::SubstChar(n, _)) => EditOp::SubstChar(n, insert),
+                        _ => EditOp::Insert(mult, insert),
+                    };
+                    for _ in 0..(mult - 1) {
+                        replay_insert(textview, &state.cur_insert_ops);
+                    }
+                    self.input_state.cur_insert_ops.clear();
+                    textview.set_cursor_style(TextCursorStyle::Block);
+                }
+                WindowEvent::Key(Key::Down, _, Action::Press, _)
+                | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
+                    state.cur_insert_ops.push(InsertOp::Down);
+                    textview.move_cursor_down(1);
                 }
                 WindowEvent::Key(Key::Up, _, Action::Press, _)
                 | WindowEvent::Key(Key::Up, _, Action::Repeat, _) => {
@@ -498,14 +3067,52 @@ impl Window {
                 }
                 WindowEvent::Key(Key::Backspace, _, Action::Press, _)
                 | WindowEvent::Key(Key::Backspace, _, Action::Repeat, _) => {
+                    // Deleting the opening character of an empty pair deletes the pair
+                    let in_pair = {
+                        let cfg = &*self.config.borrow();
+                        let syntax = cfg.syntax(textview.buffer().borrow().syntax_name());
+                        match textview.text_around_cursor() {
+                            (before, Some(next)) => before
+                                .chars()
+                                .last()
+                                .map(|prev| syntax.pairs.contains(&(prev, next)))
+                                .unwrap_or(false),
+                            _ => false,
+                        }
+                    };
+                    // Both brackets of an empty pair are deleted as one edit
                     state.cur_insert_ops.push(InsertOp::Backspace);
-                    textview.delete_left(1);
+                    if in_pair {
+                        state.cur_insert_ops.push(InsertOp::Delete);
+                        textview.delete_around(1, 1);
+                    } else {
+                        textview.delete_left(1);
+                    }
                 }
                 WindowEvent::Key(Key::Delete, _, Action::Press, _)
                 | WindowEvent::Key(Key::Delete, _, Action::Repeat, _) => {
                     state.cur_insert_ops.push(InsertOp::Delete);
                     textview.delete_right(1);
                 }
+                WindowEvent::Key(Key::W, _, Action::Press, Modifiers::Control)
+                | WindowEvent::Key(Key::W, _, Action::Repeat, Modifiers::Control) => {
+                    state.cur_insert_ops.push(InsertOp::DeleteWord);
+                    textview.delete_word_left();
+                }
+                WindowEvent::Key(Key::U, _, Action::Press, Modifiers::Control) => {
+                    state.cur_insert_ops.push(InsertOp::DeleteToLineStart);
+                    textview.delete_to_line_start();
+                }
+                WindowEvent::Key(Key::T, _, Action::Press, Modifiers::Control)
+                | WindowEvent::Key(Key::T, _, Action::Repeat, Modifiers::Control) => {
+                    state.cur_insert_ops.push(InsertOp::Indent);
+                    textview.indent_line();
+                }
+                WindowEvent::Key(Key::D, _, Action::Press, Modifiers::Control)
+                | WindowEvent::Key(Key::D, _, Action::Repeat, Modifiers::Control) => {
+                    state.cur_insert_ops.push(InsertOp::Dedent);
+                    textview.dedent_line();
+                }
                 WindowEvent::Key(Key::V, _, Action::Press, m) => {
                     if m == Modifiers::Control | Modifiers::Shift {
                         if let Some(s) = self.window.get_clipboard_string() {
@@ -515,51 +3122,197 @@ impl Window {
                 }
                 WindowEvent::Key(Key::Enter, _, Action::Press, _)
                 | WindowEvent::Key(Key::Enter, _, Action::Repeat, _) => {
-                    match state.cur_insert_ops.pop() {
-                        Some(InsertOp::Str(mut s)) => {
-                            s.push('\n');
-                            state.cur_insert_ops.push(InsertOp::Str(s));
-                        }
-                        Some(o) => {
-                            state.cur_insert_ops.push(o);
-                            state.cur_insert_ops.push(InsertOp::Str("\n".to_owned()));
+                    // Keep the indentation of the current line, and indent once more after
+                    // lines that open a block
+                    let (before, next) = textview.text_around_cursor();
+                    let indent = before
+                        .chars()
+                        .take_while(|c| *c == ' ' || *c == '\t')
+                        .collect::<String>();
+                    let (indent_more, marker, fence) = {
+                        let cfg = &*self.config.borrow();
+                        let syntax = cfg.syntax(textview.buffer().borrow().syntax_name());
+                        let indent_more = match &syntax.indent_increase {
+                            Some(re) => re.is_match(&before),
+                            None => false,
+                        };
+                        let marker = syntax
+                            .list_marker
+                            .as_ref()
+                            .and_then(|re| re.find(&before))
+                            .map(|m| m.as_str().to_owned());
+                        let fence = match &syntax.code_fence {
+                            Some(re) if next.is_none() => opened_fence(textview, re, &before),
+                            _ => None,
+                        };
+                        (indent_more, marker, fence)
+                    };
+                    if let Some(fence) = fence {
+                        // Close the block, and start typing inside it
+                        let s = format!("\n{}\n{}{}", indent, indent, fence);
+                        push_insert_str(&mut state.cur_insert_ops, &s);
+                        state.cur_insert_ops.push(InsertOp::Up);
+                        state.cur_insert_ops.push(InsertOp::End);
+                        textview.insert_str(&s);
+                        textview.move_cursor_up(1);
+                        textview.move_cursor_end_of_line();
+                    } else if marker.as_ref().map(|m| m.len() == before.len()) == Some(true)
+                        && next.is_none()
+                    {
+                        // Enter on an empty list item ends the list
+                        state.cur_insert_ops.push(InsertOp::DeleteToLineStart);
+                        textview.delete_to_line_start();
+                    } else if let Some(marker) = marker {
+                        let s = format!("\n{}", next_list_marker(&marker));
+                        push_insert_str(&mut state.cur_insert_ops, &s);
+                        textview.insert_str(&s);
+                    } else {
+                        let s = format!("\n{}", indent);
+                        push_insert_str(&mut state.cur_insert_ops, &s);
+                        textview.insert_str(&s);
+                        if indent_more {
+                            state.cur_insert_ops.push(InsertOp::Indent);
+                            textview.indent_line();
                         }
-                        _ => state.cur_insert_ops.push(InsertOp::Str("\n".to_owned())),
                     }
-                    textview.insert_char('\n');
                 }
                 WindowEvent::Key(Key::Tab, _, Action::Press, _)
                 | WindowEvent::Key(Key::Tab, _, Action::Repeat, _) => {
-                    match state.cur_insert_ops.pop() {
-                        Some(InsertOp::Str(mut s)) => {
-                            s.push('\t');
-                            state.cur_insert_ops.push(InsertOp::Str(s));
-                        }
-                        Some(o) => {
-                            state.cur_insert_ops.push(o);
-                            state.cur_insert_ops.push(InsertOp::Str("\t".to_owned()));
-                        }
-                        _ => state.cur_insert_ops.push(InsertOp::Str("\t".to_owned())),
-                    }
+                    state.cur_insert_ops.push(InsertOp::Tab);
                     textview.insert_char('\t');
                 }
                 WindowEvent::Char(c) => {
-                    match state.cur_insert_ops.pop() {
-                        Some(InsertOp::Str(mut s)) => {
-                            s.push(c);
-                            state.cur_insert_ops.push(InsertOp::Str(s));
-                        }
-                        Some(o) => {
-                            state.cur_insert_ops.push(o);
-                            state.cur_insert_ops.push(InsertOp::Str(c.to_string()));
+                    let (before, next) = textview.text_around_cursor();
+                    let prev = before.chars().last();
+                    let (type_over, pair, dedent) = {
+                        let cfg = &*self.config.borrow();
+                        let syntax = cfg.syntax(textview.buffer().borrow().syntax_name());
+                        let is_close = |c: char| syntax.pairs.iter().any(|(_, close)| *close == c);
+                        let type_over = next == Some(c) && is_close(c);
+                        let pair = syntax.pairs.iter().find(|(open, _)| *open == c).cloned();
+                        let pair = pair.filter(|(open, close)| {
+                            // Only pair before whitespace or closing characters. Quotes aren't
+                            // paired after words, which could be apostrophes
+                            let next_ok = next.map(|n| n.is_whitespace() || is_close(n));
+                            let prev_ok = open != close
+                                || !prev.map(|p| p.is_alphanumeric() || p == c).unwrap_or(false);
+                            next_ok.unwrap_or(true) && prev_ok
+                        });
+                        // Dedent lines that close a block, as the closing text is typed
+                        let dedent = match &syntax.indent_decrease {
+                            Some(re) if !type_over && pair.is_none() => {
+                                before.trim().is_empty()
+                                    && !re.is_match(&before)
+                                    && re.is_match(&format!("{}{}", before, c))
+                            }
+                            _ => false,
+                        };
+                        (type_over, pair, dedent)
+                    };
+                    if type_over {
+                        // Type over the closing character
+                        state.cur_insert_ops.push(InsertOp::Right);
+                        textview.move_cursor_right(1);
+                    } else if let Some((open, close)) = pair {
+                        let s = format!("{}{}", open, close);
+                        push_insert_str(&mut state.cur_insert_ops, &s);
+                        state.cur_insert_ops.push(InsertOp::Left);
+                        textview.insert_str(&s);
+                        textview.move_cursor_left(1);
+                    } else {
+                        push_insert_str(&mut state.cur_insert_ops, &c.to_string());
+                        textview.insert_char(c);
+                        if dedent {
+                            state.cur_insert_ops.push(InsertOp::Dedent);
+                            textview.dedent_line();
                         }
-                        _ => state.cur_insert_ops.push(InsertOp::Str(c.to_string())),
                     }
-                    textview.insert_char(c);
                 }
                 _ => {}
             },
             InputMode::Normal => match event {
+                WindowEvent::Char('q') if textview.is_scratch() => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    close_scratch = true;
+                }
+                // The count is kept for window commands that take one
+                WindowEvent::Key(Key::W, _, Action::Press, Modifiers::Control) => {
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::WindowCommand;
+                }
+                // Scroll without moving the cursor
+                WindowEvent::Key(Key::E, _, Action::Press, Modifiers::Control)
+                | WindowEvent::Key(Key::E, _, Action::Repeat, Modifiers::Control) => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    textview.scroll_lines(mult, true);
+                }
+                WindowEvent::Key(Key::Y, _, Action::Press, Modifiers::Control)
+                | WindowEvent::Key(Key::Y, _, Action::Repeat, Modifiers::Control) => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    textview.scroll_lines(mult, false);
+                }
+                // Ctrl-^, which is Ctrl-Shift-6 on most layouts, or Ctrl-6
+                WindowEvent::Key(Key::Num6, _, Action::Press, m)
+                    if m == Modifiers::Control || m == Modifiers::Control | Modifiers::Shift =>
+                {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    textview.alternate_buffer();
+                }
+                WindowEvent::Char('m') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::SetMark;
+                }
+                WindowEvent::Char(c @ 'v') | WindowEvent::Char(c @ 'V') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Visual(c == 'V');
+                    textview.start_selection(c == 'V');
+                }
+                WindowEvent::Char('\'') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::JumpMark(false);
+                }
+                WindowEvent::Char('`') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::JumpMark(true);
+                }
+                WindowEvent::Char('/') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Search;
+                    self.search_restore = Some((
+                        textview.cursor_line_num(),
+                        textview.cursor_gidx(),
+                        self.search_pattern.clone(),
+                    ));
+                    self.prompt.set_active(true);
+                    self.prompt.set_string("/");
+                }
+                WindowEvent::Char('n') => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    if let Some(pattern) = &self.search_pattern {
+                        for _ in 0..mult {
+                            textview.search(pattern, true, false);
+                        }
+                    }
+                }
+                WindowEvent::Char('N') => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    if let Some(pattern) = &self.search_pattern {
+                        for _ in 0..mult {
+                            textview.search(pattern, false, false);
+                        }
+                    }
+                }
                 WindowEvent::Char(':') => {
                     state.action_multiplier.clear();
                     state.movement_multiplier.clear();
@@ -641,33 +3394,118 @@ impl Window {
                     state.movement_multiplier.clear();
                     textview.move_cursor_right(mult);
                 }
-                WindowEvent::Char('0') if state.action_multiplier.len() == 0 => {
-                    state.action_multiplier.clear();
+                WindowEvent::Char('0') if state.action_multiplier.len() == 0 => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    textview.move_cursor_start_of_line();
+                }
+                WindowEvent::Char('$') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    textview.move_cursor_end_of_line();
+                }
+                // The count is kept for gg and gt
+                WindowEvent::Char('g') => {
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::GoPrefix;
+                }
+                WindowEvent::Char('G') => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    textview.go_to_last_line();
+                }
+                WindowEvent::Char('H') => {
+                    let n = state.get_action_multiplier();
                     state.movement_multiplier.clear();
-                    textview.move_cursor_start_of_line();
+                    go_to_view_line(textview, &MovementOp::ViewTop(n));
                 }
-                WindowEvent::Char('$') => {
+                WindowEvent::Char('M') => {
                     state.action_multiplier.clear();
                     state.movement_multiplier.clear();
-                    textview.move_cursor_end_of_line();
+                    go_to_view_line(textview, &MovementOp::ViewMiddle);
                 }
-                WindowEvent::Char('g') => {
-                    let mut linum = state.get_action_multiplier();
-                    if linum > 0 {
-                        linum -= 1;
-                    }
+                WindowEvent::Char('L') => {
+                    let n = state.get_action_multiplier();
                     state.movement_multiplier.clear();
-                    textview.go_to_line(linum);
+                    go_to_view_line(textview, &MovementOp::ViewBottom(n));
                 }
-                WindowEvent::Char('G') => {
+                WindowEvent::Char('%') => {
                     state.action_multiplier.clear();
                     state.movement_multiplier.clear();
-                    textview.go_to_last_line();
+                    textview.go_to_matching_bracket();
                 }
                 WindowEvent::Char('d') => {
                     state.mode = InputMode::DeleteMotion;
                     textview.set_cursor_style(TextCursorStyle::Underline);
                 }
+                WindowEvent::Char('>') => {
+                    state.mode = InputMode::ShiftMotion(true);
+                    textview.set_cursor_style(TextCursorStyle::Underline);
+                }
+                WindowEvent::Char('<') => {
+                    state.mode = InputMode::ShiftMotion(false);
+                    textview.set_cursor_style(TextCursorStyle::Underline);
+                }
+                WindowEvent::Char('c') => {
+                    state.mode = InputMode::ChangeMotion;
+                    textview.set_cursor_style(TextCursorStyle::Underline);
+                }
+                WindowEvent::Char(c @ 'C') | WindowEvent::Char(c @ 'S') => {
+                    let movop = if c == 'C' {
+                        MovementOp::LineEnd
+                    } else {
+                        MovementOp::Default(1)
+                    };
+                    let act_mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Insert;
+                    state.cur_insert_ops.clear();
+                    textview.set_cursor_style(TextCursorStyle::Beam);
+                    change_motion(textview, act_mult, &movop);
+                    state.pending_change =
+                        Some(EditOp::Change(act_mult, movop, Insert(Vec::new())));
+                }
+                WindowEvent::Char('s') => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Insert;
+                    state.cur_insert_ops.clear();
+                    textview.set_cursor_style(TextCursorStyle::Beam);
+                    textview.delete_right(mult);
+                    state.pending_change = Some(EditOp::SubstChar(mult, Insert(Vec::new())));
+                }
+                WindowEvent::Char('y') => {
+                    state.mode = InputMode::YankMotion;
+                    textview.set_cursor_style(TextCursorStyle::Underline);
+                }
+                WindowEvent::Char('Y') => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    let reg = Register {
+                        text: textview.lines_text(mult),
+                        linewise: true,
+                    };
+                    textview.flash_lines(mult);
+                    let cfg = &*self.config.borrow();
+                    let core = &mut *self.core.borrow_mut();
+                    set_register(&mut self.window, core, cfg, state.register.take(), reg);
+                }
+                WindowEvent::Char('"') => {
+                    state.mode = InputMode::SelectRegister;
+                }
+                WindowEvent::Char(c @ 'p') | WindowEvent::Char(c @ 'P') => {
+                    let mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    let after = c == 'p';
+                    let reg = {
+                        let cfg = &*self.config.borrow();
+                        let core = &*self.core.borrow();
+                        get_register(&self.window, core, cfg, state.register.take())
+                    };
+                    if let Some(reg) = reg {
+                        textview.paste(&reg.text.repeat(mult), reg.linewise, after);
+                    }
+                }
                 WindowEvent::Char('i') => {
                     state.mode = InputMode::Insert;
                     state.cur_insert_ops.clear();
@@ -727,47 +3565,29 @@ impl Window {
                         EditOp::DelChar(n) => {
                             textview.delete_right(amul * *n);
                         }
-                        EditOp::Delete(amul, movop) => match movop {
-                            MovementOp::Default(mmul) => textview.delete_lines(amul * mmul),
-                            MovementOp::Left(mmul) => textview.delete_left(amul * mmul),
-                            MovementOp::Right(mmul) => textview.delete_right(amul * mmul),
-                            MovementOp::Up(mmul) => textview.delete_lines_up(amul * mmul),
-                            MovementOp::Down(mmul) => textview.delete_lines_down(amul * mmul),
-                            MovementOp::Linum(mmul) => {
-                                for _ in 0..*amul {
-                                    textview.delete_to_line(*mmul);
-                                }
-                            }
-                            MovementOp::LastLine => {
-                                for _ in 0..*amul {
-                                    textview.delete_to_last_line();
-                                }
-                            }
-                            MovementOp::LineStart => textview.delete_to_line_start(),
-                            MovementOp::LineEnd => textview.delete_to_line_end(),
-                            _ => {}
-                        },
+                        EditOp::Delete(amul, movop) => delete_motion(textview, *amul, movop),
                         EditOp::Insert(n, i) => {
                             textview.set_cursor_style(TextCursorStyle::Beam);
                             for _ in 0..(amul * *n) {
-                                for op in &i.0 {
-                                    match op {
-                                        InsertOp::Str(s) => textview.insert_str(s),
-                                        InsertOp::Backspace => textview.delete_left(1),
-                                        InsertOp::Delete => textview.delete_right(1),
-                                        InsertOp::Left => textview.move_cursor_left(1),
-                                        InsertOp::Right => textview.move_cursor_right(1),
-                                        InsertOp::Up => textview.move_cursor_up(1),
-                                        InsertOp::Down => textview.move_cursor_down(1),
-                                        InsertOp::Home => textview.move_cursor_start_of_line(),
-                                        InsertOp::End => textview.move_cursor_end_of_line(),
-                                        InsertOp::PageUp => textview.page_up(),
-                                        InsertOp::PageDown => textview.page_down(),
-                                    }
-                                }
+                                replay_insert(textview, &i.0);
                             }
                             textview.set_cursor_style(TextCursorStyle::Block);
                         }
+                        EditOp::Change(amul, movop, i) => {
+                            textview.set_cursor_style(TextCursorStyle::Beam);
+                            change_motion(textview, *amul, movop);
+                            replay_insert(textview, &i.0);
+                            textview.set_cursor_style(TextCursorStyle::Block);
+                        }
+                        EditOp::SubstChar(n, i) => {
+                            textview.set_cursor_style(TextCursorStyle::Beam);
+                            textview.delete_right(amul * *n);
+                            replay_insert(textview, &i.0);
+                            textview.set_cursor_style(TextCursorStyle::Block);
+                        }
+                        EditOp::Shift(right, amul, movop) => {
+                            shift_motion(textview, *right, *amul, movop);
+                        }
                         _ => {}
                     }
                 }
@@ -792,17 +3612,23 @@ impl Window {
                 | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
                     self.prompt.down_key();
                 }
-                WindowEvent::Key(Key::Left, _, Action::Press, _)
-                | WindowEvent::Key(Key::Left, _, Action::Repeat, _) => {
-                    self.prompt.left_key();
+                WindowEvent::Key(Key::Left, _, Action::Press, m)
+                | WindowEvent::Key(Key::Left, _, Action::Repeat, m) => {
+                    self.prompt.left_key(m.contains(Modifiers::Shift));
                 }
-                WindowEvent::Key(Key::Right, _, Action::Press, _)
-                | WindowEvent::Key(Key::Right, _, Action::Repeat, _) => {
-                    self.prompt.right_key();
+                WindowEvent::Key(Key::Right, _, Action::Press, m)
+                | WindowEvent::Key(Key::Right, _, Action::Repeat, m) => {
+                    self.prompt.right_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::Home, _, Action::Press, m) => {
+                    self.prompt.home_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::End, _, Action::Press, m) => {
+                    self.prompt.end_key(m.contains(Modifiers::Shift));
                 }
                 WindowEvent::Key(Key::Tab, _, Action::Press, _)
                 | WindowEvent::Key(Key::Tab, _, Action::Repeat, _) => {
-                    // TODO: Completions
+                    self.complete_command();
                 }
                 WindowEvent::Key(Key::Backspace, _, Action::Press, _)
                 | WindowEvent::Key(Key::Backspace, _, Action::Repeat, _) => {
@@ -812,9 +3638,14 @@ impl Window {
                         state.mode = InputMode::Normal;
                     }
                 }
+                WindowEvent::Key(Key::Delete, _, Action::Press, _)
+                | WindowEvent::Key(Key::Delete, _, Action::Repeat, _) => {
+                    self.prompt.delete_right();
+                }
                 WindowEvent::Key(Key::Enter, _, Action::Press, _) => {
                     self.handle_command();
                     self.prompt.push_to_history();
+                    self.enforce_cache_budget();
                 }
                 _ => {}
             },
@@ -822,6 +3653,7 @@ impl Window {
                 WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                     state.mode = InputMode::Normal;
                     self.fuzzy_popup.set_active(false);
+                    cancel_substitute = self.fuzzy_target == FuzzyTarget::Substitute;
                 }
                 WindowEvent::Char(c) => {
                     self.fuzzy_popup.insert(c);
@@ -837,112 +3669,1587 @@ impl Window {
                     self.fuzzy_popup.delete_left();
                     self.fuzzy_popup.re_filter();
                 }
-                WindowEvent::Key(Key::Up, _, Action::Press, _)
-                | WindowEvent::Key(Key::Up, _, Action::Repeat, _) => {
-                    self.fuzzy_popup.up_key();
+                WindowEvent::Key(Key::Delete, _, Action::Press, _)
+                | WindowEvent::Key(Key::Delete, _, Action::Repeat, _) => {
+                    self.fuzzy_popup.delete_right();
+                    self.fuzzy_popup.re_filter();
+                }
+                WindowEvent::Key(Key::Left, _, Action::Press, m)
+                | WindowEvent::Key(Key::Left, _, Action::Repeat, m) => {
+                    self.fuzzy_popup.left_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::Right, _, Action::Press, m)
+                | WindowEvent::Key(Key::Right, _, Action::Repeat, m) => {
+                    self.fuzzy_popup.right_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::Up, _, Action::Press, _)
+                | WindowEvent::Key(Key::Up, _, Action::Repeat, _) => {
+                    self.fuzzy_popup.up_key();
+                }
+                WindowEvent::Key(Key::Down, _, Action::Press, _)
+                | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
+                    self.fuzzy_popup.down_key();
+                }
+                WindowEvent::Key(Key::PageUp, _, Action::Press, _)
+                | WindowEvent::Key(Key::PageUp, _, Action::Repeat, _) => {
+                    self.fuzzy_popup.page_up_key();
+                }
+                WindowEvent::Key(Key::PageDown, _, Action::Press, _)
+                | WindowEvent::Key(Key::PageDown, _, Action::Repeat, _) => {
+                    self.fuzzy_popup.page_down_key();
+                }
+                WindowEvent::Key(Key::Home, _, Action::Press, _) => {
+                    self.fuzzy_popup.home_key();
+                }
+                WindowEvent::Key(Key::End, _, Action::Press, _) => {
+                    self.fuzzy_popup.end_key();
+                }
+                WindowEvent::Key(Key::Enter, _, Action::Press, _) => {
+                    self.handle_fuzzy();
+                    self.enforce_cache_budget();
+                }
+                _ => {}
+            },
+            InputMode::Search => match event {
+                WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.mode = InputMode::Normal;
+                    self.prompt.set_active(false);
+                    if let Some((linum, gidx, pattern)) = self.search_restore.take() {
+                        textview.go_to_line_gidx(linum, gidx);
+                        self.search_pattern = pattern;
+                    }
+                }
+                WindowEvent::Key(Key::Enter, _, Action::Press, _) => {
+                    state.mode = InputMode::Normal;
+                    self.prompt.set_active(false);
+                    if !search_pattern(&self.prompt).is_empty() {
+                        self.prompt.push_to_history();
+                        let core = &mut *self.core.borrow_mut();
+                        core.add_search(search_pattern(&self.prompt));
+                    } else if let Some((linum, gidx, pattern)) = self.search_restore.take() {
+                        // Empty pattern repeats the last search, which may be from another
+                        // window or an earlier session
+                        let core = &*self.core.borrow();
+                        let pattern = pattern
+                            .or_else(|| core.registers().last_search().map(|s| s.to_owned()));
+                        textview.go_to_line_gidx(linum, gidx);
+                        if let Some(pattern) = &pattern {
+                            textview.search(pattern, true, false);
+                        }
+                        self.search_pattern = pattern;
+                    }
+                    self.search_restore = None;
+                }
+                WindowEvent::Char(c) => {
+                    self.prompt.insert(c);
+                    incremental_search(textview, &self.prompt, &self.search_restore);
+                    self.search_pattern = Some(search_pattern(&self.prompt).to_owned());
+                }
+                WindowEvent::Key(Key::Backspace, _, Action::Press, _)
+                | WindowEvent::Key(Key::Backspace, _, Action::Repeat, _) => {
+                    self.prompt.delete_left();
+                    if self.prompt.get_string().len() == 0 {
+                        state.mode = InputMode::Normal;
+                        self.prompt.set_active(false);
+                        if let Some((linum, gidx, pattern)) = self.search_restore.take() {
+                            textview.go_to_line_gidx(linum, gidx);
+                            self.search_pattern = pattern;
+                        }
+                    } else {
+                        incremental_search(textview, &self.prompt, &self.search_restore);
+                        self.search_pattern = Some(search_pattern(&self.prompt).to_owned());
+                    }
+                }
+                WindowEvent::Key(Key::Delete, _, Action::Press, _)
+                | WindowEvent::Key(Key::Delete, _, Action::Repeat, _) => {
+                    self.prompt.delete_right();
+                    incremental_search(textview, &self.prompt, &self.search_restore);
+                    self.search_pattern = Some(search_pattern(&self.prompt).to_owned());
+                }
+                WindowEvent::Key(Key::Up, _, Action::Press, _)
+                | WindowEvent::Key(Key::Up, _, Action::Repeat, _) => {
+                    self.prompt.up_key();
+                    incremental_search(textview, &self.prompt, &self.search_restore);
+                    self.search_pattern = Some(search_pattern(&self.prompt).to_owned());
+                }
+                WindowEvent::Key(Key::Down, _, Action::Press, _)
+                | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
+                    self.prompt.down_key();
+                    incremental_search(textview, &self.prompt, &self.search_restore);
+                    self.search_pattern = Some(search_pattern(&self.prompt).to_owned());
+                }
+                WindowEvent::Key(Key::Left, _, Action::Press, m)
+                | WindowEvent::Key(Key::Left, _, Action::Repeat, m) => {
+                    self.prompt.left_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::Right, _, Action::Press, m)
+                | WindowEvent::Key(Key::Right, _, Action::Repeat, m) => {
+                    self.prompt.right_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::Home, _, Action::Press, m) => {
+                    self.prompt.home_key(m.contains(Modifiers::Shift));
+                }
+                WindowEvent::Key(Key::End, _, Action::Press, m) => {
+                    self.prompt.end_key(m.contains(Modifiers::Shift));
+                }
+                _ => {}
+            },
+            InputMode::GoPrefix => match event {
+                WindowEvent::Char('g') => {
+                    let linum = state.get_action_multiplier();
+                    state.mode = InputMode::Normal;
+                    textview.go_to_line(linum.saturating_sub(1));
+                }
+                // With a count, go to the tab with that number
+                WindowEvent::Char('t') => {
+                    state.mode = InputMode::Normal;
+                    switch_tab = Some(if state.action_multiplier.is_empty() {
+                        (self.tabs.active() + 1) % self.tabs.len()
+                    } else {
+                        state.get_action_multiplier().saturating_sub(1)
+                    });
+                }
+                WindowEvent::Char('T') => {
+                    let n = state.get_action_multiplier() % self.tabs.len();
+                    state.mode = InputMode::Normal;
+                    switch_tab = Some((self.tabs.active() + self.tabs.len() - n) % self.tabs.len());
+                }
+                WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.action_multiplier.clear();
+                    state.mode = InputMode::Normal;
+                }
+                _ => {}
+            },
+            InputMode::SetMark => match event {
+                WindowEvent::Char(c) if c.is_ascii_lowercase() => {
+                    state.mode = InputMode::Normal;
+                    let id = (&mut *self.core.borrow_mut()).next_view_id();
+                    let (linum, gidx) = (textview.cursor_line_num(), textview.cursor_gidx());
+                    textview.buffer().borrow_mut().set_mark(c, id, linum, gidx);
+                }
+                WindowEvent::Char(c) if c.is_ascii_uppercase() => {
+                    state.mode = InputMode::Normal;
+                    let path = textview.buffer().borrow().path().map(|p| p.to_owned());
+                    match path {
+                        Some(path) => {
+                            let mark = FileMark {
+                                path: path,
+                                line: textview.cursor_line_num(),
+                                gidx: textview.cursor_gidx(),
+                            };
+                            (&mut *self.core.borrow_mut())
+                                .marks_mut()
+                                .set_global(c, mark);
+                        }
+                        None => error!("buffer has no path"),
+                    }
+                }
+                WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.mode = InputMode::Normal;
+                }
+                _ => {}
+            },
+            InputMode::JumpMark(exact) => match event {
+                WindowEvent::Char(c) if c.is_ascii_lowercase() => {
+                    state.mode = InputMode::Normal;
+                    let mark = textview.buffer().borrow().mark(c);
+                    match mark {
+                        Some((linum, gidx)) if exact => textview.go_to_line_gidx(linum, gidx),
+                        Some((linum, _)) => textview.go_to_line_gidx(linum, 0),
+                        None => error!("mark not set: {}", c),
+                    }
+                }
+                WindowEvent::Char(c) if c.is_ascii_uppercase() => {
+                    state.mode = InputMode::Normal;
+                    let core = &mut *self.core.borrow_mut();
+                    match core.marks().global(c).cloned() {
+                        Some(mark) => {
+                            jump_to_file_mark(core, textview, &mark, exact, self.render_ctx.dpi)
+                        }
+                        None => error!("mark not set: {}", c),
+                    }
+                }
+                WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.mode = InputMode::Normal;
+                }
+                _ => {}
+            },
+            InputMode::SelectRegister => match event {
+                WindowEvent::Char('"') => {
+                    state.mode = InputMode::Normal;
+                    state.register = None;
+                }
+                WindowEvent::Char(c) if c.is_ascii_alphanumeric() || c == '+' || c == '*' => {
+                    state.mode = InputMode::Normal;
+                    state.register = Some(c);
+                }
+                WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.action_multiplier.clear();
+                    state.mode = InputMode::Normal;
+                    state.register = None;
+                }
+                _ => {}
+            },
+            InputMode::ChangeMotion => {
+                let motion = match event {
+                    WindowEvent::Char(c) => operator_motion(c, false),
+                    _ => None,
+                };
+                let movop = match event {
+                    WindowEvent::Char(c) if state.is_count_digit(c) => {
+                        state.movement_multiplier.push(c);
+                        None
+                    }
+                    WindowEvent::Char('c') => {
+                        Some(MovementOp::Default(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char(_) if motion.is_some() => {
+                        motion.map(|m| (m.op)(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                        state.action_multiplier.clear();
+                        state.movement_multiplier.clear();
+                        state.mode = InputMode::Normal;
+                        textview.set_cursor_style(TextCursorStyle::Block);
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(movop) = movop {
+                    let act_mult = state.get_action_multiplier();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Insert;
+                    state.cur_insert_ops.clear();
+                    textview.set_cursor_style(TextCursorStyle::Beam);
+                    change_motion(textview, act_mult, &movop);
+                    state.pending_change =
+                        Some(EditOp::Change(act_mult, movop, Insert(Vec::new())));
+                }
+            }
+            InputMode::YankMotion => match event {
+                WindowEvent::Char('y') => {
+                    let act_mult = state.get_action_multiplier();
+                    let move_mult = state.get_movement_multiplier();
+                    state.mode = InputMode::Normal;
+                    textview.set_cursor_style(TextCursorStyle::Block);
+                    let reg = Register {
+                        text: textview.lines_text(act_mult * move_mult),
+                        linewise: true,
+                    };
+                    textview.flash_lines(act_mult * move_mult);
+                    let cfg = &*self.config.borrow();
+                    let core = &mut *self.core.borrow_mut();
+                    set_register(&mut self.window, core, cfg, state.register.take(), reg);
+                }
+                WindowEvent::Char(c) if c.is_digit(10) => {
+                    state.movement_multiplier.push(c);
+                }
+                WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.action_multiplier.clear();
+                    state.movement_multiplier.clear();
+                    state.mode = InputMode::Normal;
+                    state.register = None;
+                    textview.set_cursor_style(TextCursorStyle::Block);
+                }
+                _ => {}
+            },
+            InputMode::ShiftMotion(right) => {
+                let motion = match event {
+                    WindowEvent::Char(c) => operator_motion(c, true),
+                    _ => None,
+                };
+                let movop = match event {
+                    WindowEvent::Char(c) if state.is_count_digit(c) => {
+                        state.movement_multiplier.push(c);
+                        None
+                    }
+                    WindowEvent::Char('>') if right => {
+                        Some(MovementOp::Default(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char('<') if !right => {
+                        Some(MovementOp::Default(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char(_) if motion.is_some() => {
+                        motion.map(|m| (m.op)(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                        state.action_multiplier.clear();
+                        state.movement_multiplier.clear();
+                        state.mode = InputMode::Normal;
+                        textview.set_cursor_style(TextCursorStyle::Block);
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(movop) = movop {
+                    let act_mult = state.get_action_multiplier();
+                    state.mode = InputMode::Normal;
+                    textview.set_cursor_style(TextCursorStyle::Block);
+                    shift_motion(textview, right, act_mult, &movop);
+                    state.last_edit = EditOp::Shift(right, act_mult, movop);
+                }
+            }
+            InputMode::Visual(linewise) => match event {
+                WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                    state.action_multiplier.clear();
+                    state.mode = InputMode::Normal;
+                    textview.clear_selection();
+                }
+                WindowEvent::Char(c @ 'v') | WindowEvent::Char(c @ 'V') => {
+                    state.action_multiplier.clear();
+                    if linewise == (c == 'V') {
+                        state.mode = InputMode::Normal;
+                        textview.clear_selection();
+                    } else {
+                        state.mode = InputMode::Visual(c == 'V');
+                        textview.set_selection_linewise(c == 'V');
+                    }
+                }
+                WindowEvent::Char(c @ 'y')
+                | WindowEvent::Char(c @ 'd')
+                | WindowEvent::Char(c @ 'x') => {
+                    state.action_multiplier.clear();
+                    state.mode = InputMode::Normal;
+                    let deleting = c != 'y';
+                    if deleting && textview.is_scratch() {
+                        textview.clear_selection();
+                        return;
+                    }
+                    if let Some((text, linewise)) = textview.selection_text() {
+                        let reg = Register {
+                            text: text,
+                            linewise: linewise,
+                        };
+                        let cfg = &*self.config.borrow();
+                        let core = &mut *self.core.borrow_mut();
+                        set_register(&mut self.window, core, cfg, state.register.take(), reg);
+                    }
+                    if deleting {
+                        textview.delete_selection();
+                    } else {
+                        textview.clear_selection();
+                    }
+                }
+                WindowEvent::Char('h')
+                | WindowEvent::Key(Key::Left, _, Action::Press, _)
+                | WindowEvent::Key(Key::Left, _, Action::Repeat, _) => {
+                    textview.move_cursor_left(state.get_action_multiplier());
+                }
+                WindowEvent::Char('j')
+                | WindowEvent::Key(Key::Down, _, Action::Press, _)
+                | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
+                    textview.move_cursor_down(state.get_action_multiplier());
+                }
+                WindowEvent::Char('k')
+                | WindowEvent::Key(Key::Up, _, Action::Press, _)
+                | WindowEvent::Key(Key::Up, _, Action::Repeat, _) => {
+                    textview.move_cursor_up(state.get_action_multiplier());
+                }
+                WindowEvent::Char('l')
+                | WindowEvent::Key(Key::Right, _, Action::Press, _)
+                | WindowEvent::Key(Key::Right, _, Action::Repeat, _) => {
+                    textview.move_cursor_right(state.get_action_multiplier());
+                }
+                WindowEvent::Char('0') if state.action_multiplier.len() == 0 => {
+                    textview.move_cursor_start_of_line();
+                }
+                WindowEvent::Char('$') => {
+                    state.action_multiplier.clear();
+                    textview.move_cursor_end_of_line();
+                }
+                WindowEvent::Char('g') => {
+                    let linum = state.get_action_multiplier();
+                    textview.go_to_line(linum.saturating_sub(1));
+                }
+                WindowEvent::Char('G') => {
+                    state.action_multiplier.clear();
+                    textview.go_to_last_line();
+                }
+                WindowEvent::Char('H') => {
+                    let n = state.get_action_multiplier();
+                    go_to_view_line(textview, &MovementOp::ViewTop(n));
+                }
+                WindowEvent::Char('M') => {
+                    state.action_multiplier.clear();
+                    go_to_view_line(textview, &MovementOp::ViewMiddle);
                 }
-                WindowEvent::Key(Key::Down, _, Action::Press, _)
-                | WindowEvent::Key(Key::Down, _, Action::Repeat, _) => {
-                    self.fuzzy_popup.down_key();
+                WindowEvent::Char('L') => {
+                    let n = state.get_action_multiplier();
+                    go_to_view_line(textview, &MovementOp::ViewBottom(n));
                 }
-                WindowEvent::Key(Key::Enter, _, Action::Press, _) => {
-                    self.handle_fuzzy();
+                WindowEvent::Char(c) if c.is_digit(10) => {
+                    state.action_multiplier.push(c);
                 }
                 _ => {}
             },
-            InputMode::DeleteMotion => match event {
-                WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+            // Handled before getting here
+            InputMode::Leader => {}
+            InputMode::WindowCommand => match event {
+                WindowEvent::Char('z') => {
                     state.action_multiplier.clear();
-                    state.movement_multiplier.clear();
                     state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
+                    toggle_zoom = true;
                 }
-                WindowEvent::Char('h') => {
-                    let act_mult = state.get_action_multiplier();
-                    let move_mult = state.get_movement_multiplier();
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::Left(move_mult));
+                WindowEvent::Char(c @ 'h')
+                | WindowEvent::Char(c @ 'j')
+                | WindowEvent::Char(c @ 'k')
+                | WindowEvent::Char(c @ 'l') => {
+                    state.action_multiplier.clear();
                     state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_left(act_mult * move_mult);
+                    focus_edge = Some(match c {
+                        'h' => Edge::Left,
+                        'j' => Edge::Bottom,
+                        'k' => Edge::Top,
+                        _ => Edge::Right,
+                    });
                 }
-                WindowEvent::Char('l') => {
-                    let act_mult = state.get_action_multiplier();
-                    let move_mult = state.get_movement_multiplier();
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::Right(move_mult));
+                WindowEvent::Char('w') => {
+                    state.action_multiplier.clear();
                     state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_right(act_mult * move_mult);
+                    focus_next = true;
                 }
-                WindowEvent::Char('j') => {
-                    let act_mult = state.get_action_multiplier();
-                    let move_mult = state.get_movement_multiplier();
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::Down(move_mult));
+                WindowEvent::Char('>') => {
+                    let mult = state.get_action_multiplier();
                     state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_lines_down(act_mult * move_mult);
+                    resize_by = Some((mult * RESIZE_STEP) as i32);
                 }
-                WindowEvent::Char('k') => {
-                    let act_mult = state.get_action_multiplier();
-                    let move_mult = state.get_movement_multiplier();
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::Up(move_mult));
+                WindowEvent::Char('<') => {
+                    let mult = state.get_action_multiplier();
                     state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_lines_up(act_mult * move_mult);
+                    resize_by = Some(-((mult * RESIZE_STEP) as i32));
                 }
-                WindowEvent::Char('0') if state.movement_multiplier.len() == 0 => {
+                WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                     state.action_multiplier.clear();
-                    state.movement_multiplier.clear();
-                    state.last_edit = EditOp::Delete(1, MovementOp::LineStart);
                     state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_to_line_start();
                 }
-                WindowEvent::Char('$') => {
-                    state.action_multiplier.clear();
-                    state.movement_multiplier.clear();
-                    state.last_edit = EditOp::Delete(1, MovementOp::LineEnd);
+                _ => {}
+            },
+            InputMode::DeleteMotion => {
+                let motion = match event {
+                    WindowEvent::Char(c) => operator_motion(c, false),
+                    _ => None,
+                };
+                let movop = match event {
+                    WindowEvent::Char(c) if state.is_count_digit(c) => {
+                        state.movement_multiplier.push(c);
+                        None
+                    }
+                    WindowEvent::Char('d') => {
+                        Some(MovementOp::Default(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char(_) if motion.is_some() => {
+                        motion.map(|m| (m.op)(state.get_movement_multiplier()))
+                    }
+                    WindowEvent::Char(_) | WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
+                        state.action_multiplier.clear();
+                        state.movement_multiplier.clear();
+                        state.mode = InputMode::Normal;
+                        textview.set_cursor_style(TextCursorStyle::Block);
+                        None
+                    }
+                    _ => None,
+                };
+                if let Some(movop) = movop {
+                    let act_mult = state.get_action_multiplier();
                     state.mode = InputMode::Normal;
                     textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_to_line_end();
+                    // Deleted lines go to the register
+                    if let MovementOp::Default(move_mult) = movop {
+                        if !textview.is_scratch() {
+                            let reg = Register {
+                                text: textview.lines_text(act_mult * move_mult),
+                                linewise: true,
+                            };
+                            let cfg = &*self.config.borrow();
+                            let core = &mut *self.core.borrow_mut();
+                            set_register(&mut self.window, core, cfg, state.register.take(), reg);
+                        }
+                    }
+                    delete_motion(textview, act_mult, &movop);
+                    state.last_edit = EditOp::Delete(act_mult, movop);
                 }
-                WindowEvent::Char('g') => {
-                    let act_mult = state.get_action_multiplier();
-                    let mut linum = state.get_movement_multiplier();
-                    if linum > 0 {
-                        linum -= 1;
+            }
+        }
+        if toggle_zoom {
+            self.textview_tree.toggle_zoom();
+        }
+        if let Some(delta) = resize_by {
+            self.resize_pane(&format!("{:+}", delta));
+        }
+        if let Some(edge) = focus_edge {
+            self.textview_tree.focus_neighbor(edge);
+        }
+        if focus_next {
+            self.textview_tree.focus_next();
+        }
+        if let Some(idx) = switch_tab {
+            self.switch_tab(idx);
+        }
+        if close_scratch {
+            self.close_pane();
+        }
+        if cancel_substitute {
+            if let Some(pending) = self.pending_substitution.take() {
+                self.close_substitute_preview(pending.preview);
+            }
+        }
+    }
+}
+
+// Where to open the file selected from the fuzzy popup
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FuzzyTarget {
+    Current,
+    SplitH,
+    SplitV,
+    Buffer,
+    Bookmark,
+    // Selections end with "[path:line]", like symbols and build errors
+    Location,
+    // Pick whether to write modified buffers before building
+    WriteBeforeBuild,
+    // Pick how to open a file that failed the checks before opening
+    OpenMode,
+    // Pick whether to rename a symbol in a file
+    Rename,
+    // Pick whether to write a file as root, after writing it failed
+    WriteAsRoot,
+    // Pick whether to overwrite or reload a file that was changed by something else
+    Overwrite,
+    // Pick whether to replace a match of :s with the c flag
+    Substitute,
+}
+
+// Where the output of a shell command goes
+enum ShellOutput {
+    // Shown in a split
+    Show,
+    // Inserted at the cursor of the view with the given ID
+    Insert(usize),
+}
+
+// Occurrences of a word left to rename, file by file
+struct PendingRename {
+    regex: Regex,
+    old: String,
+    new: String,
+    // Files that haven't been asked about yet, with the number of matches in each
+    files: Vec<(String, usize)>,
+    renamed: usize,
+}
+
+// Matches left to ask about for :s with the c or p flag, in the active buffer
+struct PendingSubstitution {
+    substitution: Substitution,
+    // Line and byte index in it to look for the next match from, and the last line to look in
+    line: usize,
+    offset: usize,
+    end: usize,
+    // Byte range and replacement of the match being asked about
+    current: Option<(usize, usize, String)>,
+    count: usize,
+    // ID of the pane showing the preview, with the p flag
+    preview: Option<usize>,
+}
+
+// Files under dir, skipping hidden files and directories, and IGNORED_DIRS
+fn project_files(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|e| {
+            e.file_name()
+                .to_str()
+                .map(|s| {
+                    !s.starts_with(".") && !(e.file_type().is_dir() && IGNORED_DIRS.contains(&s))
+                })
+                .unwrap_or(true)
+        })
+        .filter_map(|e| e.ok())
+        .map(|e| e.into_path())
+        .filter(|path| path.is_file())
+}
+
+// Split the active pane if a file is to be opened in a split
+fn split_for_target(tree: &mut TextViewTree, core: &mut Core, target: FuzzyTarget) {
+    match target {
+        FuzzyTarget::SplitH => tree.split_h(core.next_view_id()),
+        FuzzyTarget::SplitV => tree.split_v(core.next_view_id()),
+        _ => {}
+    }
+}
+
+// Move cursor to the first match of the pattern in prompt, starting from where the search started
+fn incremental_search(
+    textview: &mut TextView,
+    prompt: &Prompt,
+    restore: &Option<(usize, usize, Option<String>)>,
+) {
+    if let Some((linum, gidx, _)) = restore {
+        textview.go_to_line_gidx(*linum, *gidx);
+        textview.search(search_pattern(prompt), true, false);
+    }
+}
+
+// Pattern typed in the prompt after the / that starts a search
+fn search_pattern(prompt: &Prompt) -> &str {
+    let line = prompt.get_string();
+    line.strip_prefix('/').unwrap_or(line)
+}
+
+// Fence of the block opened by the text before the cursor, if it is an opening fence and the
+// block isn't closed yet. Fences before the cursor line pair up, so the line opens a block if
+// there is an even number of them. Only FENCE_SCAN_LINES lines are looked at, and blocks further
+// down than that aren't closed
+fn opened_fence(textview: &TextView, re: &Regex, before: &str) -> Option<String> {
+    let fence = re.captures(before)?.get(1)?.as_str().to_owned();
+    let linum = textview.cursor_line_num();
+    if linum > FENCE_SCAN_LINES {
+        return None;
+    }
+    let text = textview.buffer().borrow().lines_text(0, linum);
+    let nfences = text.lines().take(linum).filter(|l| re.is_match(l)).count();
+    if nfences % 2 == 0 {
+        Some(fence)
+    } else {
+        None
+    }
+}
+
+// Marker for the list item after one with the given marker. Numbers are incremented, and
+// checkboxes are cleared
+fn next_list_marker(marker: &str) -> String {
+    let ret = match marker.find(|c: char| c.is_ascii_digit()) {
+        Some(start) => {
+            let end = marker[start..]
+                .find(|c: char| !c.is_ascii_digit())
+                .map(|i| start + i)
+                .unwrap_or(marker.len());
+            match marker[start..end]
+                .parse::<u64>()
+                .ok()
+                .and_then(|n| n.checked_add(1))
+            {
+                Some(n) => format!("{}{}{}", &marker[..start], n, &marker[end..]),
+                None => marker.to_owned(),
+            }
+        }
+        None => marker.to_owned(),
+    };
+    ret.replace("[x]", "[ ]").replace("[X]", "[ ]")
+}
+
+// Record text typed in insert mode, appending to the last recorded text if possible
+fn push_insert_str(ops: &mut Vec<InsertOp>, s: &str) {
+    match ops.pop() {
+        Some(InsertOp::Str(mut t)) => {
+            t.push_str(s);
+            ops.push(InsertOp::Str(t));
+        }
+        Some(o) => {
+            ops.push(o);
+            ops.push(InsertOp::Str(s.to_owned()));
+        }
+        None => ops.push(InsertOp::Str(s.to_owned())),
+    }
+}
+
+fn replay_insert(textview: &mut TextView, ops: &[InsertOp]) {
+    for op in ops {
+        match op {
+            InsertOp::Str(s) => textview.insert_str(s),
+            InsertOp::Tab => textview.insert_char('\t'),
+            InsertOp::Backspace => textview.delete_left(1),
+            InsertOp::Delete => textview.delete_right(1),
+            InsertOp::Left => textview.move_cursor_left(1),
+            InsertOp::Right => textview.move_cursor_right(1),
+            InsertOp::Up => textview.move_cursor_up(1),
+            InsertOp::Down => textview.move_cursor_down(1),
+            InsertOp::Home => textview.move_cursor_start_of_line(),
+            InsertOp::End => textview.move_cursor_end_of_line(),
+            InsertOp::PageUp => textview.page_up(),
+            InsertOp::PageDown => textview.page_down(),
+            InsertOp::DeleteWord => textview.delete_word_left(),
+            InsertOp::DeleteToLineStart => textview.delete_to_line_start(),
+            InsertOp::Indent => textview.indent_line(),
+            InsertOp::Dedent => textview.dedent_line(),
+        }
+    }
+}
+
+// Delete text covered by a delete motion
+fn delete_motion(textview: &mut TextView, amul: usize, movop: &MovementOp) {
+    match movop {
+        MovementOp::Default(mmul) => textview.delete_lines(amul * mmul),
+        MovementOp::Left(mmul) => textview.delete_left(amul * mmul),
+        MovementOp::Right(mmul) => textview.delete_right(amul * mmul),
+        MovementOp::Up(mmul) => textview.delete_lines_up(amul * mmul),
+        MovementOp::Down(mmul) => textview.delete_lines_down(amul * mmul),
+        MovementOp::Linum(mmul) => {
+            for _ in 0..amul {
+                textview.delete_to_line(*mmul);
+            }
+        }
+        MovementOp::LastLine => {
+            for _ in 0..amul {
+                textview.delete_to_last_line();
+            }
+        }
+        MovementOp::ViewTop(_) | MovementOp::ViewMiddle | MovementOp::ViewBottom(_) => {
+            delete_to_view_line(textview, amul, movop)
+        }
+        MovementOp::LineStart => textview.delete_to_line_start(),
+        MovementOp::LineEnd => textview.delete_to_line_end(),
+        _ => {}
+    }
+}
+
+// Shift lines covered by a linewise motion from the cursor line
+fn shift_motion(textview: &mut TextView, right: bool, amul: usize, movop: &MovementOp) {
+    if let Some(linum) = view_motion_line(textview, amul, movop) {
+        return shift_motion(textview, right, 1, &MovementOp::Linum(linum));
+    }
+    let cur = textview.cursor_line_num();
+    let (start, end) = match movop {
+        MovementOp::Default(mmul) => (cur, cur + amul * mmul - 1),
+        MovementOp::Up(mmul) => (cur.saturating_sub(amul * mmul), cur),
+        MovementOp::Down(mmul) => (cur, cur + amul * mmul),
+        MovementOp::Linum(linum) => (min(cur, *linum), max(cur, *linum)),
+        MovementOp::LastLine => (cur, textview.len_lines() - 1),
+        _ => return,
+    };
+    textview.shift_lines(start, end, right);
+}
+
+// Delete text covered by a change motion. Line motions leave an empty line to insert into. The
+// cursor should be in insert mode, so that it can be placed at the end of the line
+fn change_motion(textview: &mut TextView, amul: usize, movop: &MovementOp) {
+    if let Some(linum) = view_motion_line(textview, amul, movop) {
+        return change_motion(textview, 1, &MovementOp::Linum(linum));
+    }
+    let cur = textview.cursor_line_num();
+    match movop {
+        MovementOp::Default(mmul) => textview.clear_lines(cur, amul * mmul),
+        MovementOp::Left(mmul) => textview.delete_left(amul * mmul),
+        MovementOp::Right(mmul) => textview.delete_right(amul * mmul),
+        MovementOp::Up(mmul) => {
+            let n = min(amul * mmul, cur);
+            textview.clear_lines(cur - n, n + 1);
+        }
+        MovementOp::Down(mmul) => textview.clear_lines(cur, amul * mmul + 1),
+        MovementOp::Linum(linum) => {
+            let (start, end) = if *linum < cur {
+                (*linum, cur)
+            } else {
+                (cur, *linum)
+            };
+            textview.clear_lines(start, end - start + 1);
+        }
+        MovementOp::LastLine => {
+            let last = textview.len_lines() - 1;
+            textview.clear_lines(cur, last - min(cur, last) + 1);
+        }
+        MovementOp::LineStart => textview.delete_to_line_start(),
+        MovementOp::LineEnd => textview.delete_to_line_end(),
+        _ => {}
+    }
+}
+
+// Line that H, M or L moves to, counting (amul times the motion's count) from the top or bottom
+// line that is fully in view. Resolved against the view when the motion is applied, so that
+// repeating it with . uses the view at that time
+fn view_motion_line(textview: &TextView, amul: usize, movop: &MovementOp) -> Option<usize> {
+    let (first, last) = textview.visible_lines();
+    match movop {
+        MovementOp::ViewTop(n) => Some(min(first + (amul * n).saturating_sub(1), last)),
+        MovementOp::ViewMiddle => Some(first + (last - first) / 2),
+        MovementOp::ViewBottom(n) => Some(max(
+            last.saturating_sub((amul * n).saturating_sub(1)),
+            first,
+        )),
+        _ => None,
+    }
+}
+
+fn go_to_view_line(textview: &mut TextView, movop: &MovementOp) {
+    if let Some(linum) = view_motion_line(textview, 1, movop) {
+        textview.go_to_line(linum);
+    }
+}
+
+fn delete_to_view_line(textview: &mut TextView, amul: usize, movop: &MovementOp) {
+    if let Some(linum) = view_motion_line(textview, amul, movop) {
+        textview.delete_to_line(linum);
+    }
+}
+
+// Whether register is backed by the system clipboard
+fn is_clipboard_register(cfg: &Cfg, name: Option<char>) -> bool {
+    match name {
+        Some('+') | Some('*') => true,
+        None => cfg.ui.clipboard_unnamed,
+        _ => false,
+    }
+}
+
+fn set_register(
+    window: &mut glfw::Window,
+    core: &mut Core,
+    cfg: &Cfg,
+    name: Option<char>,
+    reg: Register,
+) {
+    if is_clipboard_register(cfg, name) {
+        window.set_clipboard_string(&reg.text);
+    }
+    match name {
+        Some('+') | Some('*') => core.registers_mut().set(None, reg),
+        name => core.registers_mut().set(name, reg),
+    }
+}
+
+fn get_register(
+    window: &glfw::Window,
+    core: &Core,
+    cfg: &Cfg,
+    name: Option<char>,
+) -> Option<Register> {
+    if is_clipboard_register(cfg, name) {
+        return window.get_clipboard_string().map(Register::from_clipboard);
+    }
+    core.registers().get(name).cloned()
+}
+
+// Open the file containing mark in textview if required, and move the cursor to the mark
+fn jump_to_file_mark(
+    core: &mut Core,
+    textview: &mut TextView,
+    mark: &FileMark,
+    exact: bool,
+    dpi: Size2D<u32, DPI>,
+) {
+    // Marks hold canonical paths, so the buffer may have been opened through another path
+    let open = core.buffer_for_file(&mark.path);
+    let is_current = match &open {
+        Some(buffer) => Rc::ptr_eq(buffer, &textview.buffer()),
+        None => false,
+    };
+    if !is_current {
+        let buffer = match open {
+            Some(buffer) => buffer,
+            None => match core.new_buffer_from_file(&mark.path, dpi) {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    error!("failed to open file: {:?}: {}", mark.path, e);
+                    return;
+                }
+            },
+        };
+        let view_id = core.next_view_id();
+        textview.add_buffer(buffer, view_id);
+    }
+    let gidx = if exact { mark.gidx } else { 0 };
+    textview.go_to_line_gidx(mark.line, gidx);
+}
+
+// Commands available from the prompt
+fn builtin_commands() -> CommandRegistry {
+    let mut reg = CommandRegistry::default();
+    reg.register(
+        CommandDef::new(":quit", "close pane (! discards changes)", |w, args| {
+            w.quit_active(args.bang)
+        })
+        .alias(":q"),
+    );
+    reg.register(
+        CommandDef::new(":wq", "write buffer and close pane", |w, args| {
+            if w.write_active(args.arg(0), args.bang) {
+                w.quit_active(false);
+            }
+        })
+        .arg(ArgSpec::Optional("file"))
+        .completer(complete_path),
+    );
+    reg.register(
+        CommandDef::new(
+            ":exit",
+            "write buffer if modified and close pane",
+            |w, args| {
+                let dirty = w.textview_tree.active_mut().buffer().borrow().is_dirty();
+                if !dirty || w.write_active(args.arg(0), args.bang) {
+                    w.quit_active(false);
+                }
+            },
+        )
+        .alias(":x")
+        .arg(ArgSpec::Optional("file"))
+        .completer(complete_path),
+    );
+    reg.register(
+        CommandDef::new(
+            ":write",
+            "write buffer (! overwrites changes made by others)",
+            |w, args| {
+                w.write_active(args.arg(0), args.bang);
+            },
+        )
+        .alias(":w")
+        .arg(ArgSpec::Optional("file"))
+        .completer(complete_path),
+    );
+    reg.register(
+        CommandDef::new(
+            ":edit",
+            "open file, or reload buffer (! discards changes)",
+            |w, args| match args.arg(0) {
+                Some(fname) => w.edit_file(fname),
+                None => {
+                    let buffer = w.textview_tree.active_mut().buffer();
+                    if !args.bang && buffer.borrow().is_dirty() {
+                        warning!("buffer has unwritten changes (add ! to override)");
+                        return;
                     }
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::Linum(linum));
-                    state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    for _ in 0..act_mult {
-                        textview.delete_to_line(linum);
+                    if let Err(e) = w.textview_tree.active_mut().reload_buffer() {
+                        error!("failed to reload buffer: {}", e);
                     }
                 }
-                WindowEvent::Char('G') => {
-                    let act_mult = state.get_action_multiplier();
-                    state.movement_multiplier.clear();
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::LastLine);
-                    state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    for _ in 0..act_mult {
-                        textview.delete_to_last_line();
+            },
+        )
+        .alias(":e")
+        .arg(ArgSpec::Optional("file"))
+        .completer(complete_path),
+    );
+    reg.register(
+        CommandDef::new(":rename", "move buffer's file", |w, args| {
+            w.rename_active(args.arg(0).unwrap())
+        })
+        .arg(ArgSpec::Required("new path"))
+        .completer(complete_path),
+    );
+    reg.register(CommandDef::new(
+        ":delete-file",
+        "move buffer's file to the trash (! discards changes)",
+        |w, args| w.delete_active_file(args.bang),
+    ));
+    reg.register(CommandDef::new(
+        ":restore-file",
+        "restore last deleted file",
+        |w, _| w.restore_file(),
+    ));
+    reg.register(CommandDef::new(
+        ":retab",
+        "fix mixed indentation",
+        |w, _| w.textview_tree.active_mut().retab(),
+    ));
+    reg.register(
+        CommandDef::new(
+            ":s",
+            "replace pattern in range (/pattern/replacement/flags)",
+            |w, args| {
+                w.substitute(
+                    args.range.as_ref().map(|s| s.as_str()),
+                    &args.args.join(" "),
+                )
+            },
+        )
+        .arg(ArgSpec::Required("/pattern/replacement/flags")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":indent-style",
+            "indent with tabs or a number of spaces (detect again if empty)",
+            |w, args| {
+                let buffer = w.textview_tree.active_mut().buffer();
+                let buffer = &mut *buffer.borrow_mut();
+                match args.arg(0) {
+                    None => buffer.detect_indent_style(),
+                    Some("tabs") => {
+                        let tabsize = w.config.borrow().syntax(buffer.syntax_name()).tab_width;
+                        buffer.set_indent_style(true, tabsize as usize);
                     }
+                    Some(arg) => match arg.parse() {
+                        Ok(width) if width > 0 => buffer.set_indent_style(false, width),
+                        _ => error!("invalid indent style: {} (tabs or a width)", arg),
+                    },
                 }
-                WindowEvent::Char('d') => {
-                    let act_mult = state.get_action_multiplier();
-                    let move_mult = state.get_movement_multiplier();
-                    state.last_edit = EditOp::Delete(act_mult, MovementOp::Default(move_mult));
-                    state.mode = InputMode::Normal;
-                    textview.set_cursor_style(TextCursorStyle::Block);
-                    textview.delete_lines(act_mult * move_mult);
+            },
+        )
+        .arg(ArgSpec::Optional("tabs|width")),
+    );
+    reg.register(CommandDef::new(
+        ":comment",
+        "toggle line comments on range",
+        |w, args| {
+            let prefix = {
+                let cfg = &*w.config.borrow();
+                let buffer = w.textview_tree.active_mut().buffer();
+                let syntax = cfg.syntax(buffer.borrow().syntax_name());
+                syntax.line_comment.clone()
+            };
+            let (start, end) = w.line_range(args.range.as_ref().map(|s| s.as_str()));
+            match prefix {
+                Some(prefix) => w
+                    .textview_tree
+                    .active_mut()
+                    .transform_lines(start, end, |s| toggle_comment(s, &prefix)),
+                None => error!("no line comment for this language"),
+            }
+        },
+    ));
+    reg.register(CommandDef::new(":>", "indent range", |w, args| {
+        let (start, end) = w.line_range(args.range.as_ref().map(|s| s.as_str()));
+        w.textview_tree.active_mut().shift_lines(start, end, true)
+    }));
+    reg.register(CommandDef::new(":<", "dedent range", |w, args| {
+        let (start, end) = w.line_range(args.range.as_ref().map(|s| s.as_str()));
+        w.textview_tree.active_mut().shift_lines(start, end, false)
+    }));
+    reg.register(
+        CommandDef::new(":=", "evaluate arithmetic expression", |w, args| {
+            w.eval_expression(&args.raw, false)
+        })
+        .arg(ArgSpec::Required("expression")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":put",
+            "insert result of =expression at cursor",
+            |w, args| match args.raw.strip_prefix('=') {
+                Some(expr) => w.eval_expression(expr, true),
+                None => error!(":put: only =expression is supported"),
+            },
+        )
+        .arg(ArgSpec::Required("=expression")),
+    );
+    reg.register(CommandDef::new(
+        ":sort",
+        "sort lines in range, or all lines (! reverses)",
+        |w, args| {
+            let (start, end) = match &args.range {
+                Some(range) => w.line_range(Some(range)),
+                None => (0, w.textview_tree.active_mut().len_lines() - 1),
+            };
+            w.textview_tree
+                .active_mut()
+                .sort_lines(start, end, args.bang)
+        },
+    ));
+    reg.register(CommandDef::new(":upper", "uppercase range", |w, args| {
+        let (start, end) = w.line_range(args.range.as_ref().map(|s| s.as_str()));
+        w.textview_tree
+            .active_mut()
+            .transform_lines(start, end, |s| s.to_uppercase())
+    }));
+    reg.register(CommandDef::new(":lower", "lowercase range", |w, args| {
+        let (start, end) = w.line_range(args.range.as_ref().map(|s| s.as_str()));
+        w.textview_tree
+            .active_mut()
+            .transform_lines(start, end, |s| s.to_lowercase())
+    }));
+    reg.register(CommandDef::new(":title", "title case range", |w, args| {
+        let (start, end) = w.line_range(args.range.as_ref().map(|s| s.as_str()));
+        w.textview_tree
+            .active_mut()
+            .transform_lines(start, end, to_title_case)
+    }));
+    reg.register(
+        CommandDef::new(":bnext", "next buffer in pane", |w, _| {
+            w.textview_tree.active_mut().next_buffer()
+        })
+        .alias(":bn"),
+    );
+    reg.register(
+        CommandDef::new(":bprevious", "previous buffer in pane", |w, _| {
+            w.textview_tree.active_mut().prev_buffer()
+        })
+        .alias(":bp"),
+    );
+    reg.register(
+        CommandDef::new(":balternate", "alternate buffer in pane", |w, _| {
+            w.textview_tree.active_mut().alternate_buffer()
+        })
+        .alias(":b#"),
+    );
+    reg.register(
+        CommandDef::new(
+            ":bdelete",
+            "close buffer (! discards changes)",
+            |w, args| w.delete_active_buffer(false, args.bang),
+        )
+        .alias(":bd"),
+    );
+    reg.register(
+        CommandDef::new(
+            ":bwipeout",
+            "close buffer and free its memory (! discards changes)",
+            |w, args| w.delete_active_buffer(true, args.bang),
+        )
+        .alias(":bw"),
+    );
+    reg.register(
+        CommandDef::new(
+            ":buffer",
+            "switch to buffer by number or name",
+            |w, args| {
+                let arg = match args.arg(0) {
+                    Some(arg) => arg,
+                    None => return w.start_buffer_fuzzy(),
+                };
+                if let Ok(id) = arg.parse() {
+                    return w.switch_to_buffer(id);
                 }
-                WindowEvent::Char(c) if c.is_digit(10) => {
-                    state.movement_multiplier.push(c);
+                let core = &mut *w.core.borrow_mut();
+                match core.buffer_by_name(arg) {
+                    Some(buffer) => {
+                        let view_id = core.next_view_id();
+                        w.textview_tree.active_mut().show_buffer(buffer, view_id);
+                    }
+                    None => error!("no unique buffer matching: {}", arg),
                 }
-                _ => {}
             },
+        )
+        .alias(":b")
+        .arg(ArgSpec::Optional("number or name"))
+        .completer(|w, partial| {
+            (&*w.core.borrow())
+                .buffer_list()
+                .into_iter()
+                .map(|(_, path, _)| path)
+                .filter(|path| path.starts_with(partial))
+                .collect()
+        }),
+    );
+    reg.register(
+        CommandDef::new(":buffers", "list buffers", |w, _| w.start_buffer_fuzzy()).alias(":ls"),
+    );
+    reg.register(
+        CommandDef::new(":bookmark", "bookmark cursor position", |w, args| {
+            w.add_bookmark(args.arg(0).unwrap())
+        })
+        .arg(ArgSpec::Required("name")),
+    );
+    reg.register(
+        CommandDef::new(":delbookmark", "delete bookmark", |w, args| {
+            let name = args.arg(0).unwrap();
+            if !(&mut *w.core.borrow_mut())
+                .marks_mut()
+                .remove_bookmark(name)
+            {
+                error!("no such bookmark: {}", name);
+            }
+        })
+        .arg(ArgSpec::Required("name"))
+        .completer(|w, partial| {
+            (&*w.core.borrow())
+                .marks()
+                .bookmarks()
+                .iter()
+                .map(|(name, _)| name.to_owned())
+                .filter(|name| name.starts_with(partial))
+                .collect()
+        }),
+    );
+    reg.register(CommandDef::new(":bookmarks", "pick bookmark", |w, _| {
+        w.start_bookmark_fuzzy()
+    }));
+    reg.register(
+        CommandDef::new(":note", "attach note to cursor line", |w, args| {
+            w.set_note(&args.args.join(" "))
+        })
+        .arg(ArgSpec::Required("text")),
+    );
+    reg.register(CommandDef::new(
+        ":delnote",
+        "delete note on cursor line",
+        |w, _| w.delete_note(),
+    ));
+    reg.register(CommandDef::new(":notes", "pick note in project", |w, _| {
+        w.start_notes_fuzzy()
+    }));
+    reg.register(CommandDef::new(
+        ":symbols",
+        "pick function or heading in project",
+        |w, _| w.start_symbol_fuzzy(),
+    ));
+    reg.register(
+        CommandDef::new(":vsplit", "split pane side by side", |w, _| {
+            let core = &mut *w.core.borrow_mut();
+            w.textview_tree.split_h(core.next_view_id());
+        })
+        .alias(":vsp"),
+    );
+    reg.register(
+        CommandDef::new(":split", "split pane one above the other", |w, _| {
+            let core = &mut *w.core.borrow_mut();
+            w.textview_tree.split_v(core.next_view_id());
+        })
+        .alias(":sp"),
+    );
+    reg.register(CommandDef::new(
+        ":follow",
+        "open read-only pane mirroring this one",
+        |w, _| {
+            let core = &mut *w.core.borrow_mut();
+            w.textview_tree.split_follow(core.next_view_id());
+        },
+    ));
+    reg.register(
+        CommandDef::new(
+            ":scroll-up",
+            "scroll lines up without moving cursor",
+            |w, args| {
+                let n = args.arg(0).and_then(|s| s.parse().ok()).unwrap_or(1);
+                w.textview_tree.active_mut().scroll_lines(n, false)
+            },
+        )
+        .arg(ArgSpec::Optional("lines")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":scroll-down",
+            "scroll lines down without moving cursor",
+            |w, args| {
+                let n = args.arg(0).and_then(|s| s.parse().ok()).unwrap_or(1);
+                w.textview_tree.active_mut().scroll_lines(n, true)
+            },
+        )
+        .arg(ArgSpec::Optional("lines")),
+    );
+    reg.register(CommandDef::new(
+        ":scroll-page-up",
+        "scroll page up without moving cursor",
+        |w, _| w.textview_tree.active_mut().scroll_page(false),
+    ));
+    reg.register(CommandDef::new(
+        ":scroll-page-down",
+        "scroll page down without moving cursor",
+        |w, _| w.textview_tree.active_mut().scroll_page(true),
+    ));
+    reg.register(CommandDef::new(":zoom", "toggle pane zoom", |w, _| {
+        w.textview_tree.toggle_zoom()
+    }));
+    reg.register(CommandDef::new(
+        ":present",
+        "toggle presentation mode in pane (! for all panes)",
+        |w, args| w.toggle_presentation(args.bang),
+    ));
+    reg.register(
+        CommandDef::new(
+            ":resize",
+            "set pane width to a percentage of its split (+N or -N to change it)",
+            |w, args| w.resize_pane(args.arg(0).unwrap_or("")),
+        )
+        .arg(ArgSpec::Required("[+-]N")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":tabnew",
+            "open tab, with an empty buffer or file",
+            |w, args| w.new_tab(args.arg(0)),
+        )
+        .alias(":tabedit")
+        .arg(ArgSpec::Optional("file"))
+        .completer(complete_path),
+    );
+    reg.register(
+        CommandDef::new(":tabnext", "go to next tab, or tab N", |w, args| match args
+            .arg(0)
+            .map(|s| s.parse::<usize>())
+        {
+            None => w.switch_tab((w.tabs.active() + 1) % w.tabs.len()),
+            Some(Ok(n)) if n > 0 => w.switch_tab(n - 1),
+            _ => error!("invalid tab number: {}", args.arg(0).unwrap()),
+        })
+        .alias(":tabn")
+        .arg(ArgSpec::Optional("N")),
+    );
+    reg.register(
+        CommandDef::new(":tabprevious", "go to previous tab", |w, _| {
+            w.switch_tab((w.tabs.active() + w.tabs.len() - 1) % w.tabs.len())
+        })
+        .alias(":tabprev")
+        .alias(":tabp"),
+    );
+    reg.register(
+        CommandDef::new(
+            ":tabclose",
+            "close tab, keeping its buffers open",
+            |w, _| w.close_tab(),
+        )
+        .alias(":tabc"),
+    );
+    reg.register(CommandDef::new(":fzf", "pick file", |w, _| {
+        w.start_file_fuzzy(FuzzyTarget::Current, None)
+    }));
+    reg.register(
+        CommandDef::new(":sfind", "pick file to open in a split", |w, args| {
+            let pattern = args.arg(0).map(|s| s.to_owned());
+            w.start_file_fuzzy(FuzzyTarget::SplitV, pattern);
+        })
+        .alias(":sf")
+        .arg(ArgSpec::Optional("pattern")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":vertical",
+            "split side by side for command",
+            |w, args| match args.arg(0) {
+                Some("sf") | Some("sfind") => {
+                    let pattern = args.arg(1).map(|s| s.to_owned());
+                    w.start_file_fuzzy(FuzzyTarget::SplitH, pattern);
+                }
+                _ => error!(":vertical only supports sfind"),
+            },
+        )
+        .alias(":vert")
+        .arg(ArgSpec::Required("command"))
+        .completer(|_, partial| {
+            ["sfind"]
+                .iter()
+                .filter(|s| s.starts_with(partial))
+                .map(|s| s.to_string())
+                .collect()
+        }),
+    );
+    reg.register(
+        CommandDef::new(
+            ":preview-image",
+            "show image file, or image link under cursor",
+            |w, args| w.preview_image(args.arg(0)),
+        )
+        .arg(ArgSpec::Optional("file"))
+        .completer(complete_path),
+    );
+    reg.register(
+        CommandDef::new(
+            ":set",
+            "change option, like rootmarkers=.git,Makefile",
+            |w, args| w.set_option(args.arg(0).unwrap()),
+        )
+        .arg(ArgSpec::Required("name[=value]"))
+        .completer(|_, partial| {
+            let mut options = vec![
+                "rootmarkers=".to_owned(),
+                "colorcolumn=".to_owned(),
+                "cursorline".to_owned(),
+                "nocursorline".to_owned(),
+            ];
+            options.extend(ENCODING_NAMES.iter().map(|e| format!("fileencoding={}", e)));
+            options
+                .into_iter()
+                .filter(|s| s.starts_with(partial))
+                .collect()
+        }),
+    );
+    reg.register(CommandDef::new(":config", "open config file", |w, _| {
+        w.open_config()
+    }));
+    reg.register(CommandDef::new(
+        ":reload-config",
+        "reload config file",
+        |w, _| w.reload_config(),
+    ));
+    reg.register(CommandDef::new(
+        ":checkhealth",
+        "show environment information",
+        |w, _| {
+            let lines = w.health_report();
+            w.show_output(&lines);
+        },
+    ));
+    reg.register(CommandDef::new(
+        ":messages",
+        "show earlier messages (! clears them)",
+        |w, args| w.show_messages(args.bang),
+    ));
+    reg.register(
+        CommandDef::new(":make", "run build command", |w, args| {
+            let command = if args.args.is_empty() {
+                None
+            } else {
+                Some(args.args.join(" "))
+            };
+            w.make(command.as_ref().map(|s| s.as_str()))
+        })
+        .arg(ArgSpec::Optional("command")),
+    );
+    reg.register(CommandDef::new(
+        ":errors",
+        "pick error from last build",
+        |w, _| w.start_build_errors_fuzzy(),
+    ));
+    reg.register(
+        CommandDef::new(":gitshow", "show file as of git revision", |w, args| {
+            w.git_show(args.arg(0).unwrap())
+        })
+        .arg(ArgSpec::Required("rev[:path]")),
+    );
+    reg.register(
+        CommandDef::new(":!", "run shell command and show its output", |w, args| {
+            w.run_shell(&args.raw, false)
+        })
+        .arg(ArgSpec::Required("command")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":read",
+            "insert file, or output of !command",
+            |w, args| match args.raw.strip_prefix('!') {
+                Some(command) => w.run_shell(command.trim_start(), true),
+                None => w.read_file(&args.raw),
+            },
+        )
+        .alias(":r")
+        .arg(ArgSpec::Required("file|!command")),
+    );
+    reg.register(
+        CommandDef::new(":grep", "search files for pattern", |w, args| {
+            w.start_grep(&args.args.join(" "))
+        })
+        .arg(ArgSpec::Required("pattern")),
+    );
+    reg.register(
+        CommandDef::new(
+            ":rename-symbol",
+            "rename word under cursor in all files",
+            |w, args| w.rename_symbol(args.arg(0).unwrap()),
+        )
+        .arg(ArgSpec::Required("new")),
+    );
+    reg.register(
+        CommandDef::new(":mksession", "save open files and panes", |w, args| {
+            w.make_session(args.arg(0))
+        })
+        .arg(ArgSpec::Optional("name")),
+    );
+    reg.register(CommandDef::new(":commands", "list commands", |w, _| {
+        let lines = w.command_list();
+        w.show_output(&lines);
+    }));
+    reg
+}
+
+// Target of the markdown image link, like ![alt](path "title"), under the cursor
+fn image_link_at_cursor(textview: &TextView) -> Option<String> {
+    if textview.buffer().borrow().syntax_name() != "markdown" {
+        return None;
+    }
+    let line = textview.lines_text(1);
+    let cursor = textview.text_around_cursor().0.len();
+    let re = Regex::new(r#"!\[[^\]]*\]\(\s*<?([^)\s>]+)>?(?:\s+"[^"]*")?\s*\)"#).unwrap();
+    for caps in re.captures_iter(&line) {
+        let m = caps.get(0).unwrap();
+        if m.start() <= cursor && cursor < m.end() {
+            return Some(caps[1].to_owned());
+        }
+    }
+    None
+}
+
+// Paths starting with partial path, relative to the working directory. Directories end with "/"
+fn complete_path(window: &Window, partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind('/') {
+        Some(idx) => partial.split_at(idx + 1),
+        None => ("", partial),
+    };
+    let entries = match std::fs::read_dir(window.absolute_path(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut ret = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            // Hidden files are only completed when asked for
+            if !name.starts_with(prefix) || (prefix.is_empty() && name.starts_with('.')) {
+                return None;
+            }
+            let suffix = if e.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, suffix))
+        })
+        .collect::<Vec<_>>();
+    ret.sort();
+    ret
+}
+
+// Longest common prefix of strings, or None if there are none
+fn common_prefix(strings: &[String]) -> Option<&str> {
+    let first = strings.first()?;
+    let mut len = first.len();
+    for s in &strings[1..] {
+        len = first
+            .char_indices()
+            .zip(s.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(len);
+    }
+    Some(&first[..len])
+}
+
+// Comment out lines with the line comment prefix, or uncomment them if all non-blank lines are
+// already commented out. Prefixes are added after each line's indentation
+fn toggle_comment(s: &str, prefix: &str) -> String {
+    let lines = s.split('\n').collect::<Vec<_>>();
+    let uncomment = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .all(|l| l.trim_start().starts_with(prefix));
+    lines
+        .into_iter()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() {
+                return line.to_owned();
+            }
+            let indent = &line[..(line.len() - trimmed.len())];
+            if uncomment {
+                let rest = &trimmed[prefix.len()..];
+                format!("{}{}", indent, rest.strip_prefix(' ').unwrap_or(rest))
+            } else {
+                format!("{}{} {}", indent, prefix, trimmed)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Uppercase the first character of every word, and lowercase the rest
+fn to_title_case(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for word in s.split_word_bounds() {
+        let mut chars = word.chars();
+        if let Some(c) = chars.next() {
+            ret.extend(c.to_uppercase());
+            ret.push_str(&chars.as_str().to_lowercase());
         }
     }
+    ret
+}
+
+// File name of path, or the whole path if it has none
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or(path)
+}
+
+// Human-readable size in bytes
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -952,6 +5259,23 @@ enum InputMode {
     Command,
     Fuzzy,
     DeleteMotion,
+    ChangeMotion,
+    YankMotion,
+    // Indent (true) or dedent lines covered by a motion
+    ShiftMotion(bool),
+    // Select register for the next yank, delete or paste
+    SelectRegister,
+    WindowCommand,
+    // Key after g, like gg or gt
+    GoPrefix,
+    Search,
+    SetMark,
+    // Jump to mark. Jump to the exact column if set, else to the start of the line
+    JumpMark(bool),
+    // Text is selected from where the mode was entered. Whole lines are selected if set
+    Visual(bool),
+    // Keys typed after the leader key, until they match a user mapping
+    Leader,
 }
 
 impl Default for InputMode {
@@ -960,6 +5284,195 @@ impl Default for InputMode {
     }
 }
 
+impl InputMode {
+    // Modes that belong to a view, rather than popups and prefix keys for the whole window
+    fn is_view_mode(&self) -> bool {
+        match self {
+            InputMode::Insert
+            | InputMode::Normal
+            | InputMode::DeleteMotion
+            | InputMode::ChangeMotion
+            | InputMode::YankMotion
+            | InputMode::ShiftMotion(_)
+            | InputMode::Visual(_) => true,
+            _ => false,
+        }
+    }
+
+    fn cursor_style(&self) -> TextCursorStyle {
+        match self {
+            InputMode::Insert => TextCursorStyle::Beam,
+            InputMode::DeleteMotion
+            | InputMode::ChangeMotion
+            | InputMode::YankMotion
+            | InputMode::ShiftMotion(_) => TextCursorStyle::Underline,
+            _ => TextCursorStyle::Block,
+        }
+    }
+
+    // Name shown in the statusline. Modes waiting for the key after a prefix are part of normal
+    // mode
+    fn name(&self) -> &'static str {
+        match self {
+            InputMode::Insert => "INSERT",
+            InputMode::Command => "COMMAND",
+            InputMode::Fuzzy => "FUZZY",
+            InputMode::Search => "SEARCH",
+            InputMode::Visual(false) => "VISUAL",
+            InputMode::Visual(true) => "VISUAL LINE",
+            _ => "NORMAL",
+        }
+    }
+
+    // Title and keys that can follow, if this mode is waiting for the key after a prefix
+    fn key_hints(&self) -> Option<(&'static str, Vec<(&'static str, &'static str)>)> {
+        match self {
+            InputMode::DeleteMotion => Some(("delete", operator_hints("d", false))),
+            InputMode::ChangeMotion => Some(("change", operator_hints("c", false))),
+            InputMode::YankMotion => Some(("yank", vec![("y", "lines")])),
+            InputMode::ShiftMotion(true) => Some(("indent", operator_hints(">", true))),
+            InputMode::ShiftMotion(false) => Some(("dedent", operator_hints("<", true))),
+            InputMode::SelectRegister => Some((
+                "register",
+                vec![
+                    ("a-z 0-9", "named register"),
+                    ("+ *", "system clipboard"),
+                    ("\"", "unnamed register"),
+                ],
+            )),
+            InputMode::WindowCommand => Some((
+                "window",
+                vec![
+                    ("h j k l", "focus pane left, below, above, right"),
+                    ("w", "focus next pane"),
+                    ("z", "toggle zoom"),
+                    (">", "widen pane"),
+                    ("<", "narrow pane"),
+                ],
+            )),
+            InputMode::GoPrefix => Some((
+                "go",
+                vec![
+                    ("g", "first line, or line N"),
+                    ("t", "next tab, or tab N"),
+                    ("T", "previous tab"),
+                ],
+            )),
+            InputMode::SetMark => Some((
+                "set mark",
+                vec![("a-z", "mark in buffer"), ("A-Z", "mark across files")],
+            )),
+            InputMode::JumpMark(exact) => Some((
+                if *exact {
+                    "jump to mark"
+                } else {
+                    "jump to line of mark"
+                },
+                vec![("a-z", "mark in buffer"), ("A-Z", "mark across files")],
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Motion that can follow an operator like d or c
+struct OperatorMotion {
+    key: &'static str,
+    hint: &'static str,
+    // Whether the motion covers whole lines, so that it can follow > and <
+    linewise: bool,
+    // Movement for the count typed before the motion
+    op: fn(usize) -> MovementOp,
+}
+
+// Motions that operators dispatch on, and list in key hints. Each operator's own key, like dd,
+// covers lines too, and is handled by the operator
+const OPERATOR_MOTIONS: &[OperatorMotion] = &[
+    OperatorMotion {
+        key: "h",
+        hint: "characters left",
+        linewise: false,
+        op: MovementOp::Left,
+    },
+    OperatorMotion {
+        key: "l",
+        hint: "characters right",
+        linewise: false,
+        op: MovementOp::Right,
+    },
+    OperatorMotion {
+        key: "j",
+        hint: "lines down",
+        linewise: true,
+        op: MovementOp::Down,
+    },
+    OperatorMotion {
+        key: "k",
+        hint: "lines up",
+        linewise: true,
+        op: MovementOp::Up,
+    },
+    OperatorMotion {
+        key: "0",
+        hint: "to start of line",
+        linewise: false,
+        op: |_| MovementOp::LineStart,
+    },
+    OperatorMotion {
+        key: "$",
+        hint: "to end of line",
+        linewise: false,
+        op: |_| MovementOp::LineEnd,
+    },
+    OperatorMotion {
+        key: "g",
+        hint: "to line (count)",
+        linewise: true,
+        op: |n| MovementOp::Linum(n.saturating_sub(1)),
+    },
+    OperatorMotion {
+        key: "G",
+        hint: "to last line",
+        linewise: true,
+        op: |_| MovementOp::LastLine,
+    },
+    OperatorMotion {
+        key: "H",
+        hint: "to top of view",
+        linewise: true,
+        op: MovementOp::ViewTop,
+    },
+    OperatorMotion {
+        key: "M",
+        hint: "to middle of view",
+        linewise: true,
+        op: |_| MovementOp::ViewMiddle,
+    },
+    OperatorMotion {
+        key: "L",
+        hint: "to bottom of view",
+        linewise: true,
+        op: MovementOp::ViewBottom,
+    },
+];
+
+// Motion for a key typed after an operator. Only linewise motions, if linewise is set
+fn operator_motion(c: char, linewise: bool) -> Option<&'static OperatorMotion> {
+    OPERATOR_MOTIONS
+        .iter()
+        .find(|m| m.key.starts_with(c) && (m.linewise || !linewise))
+}
+
+// Key hints for an operator, whose own key covers lines
+fn operator_hints(key: &'static str, linewise: bool) -> Vec<(&'static str, &'static str)> {
+    OPERATOR_MOTIONS
+        .iter()
+        .filter(|m| m.linewise || !linewise)
+        .map(|m| (m.key, m.hint))
+        .chain(std::iter::once((key, "lines")))
+        .collect()
+}
+
 #[derive(Debug)]
 struct InputState {
     mode: InputMode,
@@ -967,6 +5480,11 @@ struct InputState {
     movement_multiplier: String,
     cur_insert_ops: Vec<InsertOp>,
     last_edit: EditOp,
+    // Change waiting for the insert that follows it to finish, so it can be repeated
+    pending_change: Option<EditOp>,
+    register: Option<char>,
+    // Keys typed after the leader key so far
+    leader_keys: String,
 }
 
 impl Default for InputState {
@@ -977,11 +5495,55 @@ impl Default for InputState {
             movement_multiplier: String::new(),
             cur_insert_ops: Vec::new(),
             last_edit: EditOp::None,
+            pending_change: None,
+            register: None,
+            leader_keys: String::new(),
         }
     }
 }
 
+// Input state that belongs to a view, saved while another view is active
+#[derive(Default)]
+struct ViewInputState {
+    mode: InputMode,
+    action_multiplier: String,
+    movement_multiplier: String,
+    cur_insert_ops: Vec<InsertOp>,
+    pending_change: Option<EditOp>,
+    register: Option<char>,
+}
+
 impl InputState {
+    // Take the state of the active view. Popups and prefix keys aren't saved with it
+    fn take_view_state(&mut self) -> ViewInputState {
+        ViewInputState {
+            mode: if self.mode.is_view_mode() {
+                self.mode
+            } else {
+                InputMode::Normal
+            },
+            action_multiplier: std::mem::take(&mut self.action_multiplier),
+            movement_multiplier: std::mem::take(&mut self.movement_multiplier),
+            cur_insert_ops: std::mem::take(&mut self.cur_insert_ops),
+            pending_change: self.pending_change.take(),
+            register: self.register.take(),
+        }
+    }
+
+    // Restore everything but the mode, which is left to the caller
+    fn restore_view_state(&mut self, saved: ViewInputState) {
+        self.action_multiplier = saved.action_multiplier;
+        self.movement_multiplier = saved.movement_multiplier;
+        self.cur_insert_ops = saved.cur_insert_ops;
+        self.pending_change = saved.pending_change;
+        self.register = saved.register;
+    }
+
+    // Whether c continues the count typed after an operator. 0 on its own is a motion
+    fn is_count_digit(&self, c: char) -> bool {
+        c.is_ascii_digit() && (c != '0' || !self.movement_multiplier.is_empty())
+    }
+
     fn get_movement_multiplier(&mut self) -> usize {
         if self.movement_multiplier.len() == 0 {
             1
@@ -1009,6 +5571,8 @@ struct Insert(Vec<InsertOp>);
 #[derive(Clone, Debug)]
 enum InsertOp {
     Str(String),
+    // Tab, which inserts spaces if the buffer doesn't indent with tabs
+    Tab,
     Backspace,
     Delete,
     Left,
@@ -1019,16 +5583,22 @@ enum InsertOp {
     End,
     PageUp,
     PageDown,
+    DeleteWord,
+    DeleteToLineStart,
+    Indent,
+    Dedent,
 }
 
 #[derive(Debug)]
 enum EditOp {
     None,
     Delete(usize, MovementOp),
-    Change(usize, MovementOp),
+    Change(usize, MovementOp, Insert),
     DelChar(usize),
-    SubstChar(usize),
+    SubstChar(usize, Insert),
     Insert(usize, Insert),
+    // Shift lines right (true) or left
+    Shift(bool, usize, MovementOp),
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -1039,6 +5609,10 @@ enum MovementOp {
     Up(usize),
     Down(usize),
     LastLine,
+    // Count lines from the top or bottom of the view, or the middle of the view
+    ViewTop(usize),
+    ViewMiddle,
+    ViewBottom(usize),
     LineStart,
     LineEnd,
     NextWord,
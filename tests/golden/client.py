@@ -0,0 +1,41 @@
+#!/usr/bin/env python3
+# Send requests to bed's debug socket, one per argument, and print the replies. Exits with an
+# error if a "golden" request doesn't reply "ok" or "written: ..."
+
+import socket
+import sys
+import time
+
+
+def connect(path):
+    # The editor creates the socket once its window is up
+    for _ in range(100):
+        try:
+            sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+            sock.connect(path)
+            return sock
+        except OSError:
+            time.sleep(0.1)
+    sys.exit("couldn't connect to " + path)
+
+
+def main():
+    sock = connect(sys.argv[1])
+    replies = sock.makefile("r", encoding="utf-8")
+    failed = False
+    for request in sys.argv[2:]:
+        sock.sendall((request + "\n").encode("utf-8"))
+        lines = []
+        for line in replies:
+            line = line.rstrip("\n")
+            if not line:
+                break
+            lines.append(line)
+        reply = "\n".join(lines)
+        print(reply)
+        if request.startswith("golden ") and not (reply == "ok" or reply.startswith("written: ")):
+            failed = True
+    sys.exit(1 if failed else 0)
+
+
+main()
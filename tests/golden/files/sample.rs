@@ -0,0 +1,16 @@
+// Highlighted with the built-in theme
+use std::collections::HashMap;
+
+/// Count words in text
+fn count(text: &str) -> HashMap<&str, usize> {
+    let mut counts = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    counts
+}
+
+fn main() {
+    let text = "the quick brown fox jumps over the lazy dog";
+    println!("{:?} ({} words)", count(text), 9);
+}
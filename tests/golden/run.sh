@@ -0,0 +1,86 @@
+#!/bin/sh
+# Render known files with the built-in config, and compare the window against the golden images
+# in expected/. Needs a build of bed, Xvfb, python3, fontconfig's tools and version 2.37 of the
+# DejaVu Sans and DejaVu Sans Mono fonts. OpenGL can be Mesa's software renderer.
+#
+# The golden images are written with UPDATE=1, checked, and committed to expected/. The cargo
+# tests only cover comparing images, so rendering itself is only checked here.
+#
+#   BED=path/to/bed     editor to run, target/debug/bed by default
+#   UPDATE=1            write the golden images again, instead of comparing against them
+#   TOLERANCE=n         how much each color channel may differ, 2 by default
+#
+# On a mismatch the frame is saved as expected/<case>.actual.png
+
+set -eu
+
+dir=$(cd "$(dirname "$0")" && pwd)
+
+# Xvfb gives the same screen and DPI everywhere
+if [ -z "${GOLDEN_XVFB:-}" ]; then
+    GOLDEN_XVFB=1 exec xvfb-run -a -s "-screen 0 1280x1024x24 -dpi 96" "$0" "$@"
+fi
+
+bed=${BED:-$dir/../../target/debug/bed}
+tolerance=${TOLERANCE:-2}
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# Only the installed DejaVu fonts are visible to the editor, so that golden images don't depend on
+# the other fonts installed. Other versions draw different glyphs
+mkdir "$work/fonts"
+for family in "DejaVu Sans" "DejaVu Sans Mono"; do
+    found=$(fc-match -f '%{family}\n%{fontversion}\n%{file}' "$family:style=Book")
+    if [ "$(echo "$found" | sed -n 1p)" != "$family" ] ||
+        [ "$(echo "$found" | sed -n 2p)" != 155320 ]; then
+        echo "version 2.37 of $family isn't installed"
+        exit 1
+    fi
+    ln -s "$(echo "$found" | sed -n 3p)" "$work/fonts/"
+done
+cp "$dir/fonts.conf" "$work/fonts.conf"
+export FONTCONFIG_FILE="$work/fonts.conf"
+export LIBGL_ALWAYS_SOFTWARE=1
+
+failed=0
+
+# check <case> <file> [requests...]: open file, send requests, and compare the window against
+# expected/<case>.png
+check() {
+    name=$1
+    file=$2
+    shift 2
+    golden="$dir/expected/$name.png"
+    if [ -n "${UPDATE:-}" ]; then
+        rm -f "$golden"
+    elif [ ! -f "$golden" ]; then
+        echo "$name: missing $golden (run with UPDATE=1 to write it)"
+        failed=1
+        return
+    fi
+    cp "$dir/files/$file" "$work/$file"
+    (cd "$work" && exec "$bed" --default-config --debug-socket "$work/socket" "$file") &
+    pid=$!
+    if python3 "$dir/client.py" "$work/socket" "$@" "golden $golden $tolerance" \
+        > "$work/reply"; then
+        echo "$name: $(tail -n 1 "$work/reply")"
+    else
+        echo "$name: $(cat "$work/reply")"
+        failed=1
+    fi
+    kill "$pid" 2> /dev/null || true
+    wait "$pid" 2> /dev/null || true
+}
+
+# Shaping: tabs, combining marks, missing glyphs, kerning
+check unicode unicode.txt
+# Block cursor in normal mode, on a letter with combining marks
+check cursor-normal unicode.txt "keys j\$"
+# Beam cursor in insert mode, after moving over combining marks
+check cursor-insert unicode.txt "keys j\$i"
+# Selection in visual mode
+check visual unicode.txt "keys jv\$"
+# Syntax highlighting and the rest of the built-in theme
+check syntax sample.rs
+
+exit $failed